@@ -0,0 +1,287 @@
+use num_bigint::BigUint;
+use std::fmt;
+use std::str::FromStr;
+
+// keccak256("Transfer(address,address,uint256)"), shared by ERC-20 and ERC-721
+pub const TRANSFER_TOPIC: &str = "0xddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef";
+
+const WORD: usize = 32;
+
+// ---- uint256 ----
+// Stored big-endian, the way the ABI lays out a word
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct U256([u8; WORD]);
+
+impl U256 {
+    pub fn from_be_bytes(bytes: [u8; WORD]) -> Self {
+        U256(bytes)
+    }
+
+    pub fn to_be_bytes(self) -> [u8; WORD] {
+        self.0
+    }
+
+    // Accepts up to 64 hex digits, with or without a 0x prefix
+    pub fn from_hex(hex: &str) -> Result<Self, String> {
+        let digits = hex.strip_prefix("0x").unwrap_or(hex);
+        if digits.is_empty() || digits.len() > 2 * WORD {
+            return Err(format!("{} is not a 256-bit hex number.", hex));
+        }
+        let padded = format!("{:0>64}", digits);
+        let bytes = decode_hex(&padded).map_err(|_| format!("{} is not a 256-bit hex number.", hex))?;
+        Ok(U256(bytes.try_into().expect("64 hex digits are 32 bytes")))
+    }
+
+    // Full 32-byte word, as it appears in topics and call data
+    pub fn to_hex(self) -> String {
+        format!("0x{}", encode_hex(&self.0))
+    }
+
+    pub fn to_u64(self) -> Option<u64> {
+        let (high, low) = self.0.split_at(WORD - 8);
+        high.iter().all(|b| *b == 0).then(|| u64::from_be_bytes(low.try_into().unwrap()))
+    }
+
+    fn to_usize(self) -> Option<usize> {
+        self.to_u64().and_then(|v| usize::try_from(v).ok())
+    }
+
+    fn from_word(data: &[u8], at: usize) -> Result<Self, String> {
+        at.checked_add(WORD)
+            .and_then(|end| data.get(at..end))
+            .map(|word| U256(word.try_into().unwrap()))
+            .ok_or_else(|| format!("ABI data ends before the word at byte {}.", at))
+    }
+}
+
+impl From<u64> for U256 {
+    fn from(value: u64) -> Self {
+        let mut bytes = [0; WORD];
+        bytes[WORD - 8..].copy_from_slice(&value.to_be_bytes());
+        U256(bytes)
+    }
+}
+
+// Decimal, which is how token ids are shown and stored
+impl FromStr for U256 {
+    type Err = String;
+
+    fn from_str(decimal: &str) -> Result<Self, String> {
+        let invalid = || format!("{} is not a 256-bit decimal number.", decimal);
+        if decimal.is_empty() || !decimal.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        let value = BigUint::parse_bytes(decimal.as_bytes(), 10).ok_or_else(invalid)?;
+        let digits = value.to_bytes_be();
+        if digits.len() > WORD {
+            return Err(invalid());
+        }
+        let mut bytes = [0; WORD];
+        bytes[WORD - digits.len()..].copy_from_slice(&digits);
+        Ok(U256(bytes))
+    }
+}
+
+impl fmt::Display for U256 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", BigUint::from_bytes_be(&self.0))
+    }
+}
+
+// ---- Encoding ----
+pub enum AbiToken {
+    Uint(U256),
+    String(String),
+}
+
+// Head words for every token, followed by the tails of the dynamic ones
+pub fn encode_tokens(tokens: &[AbiToken]) -> Vec<u8> {
+    let head_size = WORD * tokens.len();
+    let mut head = Vec::with_capacity(head_size);
+    let mut tail = Vec::new();
+    for token in tokens {
+        match token {
+            AbiToken::Uint(value) => head.extend(value.to_be_bytes()),
+            AbiToken::String(value) => {
+                head.extend(U256::from((head_size + tail.len()) as u64).to_be_bytes());
+                tail.extend(U256::from(value.len() as u64).to_be_bytes());
+                tail.extend(value.as_bytes());
+                tail.resize(tail.len().next_multiple_of(WORD), 0);
+            }
+        }
+    }
+    head.extend(tail);
+    head
+}
+
+// Hex call data for eth_call
+pub fn encode_call(selector: [u8; 4], tokens: &[AbiToken]) -> String {
+    format!("0x{}{}", encode_hex(&selector), encode_hex(&encode_tokens(tokens)))
+}
+
+// ---- Decoding ----
+// Return data of a function returning a single `string`
+pub fn decode_string(data: &[u8]) -> Result<String, String> {
+    let offset = U256::from_word(data, 0)?
+        .to_usize()
+        .ok_or("ABI string offset is out of range.")?;
+    let length = U256::from_word(data, offset)?
+        .to_usize()
+        .ok_or("ABI string length is out of range.")?;
+    let start = offset + WORD;
+    let bytes = start
+        .checked_add(length)
+        .and_then(|end| data.get(start..end))
+        .ok_or("ABI string is longer than the return data.")?;
+    String::from_utf8(bytes.to_vec()).map_err(|e| format!("ABI string is not UTF-8: {}", e))
+}
+
+pub fn decode_string_hex(hex: &str) -> Result<String, String> {
+    decode_string(&decode_hex(hex.strip_prefix("0x").unwrap_or(hex))?)
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Erc721Transfer {
+    pub from: String,
+    pub to: String,
+    pub token_id: U256,
+}
+
+// ERC-721 indexes all three Transfer arguments. ERC-20 leaves the amount in the data, so its
+// logs have one topic fewer and are rejected here. `event_topic` is normally TRANSFER_TOPIC.
+pub fn decode_erc721_transfer(topics: &[String], event_topic: &str) -> Result<Erc721Transfer, String> {
+    match topics {
+        [signature, from, to, token_id] if signature.eq_ignore_ascii_case(event_topic) => Ok(Erc721Transfer {
+            from: decode_address_topic(from)?,
+            to: decode_address_topic(to)?,
+            token_id: decode_word_topic(token_id)?,
+        }),
+        _ => Err("Log is not an ERC-721 Transfer.".to_string()),
+    }
+}
+
+// Topics are always a full word, unlike the short hex numbers JSON-RPC uses elsewhere
+fn decode_word_topic(topic: &str) -> Result<U256, String> {
+    if topic.len() != 2 + 2 * WORD || !topic.starts_with("0x") {
+        return Err(format!("Topic {} is not a 32-byte word.", topic));
+    }
+    U256::from_hex(topic)
+}
+
+fn decode_address_topic(topic: &str) -> Result<String, String> {
+    let word = decode_word_topic(topic)?.to_be_bytes();
+    let (padding, address) = word.split_at(WORD - 20);
+    if padding.iter().any(|b| *b != 0) {
+        return Err(format!("Topic {} is not an address.", topic));
+    }
+    Ok(format!("0x{}", encode_hex(address)))
+}
+
+// ---- Hex ----
+pub(crate) fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_hex(hex: &str) -> Result<Vec<u8>, String> {
+    if !hex.len().is_multiple_of(2) || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(format!("{} is not valid hex.", hex));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| format!("{} is not valid hex.", hex)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn address_topic(address: &[u8; 20]) -> String {
+        let mut word = [0; WORD];
+        word[WORD - 20..].copy_from_slice(address);
+        U256::from_be_bytes(word).to_hex()
+    }
+
+    proptest! {
+        #[test]
+        fn u256_round_trips_through_hex_and_decimal(bytes in any::<[u8; 32]>()) {
+            let value = U256::from_be_bytes(bytes);
+            prop_assert_eq!(U256::from_hex(&value.to_hex()), Ok(value));
+            prop_assert_eq!(value.to_string().parse::<U256>(), Ok(value));
+        }
+
+        #[test]
+        fn u64_values_keep_their_decimal_form(value in any::<u64>()) {
+            prop_assert_eq!(U256::from(value).to_string(), value.to_string());
+            prop_assert_eq!(U256::from(value).to_u64(), Some(value));
+        }
+
+        #[test]
+        fn strings_round_trip_through_encoder_and_decoder(value in ".*") {
+            let encoded = encode_tokens(&[AbiToken::String(value.clone())]);
+            prop_assert_eq!(encoded.len() % WORD, 0);
+            prop_assert_eq!(decode_string(&encoded), Ok(value));
+        }
+
+        #[test]
+        fn transfer_topics_round_trip(from in any::<[u8; 20]>(), to in any::<[u8; 20]>(), token_id in any::<[u8; 32]>()) {
+            let token_id = U256::from_be_bytes(token_id);
+            let topics = vec![TRANSFER_TOPIC.to_string(), address_topic(&from), address_topic(&to), token_id.to_hex()];
+            let transfer = decode_erc721_transfer(&topics, TRANSFER_TOPIC).unwrap();
+            prop_assert_eq!(transfer.from, format!("0x{}", encode_hex(&from)));
+            prop_assert_eq!(transfer.to, format!("0x{}", encode_hex(&to)));
+            prop_assert_eq!(transfer.token_id, token_id);
+        }
+
+        #[test]
+        fn decoding_arbitrary_bytes_never_panics(data in proptest::collection::vec(any::<u8>(), 0..256)) {
+            let _ = decode_string(&data);
+        }
+    }
+
+    #[test]
+    fn token_ids_beyond_u64_keep_every_digit() {
+        let topic = format!("0x{}", "f".repeat(64));
+        let token_id = decode_word_topic(&topic).unwrap();
+        assert_eq!(token_id.to_u64(), None);
+        assert_eq!(
+            token_id.to_string(),
+            "115792089237316195423570985008687907853269984665640564039457584007913129639935"
+        );
+        assert!("115792089237316195423570985008687907853269984665640564039457584007913129639936".parse::<U256>().is_err());
+        assert!("-1".parse::<U256>().is_err());
+        assert!(U256::from_hex(&format!("0x1{}", "0".repeat(64))).is_err());
+    }
+
+    #[test]
+    fn rejects_erc20_transfers_and_malformed_topics() {
+        let from = address_topic(&[1; 20]);
+        let to = address_topic(&[2; 20]);
+        let erc20 = vec![TRANSFER_TOPIC.to_string(), from.clone(), to.clone()];
+        assert!(decode_erc721_transfer(&erc20, TRANSFER_TOPIC).is_err());
+        let short = vec![TRANSFER_TOPIC.to_string(), from.clone(), to.clone(), "0x2a".to_string()];
+        assert!(decode_erc721_transfer(&short, TRANSFER_TOPIC).is_err());
+        let not_address = vec![TRANSFER_TOPIC.to_string(), U256::from_be_bytes([1; 32]).to_hex(), to, U256::from(42).to_hex()];
+        assert!(decode_erc721_transfer(&not_address, TRANSFER_TOPIC).is_err());
+    }
+
+    #[test]
+    fn encodes_token_uri_calls_and_decodes_solidity_strings() {
+        assert_eq!(
+            encode_call([0xc8, 0x7b, 0x56, 0xdd], &[AbiToken::Uint(U256::from(7))]),
+            format!("0xc87b56dd{:064x}", 7)
+        );
+        // tokenURI() returning "ipfs://cargox/7", as produced by solc
+        let returned = format!(
+            "0x{:064x}{:064x}{}{}",
+            32,
+            15,
+            encode_hex(b"ipfs://cargox/7"),
+            "0".repeat(34)
+        );
+        assert_eq!(decode_string_hex(&returned), Ok("ipfs://cargox/7".to_string()));
+        assert!(decode_string_hex(&format!("0x{:064x}{:064x}", 32, 15)).is_err());
+        assert!(decode_string_hex(&format!("0x{:064x}", u64::MAX)).is_err());
+    }
+}
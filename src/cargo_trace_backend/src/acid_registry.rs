@@ -0,0 +1,161 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::caller;
+use ic_cdk_macros::{query, update};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use serde::Serialize;
+use std::cell::RefCell;
+
+use crate::roles::{is_admin, is_customs_officer};
+use crate::versioned::{versioned_storable, Versioned};
+use crate::{Memory, MEMORY_MANAGER};
+
+// ---- Registry records ----
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct AcidRecordInput {
+    pub acid_number: String,
+    pub importer_tax_id: String,
+    pub exporter_cargox_id: String,
+    pub hs_codes: Vec<String>,
+    // Nanoseconds since the epoch
+    pub expires_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct AcidRecord {
+    pub acid_number: String,
+    pub importer_tax_id: String,
+    pub exporter_cargox_id: String,
+    pub hs_codes: Vec<String>,
+    pub expires_at: u64,
+    pub revoked: bool,
+    pub registered_at: u64,
+    pub registered_by: Principal,
+}
+
+impl Versioned for AcidRecord {
+    const NAME: &'static str = "AcidRecord";
+    const VERSION: u8 = 1;
+}
+versioned_storable!(AcidRecord);
+
+// ---- STATE ----
+thread_local! {
+    static ACID_REGISTRY: RefCell<StableBTreeMap<String, AcidRecord, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(17))))
+    );
+}
+
+pub fn is_valid_acid_format(acid_number: &str) -> bool {
+    acid_number.len() == 9 && acid_number.chars().all(|c| c.is_ascii_digit())
+}
+
+// HS codes are 6 digits internationally, extended up to 10 by national tariffs
+fn is_valid_hs_code(code: &str) -> bool {
+    (6..=10).contains(&code.len()) && code.chars().all(|c| c.is_ascii_digit())
+}
+
+fn check_input(input: &AcidRecordInput, now: u64) -> Result<(), String> {
+    if !is_valid_acid_format(&input.acid_number) {
+        return Err(format!("Invalid ACID format for {}. Must be 9 digits.", input.acid_number));
+    }
+    if input.importer_tax_id.trim().is_empty() {
+        return Err(format!("ACID {} is missing the importer tax ID.", input.acid_number));
+    }
+    if input.exporter_cargox_id.trim().is_empty() {
+        return Err(format!("ACID {} is missing the exporter CargoX ID.", input.acid_number));
+    }
+    if input.hs_codes.is_empty() {
+        return Err(format!("ACID {} must list at least one HS code.", input.acid_number));
+    }
+    if let Some(code) = input.hs_codes.iter().find(|code| !is_valid_hs_code(code)) {
+        return Err(format!("Invalid HS code {} for ACID {}.", code, input.acid_number));
+    }
+    if input.expires_at <= now {
+        return Err(format!("ACID {} has already expired.", input.acid_number));
+    }
+    Ok(())
+}
+
+// The record for an ACID that is registered, not revoked and not expired at `now`
+pub fn active_acid_record(acid_number: &str, now: u64) -> Option<AcidRecord> {
+    ACID_REGISTRY
+        .with(|r| r.borrow().get(&acid_number.to_string()))
+        .filter(|record| !record.revoked && record.expires_at > now)
+}
+
+// ---- API ----
+// Imports are all-or-nothing; re-importing an ACID replaces its record and lifts any revocation
+#[update(guard = "is_admin")]
+pub fn import_acid_records(records: Vec<AcidRecordInput>) -> Result<u64, String> {
+    let now = ic_cdk::api::time();
+    for input in &records {
+        check_input(input, now)?;
+    }
+    let registered_by = caller();
+    let count = records.len() as u64;
+    ACID_REGISTRY.with(|r| {
+        let mut registry = r.borrow_mut();
+        for input in records {
+            let record = AcidRecord {
+                acid_number: input.acid_number.clone(),
+                importer_tax_id: input.importer_tax_id,
+                exporter_cargox_id: input.exporter_cargox_id,
+                hs_codes: input.hs_codes,
+                expires_at: input.expires_at,
+                revoked: false,
+                registered_at: now,
+                registered_by,
+            };
+            registry.insert(input.acid_number, record);
+        }
+    });
+    ic_cdk::println!("{} imported {} ACID records", registered_by, count);
+    Ok(count)
+}
+
+#[update(guard = "is_admin")]
+pub fn revoke_acid(acid_number: String) -> Result<(), String> {
+    ACID_REGISTRY.with(|r| {
+        let mut registry = r.borrow_mut();
+        let mut record = registry.get(&acid_number).ok_or("ACID not found in registry.")?;
+        record.revoked = true;
+        registry.insert(acid_number, record);
+        Ok(())
+    })
+}
+
+#[query(guard = "is_customs_officer")]
+pub fn get_acid_record(acid_number: String) -> Option<AcidRecord> {
+    ACID_REGISTRY.with(|r| r.borrow().get(&acid_number))
+}
+
+#[query(guard = "is_customs_officer")]
+pub fn list_acid_records() -> Vec<AcidRecord> {
+    ACID_REGISTRY.with(|r| r.borrow().iter().map(|entry| entry.value()).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input() -> AcidRecordInput {
+        AcidRecordInput {
+            acid_number: "123456789".to_string(),
+            importer_tax_id: "100-200-300".to_string(),
+            exporter_cargox_id: "0xexporter".to_string(),
+            hs_codes: vec!["851712".to_string(), "8471300000".to_string()],
+            expires_at: 100,
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_registry_input() {
+        assert!(check_input(&input(), 10).is_ok());
+        assert!(check_input(&input(), 100).is_err());
+        assert!(check_input(&AcidRecordInput { acid_number: "12345678a".to_string(), ..input() }, 10).is_err());
+        assert!(check_input(&AcidRecordInput { importer_tax_id: " ".to_string(), ..input() }, 10).is_err());
+        assert!(check_input(&AcidRecordInput { hs_codes: vec![], ..input() }, 10).is_err());
+        assert!(check_input(&AcidRecordInput { hs_codes: vec!["8517".to_string()], ..input() }, 10).is_err());
+    }
+}
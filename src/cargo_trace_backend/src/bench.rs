@@ -0,0 +1,202 @@
+// Cost of the owner, status and hash lookups against the full-map scans they replaced. Only built
+// with `--features bench`. Inside the canister the cost is in instructions
+// (`test_secondary_index_benchmark_at_100k_records` in src/integration_tests); natively it is in
+// nanoseconds (`cargo test --release --features bench -- --ignored` in this crate).
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::update;
+
+use crate::indexed::term;
+use crate::roles::is_admin;
+use crate::{
+    CargoXMapping, CustomsStatus, CustomsVerification, DayCount, Document, DocumentStatus, Loan, LoanStatus,
+    CARGOX_MAPPINGS, CUSTOMS_VERIFICATIONS, DOCUMENTS, LOANS,
+};
+
+// Seeded records are spread evenly over this many owners
+const OWNERS: u64 = 100;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct LookupBench {
+    pub lookup: String,
+    pub records: u64,
+    pub matches: u64,
+    pub scan_cost: u64,
+    pub indexed_cost: u64,
+}
+
+fn owner(i: u64) -> Principal {
+    Principal::from_slice(&(i % OWNERS).to_be_bytes())
+}
+
+fn tx_hash(i: u64) -> String {
+    format!("0x{:064x}", i)
+}
+
+#[cfg(target_arch = "wasm32")]
+fn measure<R>(f: impl FnOnce() -> R) -> (u64, R) {
+    let start = ic_cdk::api::performance_counter(0);
+    let result = f();
+    (ic_cdk::api::performance_counter(0) - start, result)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn measure<R>(f: impl FnOnce() -> R) -> (u64, R) {
+    let start = std::time::Instant::now();
+    let result = f();
+    (start.elapsed().as_nanos() as u64, result)
+}
+
+// Seeds records `start..start + count` of each kind and returns how many documents are stored.
+// One in ten documents is verified and one in twenty loans is active.
+#[update(guard = "is_admin")]
+pub fn bench_seed(start: u64, count: u64) -> u64 {
+    for i in start..start + count {
+        let id = format!("BENCH-{:07}", i);
+        let document = Document {
+            id: id.clone(),
+            acid_number: format!("{:09}", i % 1_000),
+            chain: "ethereum".to_string(),
+            contract: String::new(),
+            token_id: i.to_string(),
+            tx_hash: tx_hash(i),
+            value_usd: 1_000_000,
+            status: if i % 10 == 0 { DocumentStatus::Verified } else { DocumentStatus::Pending },
+            created_at: 0,
+            owner: owner(i),
+            nft_token_id: None,
+        };
+        let loan = Loan {
+            id: format!("BENCH-LOAN-{:07}", i),
+            document_id: id.clone(),
+            amount: 500_000,
+            interest_rate_bps: 500,
+            day_count: DayCount::Actual365,
+            status: if i % 20 == 0 { LoanStatus::Active } else { LoanStatus::Repaid },
+            created_at: 0,
+            borrower: owner(i),
+            repayment_date: 0,
+            transfer_block_height: None,
+            transfer_error: None,
+            principal_outstanding: 0,
+            interest_outstanding: 0,
+            accrued_until: 0,
+            total_repaid: 500_000,
+            late_penalty: None,
+        };
+        let mapping = CargoXMapping {
+            id: format!("BENCH-MAP-{:07}", i),
+            nft_hash: tx_hash(i),
+            document_id: Some(id.clone()),
+            acid_number: document.acid_number.clone(),
+            verified: false,
+            created_at: 0,
+            owner: owner(i),
+            customs_entry_id: None,
+        };
+        let verification = CustomsVerification {
+            id: format!("BENCH-VER-{:07}", i),
+            nft_hash: tx_hash(i),
+            document_id: Some(id.clone()),
+            acid_number: document.acid_number.clone(),
+            verification_status: if i % 10 == 0 { CustomsStatus::Pending } else { CustomsStatus::Verified },
+            verified_at: None,
+            customs_data: None,
+            created_at: 0,
+            verified_by: None,
+        };
+        DOCUMENTS.with(|d| d.borrow_mut().insert(document.id.clone(), document));
+        LOANS.with(|l| l.borrow_mut().insert(loan.id.clone(), loan));
+        CARGOX_MAPPINGS.with(|m| m.borrow_mut().insert(id.clone(), mapping));
+        CUSTOMS_VERIFICATIONS.with(|v| v.borrow_mut().insert(id, verification));
+    }
+    DOCUMENTS.with(|d| d.borrow().len())
+}
+
+// Runs one lookup both ways for the first seeded owner. Each scan reads a whole map, so every
+// lookup gets its own message to stay within the instruction limit.
+#[update(guard = "is_admin")]
+pub fn bench_lookup(lookup: String) -> Result<LookupBench, String> {
+    let caller = owner(0);
+    let by_owner = term("owner", &caller.to_text());
+    let (records, (scan, scanned), (indexed, found)) = match lookup.as_str() {
+        "get_my_documents" => DOCUMENTS.with(|d| {
+            let d = d.borrow();
+            let scan = measure(|| d.iter().map(|e| e.value()).filter(|doc| doc.owner == caller).count());
+            (d.len(), scan, measure(|| d.find(&by_owner).len()))
+        }),
+        "get_my_loans" => LOANS.with(|l| {
+            let l = l.borrow();
+            let scan = measure(|| l.iter().map(|e| e.value()).filter(|loan| loan.borrower == caller).count());
+            (l.len(), scan, measure(|| l.find(&by_owner).len()))
+        }),
+        "get_active_loan" => LOANS.with(|l| {
+            let l = l.borrow();
+            // Every loan of the first owner is active, so a scan stops at once. A borrower with no
+            // active loan, the usual case when a loan is requested, makes the scan read every loan.
+            let borrower = owner(1);
+            let active = |loan: &Loan| loan.borrower == borrower && loan.status == LoanStatus::Active;
+            let scan = measure(|| l.iter().map(|e| e.value()).find(active).iter().count());
+            let by_borrower = term("owner", &borrower.to_text());
+            (l.len(), scan, measure(|| l.find(&by_borrower).into_iter().find(active).iter().count()))
+        }),
+        "get_my_cargox_mappings" => CARGOX_MAPPINGS.with(|m| {
+            let m = m.borrow();
+            let scan = measure(|| m.iter().map(|e| e.value()).filter(|mapping| mapping.owner == caller).count());
+            (m.len(), scan, measure(|| m.find(&by_owner).len()))
+        }),
+        "get_pending_customs_verifications" => CUSTOMS_VERIFICATIONS.with(|v| {
+            let v = v.borrow();
+            let pending = |status: &CustomsStatus| matches!(status, CustomsStatus::Pending);
+            let scan = measure(|| v.iter().map(|e| e.value()).filter(|ver| pending(&ver.verification_status)).count());
+            let by_status = term("status", &format!("{:?}", CustomsStatus::Pending));
+            (v.len(), scan, measure(|| v.find(&by_status).len()))
+        }),
+        "documents_for_tx" => DOCUMENTS.with(|d| {
+            let d = d.borrow();
+            // The last seeded transaction is the worst case for a scan
+            let hash = tx_hash(d.len().saturating_sub(1));
+            let scan = measure(|| d.iter().map(|e| e.value()).filter(|doc| doc.tx_hash == hash).count());
+            (d.len(), scan, measure(|| d.find(&term("tx", &hash)).len()))
+        }),
+        _ => return Err(format!("Unknown lookup {}.", lookup)),
+    };
+    if scanned != found {
+        return Err(format!("The scan found {} records but the index found {}.", scanned, found));
+    }
+    Ok(LookupBench {
+        lookup,
+        records,
+        matches: found as u64,
+        scan_cost: scan,
+        indexed_cost: indexed,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RECORDS: u64 = 100_000;
+
+    // Seeds the stable maps, which natively live in heap memory, and times each lookup both ways
+    #[test]
+    #[ignore = "seeds 100,000 records of each kind; run with --release"]
+    fn secondary_indexes_beat_scans_at_100k_records() {
+        assert_eq!(bench_seed(0, RECORDS), RECORDS);
+        println!("{:<36} {:>8} {:>14} {:>14}", "lookup (ns)", "matches", "scan", "indexed");
+        for lookup in [
+            "get_my_documents",
+            "get_my_loans",
+            "get_active_loan",
+            "get_my_cargox_mappings",
+            "get_pending_customs_verifications",
+            "documents_for_tx",
+        ] {
+            let bench = bench_lookup(lookup.to_string()).unwrap();
+            println!("{:<36} {:>8} {:>14} {:>14}", bench.lookup, bench.matches, bench.scan_cost, bench.indexed_cost);
+            assert_eq!(bench.records, RECORDS);
+            // A tenth of the verifications are pending, which bounds the saving for that lookup
+            assert!(bench.indexed_cost * 2 < bench.scan_cost, "{} is not served by its index", lookup);
+        }
+    }
+}
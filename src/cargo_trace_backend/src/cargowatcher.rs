@@ -1,7 +1,16 @@
 use candid::{CandidType, Deserialize};
-use ic_cdk_macros::{update, query};
+use ic_cdk_macros::update;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
 use std::cell::RefCell;
 
+use crate::roles::is_watcher;
+use crate::versioned::{self, versioned_storable, Versioned};
+use crate::{get_next_id, CargoTraceError, Memory, MEMORY_MANAGER};
+
+// Upper bound on one ingest_transfers call
+const MAX_INGEST_BATCH: usize = 500;
+
 // ---- Payload Structure ----
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct TransferPayload {
@@ -13,21 +22,231 @@ pub struct TransferPayload {
     pub from: String,
     pub to: String,
     pub log_index: u64,
+    // Lets the finality check notice when the block is replaced by a reorg
+    pub block_hash: Option<String>,
+}
+
+impl Versioned for TransferPayload {
+    const NAME: &'static str = "TransferPayload";
+    const VERSION: u8 = 1;
+
+    // Version 0 was plain Candid
+    fn migrate(_version: u8, bytes: &[u8]) -> Result<Self, String> {
+        candid::decode_one(bytes).map_err(|e| e.to_string())
+    }
+}
+versioned_storable!(TransferPayload);
+
+impl TransferPayload {
+    pub(crate) fn token(&self) -> crate::TokenRef {
+        crate::TokenRef::new(&self.network, &self.contract, &self.token_id)
+    }
+
+    // A log is identified by its transaction and position; replays of the same log share this key
+    fn dedup_key(&self) -> String {
+        format!(
+            "{}:{}:{}:{}",
+            self.network.to_ascii_lowercase(),
+            self.contract.to_ascii_lowercase(),
+            self.tx_hash.to_ascii_lowercase(),
+            self.log_index
+        )
+    }
+
+    fn check(&self) -> Result<(), String> {
+        if self.network.trim().is_empty() {
+            return Err("Network is required.".to_string());
+        }
+        if !self.contract.starts_with("0x") || self.contract.len() <= 2 {
+            return Err(format!("Invalid contract address {}.", self.contract));
+        }
+        if !self.tx_hash.starts_with("0x") || self.tx_hash.len() <= 2 {
+            return Err(format!("Invalid transaction hash {}.", self.tx_hash));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub enum IngestOutcome {
+    New,
+    Duplicate,
+    Rejected(String),
 }
 
 // ---- Storage ----
+// Keyed by arrival order; keys come from the "transfer" counter so a removed key is never reused
 thread_local! {
-    static TRANSFERS: RefCell<Vec<TransferPayload>> = RefCell::new(Vec::new());
+    static TRANSFERS: RefCell<StableBTreeMap<u64, TransferPayload, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(10))))
+    );
+    // Dedup key -> transfer key
+    static TRANSFER_KEYS: RefCell<StableBTreeMap<String, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(28))))
+    );
+}
+
+pub(crate) fn rewrite_transfers() {
+    TRANSFERS.with(|m| versioned::rewrite_all(&mut m.borrow_mut()));
+}
+
+// Rebuilds the dedup and history indexes and drops replays stored before deduplication existed
+pub(crate) fn index_stored_transfers() {
+    TRANSFER_KEYS.with(|k| k.borrow_mut().clear_new());
+    crate::clear_transfer_index();
+    let mut replays = Vec::new();
+    for (key, transfer) in transfer_entries() {
+        let dedup_key = transfer.dedup_key();
+        if TRANSFER_KEYS.with(|k| k.borrow().contains_key(&dedup_key)) {
+            replays.push(key);
+        } else {
+            TRANSFER_KEYS.with(|k| k.borrow_mut().insert(dedup_key, key));
+            crate::index_transfer(key, &transfer);
+        }
+    }
+    for key in replays {
+        remove_transfer(key);
+    }
+}
+
+// Returns the key the transfer is stored under, or None if the same log is already stored
+pub(crate) fn store_transfer(payload: TransferPayload) -> Option<u64> {
+    let dedup_key = payload.dedup_key();
+    if TRANSFER_KEYS.with(|k| k.borrow().contains_key(&dedup_key)) {
+        return None;
+    }
+    let key = get_next_id("transfer");
+    TRANSFERS.with(|t| t.borrow_mut().insert(key, payload.clone()));
+    TRANSFER_KEYS.with(|k| k.borrow_mut().insert(dedup_key, key));
+    crate::index_transfer(key, &payload);
+    crate::name_document_tokens(&payload.tx_hash);
+    Some(key)
+}
+
+pub(crate) fn last_transfer_key() -> Option<u64> {
+    TRANSFERS.with(|t| t.borrow().last_key_value().map(|(key, _)| key))
 }
 
-// ---- Update method: ingest transfer from JS watcher ----
-#[update]
-pub fn ingest_transfer(payload: TransferPayload) {
-    TRANSFERS.with(|t| t.borrow_mut().push(payload));
+pub(crate) fn transfer_entries() -> Vec<(u64, TransferPayload)> {
+    TRANSFERS.with(|t| t.borrow().iter().map(|entry| (*entry.key(), entry.value())).collect())
 }
 
-// ---- Query method: get all transfers ----
-#[query]
-pub fn get_transfers() -> Vec<TransferPayload> {
-    TRANSFERS.with(|t| t.borrow().clone())
+// Hands `f` the transfers stored after the `after` key, decoded as it reads them
+pub(crate) fn with_transfers_after<R>(
+    after: Option<u64>,
+    f: impl FnOnce(&mut dyn Iterator<Item = (u64, TransferPayload)>) -> R,
+) -> R {
+    let start = after.map_or(0, |key| key.saturating_add(1));
+    TRANSFERS.with(|t| f(&mut t.borrow().range(start..).map(|entry| (*entry.key(), entry.value()))))
+}
+
+pub(crate) fn transfer_at(key: u64) -> Option<TransferPayload> {
+    TRANSFERS.with(|t| t.borrow().get(&key))
+}
+
+pub(crate) fn replace_transfer(key: u64, transfer: TransferPayload) {
+    if let Some(previous) = TRANSFERS.with(|t| t.borrow_mut().insert(key, transfer.clone())) {
+        crate::unindex_transfer(key, &previous);
+    }
+    crate::index_transfer(key, &transfer);
+}
+
+pub(crate) fn remove_transfer(key: u64) -> Option<TransferPayload> {
+    let transfer = TRANSFERS.with(|t| t.borrow_mut().remove(&key))?;
+    TRANSFER_KEYS.with(|k| {
+        let mut keys = k.borrow_mut();
+        if keys.get(&transfer.dedup_key()) == Some(key) {
+            keys.remove(&transfer.dedup_key());
+        }
+    });
+    crate::unindex_transfer(key, &transfer);
+    crate::forget_unconfirmed(key, &transfer.tx_hash);
+    Some(transfer)
+}
+
+// The watcher follows the chain head, so transfers on the polled network stay unconfirmed until
+// the finality check finds them deep enough. No finality source reaches the other networks; their
+// watchers wait for confirmations before reporting, and those transfers are final on arrival.
+fn ingest(payload: TransferPayload) -> IngestOutcome {
+    if let Err(reason) = payload.check() {
+        return IngestOutcome::Rejected(reason);
+    }
+    if !crate::is_watched(&payload.network, &payload.contract) {
+        return IngestOutcome::Rejected(format!("Contract {} on {} is not watched.", payload.contract, payload.network));
+    }
+    match store_transfer(payload.clone()) {
+        Some(key) => {
+            if payload.token().is_polled() {
+                crate::track_unconfirmed(key, &payload);
+            } else {
+                crate::claim_transfer(&payload);
+            }
+            IngestOutcome::New
+        }
+        None => IngestOutcome::Duplicate,
+    }
+}
+
+// ---- Update methods: ingest transfers from JS watcher ----
+// Replaying a transfer is harmless, so the watcher can resend after a restart
+#[update(guard = "is_watcher")]
+pub fn ingest_transfer(payload: TransferPayload) -> IngestOutcome {
+    ingest(payload)
+}
+
+// Outcomes are returned in the order of the payloads
+#[update(guard = "is_watcher")]
+pub fn ingest_transfers(payloads: Vec<TransferPayload>) -> Result<Vec<IngestOutcome>, CargoTraceError> {
+    if payloads.len() > MAX_INGEST_BATCH {
+        let reason = format!("At most {} transfers can be ingested per call.", MAX_INGEST_BATCH);
+        return Err(CargoTraceError::InvalidArgument(reason));
+    }
+    Ok(payloads.into_iter().map(ingest).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload() -> TransferPayload {
+        TransferPayload {
+            network: "ethereum".to_string(),
+            contract: "0xd4190DD1dA460fC7Bc41a792e688604778820aC9".to_string(),
+            tx_hash: "0xABC".to_string(),
+            block_number: 20_000_001,
+            token_id: "42".to_string(),
+            from: "0xfrom".to_string(),
+            to: "0xto".to_string(),
+            log_index: 3,
+            block_hash: None,
+        }
+    }
+
+    #[test]
+    fn replays_share_a_dedup_key() {
+        let replay = TransferPayload { tx_hash: "0xabc".to_string(), block_number: 20_000_002, ..payload() };
+        assert_eq!(payload().dedup_key(), replay.dedup_key());
+        assert_ne!(payload().dedup_key(), TransferPayload { log_index: 4, ..payload() }.dedup_key());
+
+        assert!(payload().check().is_ok());
+        assert!(TransferPayload { tx_hash: "unknown_tx".to_string(), ..payload() }.check().is_err());
+        assert!(TransferPayload { network: " ".to_string(), ..payload() }.check().is_err());
+    }
+
+    #[test]
+    fn documents_cover_a_token_their_transaction_moved() {
+        let tx_hash = format!("0x{}", "ab".repeat(32));
+        assert!(crate::check_tx_hash(&tx_hash).is_ok());
+        assert!(crate::check_tx_hash("0xabc|token").is_err());
+        let token = |token_id: &str| TransferPayload { token_id: token_id.to_string(), ..payload() }.token();
+
+        // Unseen, the transaction cannot vouch for any token
+        assert_eq!(crate::document_token(&tx_hash, None).unwrap(), None);
+        store_transfer(TransferPayload { tx_hash: tx_hash.clone(), ..payload() });
+        assert_eq!(crate::document_token(&tx_hash, None).unwrap(), Some(token("42")));
+        store_transfer(TransferPayload { tx_hash: tx_hash.clone(), token_id: "43".to_string(), log_index: 4, ..payload() });
+        assert!(crate::document_token(&tx_hash, None).is_err());
+        assert_eq!(crate::document_token(&tx_hash, Some(token("43"))).unwrap(), Some(token("43")));
+        assert!(crate::document_token(&tx_hash, Some(token("44"))).is_err());
+    }
 }
@@ -1,14 +1,23 @@
  
 use candid::{CandidType, Deserialize};
-use ic_cdk::api::management_canister::http_request::{
-    http_request, CanisterHttpRequestArgument, HttpMethod, HttpResponse, TransformContext, HttpHeader
-};
+use ic_cdk::api::management_canister::http_request::HttpResponse;
 use ic_cdk_macros::{update, query, init};
 use serde::Serialize;
 use std::collections::HashMap;
 
+use crate::abi::{decode_string_hex, encode_call, AbiToken, U256};
+use crate::roles::is_watcher;
+use crate::CargoTraceError;
+use crate::token_metadata::MetadataResolution;
+use crate::watcher_config::{is_watched, TokenRef};
+
+// tokenURI(uint256)
+const TOKEN_URI_SELECTOR: [u8; 4] = [0xc8, 0x7b, 0x56, 0xdd];
+
 #[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
 pub struct TransferEvent {
+    pub network: String,
+    pub contract: String,
     pub tx_hash: String,
     pub from: String,
     pub to: String,
@@ -17,6 +26,12 @@ pub struct TransferEvent {
     pub metadata: Option<DocumentMetadata>,
 }
 
+impl TransferEvent {
+    fn token(&self) -> TokenRef {
+        TokenRef::new(&self.network, &self.contract, &self.token_id)
+    }
+}
+
 #[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
 pub struct DocumentMetadata {
     pub name: Option<String>,
@@ -28,6 +43,25 @@ pub struct DocumentMetadata {
     pub document_type: Option<String>,
     pub issuer: Option<String>,
     pub creation_date: Option<String>,
+    pub resolution: MetadataResolution,
+}
+
+impl DocumentMetadata {
+    // Empty metadata that says why nothing could be read, instead of guessed values
+    pub(crate) fn unresolved(reason: String) -> Self {
+        DocumentMetadata {
+            name: None,
+            description: None,
+            image: None,
+            external_url: None,
+            attributes: vec![],
+            document_hash: None,
+            document_type: None,
+            issuer: None,
+            creation_date: None,
+            resolution: MetadataResolution::Unresolved { reason },
+        }
+    }
 }
 
 #[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
@@ -46,6 +80,23 @@ pub struct CargoXDocument {
     pub last_transfer: TransferEvent,
 }
 
+impl CargoXDocument {
+    fn new(latest_transfer: TransferEvent, metadata: DocumentMetadata) -> Self {
+        let document_hash = metadata.document_hash.clone().unwrap_or("Unknown".to_string());
+        let document_type = metadata.document_type.clone().unwrap_or("Unknown".to_string());
+        let mut transfer_with_metadata = latest_transfer.clone();
+        transfer_with_metadata.metadata = Some(metadata.clone());
+        CargoXDocument {
+            token_id: latest_transfer.token_id,
+            owner: latest_transfer.to,
+            document_hash,
+            document_type,
+            metadata,
+            last_transfer: transfer_with_metadata,
+        }
+    }
+}
+
 #[derive(CandidType, Deserialize)]
 pub struct ApiResponse {
     pub status: String,
@@ -63,282 +114,124 @@ pub struct LogEntry {
 }
 
 #[init]
-fn init() {
+fn init(args: Option<crate::InitArgs>) {
+    if let Err(e) = crate::apply_init_args(args.unwrap_or_default()) {
+        ic_cdk::trap(&e);
+    }
     ic_cdk::println!("CargoX Watcher Backend initialized");
+    crate::arm_maturity_timer();
+    crate::arm_eth_poller();
+    crate::arm_finality_check();
+    crate::arm_metadata_refresher();
 }
 
-#[update]
-async fn fetch_transfers_with_metadata() -> Result<Vec<TransferEvent>, String> {
-    let transfers = fetch_transfers().await?;
-    let mut enhanced_transfers = Vec::new();
-    
-    for mut transfer in transfers {
-        match fetch_token_metadata(&transfer.token_id).await {
-            Ok(metadata) => {
-                transfer.metadata = Some(metadata);
-                enhanced_transfers.push(transfer);
-            }
-            Err(e) => {
-                ic_cdk::println!("Failed to fetch metadata for token {}: {}", transfer.token_id, e);
-                enhanced_transfers.push(transfer); // Add without metadata
+// Latest transfer of each token, which names its current owner
+fn latest_transfers_by_token(transfers: Vec<TransferEvent>) -> HashMap<TokenRef, TransferEvent> {
+    let mut token_owners: HashMap<TokenRef, TransferEvent> = HashMap::new();
+    for transfer in transfers {
+        match token_owners.get(&transfer.token()) {
+            Some(existing) if existing.block_number >= transfer.block_number => {}
+            _ => {
+                token_owners.insert(transfer.token(), transfer);
             }
         }
     }
-    
-    Ok(enhanced_transfers)
+    token_owners
 }
 
-#[update]
-async fn fetch_cargox_documents() -> Result<Vec<CargoXDocument>, String> {
-    // First get basic transfers without metadata to avoid too many HTTP calls
-    let transfers = fetch_transfers().await?;
-    let mut documents: HashMap<String, CargoXDocument> = HashMap::new();
-    
-    // Group transfers by token_id to get the latest owner
-    let mut token_owners: HashMap<String, TransferEvent> = HashMap::new();
-    for transfer in transfers {
-        let token_id = transfer.token_id.clone();
-        match token_owners.get(&token_id) {
-            Some(existing) => {
-                if transfer.block_number > existing.block_number {
-                    token_owners.insert(token_id, transfer);
-                }
-            }
-            None => {
-                token_owners.insert(token_id, transfer);
-            }
-        }
-    }
-    
-    // Now fetch metadata for unique tokens only (limit to first 5 to avoid cycle issues)
-    let mut processed = 0;
-    const MAX_TOKENS: usize = 5;
-    
-    for (token_id, latest_transfer) in token_owners {
-        if processed >= MAX_TOKENS {
-            break;
-        }
-        
-        match fetch_token_metadata(&token_id).await {
-            Ok(metadata) => {
-                let document_hash = metadata.document_hash.clone().unwrap_or_default();
-                let document_type = metadata.document_type.clone().unwrap_or("Unknown".to_string());
-                
-                let mut transfer_with_metadata = latest_transfer.clone();
-                transfer_with_metadata.metadata = Some(metadata.clone());
-                
-                documents.insert(token_id.clone(), CargoXDocument {
-                    token_id: token_id.clone(),
-                    owner: latest_transfer.to.clone(),
-                    document_hash,
-                    document_type,
-                    metadata,
-                    last_transfer: transfer_with_metadata,
-                });
-            }
-            Err(e) => {
-                ic_cdk::println!("Failed to fetch metadata for token {}: {}", token_id, e);
-                // Create document without metadata
-                documents.insert(token_id.clone(), CargoXDocument {
-                    token_id: token_id.clone(),
-                    owner: latest_transfer.to.clone(),
-                    document_hash: "Unknown".to_string(),
-                    document_type: "Unknown".to_string(),
-                    metadata: DocumentMetadata {
-                        name: Some(format!("CargoX Token #{}", token_id)),
-                        description: None,
-                        image: None,
-                        external_url: None,
-                        attributes: vec![],
-                        document_hash: None,
-                        document_type: None,
-                        issuer: None,
-                        creation_date: None,
-                    },
-                    last_transfer: latest_transfer,
-                });
+// Documents come from the metadata cache; only tokens without a live cache entry cost an outcall,
+// and at most MAX_FETCHES per call. The rest show their last cached metadata until refreshed.
+// Watchers only, as the outcalls draw on the daily budget; get_cargox_documents is open to all.
+#[update(guard = "is_watcher")]
+async fn fetch_cargox_documents() -> Result<Vec<CargoXDocument>, CargoTraceError> {
+    const MAX_FETCHES: usize = 5;
+    let mut fetches = 0;
+    let mut documents = Vec::new();
+    for (token, latest_transfer) in latest_transfers_by_token(transfer_events()) {
+        let metadata = match crate::fresh_metadata(&token) {
+            Some(metadata) => metadata,
+            None if fetches < MAX_FETCHES && token.is_polled() => {
+                fetches += 1;
+                crate::token_metadata(&token).await
             }
-        }
-        processed += 1;
+            None => crate::cached_metadata(&token),
+        };
+        documents.push(CargoXDocument::new(latest_transfer, metadata));
     }
-    
-    Ok(documents.into_values().collect())
+    Ok(documents)
 }
 
+// Same listing without any outcalls; the background refresher keeps the cache filled
+#[query]
+fn get_cargox_documents() -> Vec<CargoXDocument> {
+    latest_transfers_by_token(transfer_events())
+        .into_values()
+        .map(|latest_transfer| {
+            let metadata = crate::cached_metadata(&latest_transfer.token());
+            CargoXDocument::new(latest_transfer, metadata)
+        })
+        .collect()
+}
+
+// Serves cached metadata only, like get_cargox_documents
 #[update]
-async fn get_document_by_token_id(token_id: String) -> Result<Option<CargoXDocument>, String> {
-    let metadata = fetch_token_metadata(&token_id).await?;
-    
+async fn get_document_by_token_id(token: TokenRef) -> Result<Option<CargoXDocument>, CargoTraceError> {
+    let token = token.normalized();
     // Get the latest transfer for this token
-    let transfers = fetch_transfers().await?;
-    let latest_transfer = transfers
+    let latest_transfer = transfer_events()
         .into_iter()
-        .filter(|t| t.token_id == token_id)
+        .filter(|t| t.token() == token)
         .max_by_key(|t| t.block_number);
-    
-    if let Some(transfer) = latest_transfer {
-        let document_hash = metadata.document_hash.clone().unwrap_or_default();
-        let document_type = metadata.document_type.clone().unwrap_or("Unknown".to_string());
-        
-        Ok(Some(CargoXDocument {
-            token_id: token_id.clone(),
-            owner: transfer.to.clone(),
-            document_hash,
-            document_type,
-            metadata,
-            last_transfer: transfer,
-        }))
-    } else {
-        Ok(None)
+
+    match latest_transfer {
+        Some(transfer) => Ok(Some(CargoXDocument::new(transfer, crate::cached_metadata(&token)))),
+        None => Ok(None),
     }
 }
 
-async fn fetch_token_metadata(token_id: &str) -> Result<DocumentMetadata, String> {
-    // For now, let's skip the tokenURI call and create mock metadata
-    // This avoids the complex contract interaction that might be causing issues
-    
-    // Try to fetch from a direct IPFS/HTTP URL if available
-    // CargoX often uses predictable metadata URLs
-    let potential_urls = vec![
-        format!("https://api.cargox.io/documents/{}/metadata", token_id),
-        format!("https://metadata.cargox.io/token/{}", token_id),
-        format!("https://gateway.pinata.cloud/ipfs/QmCargoXHash/{}.json", token_id),
-    ];
-    
-    for url in potential_urls {
-        match fetch_metadata_from_uri(&url).await {
-            Ok(metadata) => return Ok(metadata),
-            Err(_) => continue, // Try next URL
-        }
+// Follows the token's own tokenURI; never invents values when the URI or its content cannot be read
+pub(crate) async fn fetch_token_metadata(token: &TokenRef) -> DocumentMetadata {
+    match fetch_token_uri(token).await {
+        Ok(token_uri) => crate::resolve_metadata(&token_uri).await,
+        Err(e) => DocumentMetadata::unresolved(format!("tokenURI call failed: {}", e)),
     }
-    
-    // If all URLs fail, return mock metadata based on token_id
-    Ok(DocumentMetadata {
-        name: Some(format!("CargoX Document #{}", token_id)),
-        description: Some("CargoX Digital Document".to_string()),
-        image: None,
-        external_url: Some(format!("https://cargox.io/document/{}", token_id)),
-        attributes: vec![
-            DocumentAttribute {
-                trait_type: "Token ID".to_string(),
-                value: token_id.to_string(),
-            },
-            DocumentAttribute {
-                trait_type: "Platform".to_string(),
-                value: "CargoX".to_string(),
-            }
-        ],
-        document_hash: Some(format!("0x{:0>64}", token_id)), // Mock hash
-        document_type: Some("Trade Document".to_string()),
-        issuer: Some("CargoX Platform".to_string()),
-        creation_date: None,
-    })
 }
 
-async fn fetch_token_uri(token_id: &str) -> Result<String, String> {
-    // Convert token_id to hex for the contract call
-    let token_id_hex = format!("0x{:064x}", token_id.parse::<u64>().unwrap_or(0));
-    
-    // Encode the tokenURI function call
-    let method_id = "c87b56dd"; // tokenURI(uint256) function selector
-    let data = format!("0x{}{}", method_id, &token_id_hex[2..]);
-    
-    let url = format!(
-        "https://api.etherscan.io/api?module=proxy&action=eth_call\
-         &to=0xd4190DD1dA460fC7Bc41a792e688604778820aC9\
-         &data={}&tag=latest\
-         &apikey=RDKQ2CSHGCA17MVHIEANUG9X8YENCJ3ZTY", 
-        data
-    );
-
-    let request = CanisterHttpRequestArgument {
-        url,
-        method: HttpMethod::GET,
-        body: None,
-        max_response_bytes: Some(1_000_000),
-        transform: Some(TransformContext::from_name("transform_response".to_string(), vec![])),
-        headers: vec![],
-    };
-
-    match http_request(request, 25_000_000_000).await {
-        Ok((response,)) => {
-            let text = String::from_utf8(response.body)
-                .map_err(|e| format!("Failed to decode response: {}", e))?;
-            
-            let json: serde_json::Value = serde_json::from_str(&text)
-                .map_err(|e| format!("Failed to parse JSON: {}", e))?;
-            
-            if let Some(result) = json["result"].as_str() {
-                // Decode the hex result to get the URI
-                decode_token_uri_result(result)
-            } else {
-                Err("No result in response".to_string())
-            }
-        }
-        Err((code, msg)) => {
-            Err(format!("HTTP request failed: {:?} - {}", code, msg))
-        }
+// Checked before any outcall is made for the token
+fn polled_token_id(token: &TokenRef) -> Result<U256, String> {
+    if !token.is_polled() || !is_watched(&token.network, &token.contract) {
+        return Err(format!("Contract {} on {} is not polled.", token.contract, token.network));
     }
+    token.token_id.parse()
 }
 
-fn decode_token_uri_result(hex_result: &str) -> Result<String, String> {
-    let hex_str = hex_result.strip_prefix("0x").unwrap_or(hex_result);
-    
-    if hex_str.len() < 128 {
-        return Err("Invalid response format".to_string());
-    }
-    
-    // Skip the first 64 characters (offset) and get the length
-    let length_hex = &hex_str[64..128];
-    let length = u64::from_str_radix(length_hex, 16).unwrap_or(0) as usize;
-    
-    // Get the actual string data
-    let data_hex = &hex_str[128..];
-    let uri_bytes: Vec<u8> = (0..std::cmp::min(data_hex.len(), length * 2))
-        .step_by(2)
-        .filter_map(|i| u8::from_str_radix(&data_hex[i..i + 2], 16).ok())
-        .collect();
-    
-    String::from_utf8(uri_bytes).map_err(|e| format!("Failed to decode URI: {}", e))
+async fn fetch_token_uri(token: &TokenRef) -> Result<String, String> {
+    let token_id = polled_token_id(token)?;
+    let data = encode_call(TOKEN_URI_SELECTOR, &[AbiToken::Uint(token_id)]);
+    let result = crate::call_contract(&token.contract, &data).await?;
+    decode_string_hex(&result)
 }
 
-async fn fetch_metadata_from_uri(uri: &str) -> Result<DocumentMetadata, String> {
-    let request = CanisterHttpRequestArgument {
-        url: uri.to_string(),
-        method: HttpMethod::GET,
-        body: None,
-        max_response_bytes: Some(500_000), // Reduced size
-        transform: Some(TransformContext::from_name("transform_response".to_string(), vec![])),
-        headers: vec![
-            HttpHeader {
-                name: "User-Agent".to_string(),
-                value: "CargoX-Watcher/1.0".to_string(),
-            },
-            HttpHeader {
-                name: "Accept".to_string(),
-                value: "application/json".to_string(),
-            },
-        ],
-    };
-
-    match http_request(request, 10_000_000_000).await { // Reduced cycles
-        Ok((response,)) => {
-            if response.status == 200u16 {
-                let text = String::from_utf8(response.body)
-                    .map_err(|e| format!("Failed to decode response: {}", e))?;
-                
-                parse_metadata(&text)
-            } else {
-                Err(format!("HTTP error: {}", response.status))
-            }
-        }
-        Err((code, msg)) => {
-            Err(format!("HTTP request failed: {:?} - {}", code, msg))
-        }
+// Reads tokenURI from the token's contract through the configured Ethereum source, unless resolved
+// metadata in the cache already names it
+#[update(guard = "is_watcher")]
+async fn get_token_uri(token: TokenRef) -> Result<String, CargoTraceError> {
+    if let Some(MetadataResolution::Resolved { token_uri, .. }) =
+        crate::fresh_metadata(&token).map(|metadata| metadata.resolution)
+    {
+        return Ok(token_uri);
     }
+    polled_token_id(&token).map_err(CargoTraceError::InvalidArgument)?;
+    fetch_token_uri(&token).await.map_err(CargoTraceError::ChainCallFailed)
+}
+
+// Serves from the cache and fetches only a missing or expired entry
+#[update(guard = "is_watcher")]
+async fn get_token_metadata(token: TokenRef) -> DocumentMetadata {
+    crate::token_metadata(&token).await
 }
 
-fn parse_metadata(json_text: &str) -> Result<DocumentMetadata, String> {
+pub(crate) fn parse_metadata(json_text: &str, resolution: MetadataResolution) -> Result<DocumentMetadata, String> {
     let json: serde_json::Value = serde_json::from_str(json_text)
         .map_err(|e| format!("Failed to parse metadata JSON: {}", e))?;
 
@@ -385,108 +278,33 @@ fn parse_metadata(json_text: &str) -> Result<DocumentMetadata, String> {
         document_type,
         issuer,
         creation_date,
+        resolution,
     })
 }
 
-#[update]
-async fn fetch_transfers() -> Result<Vec<TransferEvent>, String> {
-    let url = "https://api.etherscan.io/api?module=logs&action=getLogs\
-               &fromBlock=20000000&toBlock=latest\
-               &address=0xd4190DD1dA460fC7Bc41a792e688604778820aC9\
-               &topic0=0xddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef\
-               &apikey=RDKQ2CSHGCA17MVHIEANUG9X8YENCJ3ZTY";
-
-    let request = CanisterHttpRequestArgument {
-        url: url.to_string(),
-        method: HttpMethod::GET,
-        body: None,
-        max_response_bytes: Some(1_000_000), // Reduced size
-        transform: Some(TransformContext::from_name("transform_response".to_string(), vec![])),
-        headers: vec![],
-    };
-
-    match http_request(request, 15_000_000_000).await { // Reduced cycles
-        Ok((response,)) => {
-            if response.status == 200u16 {
-                let text = String::from_utf8(response.body)
-                    .map_err(|e| format!("Failed to decode response: {}", e))?;
-                
-                parse_transfer_events(&text)
-            } else {
-                Err(format!("Etherscan API error: {}", response.status))
-            }
-        }
-        Err((code, msg)) => {
-            Err(format!("HTTP request failed: {:?} - {}", code, msg))
-        }
-    }
-}
-
-fn parse_transfer_events(json_text: &str) -> Result<Vec<TransferEvent>, String> {
-    let json: serde_json::Value = serde_json::from_str(json_text)
-        .map_err(|e| format!("Failed to parse JSON: {}", e))?;
-
-    let mut events: Vec<TransferEvent> = Vec::new();
-    
-    if let Some(logs) = json["result"].as_array() {
-        for log in logs {
-            if let Some(topics) = log["topics"].as_array() {
-                if topics.len() >= 4 {
-                    let tx_hash = log["transactionHash"]
-                        .as_str()
-                        .unwrap_or("")
-                        .to_string();
-                    
-                    let from = extract_address_from_topic(topics.get(1));
-                    let to = extract_address_from_topic(topics.get(2));
-                    let token_id = extract_token_id_from_topic(topics.get(3));
-                    
-                    let block_number = parse_hex_to_u64(
-                        log["blockNumber"].as_str().unwrap_or("0x0")
-                    );
-
-                    events.push(TransferEvent {
-                        tx_hash,
-                        from,
-                        to,
-                        token_id,
-                        block_number,
-                        metadata: None,
-                    });
-                }
-            }
-        }
-    }
-    
-    Ok(events)
-}
-
-fn extract_address_from_topic(topic: Option<&serde_json::Value>) -> String {
-    if let Some(topic_str) = topic.and_then(|t| t.as_str()) {
-        if topic_str.len() >= 42 {
-            let address = &topic_str[topic_str.len() - 40..];
-            format!("0x{}", address)
-        } else {
-            topic_str.to_string()
-        }
-    } else {
-        String::new()
-    }
+// Final transfers collected so far by the in-canister log poller and the off-chain watcher
+fn transfer_events() -> Vec<TransferEvent> {
+    crate::final_transfers()
+        .into_iter()
+        .map(|transfer| TransferEvent {
+            network: transfer.network,
+            contract: transfer.contract,
+            tx_hash: transfer.tx_hash,
+            from: transfer.from,
+            to: transfer.to,
+            token_id: transfer.token_id,
+            block_number: transfer.block_number,
+            metadata: None,
+        })
+        .collect()
 }
 
-fn extract_token_id_from_topic(topic: Option<&serde_json::Value>) -> String {
-    if let Some(topic_str) = topic.and_then(|t| t.as_str()) {
-        let hex_str = topic_str.strip_prefix("0x").unwrap_or(topic_str);
-        match u64::from_str_radix(hex_str, 16) {
-            Ok(token_id) => token_id.to_string(),
-            Err(_) => topic_str.to_string(),
-        }
-    } else {
-        String::new()
-    }
+#[update]
+async fn fetch_transfers() -> Result<Vec<TransferEvent>, String> {
+    Ok(transfer_events())
 }
 
-fn parse_hex_to_u64(hex_str: &str) -> u64 {
+pub(crate) fn parse_hex_to_u64(hex_str: &str) -> u64 {
     let clean_hex = hex_str.strip_prefix("0x").unwrap_or(hex_str);
     u64::from_str_radix(clean_hex, 16).unwrap_or(0)
 }
@@ -518,43 +336,9 @@ async fn fetch_cargox_documents_simple() -> Result<Vec<CargoXDocument>, String>
     let mut documents: Vec<CargoXDocument> = Vec::new();
     
     // Just take the first few transfers and create documents without external metadata calls
-    for (i, transfer) in transfers.iter().take(10).enumerate() {
-        let mock_metadata = DocumentMetadata {
-            name: Some(format!("CargoX Document #{}", transfer.token_id)),
-            description: Some("CargoX Trade Document".to_string()),
-            image: None,
-            external_url: Some(format!("https://cargox.io/document/{}", transfer.token_id)),
-            attributes: vec![
-                DocumentAttribute {
-                    trait_type: "Token ID".to_string(),
-                    value: transfer.token_id.clone(),
-                },
-                DocumentAttribute {
-                    trait_type: "Block Number".to_string(),
-                    value: transfer.block_number.to_string(),
-                },
-                DocumentAttribute {
-                    trait_type: "Document Type".to_string(),
-                    value: "Bill of Lading".to_string(),
-                }
-            ],
-            document_hash: Some(format!("0x{:0>64x}", i + 1)),
-            document_type: Some("Trade Document".to_string()),
-            issuer: Some("CargoX Platform".to_string()),
-            creation_date: None,
-        };
-        
-        let mut transfer_with_metadata = transfer.clone();
-        transfer_with_metadata.metadata = Some(mock_metadata.clone());
-        
-        documents.push(CargoXDocument {
-            token_id: transfer.token_id.clone(),
-            owner: transfer.to.clone(),
-            document_hash: format!("0x{:0>64x}", i + 1),
-            document_type: "Trade Document".to_string(),
-            metadata: mock_metadata,
-            last_transfer: transfer_with_metadata,
-        });
+    for transfer in transfers.iter().take(10) {
+        let metadata = crate::cached_metadata(&transfer.token());
+        documents.push(CargoXDocument::new(transfer.clone(), metadata));
     }
     
     Ok(documents)
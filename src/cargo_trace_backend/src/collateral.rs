@@ -0,0 +1,138 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::query;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+use crate::versioned::{versioned_storable, Versioned};
+use crate::indexed::term;
+use crate::{
+    documents_for_token, reassign_token, CargoTraceError, Document, LoanStatus, Memory, DOCUMENTS, LOANS, MEMORY_MANAGER,
+};
+
+// A document pledged against an open loan, from the request until the loan is rejected, repaid or
+// its collateral seized. Locked documents cannot back another loan and their NFT cannot be transferred.
+// The NFT is looked up through the document, so one minted after the lock is covered too.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CollateralLock {
+    pub document_id: String,
+    pub loan_id: String,
+    pub locked_at: u64,
+}
+
+// Locks stored with the NFT id they used to copy decode without it
+impl Versioned for CollateralLock {
+    const NAME: &'static str = "CollateralLock";
+    const VERSION: u8 = 1;
+}
+versioned_storable!(CollateralLock);
+
+// ---- STATE ----
+// Keyed by document id
+thread_local! {
+    static COLLATERAL_LOCKS: RefCell<StableBTreeMap<String, CollateralLock, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(21))))
+    );
+}
+
+pub fn collateral_lock(document_id: &str) -> Option<CollateralLock> {
+    COLLATERAL_LOCKS.with(|l| l.borrow().get(&document_id.to_string()))
+}
+
+
+// Loans that still have a claim on their document
+fn is_open(status: &LoanStatus) -> bool {
+    matches!(
+        status,
+        LoanStatus::Pending | LoanStatus::Approved | LoanStatus::TransferPending | LoanStatus::TransferFailed | LoanStatus::Active
+    )
+}
+
+// A CargoX token can back at most one open loan at a time, whichever of its documents pledges it
+pub fn check_document_available(document: &Document) -> Result<(), CargoTraceError> {
+    let document_ids = match document.token() {
+        Some(token) => documents_for_token(&token).into_iter().map(|doc| doc.id).collect(),
+        None => vec![document.id.clone()],
+    };
+    for document_id in document_ids {
+        if let Some(lock) = collateral_lock(&document_id) {
+            return Err(CargoTraceError::DocumentInUse { loan_id: lock.loan_id });
+        }
+        let open_loan = LOANS.with(|loans| {
+            loans.borrow().find(&term("document", &document_id)).into_iter().find(|loan| is_open(&loan.status))
+        });
+        if let Some(loan) = open_loan {
+            return Err(CargoTraceError::DocumentInUse { loan_id: loan.id });
+        }
+    }
+    Ok(())
+}
+
+pub fn lock_collateral(document_id: &str, loan_id: &str) {
+    let lock = CollateralLock {
+        document_id: document_id.to_string(),
+        loan_id: loan_id.to_string(),
+        locked_at: ic_cdk::api::time(),
+    };
+    COLLATERAL_LOCKS.with(|l| l.borrow_mut().insert(document_id.to_string(), lock));
+}
+
+// Loans opened before collateral was locked at request time may hold no lock yet
+pub(crate) fn lock_open_loan_collateral() {
+    let open: Vec<(String, String)> = LOANS.with(|loans| {
+        loans
+            .borrow()
+            .iter()
+            .map(|entry| entry.value())
+            .filter(|loan| is_open(&loan.status))
+            .map(|loan| (loan.document_id, loan.id))
+            .collect()
+    });
+    for (document_id, loan_id) in open {
+        if collateral_lock(&document_id).is_none() {
+            lock_collateral(&document_id, &loan_id);
+        }
+    }
+}
+
+// Called when the loan is repaid or rejected
+pub fn release_collateral(document_id: &str) {
+    COLLATERAL_LOCKS.with(|l| l.borrow_mut().remove(&document_id.to_string()));
+}
+
+// Called when the loan defaults: the document and its NFT pass to the lender, which is this canister.
+// Returns the seized token, if the document has one by now.
+pub fn seize_collateral(document_id: &str) -> Option<u64> {
+    let lender = ic_cdk::api::id();
+    COLLATERAL_LOCKS.with(|l| l.borrow_mut().remove(&document_id.to_string()));
+    let token_id = DOCUMENTS.with(|d| {
+        let mut documents = d.borrow_mut();
+        let mut document = documents.get(&document_id.to_string())?;
+        document.owner = lender;
+        let token_id = document.nft_token_id;
+        documents.insert(document_id.to_string(), document);
+        token_id
+    })?;
+    reassign_token(token_id, lender);
+    Some(token_id)
+}
+
+#[query]
+pub fn get_collateral_lock(document_id: String) -> Option<CollateralLock> {
+    collateral_lock(&document_id)
+}
+
+#[query]
+pub fn get_locked_collateral(owner: Option<Principal>) -> Vec<CollateralLock> {
+    let locks: Vec<CollateralLock> = COLLATERAL_LOCKS.with(|l| l.borrow().iter().map(|entry| entry.value()).collect());
+    match owner {
+        None => locks,
+        Some(owner) => DOCUMENTS.with(|d| {
+            let documents = d.borrow();
+            locks
+                .into_iter()
+                .filter(|lock| documents.get(&lock.document_id).is_some_and(|doc| doc.owner == owner))
+                .collect()
+        }),
+    }
+}
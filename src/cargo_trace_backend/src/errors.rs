@@ -0,0 +1,86 @@
+use candid::{CandidType, Deserialize};
+use std::fmt::Debug;
+
+use crate::{TransferError, TransferFromError};
+
+// Returned by the document, loan, customs and token endpoints so callers can match on the
+// variant instead of the message
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum CargoTraceError {
+    // `kind` names the record type, e.g. "Loan"
+    NotFound { kind: String, id: String },
+    // The caller does not own the record it acts on
+    Unauthorized,
+    // The record is in status `from`; the call would move it to, or needs it in, status `to`
+    InvalidState { from: String, to: String },
+    InvalidAcid { acid_number: String },
+    // NAFEZA could not be reached or answered with an error
+    AcidLookupFailed(String),
+    // The Ethereum source could not be reached or the contract call failed
+    ChainCallFailed(String),
+    AlreadyLinked { document_id: String },
+    // The document is locked as collateral for, or already backs, this loan
+    DocumentInUse { loan_id: String },
+    TransferNotFinal { tx_hash: String },
+    AmountTooLarge { max: u64 },
+    InvalidArgument(String),
+    InsufficientFunds { balance: candid::Nat },
+    InsufficientAllowance { allowance: candid::Nat },
+    LedgerError(TransferError),
+    // Ids of the documents a batch could not process
+    BatchFailed { failed: Vec<String> },
+    // Another call is already working on this record
+    InProgress { id: String },
+}
+
+impl CargoTraceError {
+    pub(crate) fn not_found(kind: &str, id: &str) -> Self {
+        CargoTraceError::NotFound { kind: kind.to_string(), id: id.to_string() }
+    }
+
+    pub(crate) fn invalid_state<S: Debug>(from: &S, to: S) -> Self {
+        CargoTraceError::InvalidState { from: format!("{:?}", from), to: format!("{:?}", to) }
+    }
+}
+
+// ICRC-2 shares every ICRC-1 transfer error apart from the allowance
+impl From<TransferFromError> for CargoTraceError {
+    fn from(error: TransferFromError) -> Self {
+        let transfer_error = match error {
+            TransferFromError::InsufficientFunds { balance } => return CargoTraceError::InsufficientFunds { balance },
+            TransferFromError::InsufficientAllowance { allowance } => {
+                return CargoTraceError::InsufficientAllowance { allowance }
+            }
+            TransferFromError::BadFee { expected_fee } => TransferError::BadFee { expected_fee },
+            TransferFromError::BadBurn { min_burn_amount } => TransferError::BadBurn { min_burn_amount },
+            TransferFromError::TooOld => TransferError::TooOld,
+            TransferFromError::CreatedInFuture { ledger_time } => TransferError::CreatedInFuture { ledger_time },
+            TransferFromError::Duplicate { duplicate_of } => TransferError::Duplicate { duplicate_of },
+            TransferFromError::TemporarilyUnavailable => TransferError::TemporarilyUnavailable,
+            TransferFromError::GenericError { error_code, message } => TransferError::GenericError { error_code, message },
+        };
+        CargoTraceError::LedgerError(transfer_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transfer_from_errors_keep_funds_and_allowance_apart() {
+        let balance = candid::Nat::from(5u64);
+        let error = CargoTraceError::from(TransferFromError::InsufficientFunds { balance: balance.clone() });
+        assert!(matches!(error, CargoTraceError::InsufficientFunds { balance: b } if b == balance));
+        let error = CargoTraceError::from(TransferFromError::InsufficientAllowance { allowance: balance });
+        assert!(matches!(error, CargoTraceError::InsufficientAllowance { .. }));
+        let error = CargoTraceError::from(TransferFromError::TemporarilyUnavailable);
+        assert!(matches!(error, CargoTraceError::LedgerError(TransferError::TemporarilyUnavailable)));
+    }
+
+    #[test]
+    fn invalid_state_names_both_statuses() {
+        let error = CargoTraceError::invalid_state(&crate::LoanStatus::Repaid, crate::LoanStatus::Active);
+        assert!(matches!(error, CargoTraceError::InvalidState { from, to } if from == "Repaid" && to == "Active"));
+    }
+}
@@ -0,0 +1,349 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::caller;
+use ic_cdk_macros::{query, update};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use k256::ecdsa::{RecoveryId, Signature, VerifyingKey};
+use sha2::Sha256;
+use sha3::{Digest, Keccak256};
+use std::cell::RefCell;
+
+use crate::abi::encode_hex;
+use crate::versioned::{versioned_storable, Versioned};
+use crate::{
+    collateral_lock, documents_for_token, new_id, reassign_token, CargoTraceError, CargoXMapping, Document,
+    DocumentStatus, Memory, TransferPayload, CARGOX_MAPPINGS, DOCUMENTS, MEMORY_MANAGER,
+};
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+// How long a sign-in message can be signed and submitted
+const CHALLENGE_TTL_SECS: u64 = 300;
+const ETHEREUM_CHAIN_ID: u64 = 1;
+
+// ---- Bindings ----
+// Proof that `owner` controls `address`; transfers of CargoX documents to the address are
+// claimed for the owner
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct AddressBinding {
+    // Lowercase 0x-prefixed address
+    pub address: String,
+    pub owner: Principal,
+    pub bound_at: u64,
+}
+
+impl Versioned for AddressBinding {
+    const NAME: &'static str = "AddressBinding";
+    const VERSION: u8 = 1;
+}
+versioned_storable!(AddressBinding);
+
+// An EIP-4361 message waiting for its signature
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct BindingChallenge {
+    address: String,
+    message: String,
+    expires_at: u64,
+}
+
+impl Versioned for BindingChallenge {
+    const NAME: &'static str = "BindingChallenge";
+    const VERSION: u8 = 1;
+}
+versioned_storable!(BindingChallenge);
+
+// ---- STATE ----
+thread_local! {
+    // Lowercase address -> binding
+    static ADDRESS_BINDINGS: RefCell<StableBTreeMap<String, AddressBinding, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(36))))
+    );
+    // One outstanding challenge per principal; requesting another replaces it
+    static BINDING_CHALLENGES: RefCell<StableBTreeMap<Principal, BindingChallenge, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(37))))
+    );
+}
+
+pub(crate) fn keccak256(data: &[u8]) -> [u8; 32] {
+    Keccak256::digest(data).into()
+}
+
+fn parse_address(address: &str) -> Result<[u8; 20], CargoTraceError> {
+    let digits = address.strip_prefix("0x").filter(|d| d.len() == 40 && d.bytes().all(|b| b.is_ascii_hexdigit()));
+    let digits =
+        digits.ok_or_else(|| CargoTraceError::InvalidArgument(format!("Invalid Ethereum address {}.", address)))?;
+    let mut bytes = [0; 20];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&digits[2 * i..2 * i + 2], 16).unwrap();
+    }
+    Ok(bytes)
+}
+
+// EIP-55 mixed-case form, which EIP-4361 messages must use
+fn checksum_address(address: &[u8; 20]) -> String {
+    let lower = encode_hex(address);
+    let hash = keccak256(lower.as_bytes());
+    let mixed: String = lower
+        .chars()
+        .enumerate()
+        .map(|(i, c)| {
+            let nibble = (hash[i / 2] >> if i % 2 == 0 { 4 } else { 0 }) & 0x0f;
+            if nibble >= 8 { c.to_ascii_uppercase() } else { c }
+        })
+        .collect();
+    format!("0x{}", mixed)
+}
+
+// RFC 3339 UTC timestamp, as EIP-4361 expects for Issued At and Expiration Time
+fn rfc3339(nanos: u64) -> String {
+    let secs = nanos / NANOS_PER_SECOND;
+    let (days, rem) = ((secs / 86_400) as i64, secs % 86_400);
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, rem / 3_600, rem % 3_600 / 60, rem % 60)
+}
+
+fn siwe_message(address: &[u8; 20], owner: Principal, nonce: &str, issued_at: u64, expires_at: u64) -> String {
+    let domain = format!("{}.icp0.io", ic_cdk::id());
+    format!(
+        "{domain} wants you to sign in with your Ethereum account:\n{address}\n\n\
+         Link this address to Internet Computer principal {owner} on CargoTrace.\n\n\
+         URI: https://{domain}\nVersion: 1\nChain ID: {chain_id}\nNonce: {nonce}\n\
+         Issued At: {issued_at}\nExpiration Time: {expires_at}",
+        domain = domain,
+        address = checksum_address(address),
+        owner = owner,
+        chain_id = ETHEREUM_CHAIN_ID,
+        nonce = nonce,
+        issued_at = rfc3339(issued_at),
+        expires_at = rfc3339(expires_at),
+    )
+}
+
+// Address whose key produced `signature` over the EIP-191 personal_sign hash of `message`
+pub(crate) fn recover_signer(message: &str, signature: &str) -> Result<String, CargoTraceError> {
+    let invalid = || CargoTraceError::InvalidArgument("Signature must be 65 hex-encoded bytes.".to_string());
+    let digits = signature.strip_prefix("0x").unwrap_or(signature);
+    if digits.len() != 130 || !digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(invalid());
+    }
+    let bytes: Vec<u8> = (0..65).map(|i| u8::from_str_radix(&digits[2 * i..2 * i + 2], 16).unwrap()).collect();
+    let signature = Signature::from_slice(&bytes[..64]).map_err(|_| invalid())?;
+    // High-s signatures are malleable copies of valid ones; Ethereum rejects them (EIP-2)
+    if signature.normalize_s().is_some() {
+        return Err(CargoTraceError::InvalidArgument("Signature is not in canonical low-s form.".to_string()));
+    }
+    let v = match bytes[64] {
+        27 | 28 => bytes[64] - 27,
+        v => v,
+    };
+    let recovery_id = RecoveryId::from_byte(v).filter(|id| !id.is_x_reduced()).ok_or_else(invalid)?;
+
+    let prefixed = format!("\x19Ethereum Signed Message:\n{}{}", message.len(), message);
+    let key = VerifyingKey::recover_from_prehash(&keccak256(prefixed.as_bytes()), &signature, recovery_id)
+        .map_err(|_| CargoTraceError::InvalidArgument("Signature does not match the message.".to_string()))?;
+    let point = key.to_encoded_point(false);
+    Ok(format!("0x{}", encode_hex(&keccak256(&point.as_bytes()[1..])[12..])))
+}
+
+fn bound_owner(address: &str) -> Option<Principal> {
+    ADDRESS_BINDINGS.with(|b| b.borrow().get(&address.to_ascii_lowercase())).map(|binding| binding.owner)
+}
+
+// Gives a final transfer's token to the owner of its recipient: the sender's owner hands over their
+// documents for the token, or a pending document and CargoX mapping are opened if the token has
+// none. Documents locked as collateral stay with their loan, and a token whose documents are all
+// locked or held by someone other than the sender is left disputed rather than opened twice.
+// Called once a transfer can no longer be reorganized away; replays are ignored.
+pub(crate) fn claim_transfer(transfer: &TransferPayload) {
+    let Some(owner) = bound_owner(&transfer.to) else {
+        return;
+    };
+    let documents = documents_for_token(&transfer.token());
+    if documents.iter().any(|document| document.owner == owner) {
+        return;
+    }
+    if documents.is_empty() {
+        open_claimed_document(transfer, owner);
+        ic_cdk::println!("Transfer {} claimed for {}", transfer.tx_hash, owner);
+        return;
+    }
+    let previous = bound_owner(&transfer.from);
+    let movable: Vec<Document> = documents
+        .into_iter()
+        .filter(|document| Some(document.owner) == previous && collateral_lock(&document.id).is_none())
+        .collect();
+    if movable.is_empty() {
+        ic_cdk::println!("Transfer {} is disputed: its token's documents are locked or held elsewhere", transfer.tx_hash);
+        return;
+    }
+    for document in movable {
+        move_document(document, transfer, owner);
+    }
+    ic_cdk::println!("Transfer {} claimed for {}", transfer.tx_hash, owner);
+}
+
+fn open_claimed_document(transfer: &TransferPayload, owner: Principal) {
+    let now = ic_cdk::api::time();
+    let document_id = new_id("DOC", "document");
+    let document = Document {
+        id: document_id.clone(),
+        // Filled in when the owner links the document to its ACID
+        acid_number: String::new(),
+        chain: transfer.network.clone(),
+        contract: transfer.contract.clone(),
+        token_id: transfer.token_id.clone(),
+        tx_hash: transfer.tx_hash.clone(),
+        value_usd: 0,
+        status: DocumentStatus::Pending,
+        created_at: now,
+        owner,
+        nft_token_id: None,
+    };
+    DOCUMENTS.with(|d| d.borrow_mut().insert(document_id.clone(), document));
+    let mapping = CargoXMapping {
+        id: new_id("MAP", "mapping"),
+        nft_hash: transfer.tx_hash.clone(),
+        document_id: Some(document_id.clone()),
+        acid_number: String::new(),
+        verified: false,
+        created_at: now,
+        owner,
+        customs_entry_id: None,
+    };
+    CARGOX_MAPPINGS.with(|m| m.borrow_mut().insert(document_id, mapping));
+}
+
+// The document keeps its ACID and customs status, which describe the shipment rather than its holder
+fn move_document(mut document: Document, transfer: &TransferPayload, owner: Principal) {
+    CARGOX_MAPPINGS.with(|m| {
+        let mut mappings = m.borrow_mut();
+        if let Some(mut mapping) = mappings.get(&document.id) {
+            mapping.owner = owner;
+            mappings.insert(document.id.clone(), mapping);
+        }
+    });
+    if let Some(token_id) = document.nft_token_id {
+        reassign_token(token_id, owner);
+    }
+    ic_cdk::println!("Document {} moved from {} to {}", document.id, document.owner, owner);
+    document.owner = owner;
+    document.tx_hash = transfer.tx_hash.clone();
+    DOCUMENTS.with(|d| d.borrow_mut().insert(document.id.clone(), document));
+}
+
+// ---- API ----
+// Returns the EIP-4361 message the caller signs with the address's key (personal_sign)
+#[update]
+pub fn request_address_binding(address: String) -> Result<String, CargoTraceError> {
+    let owner = caller();
+    if owner == Principal::anonymous() {
+        return Err(CargoTraceError::Unauthorized);
+    }
+    let parsed = parse_address(&address)?;
+    let now = ic_cdk::api::time();
+    let expires_at = now + CHALLENGE_TTL_SECS * NANOS_PER_SECOND;
+    let seed = [owner.as_slice(), &parsed, &now.to_be_bytes()].concat();
+    let nonce = encode_hex(&Sha256::digest(seed)[..8]);
+    let message = siwe_message(&parsed, owner, &nonce, now, expires_at);
+    let challenge = BindingChallenge { address: format!("0x{}", encode_hex(&parsed)), message: message.clone(), expires_at };
+    BINDING_CHALLENGES.with(|c| c.borrow_mut().insert(owner, challenge));
+    Ok(message)
+}
+
+// Completes the caller's outstanding challenge. An address belongs to whoever proved control of it
+// last, so signing again from a new principal moves the binding.
+#[update]
+pub fn bind_eth_address(signature: String) -> Result<AddressBinding, CargoTraceError> {
+    let owner = caller();
+    let challenge = BINDING_CHALLENGES
+        .with(|c| c.borrow().get(&owner))
+        .ok_or_else(|| CargoTraceError::not_found("BindingChallenge", &owner.to_text()))?;
+    let now = ic_cdk::api::time();
+    if now > challenge.expires_at {
+        BINDING_CHALLENGES.with(|c| c.borrow_mut().remove(&owner));
+        return Err(CargoTraceError::InvalidArgument("The binding message has expired; request a new one.".to_string()));
+    }
+    let signer = recover_signer(&challenge.message, &signature)?;
+    if signer != challenge.address {
+        return Err(CargoTraceError::InvalidArgument(format!(
+            "Signature was made by {}, not {}.",
+            signer, challenge.address
+        )));
+    }
+    BINDING_CHALLENGES.with(|c| c.borrow_mut().remove(&owner));
+    let binding = AddressBinding { address: challenge.address.clone(), owner, bound_at: now };
+    ADDRESS_BINDINGS.with(|b| b.borrow_mut().insert(challenge.address, binding.clone()));
+    Ok(binding)
+}
+
+#[update]
+pub fn unbind_eth_address(address: String) -> Result<(), CargoTraceError> {
+    let address = address.to_ascii_lowercase();
+    match bound_owner(&address) {
+        None => return Err(CargoTraceError::not_found("AddressBinding", &address)),
+        Some(owner) if owner != caller() => return Err(CargoTraceError::Unauthorized),
+        Some(_) => {}
+    }
+    ADDRESS_BINDINGS.with(|b| b.borrow_mut().remove(&address));
+    Ok(())
+}
+
+#[query]
+pub fn get_my_eth_addresses() -> Vec<AddressBinding> {
+    let owner = caller();
+    ADDRESS_BINDINGS.with(|b| {
+        b.borrow()
+            .iter()
+            .map(|entry| entry.value())
+            .filter(|binding| binding.owner == owner)
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::abi::TRANSFER_TOPIC;
+    use k256::ecdsa::SigningKey;
+
+    fn personal_sign(key: &SigningKey, message: &str) -> String {
+        let prefixed = format!("\x19Ethereum Signed Message:\n{}{}", message.len(), message);
+        let (signature, recovery_id) = key.sign_prehash_recoverable(&keccak256(prefixed.as_bytes())).unwrap();
+        format!("0x{}{:02x}", encode_hex(&signature.to_bytes()), recovery_id.to_byte() + 27)
+    }
+
+    #[test]
+    fn keccak_and_checksums_match_ethereum() {
+        assert_eq!(format!("0x{}", encode_hex(&keccak256(b"Transfer(address,address,uint256)"))), TRANSFER_TOPIC);
+        // EIP-55 test vector
+        let address = parse_address("0x5aaeb6053f3e94c9b9a09f33669435e7ef1beaed").unwrap();
+        assert_eq!(checksum_address(&address), "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed");
+        assert!(parse_address("0x5aaeb6053f3e94c9b9a09f33669435e7ef1bea").is_err());
+        assert_eq!(rfc3339(1_700_000_000 * NANOS_PER_SECOND), "2023-11-14T22:13:20Z");
+        assert_eq!(rfc3339(951_782_400 * NANOS_PER_SECOND), "2000-02-29T00:00:00Z");
+    }
+
+    #[test]
+    fn recovers_the_address_that_signed_a_message() {
+        // Private key 1 controls this well-known address
+        let mut secret = [0u8; 32];
+        secret[31] = 1;
+        let key = SigningKey::from_slice(&secret).unwrap();
+        let message = "example.icp0.io wants you to sign in with your Ethereum account:";
+        let signature = personal_sign(&key, message);
+        assert_eq!(recover_signer(message, &signature).ok().as_deref(), Some("0x7e5f4552091a69125d5dfcb7b8c2659029395bdf"));
+
+        // Any change to the message yields a different signer
+        assert_ne!(recover_signer("another message", &signature).ok().as_deref(), Some("0x7e5f4552091a69125d5dfcb7b8c2659029395bdf"));
+        assert!(recover_signer(message, &signature[..signature.len() - 2]).is_err());
+        assert!(recover_signer(message, &format!("{}1f", &signature[..signature.len() - 2])).is_err());
+    }
+}
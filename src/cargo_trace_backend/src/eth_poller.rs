@@ -0,0 +1,517 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk::api::management_canister::http_request::{
+    CanisterHttpRequestArgument, HttpMethod, HttpResponse, TransformArgs, TransformContext,
+};
+use ic_cdk_macros::{query, update};
+use ic_cdk_timers::TimerId;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableCell;
+use std::cell::RefCell;
+use std::time::Duration;
+
+use crate::abi::decode_erc721_transfer;
+use crate::cargox_watcher::parse_hex_to_u64;
+use crate::eth_binding::claim_transfer;
+use crate::evm_rpc::{self, evm_rpc_config};
+use crate::outcalls::{http_outcall, OutcallFeature};
+use crate::roles::{is_admin, is_watcher};
+use crate::versioned::{versioned_storable, Versioned};
+use crate::watcher_config::{
+    adopt_legacy_watch_settings, etherscan_api_key, polled_contracts, watcher_config, WatchedContract,
+    POLLED_NETWORK,
+};
+use crate::{store_transfer, InFlight, Memory, TransferPayload, MEMORY_MANAGER};
+
+// Etherscan returns at most 1000 logs per page and 10,000 per query (page * offset)
+const PAGE_SIZE: usize = 1_000;
+const MAX_PAGES: usize = 10;
+const LOGS_MAX_RESPONSE_BYTES: u64 = 2_000_000;
+const BLOCK_MAX_RESPONSE_BYTES: u64 = 10_000;
+
+// ---- Configuration ----
+// Which contracts are polled, from which block, and the Etherscan API key live in WatcherConfig.
+//
+// Where Ethereum data comes from: one Etherscan-compatible API, or several JSON-RPC providers
+// queried through the EVM RPC canister
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum LogSource {
+    Etherscan,
+    EvmRpc,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct EthPollerConfig {
+    pub enabled: bool,
+    pub interval_secs: u64,
+    // Etherscan-compatible API base URL
+    pub api_url: String,
+    // Maximum number of blocks fetched per poll
+    pub block_window: u64,
+    // None means Etherscan
+    pub source: Option<LogSource>,
+}
+
+impl Default for EthPollerConfig {
+    fn default() -> Self {
+        EthPollerConfig {
+            enabled: false,
+            interval_secs: 60,
+            api_url: "https://api.etherscan.io/api".to_string(),
+            block_window: 2_000,
+            source: Some(LogSource::Etherscan),
+        }
+    }
+}
+
+// Version 1 also held the contract, its start block and the API key
+#[derive(CandidType, Deserialize)]
+struct EthPollerConfigV1 {
+    enabled: bool,
+    interval_secs: u64,
+    api_url: String,
+    api_key: Option<String>,
+    contract: String,
+    start_block: u64,
+    block_window: u64,
+    source: Option<LogSource>,
+}
+
+struct LegacyWatchSettings {
+    contract: String,
+    start_block: u64,
+    api_key: Option<String>,
+}
+
+impl Versioned for EthPollerConfig {
+    const NAME: &'static str = "EthPollerConfig";
+    const VERSION: u8 = 2;
+
+    // The watch settings are held back until post_upgrade moves them into WatcherConfig
+    fn migrate(version: u8, bytes: &[u8]) -> Result<Self, String> {
+        if version != 1 {
+            return Err(format!("no migration from EthPollerConfig version {}", version));
+        }
+        let v1: EthPollerConfigV1 = candid::decode_one(bytes).map_err(|e| e.to_string())?;
+        LEGACY_WATCH_SETTINGS.with(|l| {
+            *l.borrow_mut() =
+                Some(LegacyWatchSettings { contract: v1.contract, start_block: v1.start_block, api_key: v1.api_key })
+        });
+        Ok(EthPollerConfig {
+            enabled: v1.enabled,
+            interval_secs: v1.interval_secs,
+            api_url: v1.api_url,
+            block_window: v1.block_window,
+            source: v1.source,
+        })
+    }
+}
+versioned_storable!(EthPollerConfig);
+
+// ---- Cursor ----
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct EthPollerState {
+    // Next block to scan; 0 means start from the earliest watched start_block
+    pub next_block: u64,
+    pub finalized_block: u64,
+    pub last_polled_at: u64,
+    pub last_error: Option<String>,
+    pub events_ingested: u64,
+}
+
+impl Versioned for EthPollerState {
+    const NAME: &'static str = "EthPollerState";
+    const VERSION: u8 = 1;
+}
+versioned_storable!(EthPollerState);
+
+// ---- STATE ----
+thread_local! {
+    static POLLER_CONFIG: RefCell<StableCell<EthPollerConfig, Memory>> = RefCell::new(
+        StableCell::init(MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(22))), EthPollerConfig::default())
+    );
+    static POLLER_STATE: RefCell<StableCell<EthPollerState, Memory>> = RefCell::new(
+        StableCell::init(MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(23))), EthPollerState::default())
+    );
+    static POLLER_TIMER: RefCell<Option<TimerId>> = const { RefCell::new(None) };
+    static LEGACY_WATCH_SETTINGS: RefCell<Option<LegacyWatchSettings>> = const { RefCell::new(None) };
+}
+
+fn poller_config() -> EthPollerConfig {
+    POLLER_CONFIG.with(|c| c.borrow().get().clone())
+}
+
+// Carries a version 1 poller config's contract, start block and API key over to WatcherConfig, and
+// rewrites the poller config so this only happens on the first upgrade
+pub fn move_legacy_poller_settings() {
+    let config = poller_config();
+    if let Some(legacy) = LEGACY_WATCH_SETTINGS.with(|l| l.borrow_mut().take()) {
+        adopt_legacy_watch_settings(legacy.contract, legacy.start_block, legacy.api_key);
+        POLLER_CONFIG.with(|c| c.borrow_mut().set(config));
+    }
+}
+
+fn poller_state() -> EthPollerState {
+    POLLER_STATE.with(|s| s.borrow().get().clone())
+}
+
+fn update_poller_state(f: impl FnOnce(&mut EthPollerState)) {
+    POLLER_STATE.with(|cell| {
+        let mut cell = cell.borrow_mut();
+        let mut state = cell.get().clone();
+        f(&mut state);
+        cell.set(state);
+    });
+}
+
+pub fn arm_eth_poller() {
+    let config = poller_config();
+    let timer = config.enabled.then(|| {
+        ic_cdk_timers::set_timer_interval(Duration::from_secs(config.interval_secs), || {
+            ic_cdk::spawn(async {
+                if let Err(e) = poll_once().await {
+                    ic_cdk::println!("Ethereum log poll failed: {}", e);
+                }
+            })
+        })
+    });
+    if let Some(previous) = POLLER_TIMER.with(|t| std::mem::replace(&mut *t.borrow_mut(), timer)) {
+        ic_cdk_timers::clear_timer(previous);
+    }
+}
+
+fn api_url(config: &EthPollerConfig, query: &str) -> String {
+    let mut url = format!("{}?{}", config.api_url, query);
+    if let Some(api_key) = etherscan_api_key() {
+        url.push_str(&format!("&apikey={}", api_key));
+    }
+    url
+}
+
+async fn get_json(url: String, max_response_bytes: u64, transform: &str) -> Result<serde_json::Value, String> {
+    let request = CanisterHttpRequestArgument {
+        url,
+        method: HttpMethod::GET,
+        body: None,
+        max_response_bytes: Some(max_response_bytes),
+        transform: Some(TransformContext::from_name(transform.to_string(), vec![])),
+        headers: vec![],
+    };
+    match http_outcall(OutcallFeature::EthPoller, request).await {
+        Ok(response) if response.status == 200u16 => {
+            serde_json::from_slice(&response.body).map_err(|e| format!("Failed to parse JSON: {}", e))
+        }
+        Ok(response) => Err(format!("Etherscan API error: {}", response.status)),
+        Err(e) => Err(format!("HTTP request failed: {}", e)),
+    }
+}
+
+// A block to look up through the configured source. Polling uses the finalized head rather than
+// `latest`: replicas must agree on the response, and finalized blocks cannot be reorganized.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum BlockRef {
+    Finalized,
+    Number(u64),
+}
+
+pub(crate) struct BlockHeader {
+    pub number: u64,
+    pub hash: String,
+}
+
+async fn fetch_block(config: &EthPollerConfig, block: BlockRef) -> Result<BlockHeader, String> {
+    let tag = match block {
+        BlockRef::Finalized => "finalized".to_string(),
+        BlockRef::Number(number) => format!("0x{:x}", number),
+    };
+    let url = api_url(config, &format!("module=proxy&action=eth_getBlockByNumber&tag={}&boolean=false", tag));
+    let json = get_json(url, BLOCK_MAX_RESPONSE_BYTES, "transform_block_header").await?;
+    match (json["number"].as_str(), json["hash"].as_str()) {
+        (Some(number), Some(hash)) => Ok(BlockHeader { number: parse_hex_to_u64(number), hash: hash.to_string() }),
+        _ => Err(format!("Block {:?} missing from response", block)),
+    }
+}
+
+// Keeps only the block number and hash so replicas that saw different block bodies still agree
+#[query]
+fn transform_block_header(raw: TransformArgs) -> HttpResponse {
+    let json = serde_json::from_slice::<serde_json::Value>(&raw.response.body).unwrap_or_default();
+    let body = match (json["result"]["number"].as_str(), json["result"]["hash"].as_str()) {
+        (Some(number), Some(hash)) => serde_json::json!({ "number": number, "hash": hash }).to_string().into_bytes(),
+        _ => Vec::new(),
+    };
+    HttpResponse { status: raw.response.status, body, headers: vec![] }
+}
+
+fn parse_log(contract: &str, topic: &str, log: &serde_json::Value) -> Option<TransferPayload> {
+    let topics: Vec<String> = log["topics"].as_array()?.iter().filter_map(|t| Some(t.as_str()?.to_string())).collect();
+    let transfer = decode_erc721_transfer(&topics, topic).ok()?;
+    Some(TransferPayload {
+        network: POLLED_NETWORK.to_string(),
+        contract: log["address"].as_str().unwrap_or(contract).to_string(),
+        tx_hash: log["transactionHash"].as_str()?.to_string(),
+        block_number: parse_hex_to_u64(log["blockNumber"].as_str()?),
+        token_id: transfer.token_id.to_string(),
+        from: transfer.from,
+        to: transfer.to,
+        log_index: parse_hex_to_u64(log["logIndex"].as_str().unwrap_or("0x0")),
+        block_hash: log["blockHash"].as_str().map(str::to_string),
+    })
+}
+
+// Etherscan reports an empty range as status "0" with "No records found"
+fn page_logs(json: &serde_json::Value) -> Result<Vec<serde_json::Value>, String> {
+    match (json["status"].as_str(), json["result"].as_array()) {
+        (_, Some(logs)) => Ok(logs.clone()),
+        (Some("0"), _) if json["message"].as_str() == Some("No records found") => Ok(vec![]),
+        _ => Err(format!("Etherscan getLogs failed: {}", json["result"])),
+    }
+}
+
+// One contract's transfer logs in [from, to], or None if the range holds more than Etherscan will
+// page through
+async fn fetch_logs(
+    config: &EthPollerConfig,
+    contract: &str,
+    topic: &str,
+    from: u64,
+    to: u64,
+) -> Result<Option<Vec<TransferPayload>>, String> {
+    let mut transfers = Vec::new();
+    for page in 1..=MAX_PAGES {
+        let url = api_url(config, &format!(
+            "module=logs&action=getLogs&fromBlock={}&toBlock={}&address={}&topic0={}&page={}&offset={}",
+            from, to, contract, topic, page, PAGE_SIZE
+        ));
+        let json = get_json(url, LOGS_MAX_RESPONSE_BYTES, "transform_response").await?;
+        let logs = page_logs(&json)?;
+        transfers.extend(logs.iter().filter_map(|log| parse_log(contract, topic, log)));
+        if logs.len() < PAGE_SIZE {
+            return Ok(Some(transfers));
+        }
+    }
+    Ok(None)
+}
+
+fn uses_evm_rpc(config: &EthPollerConfig) -> bool {
+    config.source == Some(LogSource::EvmRpc)
+}
+
+async fn block_header(config: &EthPollerConfig, block: BlockRef) -> Result<BlockHeader, String> {
+    if uses_evm_rpc(config) {
+        evm_rpc::get_block(&evm_rpc_config(), block).await
+    } else {
+        fetch_block(config, block).await
+    }
+}
+
+pub(crate) async fn chain_block(block: BlockRef) -> Result<BlockHeader, String> {
+    block_header(&poller_config(), block).await
+}
+
+// Transfer logs of every contract in [from, to], or None if any contract's logs cannot be fetched
+// at once. Logs before a contract's own start block are dropped.
+async fn transfer_logs(
+    config: &EthPollerConfig,
+    contracts: &[WatchedContract],
+    topic: &str,
+    from: u64,
+    to: u64,
+) -> Result<Option<Vec<TransferPayload>>, String> {
+    let transfers = if uses_evm_rpc(config) {
+        let addresses: Vec<String> = contracts.iter().map(|c| c.address.clone()).collect();
+        evm_rpc::get_transfer_logs(&evm_rpc_config(), &addresses, topic, from, to).await?
+    } else {
+        let mut transfers = Vec::new();
+        for contract in contracts {
+            match fetch_logs(config, &contract.address, topic, from, to).await? {
+                Some(logs) => transfers.extend(logs),
+                None => return Ok(None),
+            }
+        }
+        Some(transfers)
+    };
+    Ok(transfers.map(|transfers| {
+        transfers
+            .into_iter()
+            .filter(|t| {
+                contracts
+                    .iter()
+                    .any(|c| c.address.eq_ignore_ascii_case(&t.contract) && t.block_number >= c.start_block)
+            })
+            .collect()
+    }))
+}
+
+// Calls a view function on a contract on the polled network at the finalized head through the
+// configured source; `data` is the hex-encoded calldata and the result is the hex-encoded return data
+pub(crate) async fn call_contract(contract: &str, data: &str) -> Result<String, String> {
+    let config = poller_config();
+    if uses_evm_rpc(&config) {
+        return evm_rpc::eth_call(&evm_rpc_config(), contract, data).await;
+    }
+    let url = api_url(&config, &format!("module=proxy&action=eth_call&to={}&data={}&tag=finalized", contract, data));
+    let json = get_json(url, BLOCK_MAX_RESPONSE_BYTES, "transform_response").await?;
+    json["result"]
+        .as_str()
+        .map(str::to_string)
+        .ok_or_else(|| format!("Etherscan eth_call failed: {}", json["error"]))
+}
+
+// Scans one window past the cursor and stores its transfers. The cursor only advances once the
+// whole window is stored, so a failed poll is retried from the same block.
+async fn poll_once() -> Result<u64, String> {
+    // Polls span several outcalls, so a slow poll must not overlap the next tick
+    let Some(_poll) = InFlight::claim("eth_poll".to_string()) else {
+        return Ok(0);
+    };
+    let result = poll_window().await;
+    update_poller_state(|state| {
+        state.last_polled_at = ic_cdk::api::time();
+        state.last_error = result.as_ref().err().cloned();
+    });
+    result
+}
+
+async fn poll_window() -> Result<u64, String> {
+    let config = poller_config();
+    let contracts = polled_contracts();
+    let Some(start_block) = contracts.iter().map(|c| c.start_block).min() else {
+        return Ok(0);
+    };
+    let topic = watcher_config().transfer_topic;
+    let from = poller_state().next_block.max(start_block);
+    let finalized = block_header(&config, BlockRef::Finalized).await?.number;
+    update_poller_state(|state| state.finalized_block = finalized);
+    if from > finalized {
+        return Ok(0);
+    }
+
+    let mut to = finalized.min(from.saturating_add(config.block_window.max(1) - 1));
+    let transfers = loop {
+        match transfer_logs(&config, &contracts, &topic, from, to).await? {
+            Some(transfers) => break transfers,
+            None if to > from => to = from + (to - from) / 2,
+            None => return Err(format!("Block {} holds more logs than can be fetched at once", from)),
+        }
+    };
+
+    // A cursor reset rescans blocks whose transfers are already stored. Finalized transfers can be
+    // claimed for bound addresses straight away.
+    let mut count = 0;
+    for transfer in transfers {
+        if store_transfer(transfer.clone()).is_some() {
+            claim_transfer(&transfer);
+            count += 1;
+        }
+    }
+    update_poller_state(|state| {
+        state.next_block = to + 1;
+        state.events_ingested += count;
+    });
+    Ok(count)
+}
+
+// ---- API ----
+#[update(guard = "is_admin")]
+pub fn set_eth_poller_config(config: EthPollerConfig) -> Result<(), String> {
+    if config.interval_secs < 10 {
+        return Err("Poll interval must be at least 10 seconds.".to_string());
+    }
+    if config.block_window == 0 {
+        return Err("Block window must be positive.".to_string());
+    }
+    POLLER_CONFIG.with(|c| c.borrow_mut().set(config));
+    arm_eth_poller();
+    Ok(())
+}
+
+#[query(guard = "is_admin")]
+pub fn get_eth_poller_config() -> EthPollerConfig {
+    poller_config()
+}
+
+#[query]
+pub fn get_eth_poller_state() -> EthPollerState {
+    poller_state()
+}
+
+// Moves the cursor, e.g. to rescan a range
+#[update(guard = "is_admin")]
+pub fn reset_eth_poller_cursor(next_block: u64) -> Result<(), String> {
+    update_poller_state(|state| state.next_block = next_block);
+    Ok(())
+}
+
+// Polls one window now instead of waiting for the timer; returns the number of transfers stored
+#[update(guard = "is_watcher")]
+pub async fn poll_eth_logs_now() -> Result<u64, String> {
+    poll_once().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::abi::TRANSFER_TOPIC;
+
+    #[test]
+    fn parses_etherscan_transfer_logs() {
+        let json: serde_json::Value = serde_json::from_str(r#"{
+            "status": "1",
+            "message": "OK",
+            "result": [{
+                "address": "0xd4190dd1da460fc7bc41a792e688604778820ac9",
+                "topics": [
+                    "0xddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef",
+                    "0x0000000000000000000000001111111111111111111111111111111111111111",
+                    "0x0000000000000000000000002222222222222222222222222222222222222222",
+                    "0x000000000000000000000000000000000000000000000000000000000000002a"
+                ],
+                "blockNumber": "0x1312d01",
+                "transactionHash": "0xtx",
+                "logIndex": "0x3"
+            }, {
+                "address": "0xd4190dd1da460fc7bc41a792e688604778820ac9",
+                "topics": ["0xddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef"],
+                "blockNumber": "0x1312d01",
+                "transactionHash": "0xother",
+                "logIndex": "0x4"
+            }]
+        }"#).unwrap();
+        let logs = page_logs(&json).unwrap();
+        let transfers: Vec<TransferPayload> =
+            logs.iter().filter_map(|log| parse_log("0xcontract", TRANSFER_TOPIC, log)).collect();
+        assert_eq!(transfers.len(), 1);
+        assert_eq!(transfers[0].block_number, 20_000_001);
+        assert_eq!(transfers[0].token_id, "42");
+        assert_eq!(transfers[0].from, "0x1111111111111111111111111111111111111111");
+        assert_eq!(transfers[0].log_index, 3);
+
+        let empty: serde_json::Value =
+            serde_json::from_str(r#"{"status": "0", "message": "No records found", "result": []}"#).unwrap();
+        assert!(page_logs(&empty).unwrap().is_empty());
+        let failed: serde_json::Value =
+            serde_json::from_str(r#"{"status": "0", "message": "NOTOK", "result": "Invalid API Key"}"#).unwrap();
+        assert!(page_logs(&failed).is_err());
+    }
+
+    #[test]
+    fn version_1_config_hands_its_watch_settings_over() {
+        let v1 = EthPollerConfigV1 {
+            enabled: true,
+            interval_secs: 30,
+            api_url: "https://api.etherscan.io/api".to_string(),
+            api_key: Some("secret".to_string()),
+            contract: "0x1111111111111111111111111111111111111111".to_string(),
+            start_block: 19_000_000,
+            block_window: 500,
+            source: None,
+        };
+        let config = EthPollerConfig::migrate(1, &candid::encode_one(v1).unwrap()).unwrap();
+        assert!(config.enabled);
+        assert_eq!(config.block_window, 500);
+        let legacy = LEGACY_WATCH_SETTINGS.with(|l| l.borrow_mut().take()).unwrap();
+        assert_eq!(legacy.contract, "0x1111111111111111111111111111111111111111");
+        assert_eq!(legacy.start_block, 19_000_000);
+        assert_eq!(legacy.api_key.as_deref(), Some("secret"));
+    }
+}
@@ -0,0 +1,392 @@
+use candid::{CandidType, Deserialize, Nat, Principal, Reserved};
+use ic_cdk::api::call::{call_with_payment128, msg_cycles_refunded128, RejectionCode};
+use ic_cdk_macros::{query, update};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableCell;
+use std::cell::RefCell;
+
+use crate::abi::decode_erc721_transfer;
+use crate::eth_poller::{BlockHeader, BlockRef};
+use crate::outcalls::{charge_outcall, outcall_price, settle_outcall, OutcallFeature};
+use crate::roles::is_admin;
+use crate::versioned::{versioned_storable, Versioned};
+use crate::watcher_config::POLLED_NETWORK;
+use crate::{Memory, TransferPayload, MEMORY_MANAGER};
+
+const LOGS_RESPONSE_SIZE_ESTIMATE: u64 = 2_000_000;
+// A block with its transaction hashes, and a tokenURI string
+const BLOCK_RESPONSE_SIZE_ESTIMATE: u64 = 24_576;
+const CALL_RESPONSE_SIZE_ESTIMATE: u64 = 4_096;
+// The EVM RPC canister runs on the 34-node fiduciary subnet and makes one outcall per provider
+const EVM_RPC_SUBNET_SIZE: u64 = 34;
+// Upper bound on the JSON-RPC request sent to each provider
+const RPC_REQUEST_BYTES: u128 = 2_000;
+// Standard JSON-RPC code providers use for "query returned more than N results"
+const LIMIT_EXCEEDED: i64 = -32005;
+
+// ---- Configuration ----
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum EthMainnetService {
+    Alchemy,
+    Ankr,
+    BlockPi,
+    Cloudflare,
+    PublicNode,
+    Llama,
+}
+
+// How many providers must return the same response for a call to succeed
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum ConsensusStrategy {
+    Equality,
+    Threshold { total: Option<u8>, min: u8 },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct EvmRpcConfig {
+    pub canister_id: Principal,
+    pub providers: Vec<EthMainnetService>,
+    pub consensus: ConsensusStrategy,
+    // Attached on top of the outcall price computed for each call, for the EVM RPC canister's own
+    // fees; it refunds what it does not spend
+    pub cycles: u128,
+}
+
+impl Default for EvmRpcConfig {
+    fn default() -> Self {
+        EvmRpcConfig {
+            canister_id: Principal::from_text("7hfb6-caaaa-aaaar-qadga-cai").unwrap(),
+            providers: vec![EthMainnetService::Alchemy, EthMainnetService::Ankr, EthMainnetService::PublicNode],
+            consensus: ConsensusStrategy::Threshold { total: Some(3), min: 2 },
+            cycles: 2_000_000_000,
+        }
+    }
+}
+
+impl Versioned for EvmRpcConfig {
+    const NAME: &'static str = "EvmRpcConfig";
+    const VERSION: u8 = 1;
+}
+versioned_storable!(EvmRpcConfig);
+
+// ---- EVM RPC canister interface ----
+// Only the parts of the EVM RPC Candid interface this canister uses. Records may omit fields the
+// canister returns; variants must list every case it can return.
+#[derive(CandidType)]
+enum RpcServices {
+    EthMainnet(Option<Vec<EthMainnetService>>),
+}
+
+#[derive(CandidType, Deserialize)]
+struct RpcConfig {
+    #[serde(rename = "responseSizeEstimate")]
+    response_size_estimate: Option<u64>,
+    #[serde(rename = "responseConsensus")]
+    response_consensus: Option<ConsensusStrategy>,
+}
+
+#[derive(CandidType, Deserialize)]
+enum BlockTag {
+    Latest,
+    Finalized,
+    Number(Nat),
+}
+
+#[derive(CandidType, Deserialize)]
+struct GetLogsArgs {
+    #[serde(rename = "fromBlock")]
+    from_block: Option<BlockTag>,
+    #[serde(rename = "toBlock")]
+    to_block: Option<BlockTag>,
+    addresses: Vec<String>,
+    topics: Option<Vec<Vec<String>>>,
+}
+
+#[derive(CandidType)]
+struct TransactionRequest {
+    to: Option<String>,
+    input: Option<String>,
+}
+
+#[derive(CandidType)]
+struct CallArgs {
+    transaction: TransactionRequest,
+    block: Option<BlockTag>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct LogEntry {
+    address: String,
+    topics: Vec<String>,
+    #[serde(rename = "blockNumber")]
+    block_number: Option<Nat>,
+    #[serde(rename = "blockHash")]
+    block_hash: Option<String>,
+    #[serde(rename = "transactionHash")]
+    transaction_hash: Option<String>,
+    #[serde(rename = "logIndex")]
+    log_index: Option<Nat>,
+    removed: bool,
+}
+
+#[derive(CandidType, Deserialize)]
+struct Block {
+    number: Nat,
+    hash: String,
+}
+
+#[derive(CandidType, Deserialize)]
+struct JsonRpcError {
+    code: i64,
+    message: String,
+}
+
+#[derive(CandidType, Deserialize)]
+enum HttpOutcallError {
+    IcError { code: RejectionCode, message: String },
+    InvalidHttpJsonRpcResponse {
+        status: u16,
+        body: String,
+        #[serde(rename = "parsingError")]
+        parsing_error: Option<String>,
+    },
+}
+
+#[derive(CandidType, Deserialize)]
+enum ProviderError {
+    TooFewCycles { expected: Nat, received: Nat },
+    MissingRequiredProvider,
+    ProviderNotFound,
+    NoPermission,
+    InvalidRpcConfig(String),
+}
+
+#[derive(CandidType, Deserialize)]
+enum RpcError {
+    #[serde(rename = "JsonRpcError")]
+    JsonRpc(JsonRpcError),
+    #[serde(rename = "ProviderError")]
+    Provider(ProviderError),
+    #[serde(rename = "ValidationError")]
+    Validation(Reserved),
+    #[serde(rename = "HttpOutcallError")]
+    HttpOutcall(HttpOutcallError),
+}
+
+impl RpcError {
+    // The response would not fit the outcall, or the provider refused to return that many logs
+    fn is_too_large(&self) -> bool {
+        match self {
+            RpcError::JsonRpc(e) => e.code == LIMIT_EXCEEDED,
+            RpcError::HttpOutcall(HttpOutcallError::IcError { message, .. }) => message.contains("size"),
+            _ => false,
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            RpcError::JsonRpc(e) => format!("JSON-RPC error {}: {}", e.code, e.message),
+            RpcError::Provider(ProviderError::TooFewCycles { expected, received }) => {
+                format!("too few cycles: the EVM RPC canister expected {} but received {}", expected, received)
+            }
+            RpcError::Provider(ProviderError::InvalidRpcConfig(reason)) => format!("invalid RPC config: {}", reason),
+            RpcError::Provider(_) => "provider error".to_string(),
+            RpcError::Validation(_) => "validation error".to_string(),
+            RpcError::HttpOutcall(HttpOutcallError::IcError { code, message }) => {
+                format!("HTTP outcall failed: {:?} - {}", code, message)
+            }
+            RpcError::HttpOutcall(HttpOutcallError::InvalidHttpJsonRpcResponse { status, parsing_error, .. }) => {
+                format!("invalid JSON-RPC response (HTTP {}): {}", status, parsing_error.as_deref().unwrap_or("unparseable body"))
+            }
+        }
+    }
+}
+
+// Disagreeing providers are only counted, so the service type is left open
+#[derive(CandidType, Deserialize)]
+enum MultiRpcResult<T> {
+    Consistent(Result<T, RpcError>),
+    Inconsistent(Vec<(Reserved, Result<T, RpcError>)>),
+}
+
+// ---- STATE ----
+thread_local! {
+    static EVM_RPC_CONFIG: RefCell<StableCell<EvmRpcConfig, Memory>> = RefCell::new(
+        StableCell::init(MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(24))), EvmRpcConfig::default())
+    );
+}
+
+pub fn evm_rpc_config() -> EvmRpcConfig {
+    EVM_RPC_CONFIG.with(|c| c.borrow().get().clone())
+}
+
+fn nat_to_u64(n: &Nat) -> u64 {
+    u64::try_from(&n.0).unwrap_or(u64::MAX)
+}
+
+// What a call answered within `response_size_estimate` bytes costs the EVM RPC canister: one outcall
+// per provider on its subnet, plus the configured allowance for its fees
+fn call_cycles(config: &EvmRpcConfig, response_size_estimate: u64) -> u128 {
+    let per_provider = outcall_price(EVM_RPC_SUBNET_SIZE, RPC_REQUEST_BYTES, response_size_estimate as u128);
+    per_provider * config.providers.len() as u128 + config.cycles
+}
+
+// The outer error covers failed calls and providers that did not reach consensus; the inner
+// result is the response the providers agreed on
+async fn call_evm_rpc<A: CandidType, T: CandidType + for<'de> Deserialize<'de>>(
+    config: &EvmRpcConfig,
+    method: &str,
+    response_size_estimate: u64,
+    args: A,
+) -> Result<Result<T, RpcError>, String> {
+    let services = RpcServices::EthMainnet(Some(config.providers.clone()));
+    let rpc_config = RpcConfig {
+        response_size_estimate: Some(response_size_estimate),
+        response_consensus: Some(config.consensus.clone()),
+    };
+    // The EVM RPC canister refunds what its outcalls do not use
+    let cycles = call_cycles(config, response_size_estimate);
+    charge_outcall(OutcallFeature::EvmRpc, cycles)?;
+    let result: Result<(MultiRpcResult<T>,), _> =
+        call_with_payment128(config.canister_id, method, (services, Some(rpc_config), args), cycles).await;
+    settle_outcall(OutcallFeature::EvmRpc, msg_cycles_refunded128(), result.is_err());
+    match result {
+        Ok((MultiRpcResult::Consistent(result),)) => Ok(result),
+        Ok((MultiRpcResult::Inconsistent(results),)) => {
+            Err(format!("{} failed: {} providers returned inconsistent results", method, results.len()))
+        }
+        Err((code, msg)) => Err(format!("EVM RPC canister call {} failed: {:?} - {}", method, code, msg)),
+    }
+}
+
+pub(crate) async fn get_block(config: &EvmRpcConfig, block: BlockRef) -> Result<BlockHeader, String> {
+    let tag = match block {
+        BlockRef::Finalized => BlockTag::Finalized,
+        BlockRef::Number(number) => BlockTag::Number(Nat::from(number)),
+    };
+    let block: Block = call_evm_rpc(config, "eth_getBlockByNumber", BLOCK_RESPONSE_SIZE_ESTIMATE, tag)
+        .await?
+        .map_err(|e| format!("eth_getBlockByNumber failed: {}", e.describe()))?;
+    Ok(BlockHeader { number: nat_to_u64(&block.number), hash: block.hash })
+}
+
+fn transfer_from_log(log: LogEntry, topic: &str) -> Option<TransferPayload> {
+    if log.removed {
+        return None;
+    }
+    let transfer = decode_erc721_transfer(&log.topics, topic).ok()?;
+    Some(TransferPayload {
+        network: POLLED_NETWORK.to_string(),
+        contract: log.address,
+        tx_hash: log.transaction_hash?,
+        block_number: nat_to_u64(log.block_number.as_ref()?),
+        token_id: transfer.token_id.to_string(),
+        from: transfer.from,
+        to: transfer.to,
+        log_index: log.log_index.as_ref().map(nat_to_u64).unwrap_or(0),
+        block_hash: log.block_hash,
+    })
+}
+
+// All transfer logs of the contracts in [from, to], or None if the providers refuse to return that
+// many at once
+pub(crate) async fn get_transfer_logs(
+    config: &EvmRpcConfig,
+    contracts: &[String],
+    topic: &str,
+    from: u64,
+    to: u64,
+) -> Result<Option<Vec<TransferPayload>>, String> {
+    let args = GetLogsArgs {
+        from_block: Some(BlockTag::Number(Nat::from(from))),
+        to_block: Some(BlockTag::Number(Nat::from(to))),
+        addresses: contracts.to_vec(),
+        topics: Some(vec![vec![topic.to_string()]]),
+    };
+    let logs: Result<Vec<LogEntry>, RpcError> =
+        call_evm_rpc(config, "eth_getLogs", LOGS_RESPONSE_SIZE_ESTIMATE, args).await?;
+    match logs {
+        Ok(logs) => Ok(Some(logs.into_iter().filter_map(|log| transfer_from_log(log, topic)).collect())),
+        Err(e) if e.is_too_large() => Ok(None),
+        Err(e) => Err(format!("eth_getLogs failed: {}", e.describe())),
+    }
+}
+
+// Read-only contract call against the finalized state; returns the hex-encoded return data
+pub(crate) async fn eth_call(config: &EvmRpcConfig, to: &str, data: &str) -> Result<String, String> {
+    let args = CallArgs {
+        transaction: TransactionRequest { to: Some(to.to_string()), input: Some(data.to_string()) },
+        block: Some(BlockTag::Finalized),
+    };
+    call_evm_rpc(config, "eth_call", CALL_RESPONSE_SIZE_ESTIMATE, args)
+        .await?
+        .map_err(|e| format!("eth_call failed: {}", e.describe()))
+}
+
+// ---- API ----
+#[update(guard = "is_admin")]
+pub fn set_evm_rpc_config(config: EvmRpcConfig) -> Result<(), String> {
+    if config.providers.is_empty() {
+        return Err("At least one RPC provider is required.".to_string());
+    }
+    if let ConsensusStrategy::Threshold { total, min } = &config.consensus {
+        let total = total.map(usize::from).unwrap_or(config.providers.len());
+        if *min == 0 || usize::from(*min) > total || total != config.providers.len() {
+            return Err("Consensus threshold must be between 1 and the number of providers.".to_string());
+        }
+    }
+    EVM_RPC_CONFIG.with(|c| c.borrow_mut().set(config));
+    Ok(())
+}
+
+#[query(guard = "is_admin")]
+pub fn get_evm_rpc_config() -> EvmRpcConfig {
+    evm_rpc_config()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::abi::TRANSFER_TOPIC;
+
+    fn log(topics: usize) -> LogEntry {
+        LogEntry {
+            address: "0xd4190dd1da460fc7bc41a792e688604778820ac9".to_string(),
+            topics: [
+                "0xddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef",
+                "0x0000000000000000000000001111111111111111111111111111111111111111",
+                "0x0000000000000000000000002222222222222222222222222222222222222222",
+                "0x0000000000000000000000000000000000000000000000000000000000000007",
+            ][..topics]
+                .iter()
+                .map(|t| t.to_string())
+                .collect(),
+            block_number: Some(Nat::from(20_000_010u64)),
+            block_hash: Some("0xblock".to_string()),
+            transaction_hash: Some("0xtx".to_string()),
+            log_index: Some(Nat::from(2u64)),
+            removed: false,
+        }
+    }
+
+    #[test]
+    fn converts_evm_rpc_logs_to_transfers() {
+        let transfer = transfer_from_log(log(4), TRANSFER_TOPIC).unwrap();
+        assert_eq!(transfer.block_number, 20_000_010);
+        assert_eq!(transfer.token_id, "7");
+        assert_eq!(transfer.to, "0x2222222222222222222222222222222222222222");
+        assert_eq!(transfer.log_index, 2);
+
+        assert!(transfer_from_log(log(3), TRANSFER_TOPIC).is_none());
+        assert!(transfer_from_log(LogEntry { removed: true, ..log(4) }, TRANSFER_TOPIC).is_none());
+        assert!(transfer_from_log(LogEntry { transaction_hash: None, ..log(4) }, TRANSFER_TOPIC).is_none());
+    }
+
+    #[test]
+    fn log_calls_carry_enough_cycles_for_every_provider() {
+        let config = EvmRpcConfig::default();
+        // 800 cycles per response byte on each of the 34 nodes, for each of the 3 providers
+        assert!(call_cycles(&config, LOGS_RESPONSE_SIZE_ESTIMATE) > 3 * 800 * 34 * 2_000_000);
+        assert!(call_cycles(&config, BLOCK_RESPONSE_SIZE_ESTIMATE) < call_cycles(&config, LOGS_RESPONSE_SIZE_ESTIMATE));
+    }
+}
@@ -1,6 +1,11 @@
 use ic_cdk::api::time;
 use candid::{CandidType, Deserialize}; // ✅ استخدم candid مباشرة بدل ic_cdk::export
-use std::collections::HashMap;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+
+use crate::pagination::{page, page_limit, ListFilter, Listed, Page};
+use crate::versioned::{self, versioned_storable, Versioned};
+use crate::{get_next_id, CargoTraceError, Memory, MEMORY_MANAGER};
 
 #[derive(Clone, Debug, CandidType, Deserialize)] // ✅ ده اللي كان ناقص أو غلط import
 pub struct Document {
@@ -13,44 +18,76 @@ pub struct Document {
     pub transferred_at: Option<u64>,
 }
 
+impl Versioned for Document {
+    const NAME: &'static str = "Document";
+    const VERSION: u8 = 1;
+
+    // Version 0 was plain Candid
+    fn migrate(_version: u8, bytes: &[u8]) -> Result<Self, String> {
+        candid::decode_one(bytes).map_err(|e| e.to_string())
+    }
+}
+versioned_storable!(Document);
+
+impl Listed for Document {
+    const INDEXED: &'static [&'static str] = &[];
+
+    fn status(&self) -> Option<String> {
+        None
+    }
+    // Owners are free text here, compared with the filter's principal as text
+    fn owner(&self) -> Option<String> {
+        Some(self.owner.clone())
+    }
+    fn created_at(&self) -> u64 {
+        self.uploaded_at
+    }
+    fn acid_number(&self) -> Option<&str> {
+        None
+    }
+}
+
 thread_local! {
-    static DOCS: std::cell::RefCell<HashMap<u64, Document>> = std::cell::RefCell::new(HashMap::new());
-    static COUNTER: std::cell::RefCell<u64> = std::cell::RefCell::new(0);
+    static DOCS: std::cell::RefCell<StableBTreeMap<u64, Document, Memory>> = std::cell::RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(12))))
+    );
+}
+
+pub(crate) fn rewrite_files() {
+    DOCS.with(|m| versioned::rewrite_all(&mut m.borrow_mut()));
 }
 
 // Upload document
 #[ic_cdk::update]
 pub fn upload_document(name: String, content_hash: String, owner: String) -> u64 {
-    COUNTER.with(|c| {
-        let mut counter = c.borrow_mut();
-        *counter += 1;
-        let id = *counter;
-        DOCS.with(|docs| {
-            docs.borrow_mut().insert(id, Document {
-                id,
-                name,
-                content_hash,
-                owner,
-                receiver: None,
-                uploaded_at: time(),
-                transferred_at: None,
-            });
+    let id = get_next_id("file");
+    DOCS.with(|docs| {
+        docs.borrow_mut().insert(id, Document {
+            id,
+            name,
+            content_hash,
+            owner,
+            receiver: None,
+            uploaded_at: time(),
+            transferred_at: None,
         });
-        id
-    })
+    });
+    id
 }
 
 // Transfer document
 #[ic_cdk::update]
-pub fn transfer_document(doc_id: u64, new_owner: String) -> Result<String, String> {
+pub fn transfer_document(doc_id: u64, new_owner: String) -> Result<String, CargoTraceError> {
     DOCS.with(|docs| {
         let mut docs = docs.borrow_mut();
-        if let Some(doc) = docs.get_mut(&doc_id) {
+        if let Some(mut doc) = docs.get(&doc_id) {
             doc.receiver = Some(new_owner.clone());
             doc.transferred_at = Some(time());
-            Ok(format!("Document {} transferred to {}", doc.name, new_owner))
+            let message = format!("Document {} transferred to {}", doc.name, new_owner);
+            docs.insert(doc_id, doc);
+            Ok(message)
         } else {
-            Err("Document not found".to_string())
+            Err(CargoTraceError::not_found("Document", &doc_id.to_string()))
         }
     })
 }
@@ -58,11 +95,26 @@ pub fn transfer_document(doc_id: u64, new_owner: String) -> Result<String, Strin
 // Get document info
 #[ic_cdk::query]
 pub fn getdocument(doc_id: u64) -> Option<Document> {
-    DOCS.with(|docs| docs.borrow().get(&doc_id).cloned())
+    DOCS.with(|docs| docs.borrow().get(&doc_id))
 }
 
-// List all documents
+// List documents in upload order; the cursor is the last document id of the previous page
 #[ic_cdk::query]
-pub fn list_documents() -> Vec<Document> {
-    DOCS.with(|docs| docs.borrow().values().cloned().collect())
+pub fn list_documents(
+    filter: ListFilter,
+    cursor: Option<String>,
+    limit: Option<u32>,
+) -> Result<Page<Document>, CargoTraceError> {
+    let start = match cursor {
+        None => 0,
+        Some(cursor) => cursor
+            .parse::<u64>()
+            .map_err(|_| CargoTraceError::InvalidArgument(format!("Invalid cursor {}.", cursor)))?
+            .saturating_add(1),
+    };
+    Ok(DOCS.with(|docs| {
+        let docs = docs.borrow();
+        let candidates = docs.range(start..).map(|entry| (entry.key().to_string(), entry.value()));
+        page(&filter, candidates, page_limit(limit))
+    }))
 }
@@ -0,0 +1,404 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk_macros::{query, update};
+use ic_cdk_timers::TimerId;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{StableBTreeMap, StableCell};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::eth_binding::claim_transfer;
+use crate::eth_poller::{chain_block, BlockRef};
+use crate::indexed::term;
+use crate::pagination::{collect_page, page_limit};
+use crate::roles::{is_admin, is_watcher};
+use crate::versioned::{versioned_storable, Versioned};
+use crate::{
+    documents_for_tx, remove_transfer, replace_transfer, transfer_at, transfer_entries,
+    CargoTraceError, InFlight, Memory, TransferPage, TransferPayload, CARGOX_MAPPINGS, CUSTOMS_VERIFICATIONS,
+    MEMORY_MANAGER,
+};
+
+// Bounds the outcalls a single check can make
+const MAX_TRANSFERS_PER_CHECK: usize = 50;
+
+// ---- Configuration ----
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct FinalityConfig {
+    // Blocks a transfer's block must lie below the finalized head before it is final; 0 trusts
+    // the chain's finality alone
+    pub confirmation_depth: u64,
+    pub check_interval_secs: u64,
+}
+
+impl Default for FinalityConfig {
+    fn default() -> Self {
+        FinalityConfig { confirmation_depth: 0, check_interval_secs: 120 }
+    }
+}
+
+impl Versioned for FinalityConfig {
+    const NAME: &'static str = "FinalityConfig";
+    const VERSION: u8 = 1;
+}
+versioned_storable!(FinalityConfig);
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct FinalityState {
+    pub head_block: u64,
+    pub last_checked_at: u64,
+    pub confirmed_transfers: u64,
+    pub orphaned_transfers: u64,
+    pub last_error: Option<String>,
+}
+
+impl Versioned for FinalityState {
+    const NAME: &'static str = "FinalityState";
+    const VERSION: u8 = 1;
+}
+versioned_storable!(FinalityState);
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct FinalityRun {
+    pub checked: u64,
+    pub confirmed: u64,
+    pub orphaned: u64,
+}
+
+#[derive(Debug, PartialEq)]
+enum Verdict {
+    Pending,
+    Confirmed,
+    Orphaned,
+}
+
+// ---- STATE ----
+thread_local! {
+    // Transfer key -> block number, for transfers not yet final
+    static UNCONFIRMED_TRANSFERS: RefCell<StableBTreeMap<u64, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(25))))
+    );
+    // "<lowercase tx hash>|<transfer key:020>" -> transfer key, for the same transfers
+    static UNCONFIRMED_BY_TX_HASH: RefCell<StableBTreeMap<String, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(44))))
+    );
+    static FINALITY_CONFIG: RefCell<StableCell<FinalityConfig, Memory>> = RefCell::new(
+        StableCell::init(MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(26))), FinalityConfig::default())
+    );
+    static FINALITY_STATE: RefCell<StableCell<FinalityState, Memory>> = RefCell::new(
+        StableCell::init(MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(27))), FinalityState::default())
+    );
+    static FINALITY_TIMER: RefCell<Option<TimerId>> = const { RefCell::new(None) };
+    // Key the next check starts from, so transfers still pending at low keys do not starve the rest
+    static FINALITY_CURSOR: RefCell<u64> = const { RefCell::new(0) };
+}
+
+fn finality_config() -> FinalityConfig {
+    FINALITY_CONFIG.with(|c| c.borrow().get().clone())
+}
+
+fn update_finality_state(f: impl FnOnce(&mut FinalityState)) {
+    FINALITY_STATE.with(|cell| {
+        let mut cell = cell.borrow_mut();
+        let mut state = cell.get().clone();
+        f(&mut state);
+        cell.set(state);
+    });
+}
+
+pub fn arm_finality_check() {
+    let interval = Duration::from_secs(finality_config().check_interval_secs);
+    let timer = ic_cdk_timers::set_timer_interval(interval, || {
+        ic_cdk::spawn(async {
+            if let Err(e) = check_finality().await {
+                ic_cdk::println!("Finality check failed: {}", e);
+            }
+        })
+    });
+    if let Some(previous) = FINALITY_TIMER.with(|t| t.borrow_mut().replace(timer)) {
+        ic_cdk_timers::clear_timer(previous);
+    }
+}
+
+fn tx_hash_prefix(tx_hash: &str) -> String {
+    format!("{}|", term("tx", tx_hash))
+}
+
+fn tx_hash_key(tx_hash: &str, key: u64) -> String {
+    format!("{}{:020}", tx_hash_prefix(tx_hash), key)
+}
+
+pub(crate) fn track_unconfirmed(key: u64, transfer: &TransferPayload) {
+    UNCONFIRMED_TRANSFERS.with(|u| u.borrow_mut().insert(key, transfer.block_number));
+    UNCONFIRMED_BY_TX_HASH.with(|u| u.borrow_mut().insert(tx_hash_key(&transfer.tx_hash, key), key));
+}
+
+pub(crate) fn forget_unconfirmed(key: u64, tx_hash: &str) {
+    UNCONFIRMED_TRANSFERS.with(|u| u.borrow_mut().remove(&key));
+    UNCONFIRMED_BY_TX_HASH.with(|u| u.borrow_mut().remove(&tx_hash_key(tx_hash, key)));
+}
+
+// Builds the tx hash index for transfers left unconfirmed before it existed. Transfers on other
+// networks were tracked before only the polled network was; they are released as final, the way
+// they are now ingested.
+pub(crate) fn index_unconfirmed_transfers() {
+    UNCONFIRMED_BY_TX_HASH.with(|u| u.borrow_mut().clear_new());
+    let mut start = 0;
+    loop {
+        let batch = unconfirmed_from(start, MAX_TRANSFERS_PER_CHECK);
+        let Some(&(last, _)) = batch.last() else {
+            break;
+        };
+        start = last + 1;
+        for (key, transfer) in batch {
+            if transfer.token().is_polled() {
+                UNCONFIRMED_BY_TX_HASH.with(|u| u.borrow_mut().insert(tx_hash_key(&transfer.tx_hash, key), key));
+            } else {
+                UNCONFIRMED_TRANSFERS.with(|u| u.borrow_mut().remove(&key));
+                claim_transfer(&transfer);
+            }
+        }
+    }
+}
+
+// Up to `limit` unconfirmed transfers from the `start` key on
+fn unconfirmed_from(start: u64, limit: usize) -> Vec<(u64, TransferPayload)> {
+    let keys: Vec<u64> =
+        UNCONFIRMED_TRANSFERS.with(|u| u.borrow().range(start..).take(limit).map(|entry| *entry.key()).collect());
+    keys.into_iter().filter_map(|key| Some((key, transfer_at(key)?))).collect()
+}
+
+// The next MAX_TRANSFERS_PER_CHECK transfers from the cursor, wrapping to the first key at the end
+fn next_unconfirmed_batch() -> Vec<(u64, TransferPayload)> {
+    let cursor = FINALITY_CURSOR.with(|c| *c.borrow());
+    let mut batch = unconfirmed_from(cursor, MAX_TRANSFERS_PER_CHECK);
+    if batch.is_empty() && cursor > 0 {
+        batch = unconfirmed_from(0, MAX_TRANSFERS_PER_CHECK);
+    }
+    let next = match batch.last() {
+        Some(&(last, _)) if batch.len() == MAX_TRANSFERS_PER_CHECK => last.saturating_add(1),
+        _ => 0,
+    };
+    FINALITY_CURSOR.with(|c| *c.borrow_mut() = next);
+    batch
+}
+
+pub(crate) fn final_transfers() -> Vec<TransferPayload> {
+    UNCONFIRMED_TRANSFERS.with(|u| {
+        let unconfirmed = u.borrow();
+        transfer_entries()
+            .into_iter()
+            .filter(|(key, _)| !unconfirmed.contains_key(key))
+            .map(|(_, transfer)| transfer)
+            .collect()
+    })
+}
+
+// Customs linking and loans must not rely on a transfer a reorg could still remove
+pub(crate) fn check_transfer_final(tx_hash: &str) -> Result<(), CargoTraceError> {
+    let prefix = tx_hash_prefix(tx_hash);
+    let unconfirmed = UNCONFIRMED_BY_TX_HASH.with(|u| {
+        u.borrow().range(prefix.clone()..).next().is_some_and(|entry| entry.key().starts_with(&prefix))
+    });
+    if unconfirmed {
+        Err(CargoTraceError::TransferNotFinal { tx_hash: tx_hash.to_string() })
+    } else {
+        Ok(())
+    }
+}
+
+fn classify(stored_hash: Option<&str>, chain_hash: &str, block_number: u64, head: u64, depth: u64) -> Verdict {
+    if stored_hash.is_some_and(|hash| !hash.eq_ignore_ascii_case(chain_hash)) {
+        Verdict::Orphaned
+    } else if block_number.saturating_add(depth) <= head {
+        Verdict::Confirmed
+    } else {
+        Verdict::Pending
+    }
+}
+
+// Drops a transfer whose block left the canonical chain, together with any customs link that was
+// made against it. The watcher re-ingests the transaction if it is mined again.
+fn orphan_transfer(key: u64, transfer: &TransferPayload) {
+    remove_transfer(key);
+    let token = transfer.token();
+    // Another log of the same transaction still moves the token
+    if crate::transfers_in_tx(&transfer.tx_hash).iter().any(|stored| stored.token() == token) {
+        return;
+    }
+    // A document submitted before the watcher saw its transaction names no token yet
+    let linked: Vec<String> = documents_for_tx(&transfer.tx_hash)
+        .into_iter()
+        .filter(|document| {
+            document.token().is_none_or(|document_token| document_token == token)
+        })
+        .map(|document| document.id)
+        .collect();
+    for document_id in linked {
+        let made_here = CARGOX_MAPPINGS
+            .with(|m| m.borrow().get(&document_id))
+            .is_some_and(|mapping| mapping.nft_hash.eq_ignore_ascii_case(&transfer.tx_hash));
+        if made_here {
+            CARGOX_MAPPINGS.with(|m| m.borrow_mut().remove(&document_id));
+            CUSTOMS_VERIFICATIONS.with(|v| v.borrow_mut().remove(&document_id));
+        }
+    }
+    ic_cdk::println!("Transfer {} in block {} was orphaned by a reorg", transfer.tx_hash, transfer.block_number);
+}
+
+// Compares the stored block hash of each unconfirmed transfer with the polled network, the only one
+// tracked. Transfers on a replaced block are rolled back; the rest become final once `confirmation_depth` blocks below the
+// finalized head. The head is read at the finalized tag because replicas must agree on it.
+async fn check_finality() -> Result<FinalityRun, String> {
+    let Some(_check) = InFlight::claim("finality_check".to_string()) else {
+        return Ok(FinalityRun::default());
+    };
+    let result = check_unconfirmed().await;
+    update_finality_state(|state| {
+        state.last_checked_at = ic_cdk::api::time();
+        state.last_error = result.as_ref().err().cloned();
+    });
+    result
+}
+
+async fn check_unconfirmed() -> Result<FinalityRun, String> {
+    let mut run = FinalityRun::default();
+    let unconfirmed = next_unconfirmed_batch();
+    if unconfirmed.is_empty() {
+        return Ok(run);
+    }
+    let depth = finality_config().confirmation_depth;
+    let head = chain_block(BlockRef::Finalized).await?.number;
+    update_finality_state(|state| state.head_block = head);
+
+    let mut chain_hashes: HashMap<u64, String> = HashMap::new();
+    for (key, mut transfer) in unconfirmed {
+        let chain_hash = match chain_hashes.get(&transfer.block_number) {
+            Some(hash) => hash.clone(),
+            None => match chain_block(BlockRef::Number(transfer.block_number)).await {
+                Ok(block) => {
+                    chain_hashes.insert(transfer.block_number, block.hash.clone());
+                    block.hash
+                }
+                // The watcher's node may be ahead of the providers past the finalized head
+                Err(_) if transfer.block_number > head => continue,
+                Err(e) => return Err(e),
+            },
+        };
+        run.checked += 1;
+        let verdict = classify(transfer.block_hash.as_deref(), &chain_hash, transfer.block_number, head, depth);
+        if verdict == Verdict::Orphaned {
+            orphan_transfer(key, &transfer);
+            update_finality_state(|state| state.orphaned_transfers += 1);
+            run.orphaned += 1;
+            continue;
+        }
+        if transfer.block_hash.is_none() {
+            transfer.block_hash = Some(chain_hash);
+            replace_transfer(key, transfer.clone());
+        }
+        if verdict == Verdict::Confirmed {
+            forget_unconfirmed(key, &transfer.tx_hash);
+            claim_transfer(&transfer);
+            update_finality_state(|state| state.confirmed_transfers += 1);
+            run.confirmed += 1;
+        }
+    }
+    Ok(run)
+}
+
+// ---- API ----
+#[update(guard = "is_admin")]
+pub fn set_finality_config(config: FinalityConfig) -> Result<(), CargoTraceError> {
+    if config.check_interval_secs < 10 {
+        return Err(CargoTraceError::InvalidArgument("Finality check interval must be at least 10 seconds.".to_string()));
+    }
+    FINALITY_CONFIG.with(|c| c.borrow_mut().set(config));
+    arm_finality_check();
+    Ok(())
+}
+
+#[query]
+pub fn get_finality_config() -> FinalityConfig {
+    finality_config()
+}
+
+#[query]
+pub fn get_finality_state() -> FinalityState {
+    FINALITY_STATE.with(|s| s.borrow().get().clone())
+}
+
+// In arrival order; the cursor is the last transfer key of the previous page
+#[query]
+pub fn get_unconfirmed_transfers(cursor: Option<u64>, limit: Option<u32>) -> TransferPage {
+    let limit = page_limit(limit);
+    let start = cursor.map_or(0, |key| key.saturating_add(1));
+    let (transfers, next_cursor) = collect_page(unconfirmed_from(start, limit), limit, |_| true);
+    TransferPage { transfers, next_cursor }
+}
+
+#[update(guard = "is_watcher")]
+pub async fn check_finality_now() -> Result<FinalityRun, CargoTraceError> {
+    check_finality().await.map_err(CargoTraceError::ChainCallFailed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_transfers_by_depth_and_block_hash() {
+        // Confirmed once `depth` blocks are built on top
+        assert_eq!(classify(Some("0xaa"), "0xAA", 100, 111, 12), Verdict::Pending);
+        assert_eq!(classify(Some("0xaa"), "0xaa", 100, 112, 12), Verdict::Confirmed);
+        // Without a stored hash the chain's hash is adopted
+        assert_eq!(classify(None, "0xaa", 100, 112, 12), Verdict::Confirmed);
+        // A different hash at the same height means the block was replaced
+        assert_eq!(classify(Some("0xaa"), "0xbb", 100, 105, 12), Verdict::Orphaned);
+        assert_eq!(classify(Some("0xaa"), "0xbb", 100, 500, 12), Verdict::Orphaned);
+    }
+
+    fn transfer(tx_hash: &str, log_index: u64) -> TransferPayload {
+        TransferPayload {
+            network: "ethereum".to_string(),
+            contract: "0xc0".to_string(),
+            tx_hash: tx_hash.to_string(),
+            block_number: 100,
+            token_id: "1".to_string(),
+            from: "0xfrom".to_string(),
+            to: "0xto".to_string(),
+            log_index,
+            block_hash: None,
+        }
+    }
+
+    #[test]
+    fn a_transaction_is_final_once_all_its_logs_are() {
+        track_unconfirmed(1, &transfer("0xAB", 0));
+        track_unconfirmed(2, &transfer("0xab", 1));
+        track_unconfirmed(3, &transfer("0xabc", 0));
+        assert!(check_transfer_final("0xab").is_err());
+        forget_unconfirmed(1, "0xAB");
+        assert!(check_transfer_final("0xAB").is_err());
+        forget_unconfirmed(2, "0xab");
+        // A longer hash sharing the prefix does not count
+        assert!(check_transfer_final("0xab").is_ok());
+        assert!(check_transfer_final("0xabc").is_err());
+    }
+
+    #[test]
+    fn checks_walk_the_unconfirmed_transfers_from_a_cursor() {
+        for log_index in 0..(MAX_TRANSFERS_PER_CHECK as u64 + 10) {
+            let transfer = transfer("0xcursor", log_index);
+            let key = crate::store_transfer(transfer.clone()).unwrap();
+            track_unconfirmed(key, &transfer);
+        }
+        let first = next_unconfirmed_batch();
+        let second = next_unconfirmed_batch();
+        assert_eq!(first.len(), MAX_TRANSFERS_PER_CHECK);
+        assert_eq!(second.len(), 10);
+        assert!(first.last().unwrap().0 < second[0].0);
+        // Wraps around once every transfer was visited
+        assert_eq!(next_unconfirmed_batch()[0].0, first[0].0);
+    }
+}
@@ -0,0 +1,510 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::caller;
+use ic_cdk_macros::{query, update};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+use crate::abi::encode_hex;
+use crate::token_metadata::cached_metadata;
+use crate::watcher_config::TokenRef;
+use crate::versioned::{versioned_storable, Versioned};
+use crate::{collateral_lock, get_next_id, Account, Document, Memory, DOCUMENTS, MEMORY_MANAGER};
+
+// ICRC-7 collection of mirrored CargoX documents: https://github.com/dfinity/ICRC/tree/main/ICRCs/ICRC-7
+const SYMBOL: &str = "CTDOC";
+const NAME: &str = "CargoTrace Documents";
+const DESCRIPTION: &str = "Verified CargoX trade documents mirrored on the Internet Computer as loan collateral";
+const MAX_QUERY_BATCH_SIZE: usize = 100;
+const MAX_UPDATE_BATCH_SIZE: usize = 20;
+const DEFAULT_TAKE_VALUE: usize = 100;
+const MAX_TAKE_VALUE: usize = 1_000;
+const MAX_MEMO_SIZE: usize = 32;
+const TX_WINDOW_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+const PERMITTED_DRIFT_NANOS: u64 = 2 * 60 * 1_000_000_000;
+
+// ICRC-3 generic value
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum Value {
+    Blob(Vec<u8>),
+    Text(String),
+    Nat(candid::Nat),
+    Int(candid::Int),
+    Array(Vec<Value>),
+    Map(Vec<(String, Value)>),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct NftToken {
+    pub token_id: u64,
+    pub owner: Principal,
+    pub subaccount: Option<Vec<u8>>,
+    pub document_id: String,
+    pub metadata: Vec<(String, Value)>,
+    pub minted_at: u64,
+}
+
+impl NftToken {
+    fn account(&self) -> Account {
+        Account { owner: self.owner, subaccount: self.subaccount.clone() }
+    }
+}
+
+impl Versioned for NftToken {
+    const NAME: &'static str = "NftToken";
+    const VERSION: u8 = 1;
+}
+versioned_storable!(NftToken);
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Icrc7TransferArg {
+    pub from_subaccount: Option<Vec<u8>>,
+    pub to: Account,
+    pub token_id: candid::Nat,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum Icrc7TransferError {
+    NonExistingTokenId,
+    InvalidRecipient,
+    Unauthorized,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: candid::Nat },
+    GenericError { error_code: candid::Nat, message: String },
+    GenericBatchError { error_code: candid::Nat, message: String },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SupportedStandard {
+    pub name: String,
+    pub url: String,
+}
+
+// ---- STATE ----
+thread_local! {
+    static NFT_TOKENS: RefCell<StableBTreeMap<u64, NftToken, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(20))))
+    );
+    // "<created_at_time:020>|<caller>|<transfer>" -> transaction id, for transfers that set
+    // created_at_time and are still inside the deduplication window
+    static RECENT_TRANSFERS: RefCell<StableBTreeMap<String, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(43))))
+    );
+}
+
+fn token(token_id: &candid::Nat) -> Option<NftToken> {
+    let token_id: u64 = token_id.0.clone().try_into().ok()?;
+    NFT_TOKENS.with(|t| t.borrow().get(&token_id))
+}
+
+fn same_subaccount(a: &Option<Vec<u8>>, b: &Option<Vec<u8>>) -> bool {
+    // A missing subaccount is the all-zero default subaccount
+    let default = vec![0u8; 32];
+    a.as_ref().unwrap_or(&default) == b.as_ref().unwrap_or(&default)
+}
+
+fn same_account(a: &Account, b: &Account) -> bool {
+    a.owner == b.owner && same_subaccount(&a.subaccount, &b.subaccount)
+}
+
+fn text(value: &str) -> Value {
+    Value::Text(value.to_string())
+}
+
+fn document_metadata(document: &Document) -> Vec<(String, Value)> {
+    let mut metadata = vec![
+        ("icrc7:name".to_string(), text(&format!("CargoTrace {}", document.id))),
+        ("cargotrace:document_id".to_string(), text(&document.id)),
+        ("cargotrace:acid_number".to_string(), text(&document.acid_number)),
+        ("cargotrace:eth_tx_hash".to_string(), text(&document.tx_hash)),
+        ("cargotrace:value_usd".to_string(), Value::Nat(candid::Nat::from(document.value_usd))),
+    ];
+    // The token is only named once the transfer that delivered it has been seen
+    if !document.contract.is_empty() && !document.token_id.is_empty() {
+        metadata.push(("cargotrace:network".to_string(), text(&document.chain)));
+        metadata.push(("cargotrace:eth_contract".to_string(), text(&document.contract)));
+        metadata.push(("cargotrace:eth_token_id".to_string(), text(&document.token_id)));
+        let token = TokenRef::new(&document.chain, &document.contract, &document.token_id);
+        // The hash CargoX publishes in the token's metadata, if that has been fetched already
+        if let Some(hash) = cached_metadata(&token).document_hash {
+            metadata.push(("cargotrace:document_hash".to_string(), text(&hash)));
+        }
+    }
+    metadata
+}
+
+// Mints the document's token to its owner; documents already minted keep their token
+pub fn mint_document_nft(document: &Document) -> u64 {
+    if let Some(token_id) = document.nft_token_id {
+        return token_id;
+    }
+    let token_id = get_next_id("nft");
+    let token = NftToken {
+        token_id,
+        owner: document.owner,
+        subaccount: None,
+        document_id: document.id.clone(),
+        metadata: document_metadata(document),
+        minted_at: ic_cdk::api::time(),
+    };
+    NFT_TOKENS.with(|t| t.borrow_mut().insert(token_id, token));
+    ic_cdk::println!("Minted NFT {} for document {}", token_id, document.id);
+    token_id
+}
+
+// Moves a token outside icrc7_transfer, e.g. when collateral is seized
+pub(crate) fn reassign_token(token_id: u64, owner: Principal) {
+    NFT_TOKENS.with(|t| {
+        let mut tokens = t.borrow_mut();
+        if let Some(mut token) = tokens.get(&token_id) {
+            token.owner = owner;
+            token.subaccount = None;
+            tokens.insert(token_id, token);
+        }
+    });
+}
+
+// The document follows its token, so only the current holder can borrow against it
+fn sync_document_owner(document_id: &str, owner: Principal) {
+    DOCUMENTS.with(|d| {
+        let mut documents = d.borrow_mut();
+        if let Some(mut document) = documents.get(&document_id.to_string()) {
+            document.owner = owner;
+            documents.insert(document_id.to_string(), document);
+        }
+    });
+}
+
+fn subaccount_hex(subaccount: &Option<Vec<u8>>) -> String {
+    encode_hex(subaccount.as_deref().unwrap_or(&[0u8; 32]))
+}
+
+// Two transfers are duplicates when the caller and every argument match
+fn dedup_key(arg: &Icrc7TransferArg, caller: Principal, created_at: u64) -> String {
+    format!(
+        "{:020}|{}|{}|{}|{}|{}|{}",
+        created_at,
+        caller,
+        subaccount_hex(&arg.from_subaccount),
+        arg.to.owner,
+        subaccount_hex(&arg.to.subaccount),
+        arg.token_id,
+        encode_hex(arg.memo.as_deref().unwrap_or_default()),
+    )
+}
+
+// Forgets transfers created before the deduplication window; older ones fail as TooOld anyway
+fn prune_recent_transfers(now: u64) {
+    let cutoff = format!("{:020}", now.saturating_sub(TX_WINDOW_NANOS + PERMITTED_DRIFT_NANOS));
+    RECENT_TRANSFERS.with(|r| {
+        let mut recent = r.borrow_mut();
+        let expired: Vec<String> = recent.range(..cutoff).map(|entry| entry.key().clone()).collect();
+        for key in expired {
+            recent.remove(&key);
+        }
+    });
+}
+
+fn check_transfer(arg: &Icrc7TransferArg, caller: Principal, now: u64) -> Result<NftToken, Icrc7TransferError> {
+    if let Some(created_at) = arg.created_at_time {
+        if created_at < now.saturating_sub(TX_WINDOW_NANOS + PERMITTED_DRIFT_NANOS) {
+            return Err(Icrc7TransferError::TooOld);
+        }
+        if created_at > now.saturating_add(PERMITTED_DRIFT_NANOS) {
+            return Err(Icrc7TransferError::CreatedInFuture { ledger_time: now });
+        }
+        if let Some(tx_id) = RECENT_TRANSFERS.with(|r| r.borrow().get(&dedup_key(arg, caller, created_at))) {
+            return Err(Icrc7TransferError::Duplicate { duplicate_of: candid::Nat::from(tx_id) });
+        }
+    }
+    if arg.memo.as_ref().is_some_and(|memo| memo.len() > MAX_MEMO_SIZE) {
+        return Err(Icrc7TransferError::GenericError {
+            error_code: candid::Nat::from(1u64),
+            message: format!("Memo exceeds {} bytes.", MAX_MEMO_SIZE),
+        });
+    }
+    let token = token(&arg.token_id).ok_or(Icrc7TransferError::NonExistingTokenId)?;
+    // Each token names its document, whose lock covers the token
+    if collateral_lock(&token.document_id).is_some() {
+        return Err(Icrc7TransferError::GenericError {
+            error_code: candid::Nat::from(3u64),
+            message: "Token is locked as loan collateral.".to_string(),
+        });
+    }
+    let from = Account { owner: caller, subaccount: arg.from_subaccount.clone() };
+    if !same_account(&token.account(), &from) {
+        return Err(Icrc7TransferError::Unauthorized);
+    }
+    if arg.to.owner == Principal::anonymous() || same_account(&arg.to, &from) {
+        return Err(Icrc7TransferError::InvalidRecipient);
+    }
+    Ok(token)
+}
+
+// ---- ICRC-7 API ----
+#[query]
+pub fn icrc7_collection_metadata() -> Vec<(String, Value)> {
+    vec![
+        ("icrc7:symbol".to_string(), text(SYMBOL)),
+        ("icrc7:name".to_string(), text(NAME)),
+        ("icrc7:description".to_string(), text(DESCRIPTION)),
+        ("icrc7:total_supply".to_string(), Value::Nat(icrc7_total_supply())),
+        ("icrc7:max_query_batch_size".to_string(), Value::Nat(candid::Nat::from(MAX_QUERY_BATCH_SIZE))),
+        ("icrc7:max_update_batch_size".to_string(), Value::Nat(candid::Nat::from(MAX_UPDATE_BATCH_SIZE))),
+        ("icrc7:default_take_value".to_string(), Value::Nat(candid::Nat::from(DEFAULT_TAKE_VALUE))),
+        ("icrc7:max_take_value".to_string(), Value::Nat(candid::Nat::from(MAX_TAKE_VALUE))),
+        ("icrc7:max_memo_size".to_string(), Value::Nat(candid::Nat::from(MAX_MEMO_SIZE))),
+        ("icrc7:tx_window".to_string(), Value::Nat(candid::Nat::from(TX_WINDOW_NANOS))),
+        ("icrc7:permitted_drift".to_string(), Value::Nat(candid::Nat::from(PERMITTED_DRIFT_NANOS))),
+    ]
+}
+
+#[query]
+pub fn icrc7_symbol() -> String {
+    SYMBOL.to_string()
+}
+
+#[query]
+pub fn icrc7_name() -> String {
+    NAME.to_string()
+}
+
+#[query]
+pub fn icrc7_description() -> Option<String> {
+    Some(DESCRIPTION.to_string())
+}
+
+#[query]
+pub fn icrc7_logo() -> Option<String> {
+    None
+}
+
+#[query]
+pub fn icrc7_total_supply() -> candid::Nat {
+    candid::Nat::from(NFT_TOKENS.with(|t| t.borrow().len()))
+}
+
+#[query]
+pub fn icrc7_supply_cap() -> Option<candid::Nat> {
+    None
+}
+
+#[query]
+pub fn icrc7_max_query_batch_size() -> Option<candid::Nat> {
+    Some(candid::Nat::from(MAX_QUERY_BATCH_SIZE))
+}
+
+#[query]
+pub fn icrc7_max_update_batch_size() -> Option<candid::Nat> {
+    Some(candid::Nat::from(MAX_UPDATE_BATCH_SIZE))
+}
+
+#[query]
+pub fn icrc7_default_take_value() -> Option<candid::Nat> {
+    Some(candid::Nat::from(DEFAULT_TAKE_VALUE))
+}
+
+#[query]
+pub fn icrc7_max_take_value() -> Option<candid::Nat> {
+    Some(candid::Nat::from(MAX_TAKE_VALUE))
+}
+
+#[query]
+pub fn icrc7_max_memo_size() -> Option<candid::Nat> {
+    Some(candid::Nat::from(MAX_MEMO_SIZE))
+}
+
+#[query]
+pub fn icrc7_atomic_batch_transfers() -> Option<bool> {
+    Some(false)
+}
+
+#[query]
+pub fn icrc7_tx_window() -> Option<candid::Nat> {
+    Some(candid::Nat::from(TX_WINDOW_NANOS))
+}
+
+#[query]
+pub fn icrc7_permitted_drift() -> Option<candid::Nat> {
+    Some(candid::Nat::from(PERMITTED_DRIFT_NANOS))
+}
+
+#[query]
+pub fn icrc7_token_metadata(token_ids: Vec<candid::Nat>) -> Vec<Option<Vec<(String, Value)>>> {
+    token_ids
+        .iter()
+        .take(MAX_QUERY_BATCH_SIZE)
+        .map(|id| token(id).map(|token| token.metadata))
+        .collect()
+}
+
+#[query]
+pub fn icrc7_owner_of(token_ids: Vec<candid::Nat>) -> Vec<Option<Account>> {
+    token_ids
+        .iter()
+        .take(MAX_QUERY_BATCH_SIZE)
+        .map(|id| token(id).map(|token| token.account()))
+        .collect()
+}
+
+#[query]
+pub fn icrc7_balance_of(accounts: Vec<Account>) -> Vec<candid::Nat> {
+    NFT_TOKENS.with(|t| {
+        let tokens = t.borrow();
+        accounts
+            .iter()
+            .take(MAX_QUERY_BATCH_SIZE)
+            .map(|account| {
+                candid::Nat::from(tokens.iter().filter(|entry| same_account(&entry.value().account(), account)).count())
+            })
+            .collect()
+    })
+}
+
+fn take_value(take: Option<candid::Nat>) -> usize {
+    take.and_then(|take| usize::try_from(take.0).ok())
+        .unwrap_or(DEFAULT_TAKE_VALUE)
+        .min(MAX_TAKE_VALUE)
+}
+
+fn start_after(prev: Option<candid::Nat>) -> u64 {
+    prev.and_then(|prev| u64::try_from(prev.0).ok())
+        .map_or(0, |prev| prev.saturating_add(1))
+}
+
+#[query]
+pub fn icrc7_tokens(prev: Option<candid::Nat>, take: Option<candid::Nat>) -> Vec<candid::Nat> {
+    NFT_TOKENS.with(|t| {
+        t.borrow()
+            .range(start_after(prev)..)
+            .take(take_value(take))
+            .map(|entry| candid::Nat::from(*entry.key()))
+            .collect()
+    })
+}
+
+#[query]
+pub fn icrc7_tokens_of(account: Account, prev: Option<candid::Nat>, take: Option<candid::Nat>) -> Vec<candid::Nat> {
+    NFT_TOKENS.with(|t| {
+        t.borrow()
+            .range(start_after(prev)..)
+            .filter(|entry| same_account(&entry.value().account(), &account))
+            .take(take_value(take))
+            .map(|entry| candid::Nat::from(*entry.key()))
+            .collect()
+    })
+}
+
+#[update]
+pub fn icrc7_transfer(args: Vec<Icrc7TransferArg>) -> Vec<Option<Result<candid::Nat, Icrc7TransferError>>> {
+    if args.len() > MAX_UPDATE_BATCH_SIZE {
+        let error = Icrc7TransferError::GenericBatchError {
+            error_code: candid::Nat::from(2u64),
+            message: format!("Batch exceeds {} transfers.", MAX_UPDATE_BATCH_SIZE),
+        };
+        return vec![Some(Err(error))];
+    }
+    let caller = caller();
+    let now = ic_cdk::api::time();
+    prune_recent_transfers(now);
+    args.into_iter().map(|arg| Some(transfer(&arg, caller, now))).collect()
+}
+
+fn transfer(arg: &Icrc7TransferArg, caller: Principal, now: u64) -> Result<candid::Nat, Icrc7TransferError> {
+    let mut token = check_transfer(arg, caller, now)?;
+    token.owner = arg.to.owner;
+    token.subaccount = arg.to.subaccount.clone();
+    sync_document_owner(&token.document_id, token.owner);
+    NFT_TOKENS.with(|t| t.borrow_mut().insert(token.token_id, token));
+    let tx_id = get_next_id("nft_tx");
+    if let Some(created_at) = arg.created_at_time {
+        RECENT_TRANSFERS.with(|r| r.borrow_mut().insert(dedup_key(arg, caller, created_at), tx_id));
+    }
+    Ok(candid::Nat::from(tx_id))
+}
+
+#[query]
+pub fn icrc10_supported_standards() -> Vec<SupportedStandard> {
+    vec![
+        SupportedStandard {
+            name: "ICRC-7".to_string(),
+            url: "https://github.com/dfinity/ICRC/tree/main/ICRCs/ICRC-7".to_string(),
+        },
+        SupportedStandard {
+            name: "ICRC-10".to_string(),
+            url: "https://github.com/dfinity/ICRC/tree/main/ICRCs/ICRC-10".to_string(),
+        },
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DocumentStatus;
+
+    fn minted_document(id: &str, owner: Principal) -> u64 {
+        let document = Document {
+            id: id.to_string(),
+            acid_number: "123456789".to_string(),
+            chain: "ethereum".to_string(),
+            contract: String::new(),
+            token_id: String::new(),
+            tx_hash: "0xab".to_string(),
+            value_usd: 0,
+            status: DocumentStatus::NftMinted,
+            created_at: 0,
+            owner,
+            nft_token_id: None,
+        };
+        let token_id = get_next_id("nft");
+        let token = NftToken {
+            token_id,
+            owner,
+            subaccount: None,
+            document_id: id.to_string(),
+            metadata: vec![],
+            minted_at: 0,
+        };
+        NFT_TOKENS.with(|t| t.borrow_mut().insert(token_id, token));
+        DOCUMENTS.with(|d| d.borrow_mut().insert(id.to_string(), Document { nft_token_id: Some(token_id), ..document }));
+        token_id
+    }
+
+    #[test]
+    fn default_subaccount_matches_zero_subaccount() {
+        let owner = Principal::from_slice(&[3; 29]);
+        let default = Account { owner, subaccount: None };
+        assert!(same_account(&default, &Account { owner, subaccount: Some(vec![0; 32]) }));
+        assert!(!same_account(&default, &Account { owner, subaccount: Some(vec![1; 32]) }));
+        assert!(!same_account(&default, &Account { owner: Principal::anonymous(), subaccount: None }));
+    }
+
+    #[test]
+    fn transfers_move_the_document_and_reject_duplicates() {
+        let holder = Principal::from_slice(&[4; 29]);
+        let buyer = Principal::from_slice(&[5; 29]);
+        let token_id = minted_document("DOC-ICRC7", holder);
+        let now = 10 * TX_WINDOW_NANOS;
+        let arg = Icrc7TransferArg {
+            from_subaccount: None,
+            to: Account { owner: buyer, subaccount: None },
+            token_id: candid::Nat::from(token_id),
+            memo: Some(vec![1, 2]),
+            created_at_time: Some(now),
+        };
+
+        let tx_id = transfer(&arg, holder, now).unwrap();
+        let document = DOCUMENTS.with(|d| d.borrow().get(&"DOC-ICRC7".to_string())).unwrap();
+        assert_eq!(document.owner, buyer);
+        assert_eq!(transfer(&arg, holder, now), Err(Icrc7TransferError::Duplicate { duplicate_of: tx_id }));
+
+        // Outside the window the same transfer is too old rather than a duplicate
+        let later = now + TX_WINDOW_NANOS + PERMITTED_DRIFT_NANOS + 1;
+        prune_recent_transfers(later);
+        assert_eq!(transfer(&arg, holder, later), Err(Icrc7TransferError::TooOld));
+        assert!(RECENT_TRANSFERS.with(|r| r.borrow().is_empty()));
+    }
+}
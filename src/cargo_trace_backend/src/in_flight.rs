@@ -0,0 +1,39 @@
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+
+// Keys of the calls that are between an await and the state change that follows it
+thread_local! {
+    static IN_FLIGHT: RefCell<BTreeSet<String>> = const { RefCell::new(BTreeSet::new()) };
+}
+
+// Held across an await; dropping it releases the key. ic-cdk drops a call's future when its
+// callback traps, so a trapped call releases its key too.
+pub(crate) struct InFlight(String);
+
+impl InFlight {
+    // None while another call holds the key
+    pub(crate) fn claim(key: String) -> Option<Self> {
+        IN_FLIGHT.with(|f| f.borrow_mut().insert(key.clone())).then_some(InFlight(key))
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        IN_FLIGHT.with(|f| f.borrow_mut().remove(&self.0));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_is_held_until_the_guard_drops() {
+        let first = InFlight::claim("repay:LOAN-000001".to_string());
+        assert!(first.is_some());
+        assert!(InFlight::claim("repay:LOAN-000001".to_string()).is_none());
+        assert!(InFlight::claim("repay:LOAN-000002".to_string()).is_some());
+        drop(first);
+        assert!(InFlight::claim("repay:LOAN-000001".to_string()).is_some());
+    }
+}
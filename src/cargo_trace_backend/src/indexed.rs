@@ -0,0 +1,225 @@
+use ic_stable_structures::btreemap::Iter;
+use ic_stable_structures::{StableBTreeMap, Storable};
+
+use crate::versioned;
+use crate::Memory;
+
+// Records are indexed under "<field>:<value>" terms
+pub(crate) trait Indexed {
+    fn index_terms(&self) -> Vec<String>;
+}
+
+// "|" ends the term in an index key, so it is escaped in values, as is the escape character; a value
+// can then never extend into another value's range
+pub(crate) fn term(field: &str, value: &str) -> String {
+    let value = value.to_ascii_lowercase().replace('\\', "\\\\").replace('|', "\\|");
+    format!("{}:{}", field, value)
+}
+
+fn index_key(term: &str, key: &str) -> String {
+    format!("{}|{}", term, key)
+}
+
+// A StableBTreeMap whose secondary index is updated by every write, so lookups by owner, status
+// or hash read only the matching records
+pub(crate) struct IndexedMap<V: Storable + Indexed> {
+    records: StableBTreeMap<String, V, Memory>,
+    // "<term>|<record key>" -> record key
+    index: StableBTreeMap<String, String, Memory>,
+}
+
+impl<V: Storable + Indexed> IndexedMap<V> {
+    pub(crate) fn init(records: Memory, index: Memory) -> Self {
+        IndexedMap { records: StableBTreeMap::init(records), index: StableBTreeMap::init(index) }
+    }
+
+    pub(crate) fn get(&self, key: &String) -> Option<V> {
+        self.records.get(key)
+    }
+
+    pub(crate) fn contains_key(&self, key: &String) -> bool {
+        self.records.contains_key(key)
+    }
+
+    pub(crate) fn iter(&self) -> Iter<'_, String, V, Memory> {
+        self.records.iter()
+    }
+
+    #[cfg(feature = "bench")]
+    pub(crate) fn len(&self) -> u64 {
+        self.records.len()
+    }
+
+    pub(crate) fn insert(&mut self, key: String, value: V) -> Option<V> {
+        let terms = value.index_terms();
+        let old = self.records.insert(key.clone(), value);
+        let old_terms = old.as_ref().map(Indexed::index_terms).unwrap_or_default();
+        for stale in old_terms.iter().filter(|t| !terms.contains(t)) {
+            self.index.remove(&index_key(stale, &key));
+        }
+        for fresh in terms.iter().filter(|t| !old_terms.contains(t)) {
+            self.index.insert(index_key(fresh, &key), key.clone());
+        }
+        old
+    }
+
+    pub(crate) fn remove(&mut self, key: &String) -> Option<V> {
+        let old = self.records.remove(key)?;
+        for stale in old.index_terms() {
+            self.index.remove(&index_key(&stale, key));
+        }
+        Some(old)
+    }
+
+    // Keys of the records indexed under `term`, in key order
+    pub(crate) fn keys_for(&self, term: &str) -> Vec<String> {
+        let prefix = index_key(term, "");
+        self.index
+            .range(prefix.clone()..)
+            .take_while(|entry| entry.key().starts_with(&prefix))
+            .map(|entry| entry.value())
+            .collect()
+    }
+
+    pub(crate) fn find(&self, term: &str) -> Vec<V> {
+        self.keys_for(term).iter().filter_map(|key| self.records.get(key)).collect()
+    }
+
+    // Records with keys after `cursor`, in key order; only those under `term` if set. Each record
+    // is read and decoded only when the iterator reaches it.
+    pub(crate) fn scan<'a>(
+        &'a self,
+        term: Option<&str>,
+        cursor: Option<&String>,
+    ) -> Box<dyn Iterator<Item = (String, V)> + 'a> {
+        let start = cursor.cloned().unwrap_or_default();
+        let after = {
+            let cursor = cursor.cloned();
+            move |key: &String| cursor.as_ref().is_none_or(|cursor| key > cursor)
+        };
+        match term {
+            Some(term) => {
+                let prefix = index_key(term, "");
+                Box::new(
+                    self.index
+                        .range(index_key(term, &start)..)
+                        .take_while(move |entry| entry.key().starts_with(&prefix))
+                        .map(|entry| entry.value())
+                        .filter(after)
+                        .filter_map(|key| Some((key.clone(), self.records.get(&key)?))),
+                )
+            }
+            None => Box::new(
+                self.records
+                    .range(start..)
+                    .filter(move |entry| after(entry.key()))
+                    .map(|entry| (entry.key().clone(), entry.value())),
+            ),
+        }
+    }
+
+    // Re-encodes every record with the current version and rebuilds the index from scratch
+    pub(crate) fn rewrite_all(&mut self) {
+        versioned::rewrite_all(&mut self.records);
+        self.index.clear_new();
+        let entries: Vec<(String, Vec<String>)> =
+            self.records.iter().map(|entry| (entry.key().clone(), entry.value().index_terms())).collect();
+        for (key, terms) in entries {
+            for term in terms {
+                self.index.insert(index_key(&term, &key), key.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Document, DocumentStatus};
+    use candid::Principal;
+    use ic_stable_structures::memory_manager::{MemoryId, MemoryManager};
+    use ic_stable_structures::DefaultMemoryImpl;
+
+    fn document(id: &str, owner: u8, status: DocumentStatus) -> Document {
+        Document {
+            id: id.to_string(),
+            acid_number: "123456789".to_string(),
+            chain: "ethereum".to_string(),
+            contract: String::new(),
+            token_id: String::new(),
+            tx_hash: format!("0xAB{}", owner),
+            value_usd: 0,
+            status,
+            created_at: 0,
+            owner: Principal::from_slice(&[owner; 29]),
+            nft_token_id: None,
+        }
+    }
+
+    fn ids(documents: Vec<Document>) -> Vec<String> {
+        documents.into_iter().map(|doc| doc.id).collect()
+    }
+
+    #[test]
+    fn writes_keep_the_index_in_step() {
+        let mm = MemoryManager::init(DefaultMemoryImpl::default());
+        let mut map: IndexedMap<Document> = IndexedMap::init(mm.get(MemoryId::new(0)), mm.get(MemoryId::new(1)));
+        map.insert("DOC-1".to_string(), document("DOC-1", 1, DocumentStatus::Pending));
+        map.insert("DOC-2".to_string(), document("DOC-2", 2, DocumentStatus::Pending));
+        map.insert("DOC-3".to_string(), document("DOC-3", 1, DocumentStatus::Pending));
+        let owner_1 = term("owner", &Principal::from_slice(&[1; 29]).to_text());
+        let pending = term("status", "Pending");
+        assert_eq!(ids(map.find(&owner_1)), ["DOC-1", "DOC-3"]);
+        assert_eq!(map.find(&pending).len(), 3);
+        assert_eq!(ids(map.find(&term("tx", "0xab2"))), ["DOC-2"]);
+
+        map.insert("DOC-1".to_string(), document("DOC-1", 2, DocumentStatus::Verified));
+        assert_eq!(ids(map.find(&owner_1)), ["DOC-3"]);
+        assert_eq!(ids(map.find(&pending)), ["DOC-2", "DOC-3"]);
+        assert_eq!(ids(map.find(&term("status", "Verified"))), ["DOC-1"]);
+
+        map.remove(&"DOC-3".to_string());
+        assert!(map.find(&owner_1).is_empty());
+        assert_eq!(map.find(&term("acid", "123456789")).len(), 2);
+
+        // Entries written around the index are picked up by a rewrite
+        map.records.insert("DOC-4".to_string(), document("DOC-4", 1, DocumentStatus::Pending));
+        map.index.insert("stale|DOC-9".to_string(), "DOC-9".to_string());
+        map.rewrite_all();
+        assert_eq!(ids(map.find(&owner_1)), ["DOC-4"]);
+        assert!(map.find("stale").is_empty());
+        // Three documents under owner, status, tx and acid
+        assert_eq!(map.index.len(), 3 * 4);
+    }
+
+    #[test]
+    fn values_cannot_reach_into_another_values_range() {
+        let mm = MemoryManager::init(DefaultMemoryImpl::default());
+        let mut map: IndexedMap<Document> = IndexedMap::init(mm.get(MemoryId::new(0)), mm.get(MemoryId::new(1)));
+        // Each document is keyed by its own ACID number
+        for acid in ["1", "1|DOC-0", "1\\|DOC-0", "1\\"] {
+            let document = Document { acid_number: acid.to_string(), ..document(acid, 1, DocumentStatus::Pending) };
+            map.insert(acid.to_string(), document);
+        }
+        assert_eq!(ids(map.find(&term("acid", "1"))), ["1"]);
+        assert_eq!(ids(map.find(&term("acid", "1|DOC-0"))), ["1|DOC-0"]);
+        assert_eq!(ids(map.find(&term("acid", "1\\"))), ["1\\"]);
+    }
+
+    #[test]
+    fn scans_resume_after_the_cursor() {
+        let mm = MemoryManager::init(DefaultMemoryImpl::default());
+        let mut map: IndexedMap<Document> = IndexedMap::init(mm.get(MemoryId::new(0)), mm.get(MemoryId::new(1)));
+        for i in 1..=5u8 {
+            let status = if i % 2 == 0 { DocumentStatus::Verified } else { DocumentStatus::Pending };
+            let id = format!("DOC-{}", i);
+            map.insert(id.clone(), document(&id, i, status));
+        }
+        let keys = |scanned: &mut dyn Iterator<Item = (String, Document)>| scanned.map(|(key, _)| key).collect::<Vec<_>>();
+        assert_eq!(keys(&mut map.scan(None, None).take(2)), ["DOC-1", "DOC-2"]);
+        assert_eq!(keys(&mut map.scan(None, Some(&"DOC-2".to_string()))), ["DOC-3", "DOC-4", "DOC-5"]);
+        let pending = term("status", "Pending");
+        assert_eq!(keys(&mut map.scan(Some(&pending), None)), ["DOC-1", "DOC-3", "DOC-5"]);
+        assert_eq!(keys(&mut map.scan(Some(&pending), Some(&"DOC-1".to_string())).take(1)), ["DOC-3"]);
+    }
+}
@@ -0,0 +1,248 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk_macros::{query, update};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableCell;
+use std::cell::RefCell;
+
+use crate::roles::is_admin;
+use crate::versioned::{versioned_storable, Versioned};
+use crate::{get_loan, CargoTraceError, Loan, Memory, MEMORY_MANAGER};
+
+const SECONDS_PER_DAY: u64 = 86_400;
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+// ---- Day-count conventions ----
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum DayCount {
+    // Actual elapsed time over a 360-day year
+    Actual360,
+    // Actual elapsed time over a 365-day year
+    Actual365,
+    // 30E/360: every month counts as 30 days, over a 360-day year
+    Thirty360,
+}
+
+// ---- Configuration ----
+// Snapshotted onto each loan when it is requested
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct LendingConfig {
+    pub interest_rate_bps: u32,
+    pub day_count: DayCount,
+}
+
+impl Default for LendingConfig {
+    fn default() -> Self {
+        LendingConfig {
+            interest_rate_bps: 450,
+            day_count: DayCount::Actual365,
+        }
+    }
+}
+
+impl Versioned for LendingConfig {
+    const NAME: &'static str = "LendingConfig";
+    const VERSION: u8 = 1;
+}
+versioned_storable!(LendingConfig);
+
+thread_local! {
+    static LENDING_CONFIG: RefCell<StableCell<LendingConfig, Memory>> = RefCell::new(
+        StableCell::init(MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(14))), LendingConfig::default())
+    );
+}
+
+pub fn lending_config() -> LendingConfig {
+    LENDING_CONFIG.with(|c| c.borrow().get().clone())
+}
+
+#[update(guard = "is_admin")]
+pub fn set_lending_config(config: LendingConfig) -> Result<(), CargoTraceError> {
+    if config.interest_rate_bps > 10_000 {
+        return Err(CargoTraceError::InvalidArgument("Interest rate cannot exceed 10000 basis points.".to_string()));
+    }
+    LENDING_CONFIG.with(|c| c.borrow_mut().set(config));
+    Ok(())
+}
+
+#[query]
+pub fn get_lending_config() -> LendingConfig {
+    lending_config()
+}
+
+// ---- Accrual ----
+
+// Proleptic Gregorian (year, month, day) for a count of days since 1970-01-01
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+fn days_30e_360(from_secs: u64, to_secs: u64) -> u64 {
+    let (y1, m1, d1) = civil_from_days((from_secs / SECONDS_PER_DAY) as i64);
+    let (y2, m2, d2) = civil_from_days((to_secs / SECONDS_PER_DAY) as i64);
+    let days = 360 * (y2 - y1) + 30 * (m2 - m1) + (d2.min(30) - d1.min(30));
+    days.max(0) as u64
+}
+
+// Simple interest on `principal` between two timestamps in nanoseconds, rounded down to whole cents
+pub fn accrued_interest(principal: u64, rate_bps: u32, day_count: DayCount, from: u64, to: u64) -> u64 {
+    if to <= from || principal == 0 || rate_bps == 0 {
+        return 0;
+    }
+    let from_secs = from / NANOS_PER_SECOND;
+    let to_secs = to / NANOS_PER_SECOND;
+    let (elapsed_secs, year_days) = match day_count {
+        DayCount::Actual360 => (to_secs - from_secs, 360),
+        DayCount::Actual365 => (to_secs - from_secs, 365),
+        DayCount::Thirty360 => (days_30e_360(from_secs, to_secs) * SECONDS_PER_DAY, 360),
+    };
+    let numerator = principal as u128 * rate_bps as u128 * elapsed_secs as u128;
+    let denominator = 10_000u128 * year_days as u128 * SECONDS_PER_DAY as u128;
+    (numerator / denominator).min(u64::MAX as u128) as u64
+}
+
+// Interest owed on a loan at `at`, including interest accrued before `accrued_until`
+pub fn interest_owed(loan: &Loan, at: u64) -> u64 {
+    loan.interest_outstanding
+        + accrued_interest(loan.principal_outstanding, loan.interest_rate_bps, loan.day_count, loan.accrued_until, at)
+}
+
+// Brings the loan's interest up to `at`
+pub fn accrue(loan: &mut Loan, at: u64) {
+    if at > loan.accrued_until {
+        loan.interest_outstanding = interest_owed(loan, at);
+        loan.accrued_until = at;
+    }
+}
+
+// Applies a payment to accrued interest first, then principal. Returns the amount applied.
+pub fn apply_payment(loan: &mut Loan, amount: u64, at: u64) -> u64 {
+    accrue(loan, at);
+    let to_interest = amount.min(loan.interest_outstanding);
+    loan.interest_outstanding -= to_interest;
+    let to_principal = (amount - to_interest).min(loan.principal_outstanding);
+    loan.principal_outstanding -= to_principal;
+    let applied = to_interest + to_principal;
+    loan.total_repaid += applied;
+    applied
+}
+
+pub fn is_paid_off(loan: &Loan) -> bool {
+    loan.principal_outstanding == 0 && loan.interest_outstanding == 0
+}
+
+// ---- Balance query ----
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct LoanBalance {
+    pub loan_id: String,
+    pub as_of: u64,
+    pub interest_rate_bps: u32,
+    pub day_count: DayCount,
+    pub principal_outstanding: u64,
+    pub interest_outstanding: u64,
+    pub payoff_amount: u64,
+    pub total_repaid: u64,
+}
+
+// Outstanding amounts at `at` (nanoseconds), or now if omitted
+#[query]
+pub fn get_loan_balance(loan_id: String, at: Option<u64>) -> Result<LoanBalance, CargoTraceError> {
+    let loan = get_loan(loan_id.clone()).ok_or_else(|| CargoTraceError::not_found("Loan", &loan_id))?;
+    let as_of = at.unwrap_or_else(ic_cdk::api::time);
+    let interest_outstanding = interest_owed(&loan, as_of);
+    Ok(LoanBalance {
+        loan_id,
+        as_of,
+        interest_rate_bps: loan.interest_rate_bps,
+        day_count: loan.day_count,
+        principal_outstanding: loan.principal_outstanding,
+        interest_outstanding,
+        payoff_amount: loan.principal_outstanding + interest_outstanding,
+        total_repaid: loan.total_repaid,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: u64 = SECONDS_PER_DAY * NANOS_PER_SECOND;
+
+    #[test]
+    fn actual_conventions_accrue_by_elapsed_days() {
+        // 10,000.00 USD at 4.5% for 365 days
+        assert_eq!(accrued_interest(1_000_000, 450, DayCount::Actual365, 0, 365 * DAY), 45_000);
+        assert_eq!(accrued_interest(1_000_000, 450, DayCount::Actual360, 0, 360 * DAY), 45_000);
+        assert_eq!(accrued_interest(1_000_000, 450, DayCount::Actual360, 0, 365 * DAY), 45_625);
+        assert_eq!(accrued_interest(1_000_000, 450, DayCount::Actual365, 10 * DAY, 10 * DAY), 0);
+    }
+
+    #[test]
+    fn thirty_360_counts_months_as_thirty_days() {
+        // 2024-01-31 to 2024-03-01: 30E/360 counts 31 days despite February
+        let jan_31 = 19_753 * DAY;
+        let mar_01 = 19_783 * DAY;
+        assert_eq!(civil_from_days(19_753), (2024, 1, 31));
+        assert_eq!(civil_from_days(19_783), (2024, 3, 1));
+        assert_eq!(days_30e_360(jan_31 / NANOS_PER_SECOND, mar_01 / NANOS_PER_SECOND), 31);
+        assert_eq!(accrued_interest(3_600_000, 1_000, DayCount::Thirty360, jan_31, mar_01), 31_000);
+    }
+
+    fn loan(principal: u64, interest: u64, rate_bps: u32) -> Loan {
+        Loan {
+            id: "LOAN-000001".to_string(),
+            document_id: "DOC-000001".to_string(),
+            amount: principal,
+            interest_rate_bps: rate_bps,
+            day_count: DayCount::Actual365,
+            status: crate::LoanStatus::Active,
+            created_at: 0,
+            borrower: candid::Principal::anonymous(),
+            repayment_date: 365 * DAY,
+            transfer_block_height: None,
+            transfer_error: None,
+            principal_outstanding: principal,
+            interest_outstanding: interest,
+            accrued_until: 0,
+            total_repaid: 0,
+            late_penalty: None,
+        }
+    }
+
+    #[test]
+    fn partial_payments_settle_interest_before_principal() {
+        let mut loan = loan(100_000, 2_000, 0);
+        assert_eq!(apply_payment(&mut loan, 1_500, 0), 1_500);
+        assert_eq!((loan.interest_outstanding, loan.principal_outstanding), (500, 100_000));
+
+        assert_eq!(apply_payment(&mut loan, 10_500, 0), 10_500);
+        assert_eq!((loan.interest_outstanding, loan.principal_outstanding), (0, 90_000));
+        assert_eq!(loan.total_repaid, 12_000);
+        assert!(!is_paid_off(&loan));
+    }
+
+    #[test]
+    fn payment_first_accrues_interest_to_the_payment_date() {
+        // 4.5% on 1,000.00 USD for a year
+        let mut loan = loan(100_000, 0, 450);
+        assert_eq!(apply_payment(&mut loan, 4_500, 365 * DAY), 4_500);
+        assert_eq!((loan.interest_outstanding, loan.principal_outstanding), (0, 100_000));
+        assert_eq!(loan.accrued_until, 365 * DAY);
+    }
+
+    #[test]
+    fn overpayment_applies_only_the_payoff_amount() {
+        let mut loan = loan(100_000, 2_000, 0);
+        assert_eq!(apply_payment(&mut loan, 150_000, 0), 102_000);
+        assert!(is_paid_off(&loan));
+        assert_eq!(loan.total_repaid, 102_000);
+    }
+}
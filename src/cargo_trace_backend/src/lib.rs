@@ -1,11 +1,9 @@
 use candid::{CandidType, Deserialize, Principal};
-use ic_cdk::{query, update, caller, pre_upgrade, post_upgrade, api::call::call};
+use ic_cdk::{query, update, caller, pre_upgrade, post_upgrade, api::call::{call, RejectionCode}};
 use ic_cdk::export_candid;
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
-use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, Storable};
-use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, StableCell};
 use std::cell::RefCell;
-use std::collections::HashMap;
 use ic_cdk::api::management_canister::http_request::{TransformArgs, HttpResponse};
 use num_bigint::BigUint;
 
@@ -18,6 +16,56 @@ mod cargox_watcher;
 pub use cargox_watcher::*;
 mod cargowatcher;
 pub use cargowatcher::*;
+mod roles;
+pub use roles::*;
+mod interest;
+pub use interest::*;
+mod maturity;
+pub use maturity::*;
+mod acid_registry;
+pub use acid_registry::*;
+mod nafeza;
+pub use nafeza::*;
+mod icrc7;
+pub use icrc7::*;
+mod collateral;
+pub use collateral::*;
+mod eth_poller;
+pub use eth_poller::*;
+mod evm_rpc;
+pub use evm_rpc::*;
+mod finality;
+pub use finality::*;
+mod transfer_history;
+pub use transfer_history::*;
+mod token_metadata;
+pub use token_metadata::*;
+mod abi;
+pub use abi::*;
+mod watcher_config;
+pub use watcher_config::*;
+mod outcalls;
+pub use outcalls::*;
+mod eth_binding;
+pub use eth_binding::*;
+mod versioned;
+use versioned::{versioned_storable, LegacyReader, Versioned};
+mod indexed;
+use indexed::{term, Indexed, IndexedMap};
+mod pagination;
+pub use pagination::*;
+mod errors;
+pub use errors::*;
+mod in_flight;
+use in_flight::InFlight;
+#[cfg(feature = "mock-ledger")]
+mod mock_ledger;
+#[cfg(feature = "mock-ledger")]
+pub use mock_ledger::*;
+#[cfg(feature = "bench")]
+mod bench;
+#[cfg(feature = "bench")]
+pub use bench::*;
 
 // ICRC-1 Types
 #[derive(CandidType, Deserialize, Clone, Debug)]
@@ -48,39 +96,84 @@ pub enum TransferError {
     GenericError { error_code: candid::Nat, message: String },
 }
 
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TransferFromArgs {
+    pub spender_subaccount: Option<Vec<u8>>,
+    pub from: Account,
+    pub to: Account,
+    pub amount: candid::Nat,
+    pub fee: Option<candid::Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum TransferFromError {
+    BadFee { expected_fee: candid::Nat },
+    BadBurn { min_burn_amount: candid::Nat },
+    InsufficientFunds { balance: candid::Nat },
+    InsufficientAllowance { allowance: candid::Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: candid::Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: candid::Nat, message: String },
+}
+
 // #[derive(CandidType, Deserialize, Clone, Debug)]
 // pub struct BalanceArgs {
 //     pub account: Account,
 // }
 
 // Configuration
-const LEDGER_CANISTER_ID: &str = "umunu-kh777-77774-qaaca-cai"; // Replace with your actual ledger canister ID
-const TRANSFER_FEE: u64 = 100_000; // 0.0001 TCIP (with 8 decimals)
-const DECIMALS: u8 = 8;
+const LEDGER_CANISTER_ID: Option<&str> = option_env!("CANISTER_ID_LEDGER"); // Set by dfx for the `ledger` canister in dfx.json
+const DECIMALS: u32 = 8;
+// One USD cent in ledger base units
+const TOKENS_PER_CENT: u64 = 10u64.pow(DECIMALS - 2);
 
 // Convert USD cents to ICRC-1 tokens (1 USD = 100,000,000 e8s tokens)
 fn usd_to_tokens(usd_cents: u64) -> u64 {
-    usd_cents * 1_000_000 // Convert cents to e8s (100 cents = 100,000,000 e8s)
+    usd_cents * TOKENS_PER_CENT // Convert cents to e8s (100 cents = 100,000,000 e8s)
 }
 
 // Convert ICRC-1 tokens to USD cents for display
 fn tokens_to_usd_cents(tokens: u64) -> u64 {
-    tokens / 1_000_000 // Convert e8s to cents
+    tokens / TOKENS_PER_CENT // Convert e8s to cents
 }
 
 // Define memory manager
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 
+// Ledger configuration kept in a stable cell so it survives upgrades
+#[derive(CandidType, Deserialize, Clone, Default)]
+struct LedgerSettings {
+    ledger_principal: Option<Principal>,
+    // When enabled, ledger calls are served from the local BALANCES map instead of the ICRC ledger.
+    // Only builds with the `mock-ledger` feature read it.
+    test_mode: bool,
+}
+
+impl Versioned for LedgerSettings {
+    const NAME: &'static str = "LedgerSettings";
+    const VERSION: u8 = 1;
+
+    // Version 0 was plain Candid
+    fn migrate(_version: u8, bytes: &[u8]) -> Result<Self, String> {
+        candid::decode_one(bytes).map_err(|e| e.to_string())
+    }
+}
+versioned_storable!(LedgerSettings);
+
 // Define stable storage
 thread_local! {
     static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
         MemoryManager::init(DefaultMemoryImpl::default())
     );
-    static DOCUMENTS: RefCell<StableBTreeMap<String, Document, Memory>> = RefCell::new(
-        StableBTreeMap::init(MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(1))))
+    static DOCUMENTS: RefCell<IndexedMap<Document>> = RefCell::new(
+        MEMORY_MANAGER.with(|mm| IndexedMap::init(mm.borrow().get(MemoryId::new(1)), mm.borrow().get(MemoryId::new(38))))
     );
-    static LOANS: RefCell<StableBTreeMap<String, Loan, Memory>> = RefCell::new(
-        StableBTreeMap::init(MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(2))))
+    static LOANS: RefCell<IndexedMap<Loan>> = RefCell::new(
+        MEMORY_MANAGER.with(|mm| IndexedMap::init(mm.borrow().get(MemoryId::new(2)), mm.borrow().get(MemoryId::new(39))))
     );
     static ACID_VALIDATIONS: RefCell<StableBTreeMap<String, AcidValidation, Memory>> = RefCell::new(
         StableBTreeMap::init(MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(3))))
@@ -88,55 +181,52 @@ thread_local! {
     static BALANCES: RefCell<StableBTreeMap<Principal, u64, Memory>> = RefCell::new(
         StableBTreeMap::init(MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(4))))
     );
-    static CARGOX_MAPPINGS: RefCell<StableBTreeMap<String, CargoXMapping, Memory>> = RefCell::new(
-        StableBTreeMap::init(MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(5))))
+    static CARGOX_MAPPINGS: RefCell<IndexedMap<CargoXMapping>> = RefCell::new(
+        MEMORY_MANAGER.with(|mm| IndexedMap::init(mm.borrow().get(MemoryId::new(5)), mm.borrow().get(MemoryId::new(40))))
+    );
+    static CUSTOMS_VERIFICATIONS: RefCell<IndexedMap<CustomsVerification>> = RefCell::new(
+        MEMORY_MANAGER.with(|mm| IndexedMap::init(mm.borrow().get(MemoryId::new(6)), mm.borrow().get(MemoryId::new(41))))
     );
-    static CUSTOMS_VERIFICATIONS: RefCell<StableBTreeMap<String, CustomsVerification, Memory>> = RefCell::new(
-        StableBTreeMap::init(MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(6))))
+    static COUNTERS: RefCell<StableBTreeMap<String, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(8))))
+    );
+    static LEDGER_SETTINGS: RefCell<StableCell<LedgerSettings, Memory>> = RefCell::new(
+        StableCell::init(MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(9))), LedgerSettings::default())
     );
-    static COUNTERS: RefCell<HashMap<String, u64>> = RefCell::new(HashMap::new());
-    static LEDGER_PRINCIPAL: RefCell<Option<Principal>> = RefCell::new(None);
 }
 
-// Ensure balance initialization for testing
-#[update]
-pub fn init_user_balance(amount_usd_cents: u64) -> Result<(), String> {
-    let token_amount = usd_to_tokens(amount_usd_cents);
-    BALANCES.with(|b| {
-        let mut balances = b.borrow_mut();
-        let caller = caller();
-        let current = balances.get(&caller).unwrap_or(0);
-        balances.insert(caller, current + token_amount);
-        ic_cdk::println!("Initialized balance for {}: {} tokens ({} USD cents)", caller, token_amount, amount_usd_cents);
+fn update_ledger_settings(f: impl FnOnce(&mut LedgerSettings)) {
+    LEDGER_SETTINGS.with(|cell| {
+        let mut cell = cell.borrow_mut();
+        let mut settings = cell.get().clone();
+        f(&mut settings);
+        cell.set(settings);
     });
-    Ok(())
 }
 
 // Initialize ledger principal (call this during canister init)
-#[update]
-pub async fn init_ledger_principal(ledger_id: String) -> Result<(), String> {
+#[update(guard = "is_admin")]
+pub async fn init_ledger_principal(ledger_id: String) -> Result<(), CargoTraceError> {
     let principal = Principal::from_text(ledger_id)
-        .map_err(|e| format!("Invalid principal: {}", e))?;
+        .map_err(|e| CargoTraceError::InvalidArgument(format!("Invalid principal: {}", e)))?;
     
-    LEDGER_PRINCIPAL.with(|p| {
-        *p.borrow_mut() = Some(principal);
-    });
+    update_ledger_settings(|settings| settings.ledger_principal = Some(principal));
     
     Ok(())
 }
 
 // Get ledger principal
 fn get_ledger_principal() -> Result<Principal, String> {
-    LEDGER_PRINCIPAL.with(|p| {
-        p.borrow().ok_or_else(|| "Ledger principal not initialized".to_string())
-    })
+    LEDGER_SETTINGS.with(|cell| cell.borrow().get().ledger_principal)
+        .or_else(|| LEDGER_CANISTER_ID.and_then(|id| Principal::from_text(id).ok()))
+        .ok_or_else(|| "Ledger principal not initialized".to_string())
 }
 
 
 
 // Remove the BalanceArgs struct entirely or update your check_canister_balance function:
 #[update]
-pub async fn check_canister_balance() -> Result<u64, String> {
+pub async fn check_canister_balance() -> Result<u64, CargoTraceError> {
     let canister_account = Account {
         owner: ic_cdk::api::id(),
         subaccount: None,
@@ -145,67 +235,64 @@ pub async fn check_canister_balance() -> Result<u64, String> {
     icrc1_balance_of(canister_account).await
 }
 
-#[update]
-pub fn request_test_tokens(amount: u64) -> Result<(), String> {
-    let token_amount = usd_to_tokens(amount);
-    BALANCES.with(|b| {
-        let mut balances = b.borrow_mut();
-        let current = balances.get(&ic_cdk::api::id()).unwrap_or(0);
-        balances.insert(ic_cdk::api::id(), current + token_amount);
-    });
-    ic_cdk::println!("Simulated funding: {} test tokens added to canister", token_amount);
-    Ok(())
+fn ledger_call_error(code: RejectionCode, msg: String) -> (candid::Nat, String) {
+    (candid::Nat::from(code as i32 as u64), format!("Ledger call failed: {:?} - {}", code, msg))
 }
 
-// Mock ICRC-1 transfer function for testing
+// ICRC-1 transfer from the canister's default account
 async fn icrc1_transfer(args: TransferArgs) -> Result<candid::Nat, TransferError> {
-    ic_cdk::println!("icrc1_transfer: from canister to {}, amount: {}, fee: {:?}", 
-        args.to.owner, args.amount, args.fee);
-    let balance = BALANCES.with(|b| b.borrow().get(&ic_cdk::api::id()).unwrap_or(0));
-    let recipient_balance = BALANCES.with(|b| b.borrow().get(&args.to.owner).unwrap_or(0));
-
-    let amount: u64 = args.amount.0.try_into().map_err(|_| {
-        let err = TransferError::GenericError {
-            error_code: candid::Nat::from(1u64),
-            message: "Invalid amount".to_string(),
-        };
-        ic_cdk::println!("Transfer error: {:?}", err);
-        err
+    #[cfg(feature = "mock-ledger")]
+    if ledger_test_mode() {
+        return mock_icrc1_transfer(args);
+    }
+    let ledger = get_ledger_principal().map_err(|message| TransferError::GenericError {
+        error_code: candid::Nat::from(0u64),
+        message,
     })?;
-    let fee: u64 = args.fee.unwrap_or(candid::Nat::from(TRANSFER_FEE)).0.try_into().map_err(|_| {
-        let err = TransferError::GenericError {
-            error_code: candid::Nat::from(1u64),
-            message: "Invalid fee".to_string(),
-        };
-        ic_cdk::println!("Transfer error: {:?}", err);
-        err
+    let (result,): (Result<candid::Nat, TransferError>,) = call(ledger, "icrc1_transfer", (args,))
+        .await
+        .map_err(|(code, msg)| {
+            let (error_code, message) = ledger_call_error(code, msg);
+            TransferError::GenericError { error_code, message }
+        })?;
+    result
+}
+
+// ICRC-2 transfer_from, pulling funds the owner has approved for this canister
+async fn icrc2_transfer_from(args: TransferFromArgs) -> Result<candid::Nat, TransferFromError> {
+    #[cfg(feature = "mock-ledger")]
+    if ledger_test_mode() {
+        return mock_icrc2_transfer_from(args);
+    }
+    let ledger = get_ledger_principal().map_err(|message| TransferFromError::GenericError {
+        error_code: candid::Nat::from(0u64),
+        message,
     })?;
-
-    if balance < amount + fee {
-        let err = TransferError::InsufficientFunds {
-            balance: candid::Nat::from(balance),
-        };
-        ic_cdk::println!("Transfer error: {:?}", err);
-        return Err(err);
+    let (result,): (Result<candid::Nat, TransferFromError>,) = call(ledger, "icrc2_transfer_from", (args,))
+        .await
+        .map_err(|(code, msg)| {
+            let (error_code, message) = ledger_call_error(code, msg);
+            TransferFromError::GenericError { error_code, message }
+        })?;
+    result
+}
+
+async fn icrc1_balance_of(account: Account) -> Result<u64, CargoTraceError> {
+    #[cfg(feature = "mock-ledger")]
+    if ledger_test_mode() {
+        return Ok(mock_icrc1_balance_of(account));
     }
-
-    BALANCES.with(|b| {
-        let mut balances = b.borrow_mut();
-        let new_balance = balance - (amount + fee);
-        balances.insert(ic_cdk::api::id(), new_balance);
-        balances.insert(args.to.owner, recipient_balance + amount);
-    });
-
-    let block_height = candid::Nat::from(123456u64);
-    ic_cdk::println!("Transfer successful: {} tokens to {}, block: {}", amount, args.to.owner, block_height);
-    Ok(block_height)
-}
-
-// Mock ICRC-1 balance_of function for testing
-async fn icrc1_balance_of(account: Account) -> Result<u64, String> {
-    let balance = BALANCES.with(|b| b.borrow().get(&account.owner).unwrap_or(0));
-    ic_cdk::println!("icrc1_balance_of for principal {}: {} tokens", account.owner, balance);
-    Ok(balance)
+    let ledger_error = |error_code: candid::Nat, message: String| {
+        CargoTraceError::LedgerError(TransferError::GenericError { error_code, message })
+    };
+    let ledger = get_ledger_principal().map_err(|message| ledger_error(candid::Nat::from(0u64), message))?;
+    let (balance,): (candid::Nat,) = call(ledger, "icrc1_balance_of", (account,))
+        .await
+        .map_err(|(code, msg)| {
+            let (error_code, message) = ledger_call_error(code, msg);
+            ledger_error(error_code, message)
+        })?;
+    balance.0.try_into().map_err(|_| CargoTraceError::InvalidArgument("Balance does not fit in u64".to_string()))
 }
 
 // Data structures
@@ -213,14 +300,54 @@ async fn icrc1_balance_of(account: Account) -> Result<u64, String> {
 pub struct Document {
     pub id: String,
     pub acid_number: String,
-    pub ethereum_tx_hash: String,
+    // The CargoX token the document is backed by. `contract` and `token_id` stay empty until the
+    // transfer that delivered it has been seen.
+    pub chain: String,
+    pub contract: String,
+    pub token_id: String,
+    // Transaction that delivered the token
+    pub tx_hash: String,
     pub value_usd: u64,
     pub status: DocumentStatus,
     pub created_at: u64,
     pub owner: Principal,
+    // ICRC-7 token minted once the document is verified
+    pub nft_token_id: Option<u64>,
+}
+
+// Document as stored at version 1, before it named its token
+#[derive(CandidType, Deserialize)]
+struct DocumentV1 {
+    id: String,
+    acid_number: String,
+    ethereum_tx_hash: String,
+    value_usd: u64,
+    status: DocumentStatus,
+    created_at: u64,
+    owner: Principal,
+    nft_token_id: Option<u64>,
+}
+
+impl From<DocumentV1> for Document {
+    fn from(v1: DocumentV1) -> Self {
+        // Token fields are filled from the stored transfer by link_stored_documents
+        Document {
+            id: v1.id,
+            acid_number: v1.acid_number,
+            chain: POLLED_NETWORK.to_string(),
+            contract: String::new(),
+            token_id: String::new(),
+            tx_hash: v1.ethereum_tx_hash,
+            value_usd: v1.value_usd,
+            status: v1.status,
+            created_at: v1.created_at,
+            owner: v1.owner,
+            nft_token_id: v1.nft_token_id,
+        }
+    }
 }
 
-#[derive(CandidType, Deserialize, PartialEq, Clone)]
+#[derive(CandidType, Deserialize, PartialEq, Clone, Debug)]
 pub enum DocumentStatus {
     Pending,
     Verified,
@@ -233,12 +360,61 @@ pub struct Loan {
     pub id: String,
     pub document_id: String,
     pub amount: u64,
-    pub interest_rate: f64,
+    pub interest_rate_bps: u32,
+    pub day_count: DayCount,
     pub status: LoanStatus,
     pub created_at: u64,
     pub borrower: Principal,
     pub repayment_date: u64,
     pub transfer_block_height: Option<candid::Nat>,
+    pub transfer_error: Option<TransferError>,
+    // Repayment accounting in USD cents; interest is accrued up to `accrued_until`
+    pub principal_outstanding: u64,
+    pub interest_outstanding: u64,
+    pub accrued_until: u64,
+    pub total_repaid: u64,
+    // Late penalty charged by the maturity job, once the grace period has passed
+    pub late_penalty: Option<u64>,
+}
+
+// Loan as stored at version 1, before interest accrual
+#[derive(CandidType, Deserialize)]
+struct LoanV1 {
+    id: String,
+    document_id: String,
+    amount: u64,
+    interest_rate: f64,
+    status: LoanStatus,
+    created_at: u64,
+    borrower: Principal,
+    repayment_date: u64,
+    transfer_block_height: Option<candid::Nat>,
+    transfer_error: Option<TransferError>,
+}
+
+impl From<LoanV1> for Loan {
+    fn from(v1: LoanV1) -> Self {
+        // Repaid loans were closed by a single payment of at least the principal
+        let principal_outstanding = if v1.status == LoanStatus::Repaid { 0 } else { v1.amount };
+        Loan {
+            id: v1.id,
+            document_id: v1.document_id,
+            amount: v1.amount,
+            interest_rate_bps: (v1.interest_rate * 100.0).round() as u32,
+            day_count: DayCount::Actual365,
+            status: v1.status,
+            created_at: v1.created_at,
+            borrower: v1.borrower,
+            repayment_date: v1.repayment_date,
+            transfer_block_height: v1.transfer_block_height,
+            transfer_error: v1.transfer_error,
+            principal_outstanding,
+            interest_outstanding: 0,
+            accrued_until: v1.created_at,
+            total_repaid: v1.amount - principal_outstanding,
+            late_penalty: None,
+        }
+    }
 }
 
 #[derive(CandidType, Deserialize, PartialEq, Debug, Clone)]
@@ -265,6 +441,9 @@ pub struct AcidValidation {
 pub struct CargoXMapping {
     pub id: String,
     pub nft_hash: String,
+    // The linked document, whose id keys the mapping. Links made before mappings were keyed by
+    // document may have none and stay under their transaction hash.
+    pub document_id: Option<String>,
     pub acid_number: String,
     pub verified: bool,
     pub created_at: u64,
@@ -276,6 +455,8 @@ pub struct CargoXMapping {
 pub struct CustomsVerification {
     pub id: String,
     pub nft_hash: String,
+    // The document under verification, which also keys the verification
+    pub document_id: Option<String>,
     pub acid_number: String,
     pub verification_status: CustomsStatus,
     pub verified_at: Option<u64>,
@@ -284,7 +465,7 @@ pub struct CustomsVerification {
     pub verified_by: Option<Principal>,
 }
 
-#[derive(CandidType, Deserialize, Clone)]
+#[derive(CandidType, Deserialize, Clone, Debug)]
 pub enum CustomsStatus {
     Pending,
     Verified,
@@ -292,76 +473,32 @@ pub enum CustomsStatus {
     UnderReview,
 }
 
-// Implement Storable for Loan
-impl Storable for Loan {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-        let mut bytes = Vec::new();
-        bytes.extend_from_slice(self.id.as_bytes());
-        bytes.push(0);
-        bytes.extend_from_slice(self.document_id.as_bytes());
-        bytes.push(0);
-        bytes.extend_from_slice(&self.amount.to_le_bytes());
-        bytes.extend_from_slice(&self.interest_rate.to_le_bytes());
-        bytes.extend_from_slice(&self.created_at.to_le_bytes());
-        bytes.extend_from_slice(&self.repayment_date.to_le_bytes());
-        
-        let borrower_bytes = self.borrower.as_slice();
-        bytes.push(borrower_bytes.len() as u8);
-        bytes.extend_from_slice(borrower_bytes);
-        
-        match &self.status {
-            LoanStatus::Pending => bytes.push(0),
-            LoanStatus::Approved => bytes.push(1),
-            LoanStatus::Active => bytes.push(2),
-            LoanStatus::Repaid => bytes.push(3),
-            LoanStatus::Defaulted => bytes.push(4),
-            LoanStatus::Rejected => bytes.push(5),
-            LoanStatus::TransferPending => bytes.push(6),
-            LoanStatus::TransferFailed => bytes.push(7),
-        }
-        
-        if let Some(ref block_height) = self.transfer_block_height {
-            bytes.push(1);
-            let block_bytes = block_height.0.to_bytes_le();
-            bytes.extend_from_slice(&(block_bytes.len() as u32).to_le_bytes());
-            bytes.extend_from_slice(&block_bytes);
-        } else {
-            bytes.push(0);
+// Stable encoding: versioned Candid envelopes (see versioned.rs). Version 0 is the original
+// hand-written layout, decoded here so records written before versioning still load.
+impl Versioned for Loan {
+    const NAME: &'static str = "Loan";
+    const VERSION: u8 = 2;
+
+    fn migrate(version: u8, bytes: &[u8]) -> Result<Self, String> {
+        match version {
+            0 => Self::migrate_legacy(bytes).map(Loan::from),
+            1 => candid::decode_one::<LoanV1>(bytes).map(Loan::from).map_err(|e| e.to_string()),
+            _ => Err(format!("no migration from Loan version {}", version)),
         }
-        
-        std::borrow::Cow::Owned(bytes)
-    }
-
-    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        let bytes = bytes.as_ref();
-        let mut pos = 0;
-        
-        let id_end = bytes[pos..].iter().position(|&b| b == 0).unwrap();
-        let id = String::from_utf8(bytes[pos..pos+id_end].to_vec()).unwrap();
-        pos += id_end + 1;
-        
-        let doc_end = bytes[pos..].iter().position(|&b| b == 0).unwrap();
-        let document_id = String::from_utf8(bytes[pos..pos+doc_end].to_vec()).unwrap();
-        pos += doc_end + 1;
-        
-        let amount = u64::from_le_bytes(bytes[pos..pos+8].try_into().unwrap());
-        pos += 8;
-        
-        let interest_rate = f64::from_le_bytes(bytes[pos..pos+8].try_into().unwrap());
-        pos += 8;
-        
-        let created_at = u64::from_le_bytes(bytes[pos..pos+8].try_into().unwrap());
-        pos += 8;
-        
-        let repayment_date = u64::from_le_bytes(bytes[pos..pos+8].try_into().unwrap());
-        pos += 8;
-        
-        let principal_len = bytes[pos] as usize;
-        pos += 1;
-        let borrower = Principal::from_slice(&bytes[pos..pos+principal_len]);
-        pos += principal_len;
-        
-        let status = match bytes[pos] {
+    }
+}
+
+impl Loan {
+    fn migrate_legacy(bytes: &[u8]) -> Result<LoanV1, String> {
+        let mut r = LegacyReader::new(bytes);
+        let id = r.string()?;
+        let document_id = r.string()?;
+        let amount = r.u64()?;
+        let interest_rate = r.f64()?;
+        let created_at = r.u64()?;
+        let repayment_date = r.u64()?;
+        let borrower = r.principal()?;
+        let status = match r.u8()? {
             0 => LoanStatus::Pending,
             1 => LoanStatus::Approved,
             2 => LoanStatus::Active,
@@ -370,21 +507,19 @@ impl Storable for Loan {
             5 => LoanStatus::Rejected,
             6 => LoanStatus::TransferPending,
             7 => LoanStatus::TransferFailed,
-            _ => LoanStatus::Pending,
+            other => return Err(format!("unknown loan status {}", other)),
+        };
+        let transfer_block_height = match r.u8()? {
+            1 => Some(candid::Nat(BigUint::from_bytes_le(r.blob()?))),
+            _ => None,
         };
-        pos += 1;
-        
-        let transfer_block_height = if bytes[pos] == 1 {
-            pos += 1;
-            let len = u32::from_le_bytes(bytes[pos..pos+4].try_into().unwrap()) as usize;
-            pos += 4;
-            let block_bytes = &bytes[pos..pos+len];
-            Some(candid::Nat(BigUint::from_bytes_le(block_bytes)))
+        // Trailer added after the original layout; absent in the oldest records
+        let transfer_error = if !r.is_empty() && r.u8()? == 1 {
+            candid::decode_one(r.blob()?).ok()
         } else {
             None
         };
-        
-        Loan {
+        Ok(LoanV1 {
             id,
             document_id,
             amount,
@@ -394,120 +529,47 @@ impl Storable for Loan {
             borrower,
             repayment_date,
             transfer_block_height,
-        }
+            transfer_error,
+        })
     }
+}
+versioned_storable!(Loan);
 
-    fn into_bytes(self) -> Vec<u8> {
-        self.to_bytes().into_owned()
-    }
+impl Versioned for Document {
+    const NAME: &'static str = "Document";
+    const VERSION: u8 = 2;
 
-    const BOUND: Bound = Bound::Bounded {
-        max_size: 1524,
-        is_fixed_size: false,
-    };
+    fn migrate(version: u8, bytes: &[u8]) -> Result<Self, String> {
+        match version {
+            0 => Self::migrate_legacy(bytes).map(Document::from),
+            1 => candid::decode_one::<DocumentV1>(bytes).map(Document::from).map_err(|e| e.to_string()),
+            _ => Err(format!("no migration from Document version {}", version)),
+        }
+    }
 }
 
-// Storable implementation for Document
-impl Storable for Document {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-        let mut bytes = Vec::new();
-        bytes.extend_from_slice(self.id.as_bytes());
-        bytes.push(0);
-        bytes.extend_from_slice(self.acid_number.as_bytes());
-        bytes.push(0);
-        bytes.extend_from_slice(self.ethereum_tx_hash.as_bytes());
-        bytes.push(0);
-        bytes.extend_from_slice(&self.value_usd.to_le_bytes());
-        bytes.extend_from_slice(&self.created_at.to_le_bytes());
-        let owner_bytes = self.owner.as_slice();
-        bytes.push(owner_bytes.len() as u8);
-        bytes.extend_from_slice(owner_bytes);
-        match &self.status {
-            DocumentStatus::Pending => bytes.push(0),
-            DocumentStatus::Verified => bytes.push(1),
-            DocumentStatus::Rejected => bytes.push(2),
-            DocumentStatus::NftMinted => bytes.push(3),
-        }
-        std::borrow::Cow::Owned(bytes)
-    }
-
-    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        let bytes = bytes.as_ref();
-        let mut pos = 0;
-        
-        let check_bounds = |pos: usize, needed: usize, total: usize| -> Result<(), String> {
-            if pos + needed > total {
-                return Err(format!(
-                    "Buffer underrun: need {} bytes at position {}, but only {} bytes total", 
-                    needed, pos, total
-                ));
-            }
-            Ok(())
-        };
-        
-        let id = match bytes[pos..].iter().position(|&b| b == 0) {
-            Some(id_end) => {
-                check_bounds(pos, id_end, bytes.len()).expect("ID bounds check failed");
-                let id = String::from_utf8(bytes[pos..pos+id_end].to_vec())
-                    .expect("Invalid UTF-8 in ID");
-                pos += id_end + 1;
-                id
-            }
-            None => panic!("No null terminator found for ID")
-        };
-        
-        let acid_number = match bytes[pos..].iter().position(|&b| b == 0) {
-            Some(acid_end) => {
-                check_bounds(pos, acid_end, bytes.len()).expect("ACID bounds check failed");
-                let acid = String::from_utf8(bytes[pos..pos+acid_end].to_vec())
-                    .expect("Invalid UTF-8 in ACID number");
-                pos += acid_end + 1;
-                acid
-            }
-            None => panic!("No null terminator found for ACID number")
-        };
-        
-        let ethereum_tx_hash = match bytes[pos..].iter().position(|&b| b == 0) {
-            Some(tx_end) => {
-                check_bounds(pos, tx_end, bytes.len()).expect("TX hash bounds check failed");
-                let tx_hash = String::from_utf8(bytes[pos..pos+tx_end].to_vec())
-                    .expect("Invalid UTF-8 in TX hash");
-                pos += tx_end + 1;
-                tx_hash
-            }
-            None => panic!("No null terminator found for TX hash")
-        };
-        
-        check_bounds(pos, 8, bytes.len()).expect("Value USD bounds check failed");
-        let value_usd = u64::from_le_bytes(
-            bytes[pos..pos+8].try_into().expect("Failed to parse value_usd")
-        );
-        pos += 8;
-        
-        check_bounds(pos, 8, bytes.len()).expect("Created at bounds check failed");
-        let created_at = u64::from_le_bytes(
-            bytes[pos..pos+8].try_into().expect("Failed to parse created_at")
-        );
-        pos += 8;
-        
-        check_bounds(pos, 1, bytes.len()).expect("Principal length bounds check failed");
-        let principal_len = bytes[pos] as usize;
-        pos += 1;
-        
-        check_bounds(pos, principal_len, bytes.len()).expect("Principal data bounds check failed");
-        let owner = Principal::from_slice(&bytes[pos..pos+principal_len]);
-        pos += principal_len;
-        
-        check_bounds(pos, 1, bytes.len()).expect("Status bounds check failed");
-        let status = match bytes[pos] {
+impl Document {
+    // None until the document is linked to the transfer that names its CargoX token
+    pub(crate) fn token(&self) -> Option<TokenRef> {
+        (!self.token_id.is_empty()).then(|| TokenRef::new(&self.chain, &self.contract, &self.token_id))
+    }
+
+    fn migrate_legacy(bytes: &[u8]) -> Result<DocumentV1, String> {
+        let mut r = LegacyReader::new(bytes);
+        let id = r.string()?;
+        let acid_number = r.string()?;
+        let ethereum_tx_hash = r.string()?;
+        let value_usd = r.u64()?;
+        let created_at = r.u64()?;
+        let owner = r.principal()?;
+        let status = match r.u8()? {
             0 => DocumentStatus::Pending,
             1 => DocumentStatus::Verified,
             2 => DocumentStatus::Rejected,
             3 => DocumentStatus::NftMinted,
-            _ => DocumentStatus::Pending,
+            other => return Err(format!("unknown document status {}", other)),
         };
-        
-        Document {
+        Ok(DocumentV1 {
             id,
             acid_number,
             ethereum_tx_hash,
@@ -515,125 +577,87 @@ impl Storable for Document {
             status,
             created_at,
             owner,
-        }
+            nft_token_id: None,
+        })
     }
-
-    fn into_bytes(self) -> Vec<u8> {
-        self.to_bytes().into_owned()
-    }
-
-    const BOUND: Bound = Bound::Bounded {
-        max_size: 2048,
-        is_fixed_size: false,
-    };
 }
+versioned_storable!(Document);
 
-// Storable implementation for AcidValidation
-impl Storable for AcidValidation {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-        let mut bytes = Vec::new();
-        bytes.extend_from_slice(self.acid_number.as_bytes());
-        bytes.push(0);
-        bytes.push(if self.is_valid { 1 } else { 0 });
-        bytes.extend_from_slice(&self.validation_date.to_le_bytes());
-        if let Some(ref data) = self.customs_data {
-            bytes.extend_from_slice(data.as_bytes());
+impl Versioned for AcidValidation {
+    const NAME: &'static str = "AcidValidation";
+    const VERSION: u8 = 1;
+
+    fn migrate(version: u8, bytes: &[u8]) -> Result<Self, String> {
+        if version != 0 {
+            return Err(format!("no migration from AcidValidation version {}", version));
         }
-        bytes.push(0);
-        std::borrow::Cow::Owned(bytes)
-    }
-
-    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        let bytes = bytes.as_ref();
-        let mut pos = 0;
-        let acid_end = bytes[pos..].iter().position(|&b| b == 0).unwrap();
-        let acid_number = String::from_utf8(bytes[pos..pos+acid_end].to_vec()).unwrap();
-        pos += acid_end + 1;
-        let is_valid = bytes[pos] == 1;
-        pos += 1;
-        let validation_date = u64::from_le_bytes(bytes[pos..pos+8].try_into().unwrap());
-        pos += 8;
-        let customs_data = if pos < bytes.len() - 1 {
-            let data_end = bytes[pos..].iter().position(|&b| b == 0).unwrap_or(bytes.len() - pos);
-            if data_end > 0 {
-                Some(String::from_utf8(bytes[pos..pos+data_end].to_vec()).unwrap())
-            } else {
-                None
-            }
-        } else {
-            None
-        };
-        AcidValidation {
+        let mut r = LegacyReader::new(bytes);
+        let acid_number = r.string()?;
+        let is_valid = r.u8()? == 1;
+        let validation_date = r.u64()?;
+        let customs_data = r.optional_string()?;
+        Ok(AcidValidation {
             acid_number,
             is_valid,
             customs_data,
             validation_date,
-        }
+        })
     }
+}
+versioned_storable!(AcidValidation);
+
+impl Versioned for CargoXMapping {
+    const NAME: &'static str = "CargoXMapping";
+    const VERSION: u8 = 2;
 
-    fn into_bytes(self) -> Vec<u8> {
-        self.to_bytes().into_owned()
+    // Mappings from before version 2 get their document_id from link_stored_documents
+    fn migrate(version: u8, bytes: &[u8]) -> Result<Self, String> {
+        match version {
+            0 => Self::migrate_legacy(bytes).map(CargoXMapping::from),
+            1 => candid::decode_one::<CargoXMappingV1>(bytes).map(CargoXMapping::from).map_err(|e| e.to_string()),
+            _ => Err(format!("no migration from CargoXMapping version {}", version)),
+        }
     }
+}
 
-    const BOUND: Bound = Bound::Bounded {
-        max_size: 512,
-        is_fixed_size: false,
-    };
+// CargoXMapping as stored at version 1, before it referenced a document
+#[derive(CandidType, Deserialize)]
+struct CargoXMappingV1 {
+    id: String,
+    nft_hash: String,
+    acid_number: String,
+    verified: bool,
+    created_at: u64,
+    owner: Principal,
+    customs_entry_id: Option<String>,
 }
 
-// Storable implementation for CargoXMapping
-impl Storable for CargoXMapping {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-        let mut bytes = Vec::new();
-        bytes.extend_from_slice(self.id.as_bytes());
-        bytes.push(0);
-        bytes.extend_from_slice(self.nft_hash.as_bytes());
-        bytes.push(0);
-        bytes.extend_from_slice(self.acid_number.as_bytes());
-        bytes.push(0);
-        bytes.push(if self.verified { 1 } else { 0 });
-        bytes.extend_from_slice(&self.created_at.to_le_bytes());
-        let owner_bytes = self.owner.as_slice();
-        bytes.push(owner_bytes.len() as u8);
-        bytes.extend_from_slice(owner_bytes);
-        if let Some(ref entry_id) = self.customs_entry_id {
-            bytes.extend_from_slice(entry_id.as_bytes());
-        }
-        bytes.push(0);
-        std::borrow::Cow::Owned(bytes)
-    }
-
-    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        let bytes = bytes.as_ref();
-        let mut pos = 0;
-        let id_end = bytes[pos..].iter().position(|&b| b == 0).unwrap();
-        let id = String::from_utf8(bytes[pos..pos+id_end].to_vec()).unwrap();
-        pos += id_end + 1;
-        let nft_end = bytes[pos..].iter().position(|&b| b == 0).unwrap();
-        let nft_hash = String::from_utf8(bytes[pos..pos+nft_end].to_vec()).unwrap();
-        pos += nft_end + 1;
-        let acid_end = bytes[pos..].iter().position(|&b| b == 0).unwrap();
-        let acid_number = String::from_utf8(bytes[pos..pos+acid_end].to_vec()).unwrap();
-        pos += acid_end + 1;
-        let verified = bytes[pos] == 1;
-        pos += 1;
-        let created_at = u64::from_le_bytes(bytes[pos..pos+8].try_into().unwrap());
-        pos += 8;
-        let principal_len = bytes[pos] as usize;
-        pos += 1;
-        let owner = Principal::from_slice(&bytes[pos..pos+principal_len]);
-        pos += principal_len;
-        let customs_entry_id = if pos < bytes.len() - 1 {
-            let entry_end = bytes[pos..].iter().position(|&b| b == 0).unwrap_or(bytes.len() - pos);
-            if entry_end > 0 {
-                Some(String::from_utf8(bytes[pos..pos+entry_end].to_vec()).unwrap())
-            } else {
-                None
-            }
-        } else {
-            None
-        };
+impl From<CargoXMappingV1> for CargoXMapping {
+    fn from(v1: CargoXMappingV1) -> Self {
         CargoXMapping {
+            id: v1.id,
+            nft_hash: v1.nft_hash,
+            document_id: None,
+            acid_number: v1.acid_number,
+            verified: v1.verified,
+            created_at: v1.created_at,
+            owner: v1.owner,
+            customs_entry_id: v1.customs_entry_id,
+        }
+    }
+}
+
+impl CargoXMapping {
+    fn migrate_legacy(bytes: &[u8]) -> Result<CargoXMappingV1, String> {
+        let mut r = LegacyReader::new(bytes);
+        let id = r.string()?;
+        let nft_hash = r.string()?;
+        let acid_number = r.string()?;
+        let verified = r.u8()? == 1;
+        let created_at = r.u64()?;
+        let owner = r.principal()?;
+        let customs_entry_id = r.optional_string()?;
+        Ok(CargoXMappingV1 {
             id,
             nft_hash,
             acid_number,
@@ -641,108 +665,80 @@ impl Storable for CargoXMapping {
             created_at,
             owner,
             customs_entry_id,
-        }
+        })
     }
+}
+versioned_storable!(CargoXMapping);
 
-    fn into_bytes(self) -> Vec<u8> {
-        self.to_bytes().into_owned()
+impl Versioned for CustomsVerification {
+    const NAME: &'static str = "CustomsVerification";
+    const VERSION: u8 = 2;
+
+    // Verifications from before version 2 get their document_id from key_customs_links_by_document
+    fn migrate(version: u8, bytes: &[u8]) -> Result<Self, String> {
+        match version {
+            0 => Self::migrate_legacy(bytes).map(CustomsVerification::from),
+            1 => candid::decode_one::<CustomsVerificationV1>(bytes)
+                .map(CustomsVerification::from)
+                .map_err(|e| e.to_string()),
+            _ => Err(format!("no migration from CustomsVerification version {}", version)),
+        }
     }
+}
 
-    const BOUND: Bound = Bound::Bounded {
-        max_size: 1024,
-        is_fixed_size: false,
-    };
+// CustomsVerification as stored at version 1, before it referenced a document
+#[derive(CandidType, Deserialize)]
+struct CustomsVerificationV1 {
+    id: String,
+    nft_hash: String,
+    acid_number: String,
+    verification_status: CustomsStatus,
+    verified_at: Option<u64>,
+    customs_data: Option<String>,
+    created_at: u64,
+    verified_by: Option<Principal>,
 }
 
-// Storable implementation for CustomsVerification
-impl Storable for CustomsVerification {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-        let mut bytes = Vec::new();
-        bytes.extend_from_slice(self.id.as_bytes());
-        bytes.push(0);
-        bytes.extend_from_slice(self.nft_hash.as_bytes());
-        bytes.push(0);
-        bytes.extend_from_slice(self.acid_number.as_bytes());
-        bytes.push(0);
-        match &self.verification_status {
-            CustomsStatus::Pending => bytes.push(0),
-            CustomsStatus::Verified => bytes.push(1),
-            CustomsStatus::Rejected => bytes.push(2),
-            CustomsStatus::UnderReview => bytes.push(3),
-        }
-        if let Some(verified_at) = self.verified_at {
-            bytes.push(1);
-            bytes.extend_from_slice(&verified_at.to_le_bytes());
-        } else {
-            bytes.push(0);
-        }
-        if let Some(ref data) = self.customs_data {
-            bytes.extend_from_slice(data.as_bytes());
-        }
-        bytes.push(0);
-        bytes.extend_from_slice(&self.created_at.to_le_bytes());
-        if let Some(verified_by) = self.verified_by {
-            bytes.push(1);
-            let principal_bytes = verified_by.as_slice();
-            bytes.push(principal_bytes.len() as u8);
-            bytes.extend_from_slice(principal_bytes);
-        } else {
-            bytes.push(0);
+impl From<CustomsVerificationV1> for CustomsVerification {
+    fn from(v1: CustomsVerificationV1) -> Self {
+        CustomsVerification {
+            id: v1.id,
+            nft_hash: v1.nft_hash,
+            document_id: None,
+            acid_number: v1.acid_number,
+            verification_status: v1.verification_status,
+            verified_at: v1.verified_at,
+            customs_data: v1.customs_data,
+            created_at: v1.created_at,
+            verified_by: v1.verified_by,
         }
-        std::borrow::Cow::Owned(bytes)
-    }
-
-    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        let bytes = bytes.as_ref();
-        let mut pos = 0;
-        let id_end = bytes[pos..].iter().position(|&b| b == 0).unwrap();
-        let id = String::from_utf8(bytes[pos..pos+id_end].to_vec()).unwrap();
-        pos += id_end + 1;
-        let nft_end = bytes[pos..].iter().position(|&b| b == 0).unwrap();
-        let nft_hash = String::from_utf8(bytes[pos..pos+nft_end].to_vec()).unwrap();
-        pos += nft_end + 1;
-        let acid_end = bytes[pos..].iter().position(|&b| b == 0).unwrap();
-        let acid_number = String::from_utf8(bytes[pos..pos+acid_end].to_vec()).unwrap();
-        pos += acid_end + 1;
-        let verification_status = match bytes[pos] {
+    }
+}
+
+impl CustomsVerification {
+    fn migrate_legacy(bytes: &[u8]) -> Result<CustomsVerificationV1, String> {
+        let mut r = LegacyReader::new(bytes);
+        let id = r.string()?;
+        let nft_hash = r.string()?;
+        let acid_number = r.string()?;
+        let verification_status = match r.u8()? {
             0 => CustomsStatus::Pending,
             1 => CustomsStatus::Verified,
             2 => CustomsStatus::Rejected,
             3 => CustomsStatus::UnderReview,
-            _ => CustomsStatus::Pending,
+            other => return Err(format!("unknown customs status {}", other)),
         };
-        pos += 1;
-        let verified_at = if bytes[pos] == 1 {
-            pos += 1;
-            let verified_at = u64::from_le_bytes(bytes[pos..pos+8].try_into().unwrap());
-            pos += 8;
-            Some(verified_at)
-        } else {
-            pos += 1;
-            None
+        let verified_at = match r.u8()? {
+            1 => Some(r.u64()?),
+            _ => None,
         };
-        let customs_data = if pos < bytes.len() - 1 {
-            let data_end = bytes[pos..].iter().position(|&b| b == 0).unwrap_or(bytes.len() - pos);
-            if data_end > 0 {
-                Some(String::from_utf8(bytes[pos..pos+data_end].to_vec()).unwrap())
-            } else {
-                None
-            }
-        } else {
-            None
+        let customs_data = r.optional_string()?;
+        let created_at = r.u64()?;
+        let verified_by = match r.u8()? {
+            1 => Some(r.principal()?),
+            _ => None,
         };
-        pos += customs_data.as_ref().map(|d| d.len() + 1).unwrap_or(1);
-        let created_at = u64::from_le_bytes(bytes[pos..pos+8].try_into().unwrap());
-        pos += 8;
-        let verified_by = if bytes[pos] == 1 {
-            pos += 1;
-            let principal_len = bytes[pos] as usize;
-            pos += 1;
-            Some(Principal::from_slice(&bytes[pos..pos+principal_len]))
-        } else {
-            None
-        };
-        CustomsVerification {
+        Ok(CustomsVerificationV1 {
             id,
             nft_hash,
             acid_number,
@@ -751,43 +747,235 @@ impl Storable for CustomsVerification {
             customs_data,
             created_at,
             verified_by,
+        })
+    }
+}
+versioned_storable!(CustomsVerification);
+
+// Secondary index terms; see indexed.rs
+impl Indexed for Document {
+    fn index_terms(&self) -> Vec<String> {
+        let mut terms = vec![
+            term("owner", &self.owner.to_text()),
+            term("status", &format!("{:?}", self.status)),
+            term("tx", &self.tx_hash),
+        ];
+        if !self.acid_number.is_empty() {
+            terms.push(term("acid", &self.acid_number));
         }
+        if let Some(token) = self.token() {
+            terms.push(term("token", &token.key()));
+        }
+        terms
+    }
+}
+
+impl Indexed for Loan {
+    fn index_terms(&self) -> Vec<String> {
+        vec![
+            term("owner", &self.borrower.to_text()),
+            term("status", &format!("{:?}", self.status)),
+            term("document", &self.document_id),
+        ]
+    }
+}
+
+impl Indexed for CargoXMapping {
+    fn index_terms(&self) -> Vec<String> {
+        vec![term("owner", &self.owner.to_text())]
+    }
+}
+
+impl Indexed for CustomsVerification {
+    fn index_terms(&self) -> Vec<String> {
+        vec![term("status", &format!("{:?}", self.verification_status))]
+    }
+}
+
+impl Listed for Document {
+    const INDEXED: &'static [&'static str] = &["owner", "status", "acid"];
+
+    fn status(&self) -> Option<String> {
+        Some(format!("{:?}", self.status))
+    }
+    fn owner(&self) -> Option<String> {
+        Some(self.owner.to_text())
+    }
+    fn created_at(&self) -> u64 {
+        self.created_at
+    }
+    fn acid_number(&self) -> Option<&str> {
+        Some(&self.acid_number)
+    }
+}
+
+impl Listed for Loan {
+    const INDEXED: &'static [&'static str] = &["owner", "status"];
+
+    fn status(&self) -> Option<String> {
+        Some(format!("{:?}", self.status))
+    }
+    fn owner(&self) -> Option<String> {
+        Some(self.borrower.to_text())
     }
+    fn created_at(&self) -> u64 {
+        self.created_at
+    }
+    fn acid_number(&self) -> Option<&str> {
+        None
+    }
+}
+
+impl Listed for CargoXMapping {
+    const INDEXED: &'static [&'static str] = &["owner"];
+
+    fn status(&self) -> Option<String> {
+        None
+    }
+    fn owner(&self) -> Option<String> {
+        Some(self.owner.to_text())
+    }
+    fn created_at(&self) -> u64 {
+        self.created_at
+    }
+    fn acid_number(&self) -> Option<&str> {
+        Some(&self.acid_number)
+    }
+}
+
+impl Listed for CustomsVerification {
+    const INDEXED: &'static [&'static str] = &["status"];
 
-    fn into_bytes(self) -> Vec<u8> {
-        self.to_bytes().into_owned()
+    fn status(&self) -> Option<String> {
+        Some(format!("{:?}", self.verification_status))
     }
+    fn owner(&self) -> Option<String> {
+        None
+    }
+    fn created_at(&self) -> u64 {
+        self.created_at
+    }
+    fn acid_number(&self) -> Option<&str> {
+        Some(&self.acid_number)
+    }
+}
+
+// Documents submitted or claimed for a transaction
+fn documents_for_tx(tx_hash: &str) -> Vec<Document> {
+    DOCUMENTS.with(|d| d.borrow().find(&term("tx", tx_hash)))
+}
 
-    const BOUND: Bound = Bound::Bounded {
-        max_size: 1024,
-        is_fixed_size: false,
+fn documents_for_token(token: &TokenRef) -> Vec<Document> {
+    DOCUMENTS.with(|d| d.borrow().find(&term("token", &token.key())))
+}
+
+// A 0x-prefixed 32-byte hex hash; documents are indexed by it
+fn check_tx_hash(tx_hash: &str) -> Result<(), CargoTraceError> {
+    let digits = tx_hash.strip_prefix("0x").filter(|d| d.len() == 64 && d.bytes().all(|b| b.is_ascii_hexdigit()));
+    digits.map(|_| ()).ok_or_else(|| CargoTraceError::InvalidArgument(format!("Invalid transaction hash {}.", tx_hash)))
+}
+
+// The token a document for the transaction covers: the one named, which the transaction must have
+// moved if it has been seen, or else the only token it moved. None while the transaction is unseen.
+fn document_token(tx_hash: &str, named: Option<TokenRef>) -> Result<Option<TokenRef>, CargoTraceError> {
+    let mut moved: Vec<TokenRef> = Vec::new();
+    for transfer in transfers_in_tx(tx_hash) {
+        let token = transfer.token();
+        if !moved.contains(&token) {
+            moved.push(token);
+        }
+    }
+    match named.map(TokenRef::normalized) {
+        Some(token) if moved.is_empty() || moved.contains(&token) => Ok(Some(token)),
+        Some(token) => {
+            Err(CargoTraceError::InvalidArgument(format!("Transaction {} did not move token {}.", tx_hash, token.key())))
+        }
+        None if moved.len() > 1 => Err(CargoTraceError::InvalidArgument(format!(
+            "Transaction {} moved several tokens; name the one the document covers.",
+            tx_hash
+        ))),
+        None => Ok(moved.pop()),
+    }
+}
+
+// Documents submitted before the watcher saw their transaction get its token when it arrives, as
+// long as the transaction has moved only that one token
+pub(crate) fn name_document_tokens(tx_hash: &str) {
+    let unnamed: Vec<Document> = documents_for_tx(tx_hash).into_iter().filter(|doc| doc.token().is_none()).collect();
+    if unnamed.is_empty() {
+        return;
+    }
+    let Ok(Some(token)) = document_token(tx_hash, None) else {
+        return;
     };
+    for mut document in unnamed {
+        document.chain = token.network.clone();
+        document.contract = token.contract.clone();
+        document.token_id = token.token_id.clone();
+        DOCUMENTS.with(|d| d.borrow_mut().insert(document.id.clone(), document));
+    }
 }
 
 // Helper function to get next ID
 fn get_next_id(counter_name: &str) -> u64 {
     COUNTERS.with(|counters| {
         let mut counters = counters.borrow_mut();
-        let id = counters.get(counter_name).unwrap_or(&0) + 1;
+        let id = counters.get(&counter_name.to_string()).unwrap_or(0) + 1;
         counters.insert(counter_name.to_string(), id);
         id
     })
 }
 
+// Ids are zero-padded to the width of a u64 so they sort in the order they were issued
+fn new_id(prefix: &str, counter_name: &str) -> String {
+    format!("{}-{:020}", prefix, get_next_id(counter_name))
+}
+
+// Parses the numeric suffix of ids such as "DOC-00000000000000000042"
+fn id_sequence(id: &str) -> u64 {
+    id.rsplit('-').next().and_then(|n| n.parse().ok()).unwrap_or(0)
+}
+
+// Counters used to live on the heap and were reset by every upgrade. Seed any missing
+// counter from the highest id already stored so new ids never overwrite existing records.
+fn seed_missing_counters() {
+    let highest = |ids: Vec<String>| ids.iter().map(|id| id_sequence(id)).max().unwrap_or(0);
+    let seeds = [
+        ("document", highest(DOCUMENTS.with(|d| d.borrow().iter().map(|e| e.key().clone()).collect()))),
+        ("loan", highest(LOANS.with(|l| l.borrow().iter().map(|e| e.key().clone()).collect()))),
+        ("mapping", highest(CARGOX_MAPPINGS.with(|m| m.borrow().iter().map(|e| e.value().id.clone()).collect()))),
+        ("verification", highest(CUSTOMS_VERIFICATIONS.with(|v| v.borrow().iter().map(|e| e.value().id.clone()).collect()))),
+        // Transfer keys start at 0, so the counter is one past the last key
+        ("transfer", last_transfer_key().map_or(0, |key| key + 1)),
+    ];
+    COUNTERS.with(|counters| {
+        let mut counters = counters.borrow_mut();
+        for (name, value) in seeds {
+            if value > 0 && counters.get(&name.to_string()).is_none() {
+                counters.insert(name.to_string(), value);
+            }
+        }
+    });
+}
+
 // Updated loan approval function with ICRC-1 transfer
-#[update]
-pub async fn approve_loan(loan_id: String) -> Result<(), String> {
+#[update(guard = "is_loan_officer")]
+pub async fn approve_loan(loan_id: String) -> Result<(), CargoTraceError> {
     let loan = LOANS.with(|loans| {
         let mut loans = loans.borrow_mut();
         if let Some(mut loan) = loans.get(&loan_id) {
             if loan.status != LoanStatus::Pending {
-                return Err("Can only approve pending loans.".to_string());
+                return Err(CargoTraceError::invalid_state(&loan.status, LoanStatus::Active));
+            }
+            // The lock taken when the loan was requested; another loan's lock means this one lost it
+            if let Some(lock) = collateral_lock(&loan.document_id).filter(|lock| lock.loan_id != loan_id) {
+                return Err(CargoTraceError::DocumentInUse { loan_id: lock.loan_id });
             }
             loan.status = LoanStatus::TransferPending;
             loans.insert(loan_id.clone(), loan.clone());
             Ok(loan)
         } else {
-            Err("Loan not found.".to_string())
+            Err(CargoTraceError::not_found("Loan", &loan_id))
         }
     })?;
 
@@ -801,18 +989,25 @@ pub async fn approve_loan(loan_id: String) -> Result<(), String> {
             subaccount: None,
         },
         amount: candid::Nat::from(token_amount),
-        fee: Some(candid::Nat::from(TRANSFER_FEE)),
+        fee: None,
         memo: Some(format!("Loan approval: {}", loan_id).as_bytes().to_vec()),
         created_at_time: Some(ic_cdk::api::time()),
     };
 
-    match icrc1_transfer(transfer_args).await {
+    // A duplicate means the ledger already executed this exact transfer
+    let transfer_result = match icrc1_transfer(transfer_args).await {
+        Err(TransferError::Duplicate { duplicate_of }) => Ok(duplicate_of),
+        result => result,
+    };
+
+    match transfer_result {
         Ok(block_height) => {
             LOANS.with(|loans| {
                 let mut loans = loans.borrow_mut();
                 if let Some(mut loan) = loans.get(&loan_id) {
                     loan.status = LoanStatus::Active;
                     loan.transfer_block_height = Some(block_height.clone());
+                    loan.transfer_error = None;
                     loans.insert(loan_id.clone(), loan);
                 }
             });
@@ -828,43 +1023,38 @@ pub async fn approve_loan(loan_id: String) -> Result<(), String> {
                 let mut loans = loans.borrow_mut();
                 if let Some(mut loan) = loans.get(&loan_id) {
                     loan.status = LoanStatus::TransferFailed;
+                    loan.transfer_error = Some(transfer_error.clone());
                     loans.insert(loan_id.clone(), loan);
                 }
             });
             
-            Err(format!("Transfer failed: {:?}", transfer_error))
+            Err(CargoTraceError::LedgerError(transfer_error))
         }
     }
 }
 
 // FIXED: Get user's ICRC-1 wallet balance in tokens (e8s)
 #[update]
-pub async fn get_wallet_balance_async() -> Result<u64, String> {
+pub async fn get_wallet_balance_async() -> Result<u64, CargoTraceError> {
     let caller = caller();
     let account = Account {
         owner: caller,
         subaccount: None,
     };
     
-    match icrc1_balance_of(account).await {
-        Ok(balance) => {
-            // balance is already u64, return it directly
-            Ok(balance)
-        },
-        Err(e) => Err(format!("Failed to get wallet balance: {:?}", e)),
-    }
+    icrc1_balance_of(account).await
 }
 
 // Get user's wallet balance in USD cents for display
 #[update]
-pub async fn get_wallet_balance_usd_cents() -> Result<u64, String> {
+pub async fn get_wallet_balance_usd_cents() -> Result<u64, CargoTraceError> {
     let balance_tokens = get_wallet_balance_async().await?;
     Ok(tokens_to_usd_cents(balance_tokens))
 }
 
 // Get user's wallet balance in USD dollars (for display)
 #[update]
-pub async fn get_wallet_balance_usd() -> Result<f64, String> {
+pub async fn get_wallet_balance_usd() -> Result<f64, CargoTraceError> {
     let balance_cents = get_wallet_balance_usd_cents().await?;
     Ok(balance_cents as f64 / 100.0) // Convert cents to dollars
 }
@@ -876,13 +1066,12 @@ pub fn get_active_loan() -> Option<Loan> {
     ic_cdk::println!("get_active_loan for principal {}", caller);
     LOANS.with(|loans| {
         let loan = loans.borrow()
-            .iter()
-            .find(|entry| {
-                entry.value().borrower == caller && 
-                matches!(entry.value().status, 
+            .find(&term("owner", &caller.to_text()))
+            .into_iter()
+            .find(|loan| {
+                matches!(loan.status,
                     LoanStatus::Active | LoanStatus::TransferPending | LoanStatus::TransferFailed | LoanStatus::Approved)
-            })
-            .map(|entry| entry.value().clone());
+            });
         ic_cdk::println!("get_active_loan result: {:?}", loan);
         loan
     })
@@ -890,15 +1079,15 @@ pub fn get_active_loan() -> Option<Loan> {
 
 // Retry failed transfer for a loan
 // FIXED: Updated retry_loan_transfer to match return type
-#[update]
-pub async fn retry_loan_transfer(loan_id: String) -> Result<(), String> {
+#[update(guard = "is_loan_officer")]
+pub async fn retry_loan_transfer(loan_id: String) -> Result<(), CargoTraceError> {
     let loan = LOANS.with(|loans| {
         let loans = loans.borrow();
         loans.get(&loan_id)
-    }).ok_or("Loan not found")?;
+    }).ok_or_else(|| CargoTraceError::not_found("Loan", &loan_id))?;
 
     if loan.status != LoanStatus::TransferFailed {
-        return Err("Can only retry failed transfers".to_string());
+        return Err(CargoTraceError::invalid_state(&loan.status, LoanStatus::TransferPending));
     }
 
     LOANS.with(|loans| {
@@ -909,11 +1098,11 @@ pub async fn retry_loan_transfer(loan_id: String) -> Result<(), String> {
         }
     });
 
-    approve_loan(loan_id).await // FIXED: Now both functions return Result<(), String>
+    approve_loan(loan_id).await
 }
 
 #[update]
-pub async fn refresh_wallet_balance() -> Result<f64, String> {
+pub async fn refresh_wallet_balance() -> Result<f64, CargoTraceError> {
     let balance_tokens = get_wallet_balance_async().await?;
     let balance_usd = tokens_to_usd_cents(balance_tokens) as f64 / 100.0;
     Ok(balance_usd)
@@ -921,18 +1110,26 @@ pub async fn refresh_wallet_balance() -> Result<f64, String> {
 
 
 // ACID Validation Functions
-#[update]
-pub fn validate_acid(acid_number: String) -> Result<bool, String> {
-    if acid_number.len() != 9 || !acid_number.chars().all(char::is_numeric) {
-        return Err("Invalid ACID format. Must be 9 digits.".to_string());
+// With NAFEZA enabled an ACID is valid while NAFEZA reports it approved; otherwise while its
+// registry record is neither revoked nor expired.
+#[update(guard = "is_signed_in")]
+pub async fn validate_acid(acid_number: String) -> Result<bool, CargoTraceError> {
+    if !is_valid_acid_format(&acid_number) {
+        return Err(CargoTraceError::InvalidAcid { acid_number });
     }
 
-    let is_valid = is_acid_in_static_dataset(&acid_number);
+    let (is_valid, customs_data) = if nafeza_config().enabled {
+        let info = lookup_acid(&acid_number).await.map_err(CargoTraceError::AcidLookupFailed)?;
+        (info.is_active(), serde_json::to_string(&info).ok())
+    } else {
+        let record = active_acid_record(&acid_number, ic_cdk::api::time());
+        (record.is_some(), record.and_then(|record| serde_json::to_string(&record).ok()))
+    };
     
     let validation = AcidValidation {
         acid_number: acid_number.clone(),
         is_valid,
-        customs_data: if is_valid { Some("Simulated customs data".to_string()) } else { None },
+        customs_data,
         validation_date: ic_cdk::api::time(),
     };
     
@@ -950,57 +1147,143 @@ pub fn get_acid_validation(acid_number: String) -> Option<AcidValidation> {
     })
 }
 
-fn is_acid_in_static_dataset(acid_number: &str) -> bool {
-    let valid_acids = vec![
-        "123456789",
-        "987654321", 
-        "456789123",
-        "789123456",
-        "321654987"
-    ];
-    valid_acids.contains(&acid_number)
+fn migrate_stored_records() {
+    versioned::migrate_stored_records(|| {
+        DOCUMENTS.with(|m| m.borrow_mut().rewrite_all());
+        LOANS.with(|m| m.borrow_mut().rewrite_all());
+        ACID_VALIDATIONS.with(|m| versioned::rewrite_all(&mut m.borrow_mut()));
+        CARGOX_MAPPINGS.with(|m| m.borrow_mut().rewrite_all());
+        CUSTOMS_VERIFICATIONS.with(|m| m.borrow_mut().rewrite_all());
+        cargowatcher::rewrite_transfers();
+        index_stored_transfers();
+        files::rewrite_files();
+        link_stored_documents();
+        key_customs_links_by_document();
+        index_loan_events();
+        index_unconfirmed_transfers();
+    });
+}
+
+// Documents stored before they named their token get it from the stored transfers, and mappings
+// made before they referenced a document point at their owner's document for the transfer
+fn link_stored_documents() {
+    let unnamed: Vec<String> = DOCUMENTS.with(|d| {
+        d.borrow().iter().map(|entry| entry.value()).filter(|doc| doc.token().is_none()).map(|doc| doc.tx_hash).collect()
+    });
+    for tx_hash in unnamed {
+        name_document_tokens(&tx_hash);
+    }
+
+    let unlinked: Vec<(String, CargoXMapping)> = CARGOX_MAPPINGS.with(|m| {
+        m.borrow()
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value()))
+            .filter(|(_, mapping)| mapping.document_id.is_none())
+            .collect()
+    });
+    for (key, mut mapping) in unlinked {
+        let owned = documents_for_tx(&mapping.nft_hash).into_iter().find(|doc| doc.owner == mapping.owner);
+        if let Some(document) = owned {
+            mapping.document_id = Some(document.id);
+            CARGOX_MAPPINGS.with(|m| m.borrow_mut().insert(key, mapping));
+        }
+    }
+}
+
+// Mappings and verifications were keyed by transaction hash, which every token moved in the same
+// transaction shared; they move under their document's id. Links without a document keep their key.
+fn key_customs_links_by_document() {
+    let entries: Vec<(String, CargoXMapping)> =
+        CARGOX_MAPPINGS.with(|m| m.borrow().iter().map(|entry| (entry.key().clone(), entry.value())).collect());
+    for (key, mapping) in entries {
+        let Some(document_id) = mapping.document_id.clone() else {
+            continue;
+        };
+        if key == document_id || CARGOX_MAPPINGS.with(|m| m.borrow().contains_key(&document_id)) {
+            continue;
+        }
+        CARGOX_MAPPINGS.with(|m| {
+            let mut mappings = m.borrow_mut();
+            mappings.remove(&key);
+            mappings.insert(document_id.clone(), mapping);
+        });
+        if let Some(verification) = CUSTOMS_VERIFICATIONS.with(|v| v.borrow_mut().remove(&key)) {
+            let verification = CustomsVerification { document_id: Some(document_id.clone()), ..verification };
+            CUSTOMS_VERIFICATIONS.with(|v| v.borrow_mut().insert(document_id, verification));
+        }
+    }
+}
+
+// Documents marked NftMinted before ICRC-7 support never received a token
+fn mint_missing_document_nfts() {
+    let unminted: Vec<Document> = DOCUMENTS.with(|d| {
+        d.borrow()
+            .iter()
+            .map(|entry| entry.value())
+            .filter(|doc| doc.status == DocumentStatus::NftMinted && doc.nft_token_id.is_none())
+            .collect()
+    });
+    for mut document in unminted {
+        document.nft_token_id = Some(mint_document_nft(&document));
+        DOCUMENTS.with(|d| d.borrow_mut().insert(document.id.clone(), document));
+    }
 }
 
 // Consolidated upgrade functions
+// All state lives in stable structures under MEMORY_MANAGER, so nothing is serialized here.
+// Never use stable_save: it writes from offset 0 and overwrites the memory manager header.
 #[pre_upgrade]
 fn pre_upgrade() {
     ic_cdk::println!("Preparing for upgrade...");
-    use ic_cdk::storage;
-    let principals = login::save_principals_state();
-    storage::stable_save((principals,)).unwrap();
-    ic_cdk::println!("Upgrade preparation complete");
 }
 
 #[post_upgrade]
 fn post_upgrade() {
     ic_cdk::println!("Restoring state after upgrade...");
-    use ic_cdk::storage;
-    use std::collections::BTreeSet;
-    use candid::Principal;
-    let (saved_principals,): (BTreeSet<Principal>,) = storage::stable_restore().unwrap();
-    login::restore_principals_state(saved_principals);
+    restore_legacy_principals();
+    seed_missing_counters();
+    migrate_stored_records();
+    move_legacy_poller_settings();
+    mint_missing_document_nfts();
+    lock_open_loan_collateral();
+    arm_maturity_timer();
+    arm_eth_poller();
+    arm_finality_check();
+    arm_metadata_refresher();
     ic_cdk::println!("State restoration complete");
 }
 
 // Document Management Functions
-#[update]
-pub fn submit_document(acid_number: String, ethereum_tx_hash: String, value_usd: u64) -> Result<String, String> {
-    let acid_validation = validate_acid(acid_number.clone())?;
+// `token` names the CargoX token the document covers; it can be left out when the transaction moved
+// only one token, or has not been seen yet
+#[update(guard = "is_signed_in")]
+pub async fn submit_document(
+    acid_number: String,
+    tx_hash: String,
+    value_usd: u64,
+    token: Option<TokenRef>,
+) -> Result<String, CargoTraceError> {
+    check_tx_hash(&tx_hash)?;
+    let acid_validation = validate_acid(acid_number.clone()).await?;
     if !acid_validation {
-        return Err("Invalid ACID number.".to_string());
+        return Err(CargoTraceError::InvalidAcid { acid_number });
     }
+    let token = document_token(&tx_hash, token)?;
 
-    let document_id = format!("DOC-{:06}", get_next_id("document"));
+    let document_id = new_id("DOC", "document");
     let document = Document {
         id: document_id.clone(),
         acid_number,
-        ethereum_tx_hash,
+        chain: token.as_ref().map_or(POLLED_NETWORK.to_string(), |t| t.network.clone()),
+        contract: token.as_ref().map(|t| t.contract.clone()).unwrap_or_default(),
+        token_id: token.map(|t| t.token_id).unwrap_or_default(),
+        tx_hash,
         value_usd,
         status: DocumentStatus::Pending,
         created_at: ic_cdk::api::time(),
         owner: caller(),
+        nft_token_id: None,
     };
-
     DOCUMENTS.with(|documents| {
         documents.borrow_mut().insert(document_id.clone(), document);
     });
@@ -1018,84 +1301,107 @@ pub fn get_document(document_id: String) -> Option<Document> {
 #[query]
 pub fn get_my_documents() -> Vec<Document> {
     let caller = caller();
-    DOCUMENTS.with(|documents| {
-        documents.borrow()
-            .iter()
-            .filter(|entry| entry.value().owner == caller)
-            .map(|entry| entry.value().clone())
-            .collect()
-    })
+    DOCUMENTS.with(|documents| documents.borrow().find(&term("owner", &caller.to_text())))
 }
 
-#[update]
-pub fn approve_document(document_id: String) -> Result<(), String> {
+// Documents declared under an ACID, for customs review
+#[query(guard = "is_customs_officer")]
+pub fn get_documents_by_acid(acid_number: String) -> Vec<Document> {
+    DOCUMENTS.with(|documents| documents.borrow().find(&term("acid", &acid_number)))
+}
+
+#[update(guard = "is_customs_officer")]
+pub fn approve_document(document_id: String) -> Result<(), CargoTraceError> {
     DOCUMENTS.with(|documents| {
         let mut documents = documents.borrow_mut();
         if let Some(mut document) = documents.get(&document_id) {
             if document.status != DocumentStatus::Pending {
-                return Err("Can only approve pending documents.".to_string());
+                return Err(CargoTraceError::invalid_state(&document.status, DocumentStatus::NftMinted));
             }
+            document.nft_token_id = Some(mint_document_nft(&document));
             document.status = DocumentStatus::NftMinted;
             documents.insert(document_id, document);
             Ok(())
         } else {
-            Err("Document not found.".to_string())
+            Err(CargoTraceError::not_found("Document", &document_id))
         }
     })
 }
 
 #[update]
-pub fn reject_document(document_id: String) -> Result<(), String> {
+pub fn reject_document(document_id: String) -> Result<(), CargoTraceError> {
     let caller = caller();
     DOCUMENTS.with(|documents| {
         let mut documents = documents.borrow_mut();
         if let Some(mut document) = documents.get(&document_id) {
             if document.owner != caller {
-                return Err("Only document owner can reject.".to_string());
+                return Err(CargoTraceError::Unauthorized);
             }
             if document.status != DocumentStatus::Pending {
-                return Err("Can only reject pending documents.".to_string());
+                return Err(CargoTraceError::invalid_state(&document.status, DocumentStatus::Rejected));
             }
             document.status = DocumentStatus::Rejected;
             documents.insert(document_id, document);
             Ok(())
         } else {
-            Err("Document not found.".to_string())
+            Err(CargoTraceError::not_found("Document", &document_id))
         }
     })
 }
 
 // Loan Management Functions
-#[update]
-pub fn request_loan(document_id: String, amount: u64, repayment_date: u64) -> Result<String, String> {
+#[update(guard = "is_borrower")]
+pub fn request_loan(document_id: String, amount: u64, repayment_date: u64) -> Result<String, CargoTraceError> {
     let caller = caller();
-    let document = get_document(document_id.clone()).ok_or("Document not found.")?;
+    let document = get_document(document_id.clone()).ok_or_else(|| CargoTraceError::not_found("Document", &document_id))?;
+    if document.owner != caller {
+        return Err(CargoTraceError::Unauthorized);
+    }
     
     match document.status {
         DocumentStatus::NftMinted => {},
-        _ => return Err("Document must be approved and NFT minted before requesting loan.".to_string()),
+        _ => return Err(CargoTraceError::invalid_state(&document.status, DocumentStatus::NftMinted)),
     }
     
-    if amount > document.value_usd * 80 / 100 {
-        return Err("Loan amount cannot exceed 80% of document value.".to_string());
+    // At most 80% of the document value
+    let max = document.value_usd * 80 / 100;
+    if amount > max {
+        return Err(CargoTraceError::AmountTooLarge { max });
     }
+    check_document_available(&document)?;
+    check_transfer_final(&document.tx_hash)?;
     
-    let loan_id = format!("LOAN-{:06}", get_next_id("loan"));
+    let now = ic_cdk::api::time();
+    if repayment_date <= now {
+        return Err(CargoTraceError::InvalidArgument("Repayment date must be in the future.".to_string()));
+    }
+
+    let loan_id = new_id("LOAN", "loan");
+    let config = lending_config();
     let loan = Loan {
         id: loan_id.clone(),
-        document_id,
+        document_id: document_id.clone(),
         amount,
-        interest_rate: 4.5,
+        interest_rate_bps: config.interest_rate_bps,
+        day_count: config.day_count,
         status: LoanStatus::Pending,
-        created_at: ic_cdk::api::time(),
+        created_at: now,
         borrower: caller,
         repayment_date,
         transfer_block_height: None,
+        transfer_error: None,
+        principal_outstanding: amount,
+        interest_outstanding: 0,
+        accrued_until: now,
+        total_repaid: 0,
+        late_penalty: None,
     };
     
     LOANS.with(|loans| {
         loans.borrow_mut().insert(loan_id.clone(), loan);
     });
+    // Pledged from the request on, so the token cannot change hands while the loan is reviewed
+    lock_collateral(&document_id, &loan_id);
     
     Ok(loan_id)
 }
@@ -1110,80 +1416,119 @@ pub fn get_loan(loan_id: String) -> Option<Loan> {
 #[query]
 pub fn get_my_loans() -> Vec<Loan> {
     let caller = caller();
-    LOANS.with(|loans| {
-        loans.borrow()
-            .iter()
-            .filter(|entry| entry.value().borrower == caller)
-            .map(|entry| entry.value().clone())
-            .collect()
-    })
+    LOANS.with(|loans| loans.borrow().find(&term("owner", &caller.to_text())))
 }
 
 #[query]
-pub fn get_all_loans() -> Vec<Loan> {
-    LOANS.with(|loans| {
-        loans.borrow()
-            .iter()
-            .map(|entry| entry.value().clone())
-            .collect()
-    })
+pub fn get_all_loans(filter: ListFilter, cursor: Option<String>, limit: Option<u32>) -> Page<Loan> {
+    LOANS.with(|loans| pagination::list(&loans.borrow(), &filter, cursor, limit))
 }
 
+// Repayments are pulled with ICRC-2, so the borrower must first approve this canister as spender.
+// Partial payments accumulate: each one settles accrued interest first, then principal. Whatever
+// cannot be applied once the transfer lands is refunded.
 #[update]
-pub fn repay_loan(loan_id: String, amount: u64) -> Result<(), String> {
+pub async fn repay_loan(loan_id: String, amount: u64) -> Result<(), CargoTraceError> {
     let caller = caller();
-    let current_balance = get_balance();
-    if current_balance < amount {
-        return Err("Insufficient balance for repayment.".to_string());
+    let loan = get_loan(loan_id.clone()).ok_or_else(|| CargoTraceError::not_found("Loan", &loan_id))?;
+    if loan.borrower != caller {
+        return Err(CargoTraceError::Unauthorized);
     }
-    
-    LOANS.with(|loans| {
+    if loan.status != LoanStatus::Active {
+        return Err(CargoTraceError::invalid_state(&loan.status, LoanStatus::Repaid));
+    }
+    if amount == 0 {
+        return Err(CargoTraceError::InvalidArgument("Repayment amount must be positive.".to_string()));
+    }
+    // One repayment per loan at a time, so the payoff below still holds when the transfer lands
+    let _repaying = InFlight::claim(format!("repay:{}", loan_id))
+        .ok_or_else(|| CargoTraceError::InProgress { id: loan_id.clone() })?;
+    // Never pull more than the current payoff amount
+    let payoff = loan.principal_outstanding + interest_owed(&loan, ic_cdk::api::time());
+    let amount = amount.min(payoff);
+
+    let transfer_from_args = TransferFromArgs {
+        spender_subaccount: None,
+        from: Account {
+            owner: caller,
+            subaccount: None,
+        },
+        to: Account {
+            owner: ic_cdk::api::id(),
+            subaccount: None,
+        },
+        amount: candid::Nat::from(usd_to_tokens(amount)),
+        fee: None,
+        memo: Some(format!("Loan repayment: {}", loan_id).as_bytes().to_vec()),
+        created_at_time: Some(ic_cdk::api::time()),
+    };
+
+    icrc2_transfer_from(transfer_from_args).await?;
+
+    // The maturity job may have defaulted the loan while the transfer was in flight
+    let applied = LOANS.with(|loans| {
         let mut loans = loans.borrow_mut();
-        if let Some(mut loan) = loans.get(&loan_id) {
-            if loan.borrower != caller {
-                return Err("Only loan borrower can repay.".to_string());
-            }
-            BALANCES.with(|balances| {
-                let mut balances = balances.borrow_mut();
-                let current_balance = balances.get(&caller).unwrap_or(0);
-                balances.insert(caller, current_balance - amount);
-            });
-            if amount >= loan.amount {
-                loan.status = LoanStatus::Repaid;
-            }
-            loans.insert(loan_id, loan);
-            Ok(())
-        } else {
-            Err("Loan not found.".to_string())
+        let mut loan = loans.get(&loan_id).ok_or_else(|| CargoTraceError::not_found("Loan", &loan_id))?;
+        if loan.status != LoanStatus::Active {
+            return Err(CargoTraceError::invalid_state(&loan.status, LoanStatus::Repaid));
         }
+        let applied = apply_payment(&mut loan, amount, ic_cdk::api::time());
+        if is_paid_off(&loan) {
+            loan.status = LoanStatus::Repaid;
+            release_collateral(&loan.document_id);
+        }
+        loans.insert(loan_id.clone(), loan);
+        Ok(applied)
+    });
+    let unapplied = amount - applied.as_ref().copied().unwrap_or(0);
+    if unapplied > 0 {
+        refund_repayment(caller, &loan_id, unapplied).await?;
+    }
+    applied.map(|_| ())
+}
+
+// Sends back the part of a repayment that could not be applied to the loan
+async fn refund_repayment(to: Principal, loan_id: &str, amount: u64) -> Result<(), CargoTraceError> {
+    let transfer_args = TransferArgs {
+        from_subaccount: None,
+        to: Account {
+            owner: to,
+            subaccount: None,
+        },
+        amount: candid::Nat::from(usd_to_tokens(amount)),
+        fee: None,
+        memo: Some(format!("Loan repayment refund: {}", loan_id).as_bytes().to_vec()),
+        created_at_time: Some(ic_cdk::api::time()),
+    };
+    icrc1_transfer(transfer_args).await.map(|_| ()).map_err(|e| {
+        ic_cdk::println!("Refund of {} cents on loan {} to {} failed: {:?}", amount, loan_id, to, e);
+        CargoTraceError::LedgerError(e)
     })
 }
 
-#[update]
-pub fn reject_loan(loan_id: String) -> Result<(), String> {
+#[update(guard = "is_loan_officer")]
+pub fn reject_loan(loan_id: String) -> Result<(), CargoTraceError> {
     LOANS.with(|loans| {
         let mut loans = loans.borrow_mut();
         if let Some(mut loan) = loans.get(&loan_id) {
-            if loan.status != LoanStatus::Pending {
-                return Err("Can only reject pending loans.".to_string());
+            // A failed disbursement moved no funds, so the loan can still be turned down
+            if !matches!(loan.status, LoanStatus::Pending | LoanStatus::TransferFailed) {
+                return Err(CargoTraceError::invalid_state(&loan.status, LoanStatus::Rejected));
             }
             loan.status = LoanStatus::Rejected;
+            release_collateral(&loan.document_id);
             loans.insert(loan_id, loan.clone());
             Ok(())
         } else {
-            Err("Loan not found.".to_string())
+            Err(CargoTraceError::not_found("Loan", &loan_id))
         }
     })
 }
 
 #[query]
-pub fn get_all_loan_ids() -> Vec<String> {
-    LOANS.with(|loans| {
-        loans.borrow()
-            .iter()
-            .map(|entry| entry.key().clone())
-            .collect()
-    })
+pub fn get_all_loan_ids(filter: ListFilter, cursor: Option<String>, limit: Option<u32>) -> Page<String> {
+    let page = LOANS.with(|loans| pagination::list(&loans.borrow(), &filter, cursor, limit));
+    Page { items: page.items.into_iter().map(|loan| loan.id).collect(), next_cursor: page.next_cursor }
 }
 
 // Token Management Functions
@@ -1195,7 +1540,7 @@ pub fn get_balance() -> u64 {
     })
 }
 
-#[update]
+#[update(guard = "is_admin")]
 pub fn mint(amount: u64) {
     let caller = caller();
     BALANCES.with(|balances| {
@@ -1206,11 +1551,11 @@ pub fn mint(amount: u64) {
 }
 
 #[update]
-pub fn transfer(to: Principal, amount: u64) -> Result<(), String> {
+pub fn transfer(to: Principal, amount: u64) -> Result<(), CargoTraceError> {
     let caller = caller();
     let current_balance = get_balance();
     if current_balance < amount {
-        return Err("Insufficient balance.".to_string());
+        return Err(CargoTraceError::InsufficientFunds { balance: candid::Nat::from(current_balance) });
     }
     BALANCES.with(|balances| {
         let mut balances = balances.borrow_mut();
@@ -1244,19 +1589,29 @@ pub fn remove_id(_id: u64) -> bool {
 }
 
 // Customs Integration Functions
-#[update]
-pub fn link_cargox_to_acid(nft_hash: String, acid_number: String) -> Result<String, String> {
-    let acid_validation = validate_acid(acid_number.clone())?;
+// Mappings and verifications are keyed by the id of the document they link
+#[update(guard = "is_signed_in")]
+pub async fn link_cargox_to_acid(document_id: String, acid_number: String) -> Result<String, CargoTraceError> {
+    let acid_validation = validate_acid(acid_number.clone()).await?;
     if !acid_validation {
-        return Err("Invalid ACID number.".to_string());
+        return Err(CargoTraceError::InvalidAcid { acid_number });
     }
-    if CARGOX_MAPPINGS.with(|mappings| mappings.borrow().get(&nft_hash).is_some()) {
-        return Err("CargoX NFT hash already linked to an ACID number.".to_string());
+    let document = get_document(document_id.clone()).ok_or_else(|| CargoTraceError::not_found("Document", &document_id))?;
+    if document.owner != caller() {
+        return Err(CargoTraceError::Unauthorized);
     }
-    let mapping_id = format!("MAP-{:06}", get_next_id("mapping"));
+    check_transfer_final(&document.tx_hash)?;
+    // A transfer claimed through a bound address arrives without an ACID; its owner supplies one here
+    let mapping_id = match CARGOX_MAPPINGS.with(|mappings| mappings.borrow().get(&document_id)) {
+        Some(mapping) if mapping.acid_number.is_empty() => mapping.id,
+        Some(_) => return Err(CargoTraceError::AlreadyLinked { document_id }),
+        None => new_id("MAP", "mapping"),
+    };
+    set_claimed_document_acid(&document_id, &acid_number);
     let mapping = CargoXMapping {
         id: mapping_id.clone(),
-        nft_hash: nft_hash.clone(),
+        nft_hash: document.tx_hash.clone(),
+        document_id: Some(document_id.clone()),
         acid_number: acid_number.clone(),
         verified: false,
         created_at: ic_cdk::api::time(),
@@ -1264,12 +1619,13 @@ pub fn link_cargox_to_acid(nft_hash: String, acid_number: String) -> Result<Stri
         customs_entry_id: None,
     };
     CARGOX_MAPPINGS.with(|mappings| {
-        mappings.borrow_mut().insert(nft_hash.clone(), mapping);
+        mappings.borrow_mut().insert(document_id.clone(), mapping);
     });
-    let verification_id = format!("VER-{:06}", get_next_id("verification"));
+    let verification_id = new_id("VER", "verification");
     let verification = CustomsVerification {
         id: verification_id,
-        nft_hash: nft_hash.clone(),
+        nft_hash: document.tx_hash,
+        document_id: Some(document_id.clone()),
         acid_number: acid_number.clone(),
         verification_status: CustomsStatus::Pending,
         verified_at: None,
@@ -1278,142 +1634,139 @@ pub fn link_cargox_to_acid(nft_hash: String, acid_number: String) -> Result<Stri
         verified_by: None,
     };
     CUSTOMS_VERIFICATIONS.with(|verifications| {
-        verifications.borrow_mut().insert(nft_hash, verification);
+        verifications.borrow_mut().insert(document_id, verification);
     });
     Ok(mapping_id)
 }
 
+fn set_claimed_document_acid(document_id: &str, acid_number: &str) {
+    DOCUMENTS.with(|documents| {
+        let mut documents = documents.borrow_mut();
+        if let Some(mut document) = documents.get(&document_id.to_string()) {
+            if document.acid_number.is_empty() {
+                document.acid_number = acid_number.to_string();
+                documents.insert(document.id.clone(), document);
+            }
+        }
+    });
+}
+
+// Settles a verification that is still open, returning it with the mapping it belongs to
+fn open_customs_entry(key: &String, to: CustomsStatus) -> Result<(CargoXMapping, CustomsVerification), CargoTraceError> {
+    let mapping = CARGOX_MAPPINGS
+        .with(|mappings| mappings.borrow().get(key))
+        .ok_or_else(|| CargoTraceError::not_found("CargoXMapping", key))?;
+    let verification = CUSTOMS_VERIFICATIONS
+        .with(|verifications| verifications.borrow().get(key))
+        .ok_or_else(|| CargoTraceError::not_found("CustomsVerification", key))?;
+    match verification.verification_status {
+        CustomsStatus::Pending | CustomsStatus::UnderReview => Ok((mapping, verification)),
+        ref status => Err(CargoTraceError::invalid_state(status, to)),
+    }
+}
+
+// Moves the document a CargoX mapping references from Pending to `status`, the result of its review
+fn set_mapped_document_status(mapping: &CargoXMapping, status: DocumentStatus) -> Result<(), CargoTraceError> {
+    let Some(document_id) = &mapping.document_id else {
+        return Ok(());
+    };
+    DOCUMENTS.with(|documents| {
+        let mut documents = documents.borrow_mut();
+        let Some(mut document) = documents.get(document_id) else {
+            return Ok(());
+        };
+        if document.status != DocumentStatus::Pending {
+            return Err(CargoTraceError::invalid_state(&document.status, status));
+        }
+        document.status = status;
+        documents.insert(document_id.clone(), document);
+        Ok(())
+    })
+}
+
 #[query]
-pub fn get_cargox_mapping(nft_hash: String) -> Option<CargoXMapping> {
+pub fn get_cargox_mapping(document_id: String) -> Option<CargoXMapping> {
     CARGOX_MAPPINGS.with(|mappings| {
-        mappings.borrow().get(&nft_hash)
+        mappings.borrow().get(&document_id)
     })
 }
 
 #[query]
 pub fn get_my_cargox_mappings() -> Vec<CargoXMapping> {
     let caller = caller();
-    CARGOX_MAPPINGS.with(|mappings| {
-        mappings.borrow()
-            .iter()
-            .filter(|entry| entry.value().owner == caller)
-            .map(|entry| entry.value().clone())
-            .collect()
-    })
+    CARGOX_MAPPINGS.with(|mappings| mappings.borrow().find(&term("owner", &caller.to_text())))
 }
 
 #[query]
-pub fn get_all_cargox_mappings() -> Vec<CargoXMapping> {
-    CARGOX_MAPPINGS.with(|mappings| {
-        mappings.borrow()
-            .iter()
-            .map(|entry| entry.value().clone())
-            .collect()
-    })
+pub fn get_all_cargox_mappings(filter: ListFilter, cursor: Option<String>, limit: Option<u32>) -> Page<CargoXMapping> {
+    CARGOX_MAPPINGS.with(|mappings| pagination::list(&mappings.borrow(), &filter, cursor, limit))
 }
 
-#[update]
-pub fn verify_customs_entry(nft_hash: String) -> Result<(), String> {
-    let _mapping = CARGOX_MAPPINGS.with(|mappings| {
-        mappings.borrow().get(&nft_hash)
-    }).ok_or("CargoX mapping not found")?;
+#[update(guard = "is_customs_officer")]
+pub fn verify_customs_entry(document_id: String) -> Result<(), CargoTraceError> {
+    let (mut mapping, mut verification) = open_customs_entry(&document_id, CustomsStatus::Verified)?;
+    set_mapped_document_status(&mapping, DocumentStatus::Verified)?;
+    mapping.verified = true;
     CARGOX_MAPPINGS.with(|mappings| {
-        let mut mappings = mappings.borrow_mut();
-        if let Some(mut mapping) = mappings.get(&nft_hash) {
-            mapping.verified = true;
-            mappings.insert(nft_hash.clone(), mapping);
-        }
+        mappings.borrow_mut().insert(document_id.clone(), mapping);
     });
+    verification.verification_status = CustomsStatus::Verified;
+    verification.verified_at = Some(ic_cdk::api::time());
+    verification.verified_by = Some(caller());
+    verification.customs_data = Some("Customs entry verified manually".to_string());
     CUSTOMS_VERIFICATIONS.with(|verifications| {
-        let mut verifications = verifications.borrow_mut();
-        if let Some(mut verification) = verifications.get(&nft_hash) {
-            verification.verification_status = CustomsStatus::Verified;
-            verification.verified_at = Some(ic_cdk::api::time());
-            verification.verified_by = Some(caller());
-            verification.customs_data = Some("Customs entry verified manually".to_string());
-            verifications.insert(nft_hash.clone(), verification);
-        }
+        verifications.borrow_mut().insert(document_id, verification);
     });
-    let document = DOCUMENTS.with(|documents| {
-        documents.borrow()
-            .iter()
-            .find(|entry| entry.value().ethereum_tx_hash == nft_hash)
-            .map(|entry| entry.value().clone())
-    });
-    if let Some(mut doc) = document {
-        doc.status = DocumentStatus::Verified;
-        DOCUMENTS.with(|documents| {
-            documents.borrow_mut().insert(doc.id.clone(), doc);
-        });
-    }
     Ok(())
 }
 
-#[update]
-pub fn reject_customs_entry(nft_hash: String, reason: String) -> Result<(), String> {
+#[update(guard = "is_customs_officer")]
+pub fn reject_customs_entry(document_id: String, reason: String) -> Result<(), CargoTraceError> {
+    let (mapping, mut verification) = open_customs_entry(&document_id, CustomsStatus::Rejected)?;
+    set_mapped_document_status(&mapping, DocumentStatus::Rejected)?;
+    verification.verification_status = CustomsStatus::Rejected;
+    verification.verified_at = Some(ic_cdk::api::time());
+    verification.verified_by = Some(caller());
+    verification.customs_data = Some(format!("Rejected: {}", reason));
     CUSTOMS_VERIFICATIONS.with(|verifications| {
-        let mut verifications = verifications.borrow_mut();
-        if let Some(mut verification) = verifications.get(&nft_hash) {
-            verification.verification_status = CustomsStatus::Rejected;
-            verification.verified_at = Some(ic_cdk::api::time());
-            verification.verified_by = Some(caller());
-            verification.customs_data = Some(format!("Rejected: {}", reason));
-            verifications.insert(nft_hash.clone(), verification);
-        }
-    });
-    let document = DOCUMENTS.with(|documents| {
-        documents.borrow()
-            .iter()
-            .find(|entry| entry.value().ethereum_tx_hash == nft_hash)
-            .map(|entry| entry.value().clone())
+        verifications.borrow_mut().insert(document_id, verification);
     });
-    if let Some(mut doc) = document {
-        doc.status = DocumentStatus::Rejected;
-        DOCUMENTS.with(|documents| {
-            documents.borrow_mut().insert(doc.id.clone(), doc);
-        });
-    }
     Ok(())
 }
 
 #[query]
-pub fn get_customs_verification(nft_hash: String) -> Option<CustomsVerification> {
+pub fn get_customs_verification(document_id: String) -> Option<CustomsVerification> {
     CUSTOMS_VERIFICATIONS.with(|verifications| {
-        verifications.borrow().get(&nft_hash)
+        verifications.borrow().get(&document_id)
     })
 }
 
 #[query]
-pub fn get_all_customs_verifications() -> Vec<CustomsVerification> {
-    CUSTOMS_VERIFICATIONS.with(|verifications| {
-        verifications.borrow()
-            .iter()
-            .map(|entry| entry.value().clone())
-            .collect()
-    })
+pub fn get_all_customs_verifications(
+    filter: ListFilter,
+    cursor: Option<String>,
+    limit: Option<u32>,
+) -> Page<CustomsVerification> {
+    CUSTOMS_VERIFICATIONS.with(|verifications| pagination::list(&verifications.borrow(), &filter, cursor, limit))
 }
 
 #[query]
 pub fn get_pending_customs_verifications() -> Vec<CustomsVerification> {
-    CUSTOMS_VERIFICATIONS.with(|verifications| {
-        verifications.borrow()
-            .iter()
-            .filter(|entry| matches!(entry.value().verification_status, CustomsStatus::Pending))
-            .map(|entry| entry.value().clone())
-            .collect()
-    })
+    let pending = term("status", &format!("{:?}", CustomsStatus::Pending));
+    CUSTOMS_VERIFICATIONS.with(|verifications| verifications.borrow().find(&pending))
 }
 
 // Lending Integration Functions
-#[update]
-pub fn trigger_lending(document_id: String) -> Result<(), String> {
+#[update(guard = "is_loan_officer")]
+pub fn trigger_lending(document_id: String) -> Result<(), CargoTraceError> {
     let document = get_document(document_id.clone())
-        .ok_or("Document not found")?;
+        .ok_or_else(|| CargoTraceError::not_found("Document", &document_id))?;
     match document.status {
         DocumentStatus::Verified => {
             DOCUMENTS.with(|documents| {
                 let mut documents = documents.borrow_mut();
                 if let Some(mut doc) = documents.get(&document_id) {
+                    doc.nft_token_id = Some(mint_document_nft(&doc));
                     doc.status = DocumentStatus::NftMinted;
                     documents.insert(document_id.clone(), doc);
                 }
@@ -1424,38 +1777,31 @@ pub fn trigger_lending(document_id: String) -> Result<(), String> {
         DocumentStatus::NftMinted => {
             Ok(())
         },
-        _ => Err("Document must be verified before triggering lending".to_string())
+        status => Err(CargoTraceError::invalid_state(&status, DocumentStatus::NftMinted))
     }
 }
 
-#[update]
-pub fn batch_trigger_lending(document_ids: Vec<String>) -> Result<Vec<String>, String> {
+#[update(guard = "is_loan_officer")]
+pub fn batch_trigger_lending(document_ids: Vec<String>) -> Result<Vec<String>, CargoTraceError> {
     let mut successful = Vec::new();
     let mut failed = Vec::new();
     for document_id in document_ids {
         match trigger_lending(document_id.clone()) {
             Ok(_) => successful.push(document_id),
-            Err(e) => failed.push(format!("{}: {}", document_id, e)),
+            Err(e) => {
+                ic_cdk::println!("Triggering lending for {} failed: {:?}", document_id, e);
+                failed.push(document_id);
+            }
         }
     }
     if failed.is_empty() {
         Ok(successful)
     } else {
-        Err(format!("Some documents failed: {:?}", failed))
+        Err(CargoTraceError::BatchFailed { failed })
     }
 }
 
 // Utility Functions for Customs Integration
-#[query]
-pub fn get_document_by_nft_hash(nft_hash: String) -> Option<Document> {
-    DOCUMENTS.with(|documents| {
-        documents.borrow()
-            .iter()
-            .find(|entry| entry.value().ethereum_tx_hash == nft_hash)
-            .map(|entry| entry.value().clone())
-    })
-}
-
 #[query]
 pub fn get_verification_stats() -> (u64, u64, u64, u64) {
     let (pending, verified, rejected, under_review) = CUSTOMS_VERIFICATIONS.with(|verifications| {
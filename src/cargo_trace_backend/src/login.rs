@@ -1,23 +1,93 @@
 use candid::Principal;
 use ic_cdk_macros::*;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{DefaultMemoryImpl, Memory as RawMemory, StableBTreeSet};
 use std::collections::BTreeSet;
 
+use crate::{Memory, MEMORY_MANAGER};
+
 // ---- STATE ----
 thread_local! {
-    static PRINCIPALS: std::cell::RefCell<BTreeSet<Principal>> =
-        std::cell::RefCell::new(BTreeSet::new());
+    static PRINCIPALS: std::cell::RefCell<StableBTreeSet<Principal, Memory>> = std::cell::RefCell::new(
+        StableBTreeSet::init(MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(11))))
+    );
 }
 
-// Remove the upgrade hooks from this module - they conflict with main lib.rs
-// The main lib.rs should handle all upgrade logic
+// ---- Legacy stable blob ----
+// Builds before the stable-memory migration kept the principals on the heap and wrote them with
+// `stable_save` in pre_upgrade, over the start of the memory manager's header. Upgrading from one
+// of them leaves that Candid blob at offset 0 and the bucket table after the header intact.
+const LEGACY_BLOB_MAGIC: &[u8; 4] = b"DIDL";
+
+// Memory manager layout of ic-stable-structures 0.7: a 2080-byte header (magic, layout version,
+// bucket count, bucket size, 32 reserved bytes, 255 memory sizes) followed by one byte per bucket
+// naming the memory that owns it
+const MANAGER_HEADER_SIZE: u64 = 2080;
+const MAX_NUM_BUCKETS: usize = 32768;
+const BUCKET_SIZE_IN_PAGES: u64 = 128;
+const UNALLOCATED_BUCKET: u8 = 255;
+
+// Decodes the legacy blob, if there is one, and writes back a memory manager header rebuilt from
+// the bucket table so the stable maps survive. Must run before anything touches MEMORY_MANAGER.
+fn take_legacy_principals<M: RawMemory>(memory: &M) -> Option<BTreeSet<Principal>> {
+    if memory.size() == 0 {
+        return None;
+    }
+    let mut magic = [0; 4];
+    memory.read(0, &mut magic);
+    if &magic != LEGACY_BLOB_MAGIC {
+        return None;
+    }
 
-// Helper functions for the main upgrade hooks to call
-pub fn save_principals_state() -> BTreeSet<Principal> {
-    PRINCIPALS.with(|p| p.borrow().clone())
+    let mut buckets = vec![0; MANAGER_HEADER_SIZE as usize + MAX_NUM_BUCKETS];
+    memory.read(0, &mut buckets);
+    let principals: BTreeSet<Principal> = candid::de::IDLDeserialize::new(&buckets)
+        .and_then(|mut de| de.get_value())
+        .unwrap_or_else(|e| ic_cdk::trap(&format!("Unreadable legacy stable blob: {}", e)));
+    let blob_len = candid::encode_one(&principals).map_or(usize::MAX, |blob| blob.len());
+    if blob_len > MANAGER_HEADER_SIZE as usize {
+        ic_cdk::trap("The legacy stable blob overwrote the bucket table; upgrade with --skip-pre-upgrade.");
+    }
+
+    // Buckets are handed out in order, so a table written by the memory manager is a run of
+    // owners followed by unallocated markers. Anything else means there was no memory manager
+    // and it can start from scratch.
+    let table = buckets.split_off(MANAGER_HEADER_SIZE as usize);
+    let allocated = table.iter().take_while(|owner| **owner != UNALLOCATED_BUCKET).count();
+    let well_formed = table[allocated..].iter().all(|owner| *owner == UNALLOCATED_BUCKET)
+        && memory.size() > allocated as u64 * BUCKET_SIZE_IN_PAGES;
+    if well_formed {
+        // Exact sizes are lost with the header; each memory gets the whole of its buckets
+        let mut sizes_in_pages = [0u64; UNALLOCATED_BUCKET as usize];
+        for owner in &table[..allocated] {
+            sizes_in_pages[*owner as usize] += BUCKET_SIZE_IN_PAGES;
+        }
+        let mut header = Vec::with_capacity(MANAGER_HEADER_SIZE as usize);
+        header.extend_from_slice(b"MGR");
+        header.push(1);
+        header.extend_from_slice(&(allocated as u16).to_le_bytes());
+        header.extend_from_slice(&(BUCKET_SIZE_IN_PAGES as u16).to_le_bytes());
+        header.extend_from_slice(&[0; 32]);
+        sizes_in_pages.iter().for_each(|size| header.extend_from_slice(&size.to_le_bytes()));
+        memory.write(0, &header);
+    } else {
+        memory.write(0, &[0; 4]);
+    }
+    Some(principals)
 }
 
-pub fn restore_principals_state(saved_principals: BTreeSet<Principal>) {
-    PRINCIPALS.with(|p| *p.borrow_mut() = saved_principals);
+// One-shot migration of the principals a legacy build saved with `stable_save`
+pub(crate) fn restore_legacy_principals() {
+    let Some(principals) = take_legacy_principals(&DefaultMemoryImpl::default()) else {
+        return;
+    };
+    PRINCIPALS.with(|p| {
+        let mut saved = p.borrow_mut();
+        principals.iter().for_each(|principal| {
+            saved.insert(*principal);
+        });
+    });
+    ic_cdk::println!("Restored {} principals from the legacy stable blob", principals.len());
 }
 
 // ---- API ----
@@ -30,7 +100,7 @@ pub fn save_principal(principal: Principal) {
 
 #[query]
 pub fn get_principals() -> Vec<Principal> {
-    PRINCIPALS.with(|p| p.borrow().iter().cloned().collect())
+    PRINCIPALS.with(|p| p.borrow().iter().collect())
 }
 
 
@@ -71,3 +141,42 @@ pub fn get_principals() -> Vec<Principal> {
 // pub fn get_principals() -> Vec<Principal> {
 //     PRINCIPALS.with(|p| p.borrow().iter().cloned().collect())
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_stable_structures::memory_manager::MemoryManager;
+    use ic_stable_structures::{StableBTreeMap, VectorMemory};
+
+    #[test]
+    fn legacy_blob_is_restored_and_the_stable_maps_survive() {
+        let memory = VectorMemory::default();
+        {
+            let manager = MemoryManager::init(memory.clone());
+            let mut documents: StableBTreeMap<u64, u64, _> = StableBTreeMap::init(manager.get(MemoryId::new(1)));
+            let mut loans: StableBTreeMap<u64, u64, _> = StableBTreeMap::init(manager.get(MemoryId::new(2)));
+            documents.insert(1, 10);
+            loans.insert(2, 20);
+        }
+        let principals: BTreeSet<Principal> = [Principal::anonymous(), Principal::from_slice(&[2; 29])].into();
+        memory.write(0, &candid::encode_one(&principals).unwrap());
+
+        assert_eq!(take_legacy_principals(&memory), Some(principals));
+        let manager = MemoryManager::init(memory.clone());
+        let documents: StableBTreeMap<u64, u64, _> = StableBTreeMap::init(manager.get(MemoryId::new(1)));
+        let loans: StableBTreeMap<u64, u64, _> = StableBTreeMap::init(manager.get(MemoryId::new(2)));
+        assert_eq!(documents.get(&1), Some(10));
+        assert_eq!(loans.get(&2), Some(20));
+        assert_eq!(take_legacy_principals(&memory), None);
+    }
+
+    #[test]
+    fn memory_without_a_legacy_blob_is_left_alone() {
+        let memory = VectorMemory::default();
+        assert_eq!(take_legacy_principals(&memory), None);
+        let manager = MemoryManager::init(memory.clone());
+        let mut documents: StableBTreeMap<u64, u64, _> = StableBTreeMap::init(manager.get(MemoryId::new(1)));
+        documents.insert(1, 10);
+        assert_eq!(take_legacy_principals(&memory), None);
+    }
+}
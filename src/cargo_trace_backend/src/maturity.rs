@@ -0,0 +1,377 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk_macros::{query, update};
+use ic_cdk_timers::TimerId;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{StableBTreeMap, StableCell};
+use std::cell::RefCell;
+use std::time::Duration;
+
+use crate::roles::{is_admin, is_loan_officer};
+use crate::versioned::{versioned_storable, Versioned};
+use crate::indexed::{term, IndexedMap};
+use crate::pagination::page_limit;
+use crate::{
+    accrue, get_loan, get_next_id, seize_collateral, CargoTraceError, Loan, LoanStatus, Memory, Page, LOANS,
+    MEMORY_MANAGER,
+};
+
+const NANOS_PER_DAY: u64 = 86_400 * 1_000_000_000;
+const MIN_INTERVAL_SECS: u64 = 60;
+// Bounds the loans one run updates; the rest wait for the next tick
+const MAX_LOANS_PER_RUN: usize = 200;
+
+// ---- Configuration ----
+// Days are counted from the loan's repayment_date
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct MaturityConfig {
+    // How often the maturity job scans active loans
+    pub interval_secs: u64,
+    // Days after maturity before the late penalty is charged
+    pub grace_days: u32,
+    // One-off penalty on outstanding principal, added to interest owed
+    pub late_penalty_bps: u32,
+    // Days after maturity before an unpaid loan is marked Defaulted
+    pub default_after_days: u32,
+}
+
+impl Default for MaturityConfig {
+    fn default() -> Self {
+        MaturityConfig {
+            interval_secs: 3_600,
+            grace_days: 5,
+            late_penalty_bps: 200,
+            default_after_days: 30,
+        }
+    }
+}
+
+impl Versioned for MaturityConfig {
+    const NAME: &'static str = "MaturityConfig";
+    const VERSION: u8 = 1;
+}
+versioned_storable!(MaturityConfig);
+
+// ---- Events ----
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum LoanEventKind {
+    PenaltyApplied { amount: u64 },
+    Defaulted { principal_outstanding: u64, interest_outstanding: u64 },
+    CollateralSeized { document_id: String, nft_token_id: Option<u64> },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct LoanEvent {
+    pub id: u64,
+    pub loan_id: String,
+    pub kind: LoanEventKind,
+    pub at: u64,
+}
+
+impl Versioned for LoanEvent {
+    const NAME: &'static str = "LoanEvent";
+    const VERSION: u8 = 1;
+}
+versioned_storable!(LoanEvent);
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct MaturityRun {
+    pub scanned: u64,
+    pub penalized: u64,
+    pub defaulted: u64,
+}
+
+// ---- STATE ----
+thread_local! {
+    static MATURITY_CONFIG: RefCell<StableCell<MaturityConfig, Memory>> = RefCell::new(
+        StableCell::init(MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(15))), MaturityConfig::default())
+    );
+    static LOAN_EVENTS: RefCell<StableBTreeMap<u64, LoanEvent, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(16))))
+    );
+    // "<loan id>|<zero-padded event id>" -> event id, so one loan's events are read in order
+    static LOAN_EVENT_INDEX: RefCell<StableBTreeMap<String, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(42))))
+    );
+    // Timers do not survive upgrades, so this is re-armed from init and post_upgrade
+    static MATURITY_TIMER: RefCell<Option<TimerId>> = const { RefCell::new(None) };
+    // Loan id the next run resumes after; runs walk the active loans in id order and wrap around
+    static MATURITY_CURSOR: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+fn maturity_config() -> MaturityConfig {
+    MATURITY_CONFIG.with(|c| c.borrow().get().clone())
+}
+
+pub fn arm_maturity_timer() {
+    let interval = Duration::from_secs(maturity_config().interval_secs);
+    let timer = ic_cdk_timers::set_timer_interval(interval, || {
+        let run = process_maturities(ic_cdk::api::time());
+        if run.penalized > 0 || run.defaulted > 0 {
+            ic_cdk::println!("Maturity check: {} penalized, {} defaulted", run.penalized, run.defaulted);
+        }
+    });
+    if let Some(previous) = MATURITY_TIMER.with(|t| t.borrow_mut().replace(timer)) {
+        ic_cdk_timers::clear_timer(previous);
+    }
+}
+
+fn event_index_key(loan_id: &str, id: u64) -> String {
+    format!("{}|{:020}", loan_id, id)
+}
+
+fn record_event(loan_id: &str, kind: LoanEventKind, at: u64) {
+    let id = get_next_id("loan_event");
+    let event = LoanEvent { id, loan_id: loan_id.to_string(), kind, at };
+    LOAN_EVENTS.with(|e| e.borrow_mut().insert(id, event));
+    LOAN_EVENT_INDEX.with(|i| i.borrow_mut().insert(event_index_key(loan_id, id), id));
+}
+
+// Indexes the events recorded before the per-loan index existed
+pub(crate) fn index_loan_events() {
+    let entries: Vec<(String, u64)> = LOAN_EVENTS.with(|e| {
+        e.borrow().iter().map(|entry| (event_index_key(&entry.value().loan_id, *entry.key()), *entry.key())).collect()
+    });
+    LOAN_EVENT_INDEX.with(|i| {
+        let mut index = i.borrow_mut();
+        for (key, id) in entries {
+            index.insert(key, id);
+        }
+    });
+}
+
+// Applies the late penalty and default transitions that are due at `now`
+fn process_loan(loan: &mut Loan, config: &MaturityConfig, now: u64) -> Vec<LoanEventKind> {
+    let mut events = Vec::new();
+    let penalty_at = loan.repayment_date.saturating_add(config.grace_days as u64 * NANOS_PER_DAY);
+    let default_at = loan.repayment_date.saturating_add(config.default_after_days as u64 * NANOS_PER_DAY);
+    if now < penalty_at && now < default_at {
+        return events;
+    }
+
+    accrue(loan, now);
+    if now >= penalty_at && loan.late_penalty.is_none() {
+        let amount = (loan.principal_outstanding as u128 * config.late_penalty_bps as u128 / 10_000) as u64;
+        loan.interest_outstanding += amount;
+        loan.late_penalty = Some(amount);
+        events.push(LoanEventKind::PenaltyApplied { amount });
+    }
+    if now >= default_at {
+        loan.status = LoanStatus::Defaulted;
+        events.push(LoanEventKind::Defaulted {
+            principal_outstanding: loan.principal_outstanding,
+            interest_outstanding: loan.interest_outstanding,
+        });
+    }
+    events
+}
+
+// The next MAX_LOANS_PER_RUN active loans from the cursor, wrapping to the first at the end
+fn next_active_batch(loans: &IndexedMap<Loan>) -> Vec<Loan> {
+    let active = term("status", &format!("{:?}", LoanStatus::Active));
+    let cursor = MATURITY_CURSOR.with(|c| c.borrow().clone());
+    let batch = |cursor: Option<&String>| -> Vec<Loan> {
+        loans.scan(Some(&active), cursor).take(MAX_LOANS_PER_RUN).map(|(_, loan)| loan).collect()
+    };
+    let mut loans = batch(cursor.as_ref());
+    if loans.is_empty() && cursor.is_some() {
+        loans = batch(None);
+    }
+    let next = loans.last().filter(|_| loans.len() == MAX_LOANS_PER_RUN).map(|loan| loan.id.clone());
+    MATURITY_CURSOR.with(|c| *c.borrow_mut() = next);
+    loans
+}
+
+fn process_maturities(now: u64) -> MaturityRun {
+    let config = maturity_config();
+    let mut run = MaturityRun::default();
+    LOANS.with(|loans| {
+        let mut loans = loans.borrow_mut();
+        let active = next_active_batch(&loans);
+        run.scanned = active.len() as u64;
+        for mut loan in active {
+            let events = process_loan(&mut loan, &config, now);
+            if events.is_empty() {
+                continue;
+            }
+            for kind in events {
+                match kind {
+                    LoanEventKind::PenaltyApplied { .. } => run.penalized += 1,
+                    LoanEventKind::Defaulted { .. } => run.defaulted += 1,
+                    LoanEventKind::CollateralSeized { .. } => {}
+                }
+                record_event(&loan.id, kind, now);
+            }
+            if loan.status == LoanStatus::Defaulted {
+                let nft_token_id = seize_collateral(&loan.document_id);
+                let kind = LoanEventKind::CollateralSeized { document_id: loan.document_id.clone(), nft_token_id };
+                record_event(&loan.id, kind, now);
+            }
+            loans.insert(loan.id.clone(), loan);
+        }
+    });
+    run
+}
+
+// ---- API ----
+#[update(guard = "is_admin")]
+pub fn set_maturity_config(config: MaturityConfig) -> Result<(), CargoTraceError> {
+    if config.interval_secs < MIN_INTERVAL_SECS {
+        let reason = format!("Maturity check interval must be at least {} seconds.", MIN_INTERVAL_SECS);
+        return Err(CargoTraceError::InvalidArgument(reason));
+    }
+    if config.late_penalty_bps > 10_000 {
+        return Err(CargoTraceError::InvalidArgument("Late penalty cannot exceed 10000 basis points.".to_string()));
+    }
+    if config.default_after_days < config.grace_days {
+        let reason = "Default period cannot be shorter than the grace period.".to_string();
+        return Err(CargoTraceError::InvalidArgument(reason));
+    }
+    MATURITY_CONFIG.with(|c| c.borrow_mut().set(config));
+    arm_maturity_timer();
+    Ok(())
+}
+
+#[query]
+pub fn get_maturity_config() -> MaturityConfig {
+    maturity_config()
+}
+
+// Runs the next batch of the maturity job now instead of waiting for the next tick
+#[update(guard = "is_loan_officer")]
+pub fn run_maturity_check() -> MaturityRun {
+    process_maturities(ic_cdk::api::time())
+}
+
+// Events for one loan, to its borrower and loan officers, or for every loan, to loan officers only.
+// Pass back `next_cursor`, the id of the last event returned, for the next page.
+#[query]
+pub fn get_loan_events(
+    loan_id: Option<String>,
+    cursor: Option<String>,
+    limit: Option<u32>,
+) -> Result<Page<LoanEvent>, CargoTraceError> {
+    let officer = is_loan_officer().is_ok();
+    let start = match cursor {
+        Some(cursor) => cursor
+            .parse::<u64>()
+            .map_err(|_| CargoTraceError::InvalidArgument("Invalid cursor.".to_string()))?
+            .saturating_add(1),
+        None => 0,
+    };
+    match &loan_id {
+        Some(loan_id) => {
+            let loan = get_loan(loan_id.clone()).ok_or_else(|| CargoTraceError::not_found("Loan", loan_id))?;
+            if !officer && loan.borrower != ic_cdk::caller() {
+                return Err(CargoTraceError::Unauthorized);
+            }
+        }
+        None if officer => {}
+        None => return Err(CargoTraceError::Unauthorized),
+    }
+    Ok(loan_events(loan_id.as_deref(), start, page_limit(limit)))
+}
+
+// Up to `limit` events from id `start` on, of one loan if given
+fn loan_events(loan_id: Option<&str>, start: u64, limit: usize) -> Page<LoanEvent> {
+    let ids: Vec<u64> = match loan_id {
+        Some(loan_id) => {
+            let prefix = format!("{}|", loan_id);
+            LOAN_EVENT_INDEX.with(|i| {
+                i.borrow()
+                    .range(event_index_key(loan_id, start)..)
+                    .take_while(|entry| entry.key().starts_with(&prefix))
+                    .take(limit)
+                    .map(|entry| entry.value())
+                    .collect()
+            })
+        }
+        None => LOAN_EVENTS.with(|e| e.borrow().range(start..).take(limit).map(|entry| *entry.key()).collect()),
+    };
+    let next_cursor = ids.last().filter(|_| ids.len() == limit).map(|id| id.to_string());
+    let items = LOAN_EVENTS.with(|e| {
+        let events = e.borrow();
+        ids.iter().filter_map(|id| events.get(id)).collect()
+    });
+    Page { items, next_cursor }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::Principal;
+
+    fn loan(repayment_date: u64) -> Loan {
+        Loan {
+            id: "LOAN-000001".to_string(),
+            document_id: "DOC-000001".to_string(),
+            amount: 100_000,
+            interest_rate_bps: 0,
+            day_count: crate::DayCount::Actual365,
+            status: LoanStatus::Active,
+            created_at: 0,
+            borrower: Principal::anonymous(),
+            repayment_date,
+            transfer_block_height: None,
+            transfer_error: None,
+            principal_outstanding: 100_000,
+            interest_outstanding: 0,
+            accrued_until: 0,
+            total_repaid: 0,
+            late_penalty: None,
+        }
+    }
+
+    #[test]
+    fn runs_walk_the_active_loans_from_a_cursor() {
+        LOANS.with(|loans| {
+            let mut loans = loans.borrow_mut();
+            for i in 0..MAX_LOANS_PER_RUN + 5 {
+                let id = format!("LOAN-{:06}", i);
+                loans.insert(id.clone(), Loan { id, ..loan(u64::MAX) });
+            }
+        });
+        assert_eq!(process_maturities(0).scanned, MAX_LOANS_PER_RUN as u64);
+        assert_eq!(process_maturities(0).scanned, 5);
+        assert_eq!(process_maturities(0).scanned, MAX_LOANS_PER_RUN as u64);
+    }
+
+    #[test]
+    fn penalty_after_grace_then_default() {
+        let config = MaturityConfig::default();
+        let due = 10 * NANOS_PER_DAY;
+        let mut loan = loan(due);
+
+        // Overdue but inside the grace period
+        assert!(process_loan(&mut loan, &config, due + NANOS_PER_DAY).is_empty());
+
+        let events = process_loan(&mut loan, &config, due + 5 * NANOS_PER_DAY);
+        assert_eq!(events, vec![LoanEventKind::PenaltyApplied { amount: 2_000 }]);
+        assert_eq!(loan.interest_outstanding, 2_000);
+        assert_eq!(loan.status, LoanStatus::Active);
+
+        // The penalty is charged only once
+        assert!(process_loan(&mut loan, &config, due + 6 * NANOS_PER_DAY).is_empty());
+
+        let events = process_loan(&mut loan, &config, due + 30 * NANOS_PER_DAY);
+        assert_eq!(events.len(), 1);
+        assert_eq!(loan.status, LoanStatus::Defaulted);
+        assert_eq!(loan.interest_outstanding, 2_000);
+    }
+
+    #[test]
+    fn events_are_paged_per_loan() {
+        for loan_id in ["LOAN-000001", "LOAN-000002", "LOAN-000001", "LOAN-000001"] {
+            record_event(loan_id, LoanEventKind::PenaltyApplied { amount: 1 }, 0);
+        }
+        let first = loan_events(Some("LOAN-000001"), 0, 2);
+        assert_eq!(first.items.iter().map(|e| e.loan_id.as_str()).collect::<Vec<_>>(), ["LOAN-000001"; 2]);
+        let cursor: u64 = first.next_cursor.unwrap().parse().unwrap();
+        let rest = loan_events(Some("LOAN-000001"), cursor + 1, 2);
+        assert_eq!(rest.items.len(), 1);
+        assert!(rest.items[0].id > cursor);
+        assert_eq!(rest.next_cursor, None);
+
+        assert_eq!(loan_events(None, 0, 10).items.len(), 4);
+        assert_eq!(loan_events(Some("LOAN-000003"), 0, 10).items.len(), 0);
+    }
+}
@@ -0,0 +1,138 @@
+// In-canister stand-in for the ICRC ledger, switched on at runtime with `set_ledger_test_mode`. Only
+// built with `--features mock-ledger` so a deployed canister can never disburse from it.
+use ic_cdk::caller;
+use ic_cdk_macros::{query, update};
+
+use crate::roles::is_admin;
+use crate::{
+    update_ledger_settings, usd_to_tokens, Account, CargoTraceError, TransferArgs, TransferError, TransferFromArgs,
+    TransferFromError, BALANCES, LEDGER_SETTINGS,
+};
+
+const TRANSFER_FEE: u64 = 100_000; // 0.0001 TCIP (with 8 decimals)
+
+pub(crate) fn ledger_test_mode() -> bool {
+    LEDGER_SETTINGS.with(|cell| cell.borrow().get().test_mode)
+}
+
+#[update(guard = "is_admin")]
+pub fn set_ledger_test_mode(enabled: bool) -> Result<(), CargoTraceError> {
+    update_ledger_settings(|settings| settings.test_mode = enabled);
+    ic_cdk::println!("Ledger test mode {}", if enabled { "enabled" } else { "disabled" });
+    Ok(())
+}
+
+#[query]
+pub fn get_ledger_test_mode() -> bool {
+    ledger_test_mode()
+}
+
+// Ensure balance initialization for testing
+#[update]
+pub fn init_user_balance(amount_usd_cents: u64) -> Result<(), CargoTraceError> {
+    if !ledger_test_mode() {
+        return Err(CargoTraceError::InvalidArgument("Test balances are only available in ledger test mode.".to_string()));
+    }
+    let token_amount = usd_to_tokens(amount_usd_cents);
+    BALANCES.with(|b| {
+        let mut balances = b.borrow_mut();
+        let caller = caller();
+        let current = balances.get(&caller).unwrap_or(0);
+        balances.insert(caller, current + token_amount);
+        ic_cdk::println!("Initialized balance for {}: {} tokens ({} USD cents)", caller, token_amount, amount_usd_cents);
+    });
+    Ok(())
+}
+
+#[update]
+pub fn request_test_tokens(amount: u64) -> Result<(), CargoTraceError> {
+    if !ledger_test_mode() {
+        return Err(CargoTraceError::InvalidArgument("Test tokens are only available in ledger test mode.".to_string()));
+    }
+    let token_amount = usd_to_tokens(amount);
+    BALANCES.with(|b| {
+        let mut balances = b.borrow_mut();
+        let current = balances.get(&ic_cdk::api::id()).unwrap_or(0);
+        balances.insert(ic_cdk::api::id(), current + token_amount);
+    });
+    ic_cdk::println!("Simulated funding: {} test tokens added to canister", token_amount);
+    Ok(())
+}
+
+// Mock ICRC-1 transfer function for testing
+pub(crate) fn mock_icrc1_transfer(args: TransferArgs) -> Result<candid::Nat, TransferError> {
+    ic_cdk::println!("icrc1_transfer: from canister to {}, amount: {}, fee: {:?}", 
+        args.to.owner, args.amount, args.fee);
+    let balance = BALANCES.with(|b| b.borrow().get(&ic_cdk::api::id()).unwrap_or(0));
+    let recipient_balance = BALANCES.with(|b| b.borrow().get(&args.to.owner).unwrap_or(0));
+
+    let amount: u64 = args.amount.0.try_into().map_err(|_| {
+        let err = TransferError::GenericError {
+            error_code: candid::Nat::from(1u64),
+            message: "Invalid amount".to_string(),
+        };
+        ic_cdk::println!("Transfer error: {:?}", err);
+        err
+    })?;
+    let fee: u64 = args.fee.unwrap_or(candid::Nat::from(TRANSFER_FEE)).0.try_into().map_err(|_| {
+        let err = TransferError::GenericError {
+            error_code: candid::Nat::from(1u64),
+            message: "Invalid fee".to_string(),
+        };
+        ic_cdk::println!("Transfer error: {:?}", err);
+        err
+    })?;
+
+    if balance < amount + fee {
+        let err = TransferError::InsufficientFunds {
+            balance: candid::Nat::from(balance),
+        };
+        ic_cdk::println!("Transfer error: {:?}", err);
+        return Err(err);
+    }
+
+    BALANCES.with(|b| {
+        let mut balances = b.borrow_mut();
+        let new_balance = balance - (amount + fee);
+        balances.insert(ic_cdk::api::id(), new_balance);
+        balances.insert(args.to.owner, recipient_balance + amount);
+    });
+
+    let block_height = candid::Nat::from(123456u64);
+    ic_cdk::println!("Transfer successful: {} tokens to {}, block: {}", amount, args.to.owner, block_height);
+    Ok(block_height)
+}
+
+// Mock ICRC-2 transfer_from function for testing
+pub(crate) fn mock_icrc2_transfer_from(args: TransferFromArgs) -> Result<candid::Nat, TransferFromError> {
+    ic_cdk::println!("icrc2_transfer_from: from {} to {}, amount: {}",
+        args.from.owner, args.to.owner, args.amount);
+    let invalid = |message: &str| TransferFromError::GenericError {
+        error_code: candid::Nat::from(1u64),
+        message: message.to_string(),
+    };
+    let amount: u64 = args.amount.0.try_into().map_err(|_| invalid("Invalid amount"))?;
+    let fee: u64 = args.fee.unwrap_or(candid::Nat::from(TRANSFER_FEE)).0.try_into().map_err(|_| invalid("Invalid fee"))?;
+
+    let balance = BALANCES.with(|b| b.borrow().get(&args.from.owner).unwrap_or(0));
+    if balance < amount + fee {
+        return Err(TransferFromError::InsufficientFunds {
+            balance: candid::Nat::from(balance),
+        });
+    }
+
+    BALANCES.with(|b| {
+        let mut balances = b.borrow_mut();
+        balances.insert(args.from.owner, balance - (amount + fee));
+        let recipient_balance = balances.get(&args.to.owner).unwrap_or(0);
+        balances.insert(args.to.owner, recipient_balance + amount);
+    });
+    Ok(candid::Nat::from(123456u64))
+}
+
+// Mock ICRC-1 balance_of function for testing
+pub(crate) fn mock_icrc1_balance_of(account: Account) -> u64 {
+    let balance = BALANCES.with(|b| b.borrow().get(&account.owner).unwrap_or(0));
+    ic_cdk::println!("icrc1_balance_of for principal {}: {} tokens", account.owner, balance);
+    balance
+}
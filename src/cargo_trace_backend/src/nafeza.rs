@@ -0,0 +1,265 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk::api::management_canister::http_request::{
+    CanisterHttpRequestArgument, HttpHeader, HttpMethod, TransformContext,
+};
+use ic_cdk_macros::{query, update};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{StableBTreeMap, StableCell};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use crate::outcalls::{http_outcall, OutcallFeature};
+use crate::roles::is_admin;
+use crate::versioned::{versioned_storable, Versioned};
+use crate::{Memory, MEMORY_MANAGER};
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+const NAFEZA_MAX_RESPONSE_BYTES: u64 = 20_000;
+// A failed lookup is retried only after this long, so an unknown or unreachable ACID costs at most
+// one outcall per window
+const FAILED_LOOKUP_TTL_SECS: u64 = 300;
+
+// ---- Configuration ----
+// When enabled, NAFEZA replaces the local ACID registry as the source of truth for validate_acid
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct NafezaConfig {
+    pub enabled: bool,
+    // ACIDs are looked up at {base_url}/acid/{acid_number}
+    pub base_url: String,
+    pub api_key: Option<String>,
+    pub cache_ttl_secs: u64,
+}
+
+impl Versioned for NafezaConfig {
+    const NAME: &'static str = "NafezaConfig";
+    const VERSION: u8 = 1;
+}
+versioned_storable!(NafezaConfig);
+
+// ---- Lookup results ----
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct DeclaredGoods {
+    pub hs_code: String,
+    pub description: String,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct NafezaAcidInfo {
+    pub acid_number: String,
+    pub status: String,
+    pub importer_name: String,
+    pub importer_tax_id: String,
+    pub goods: Vec<DeclaredGoods>,
+    pub fetched_at: u64,
+}
+
+impl NafezaAcidInfo {
+    // NAFEZA keeps rejected and cancelled ACIDs queryable, so only these statuses admit a shipment
+    pub fn is_active(&self) -> bool {
+        matches!(self.status.to_ascii_uppercase().as_str(), "APPROVED" | "ACTIVE")
+    }
+}
+
+impl Versioned for NafezaAcidInfo {
+    const NAME: &'static str = "NafezaAcidInfo";
+    const VERSION: u8 = 1;
+}
+versioned_storable!(NafezaAcidInfo);
+
+// ---- STATE ----
+thread_local! {
+    static NAFEZA_CONFIG: RefCell<StableCell<NafezaConfig, Memory>> = RefCell::new(
+        StableCell::init(MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(18))), NafezaConfig::default())
+    );
+    static NAFEZA_CACHE: RefCell<StableBTreeMap<String, NafezaAcidInfo, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(19))))
+    );
+    // ACID -> (failed at, error); only kept on the heap since an upgrade may as well retry
+    static FAILED_LOOKUPS: RefCell<BTreeMap<String, (u64, String)>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+pub fn nafeza_config() -> NafezaConfig {
+    NAFEZA_CONFIG.with(|c| c.borrow().get().clone())
+}
+
+// Only HTTPS endpoints are allowed, except plain HTTP to a local mock server
+fn check_base_url(url: &str) -> Result<(), String> {
+    let is_local = ["http://localhost", "http://127.0.0.1"]
+        .iter()
+        .any(|prefix| url.starts_with(prefix));
+    if url.starts_with("https://") || is_local {
+        Ok(())
+    } else {
+        Err("NAFEZA base URL must use https.".to_string())
+    }
+}
+
+// Parses a NAFEZA-style ACID lookup response:
+// {"acidNumber": "...", "status": "APPROVED", "importer": {"name": "...", "taxId": "..."},
+//  "goods": [{"hsCode": "...", "description": "..."}]}
+fn parse_acid_response(acid_number: &str, body: &[u8], fetched_at: u64) -> Result<NafezaAcidInfo, String> {
+    let json: serde_json::Value = serde_json::from_slice(body)
+        .map_err(|e| format!("Failed to parse NAFEZA response: {}", e))?;
+    let field = |value: &serde_json::Value, name: &str| -> Result<String, String> {
+        value[name]
+            .as_str()
+            .map(str::to_string)
+            .ok_or_else(|| format!("NAFEZA response is missing {}", name))
+    };
+
+    let returned = field(&json, "acidNumber")?;
+    if returned != acid_number {
+        return Err(format!("NAFEZA returned ACID {} for {}", returned, acid_number));
+    }
+    let goods = json["goods"]
+        .as_array()
+        .map(|goods| goods.as_slice())
+        .unwrap_or_default()
+        .iter()
+        .map(|item| {
+            Ok(DeclaredGoods {
+                hs_code: field(item, "hsCode")?,
+                description: field(item, "description")?,
+            })
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    Ok(NafezaAcidInfo {
+        acid_number: returned,
+        status: field(&json, "status")?,
+        importer_name: field(&json["importer"], "name")?,
+        importer_tax_id: field(&json["importer"], "taxId")?,
+        goods,
+        fetched_at,
+    })
+}
+
+async fn fetch_acid(config: &NafezaConfig, acid_number: &str) -> Result<NafezaAcidInfo, String> {
+    let mut headers = vec![HttpHeader {
+        name: "Accept".to_string(),
+        value: "application/json".to_string(),
+    }];
+    if let Some(api_key) = &config.api_key {
+        headers.push(HttpHeader {
+            name: "Authorization".to_string(),
+            value: format!("Bearer {}", api_key),
+        });
+    }
+    let request = CanisterHttpRequestArgument {
+        url: format!("{}/acid/{}", config.base_url.trim_end_matches('/'), acid_number),
+        method: HttpMethod::GET,
+        body: None,
+        max_response_bytes: Some(NAFEZA_MAX_RESPONSE_BYTES),
+        transform: Some(TransformContext::from_name("transform_response".to_string(), vec![])),
+        headers,
+    };
+
+    match http_outcall(OutcallFeature::Nafeza, request).await {
+        Ok(response) if response.status == 200u16 => {
+            parse_acid_response(acid_number, &response.body, ic_cdk::api::time())
+        }
+        Ok(response) => Err(format!("NAFEZA returned HTTP {}", response.status)),
+        Err(e) => Err(format!("NAFEZA request failed: {}", e)),
+    }
+}
+
+fn recent_failure(acid_number: &str, now: u64) -> Option<String> {
+    let ttl = FAILED_LOOKUP_TTL_SECS * NANOS_PER_SECOND;
+    FAILED_LOOKUPS.with(|f| {
+        f.borrow()
+            .get(acid_number)
+            .filter(|(failed_at, _)| now.saturating_sub(*failed_at) < ttl)
+            .map(|(_, error)| error.clone())
+    })
+}
+
+fn remember_failure(acid_number: &str, error: &str, now: u64) {
+    let ttl = FAILED_LOOKUP_TTL_SECS * NANOS_PER_SECOND;
+    FAILED_LOOKUPS.with(|f| {
+        let mut failed = f.borrow_mut();
+        failed.retain(|_, (failed_at, _)| now.saturating_sub(*failed_at) < ttl);
+        failed.insert(acid_number.to_string(), (now, error.to_string()));
+    });
+}
+
+// Looks up an ACID, serving from the cache while the last result is younger than the TTL, and
+// repeating the last error while a failed lookup is younger than FAILED_LOOKUP_TTL_SECS
+pub async fn lookup_acid(acid_number: &str) -> Result<NafezaAcidInfo, String> {
+    let config = nafeza_config();
+    let now = ic_cdk::api::time();
+    let ttl = config.cache_ttl_secs.saturating_mul(NANOS_PER_SECOND);
+    let cached = NAFEZA_CACHE.with(|c| c.borrow().get(&acid_number.to_string()));
+    if let Some(info) = cached.filter(|info| now.saturating_sub(info.fetched_at) < ttl) {
+        return Ok(info);
+    }
+    if let Some(error) = recent_failure(acid_number, now) {
+        return Err(error);
+    }
+
+    match fetch_acid(&config, acid_number).await {
+        Ok(info) => {
+            NAFEZA_CACHE.with(|c| c.borrow_mut().insert(acid_number.to_string(), info.clone()));
+            FAILED_LOOKUPS.with(|f| f.borrow_mut().remove(acid_number));
+            Ok(info)
+        }
+        Err(error) => {
+            remember_failure(acid_number, &error, now);
+            Err(error)
+        }
+    }
+}
+
+// ---- API ----
+#[update(guard = "is_admin")]
+pub fn set_nafeza_config(config: NafezaConfig) -> Result<(), String> {
+    if config.enabled {
+        check_base_url(&config.base_url)?;
+    }
+    NAFEZA_CONFIG.with(|c| c.borrow_mut().set(config));
+    // Cached results may come from a different endpoint
+    NAFEZA_CACHE.with(|c| c.borrow_mut().clear_new());
+    FAILED_LOOKUPS.with(|f| f.borrow_mut().clear());
+    Ok(())
+}
+
+#[query(guard = "is_admin")]
+pub fn get_nafeza_config() -> NafezaConfig {
+    nafeza_config()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_acid_lookup_response() {
+        let body = br#"{
+            "acidNumber": "123456789",
+            "status": "Approved",
+            "importer": {"name": "Nile Imports", "taxId": "100-200-300"},
+            "goods": [{"hsCode": "851712", "description": "Mobile phones", "quantity": 40}]
+        }"#;
+        let info = parse_acid_response("123456789", body, 5).unwrap();
+        assert!(info.is_active());
+        assert_eq!(info.importer_tax_id, "100-200-300");
+        assert_eq!(info.goods.len(), 1);
+        assert_eq!(info.goods[0].hs_code, "851712");
+
+        assert!(parse_acid_response("987654321", body, 5).is_err());
+        assert!(parse_acid_response("123456789", br#"{"acidNumber": "123456789"}"#, 5).is_err());
+    }
+
+    #[test]
+    fn failed_lookups_are_remembered_until_they_expire() {
+        let ttl = FAILED_LOOKUP_TTL_SECS * NANOS_PER_SECOND;
+        remember_failure("999999999", "NAFEZA returned HTTP 404", 10);
+        assert_eq!(recent_failure("999999999", 10 + ttl - 1).as_deref(), Some("NAFEZA returned HTTP 404"));
+        assert_eq!(recent_failure("999999999", 10 + ttl), None);
+        assert_eq!(recent_failure("123456789", 10), None);
+
+        // Expired failures are dropped as new ones come in
+        remember_failure("123456789", "NAFEZA request failed", 10 + ttl);
+        FAILED_LOOKUPS.with(|f| assert_eq!(f.borrow().keys().collect::<Vec<_>>(), ["123456789"]));
+    }
+}
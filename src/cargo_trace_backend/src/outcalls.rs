@@ -0,0 +1,301 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk::api::call::msg_cycles_refunded128;
+use ic_cdk::api::management_canister::http_request::{http_request, CanisterHttpRequestArgument, HttpResponse};
+use ic_cdk_macros::{query, update};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableCell;
+use std::cell::RefCell;
+
+use crate::roles::is_admin;
+use crate::versioned::{versioned_storable, Versioned};
+use crate::{Memory, MEMORY_MANAGER};
+
+const NANOS_PER_DAY: u64 = 86_400_000_000_000;
+// What the system assumes when a request sets no response limit
+const DEFAULT_MAX_RESPONSE_BYTES: u64 = 2_000_000;
+// The default poller's worst case, a block and a 2 MB log outcall every minute on a 13-node subnet,
+// costs about 30T cycles a day; the rest is headroom for metadata and NAFEZA lookups
+const DEFAULT_DAILY_BUDGET_CYCLES: u128 = 40_000_000_000_000;
+// Held back for the poller so lookups, which callers can trigger, cannot starve it
+const DEFAULT_CHAIN_RESERVE_CYCLES: u128 = 30_000_000_000_000;
+
+// ---- Configuration ----
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct OutcallConfig {
+    // Outcalls stop for the rest of the UTC day once this much has been spent; None means no limit
+    pub daily_budget_cycles: Option<u128>,
+    // Part of the daily budget only the Ethereum poller and EVM RPC calls may spend; metadata and
+    // NAFEZA lookups share the rest
+    pub chain_reserve_cycles: u128,
+    // Nodes in the canister's subnet, which HTTP outcall prices scale with
+    pub subnet_size: u64,
+}
+
+impl Default for OutcallConfig {
+    fn default() -> Self {
+        OutcallConfig {
+            daily_budget_cycles: Some(DEFAULT_DAILY_BUDGET_CYCLES),
+            chain_reserve_cycles: DEFAULT_CHAIN_RESERVE_CYCLES,
+            subnet_size: 13,
+        }
+    }
+}
+
+impl Versioned for OutcallConfig {
+    const NAME: &'static str = "OutcallConfig";
+    const VERSION: u8 = 2;
+
+    // Version 1 had no reserve and gets the default one
+    fn migrate(version: u8, bytes: &[u8]) -> Result<Self, String> {
+        if version != 1 {
+            return Err(format!("no migration from OutcallConfig version {}", version));
+        }
+        let v1: OutcallConfigV1 = candid::decode_one(bytes).map_err(|e| e.to_string())?;
+        Ok(OutcallConfig {
+            daily_budget_cycles: v1.daily_budget_cycles,
+            chain_reserve_cycles: DEFAULT_CHAIN_RESERVE_CYCLES,
+            subnet_size: v1.subnet_size,
+        })
+    }
+}
+versioned_storable!(OutcallConfig);
+
+#[derive(CandidType, Deserialize)]
+struct OutcallConfigV1 {
+    daily_budget_cycles: Option<u128>,
+    subnet_size: u64,
+}
+
+// ---- Spend ----
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum OutcallFeature {
+    EthPoller,
+    EvmRpc,
+    Nafeza,
+    TokenMetadata,
+}
+
+impl OutcallFeature {
+    // Features that keep the chain view current and may spend the reserved part of the budget
+    fn uses_chain_reserve(self) -> bool {
+        matches!(self, OutcallFeature::EthPoller | OutcallFeature::EvmRpc)
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct FeatureSpend {
+    pub feature: OutcallFeature,
+    pub calls: u64,
+    pub failures: u64,
+    // Attached cycles minus refunds, since install
+    pub cycles_spent: u128,
+    pub cycles_spent_today: u128,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct OutcallStats {
+    // Days since the Unix epoch, UTC
+    pub day: u64,
+    pub cycles_spent_today: u128,
+    pub daily_budget_cycles: Option<u128>,
+    pub features: Vec<FeatureSpend>,
+}
+
+impl Versioned for OutcallStats {
+    const NAME: &'static str = "OutcallStats";
+    const VERSION: u8 = 1;
+}
+versioned_storable!(OutcallStats);
+
+impl OutcallStats {
+    fn roll_over(&mut self, day: u64) {
+        if self.day != day {
+            self.day = day;
+            self.cycles_spent_today = 0;
+            for feature in &mut self.features {
+                feature.cycles_spent_today = 0;
+            }
+        }
+    }
+
+    fn feature(&mut self, feature: OutcallFeature) -> &mut FeatureSpend {
+        let index = match self.features.iter().position(|f| f.feature == feature) {
+            Some(index) => index,
+            None => {
+                self.features.push(FeatureSpend { feature, calls: 0, failures: 0, cycles_spent: 0, cycles_spent_today: 0 });
+                self.features.len() - 1
+            }
+        };
+        &mut self.features[index]
+    }
+
+    // Spent today by the features outside the chain reserve
+    fn lookups_spent_today(&self) -> u128 {
+        self.features.iter().filter(|f| !f.feature.uses_chain_reserve()).map(|f| f.cycles_spent_today).sum()
+    }
+}
+
+// ---- STATE ----
+thread_local! {
+    static OUTCALL_CONFIG: RefCell<StableCell<OutcallConfig, Memory>> = RefCell::new(
+        StableCell::init(MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(34))), OutcallConfig::default())
+    );
+    static OUTCALL_STATS: RefCell<StableCell<OutcallStats, Memory>> = RefCell::new(
+        StableCell::init(MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(35))), OutcallStats::default())
+    );
+}
+
+fn outcall_config() -> OutcallConfig {
+    OUTCALL_CONFIG.with(|c| c.borrow().get().clone())
+}
+
+fn update_stats<R>(f: impl FnOnce(&mut OutcallStats) -> R) -> R {
+    OUTCALL_STATS.with(|cell| {
+        let mut cell = cell.borrow_mut();
+        let mut stats = cell.get().clone();
+        stats.roll_over(ic_cdk::api::time() / NANOS_PER_DAY);
+        let result = f(&mut stats);
+        cell.set(stats);
+        result
+    })
+}
+
+// Documented HTTP outcall price for an n-node subnet: a base fee plus a fee per request byte and
+// per byte of the response limit
+pub(crate) fn outcall_price(subnet_size: u64, request_bytes: u128, response_bytes: u128) -> u128 {
+    let n = subnet_size as u128;
+    (3_000_000 + 60_000 * n) * n + 400 * n * request_bytes + 800 * n * response_bytes
+}
+
+pub(crate) fn http_request_cost(request: &CanisterHttpRequestArgument, subnet_size: u64) -> u128 {
+    let headers: usize = request.headers.iter().map(|h| h.name.len() + h.value.len()).sum();
+    let transform = request.transform.as_ref().map_or(0, |t| t.function.0.method.len() + t.context.len());
+    let request_bytes = request.url.len() + headers + request.body.as_ref().map_or(0, Vec::len) + transform;
+    let response_bytes = request.max_response_bytes.unwrap_or(DEFAULT_MAX_RESPONSE_BYTES);
+    outcall_price(subnet_size, request_bytes as u128, response_bytes as u128)
+}
+
+// Reserves `cycles` against today's budget before a call is made. Lookups may only spend what the
+// chain reserve leaves of the budget, however little the poller has used.
+pub(crate) fn charge_outcall(feature: OutcallFeature, cycles: u128) -> Result<(), String> {
+    let config = outcall_config();
+    update_stats(|stats| {
+        if let Some(budget) = config.daily_budget_cycles {
+            if stats.cycles_spent_today.saturating_add(cycles) > budget {
+                return Err(format!("The daily outcall budget of {} cycles is exhausted.", budget));
+            }
+            let lookup_budget = budget.saturating_sub(config.chain_reserve_cycles);
+            if !feature.uses_chain_reserve() && stats.lookups_spent_today().saturating_add(cycles) > lookup_budget {
+                return Err(format!("The daily outcall budget of {} cycles for lookups is exhausted.", lookup_budget));
+            }
+        }
+        stats.cycles_spent_today += cycles;
+        let spend = stats.feature(feature);
+        spend.calls += 1;
+        spend.cycles_spent += cycles;
+        spend.cycles_spent_today += cycles;
+        Ok(())
+    })
+}
+
+// Gives back what the callee refunded and counts failed calls
+pub(crate) fn settle_outcall(feature: OutcallFeature, refunded: u128, failed: bool) {
+    update_stats(|stats| {
+        stats.cycles_spent_today = stats.cycles_spent_today.saturating_sub(refunded);
+        let spend = stats.feature(feature);
+        spend.cycles_spent = spend.cycles_spent.saturating_sub(refunded);
+        spend.cycles_spent_today = spend.cycles_spent_today.saturating_sub(refunded);
+        if failed {
+            spend.failures += 1;
+        }
+    });
+}
+
+// http_request with the cycles it needs, charged to `feature`
+pub(crate) async fn http_outcall(
+    feature: OutcallFeature,
+    request: CanisterHttpRequestArgument,
+) -> Result<HttpResponse, String> {
+    let cycles = http_request_cost(&request, outcall_config().subnet_size);
+    charge_outcall(feature, cycles)?;
+    let result = http_request(request, cycles).await;
+    settle_outcall(feature, msg_cycles_refunded128(), result.is_err());
+    result.map(|(response,)| response).map_err(|(code, msg)| format!("{:?} - {}", code, msg))
+}
+
+// ---- API ----
+#[update(guard = "is_admin")]
+pub fn set_outcall_config(config: OutcallConfig) -> Result<(), String> {
+    if config.subnet_size == 0 {
+        return Err("Subnet size must be positive.".to_string());
+    }
+    if config.daily_budget_cycles.is_some_and(|budget| config.chain_reserve_cycles > budget) {
+        return Err("The chain reserve cannot exceed the daily budget.".to_string());
+    }
+    OUTCALL_CONFIG.with(|c| c.borrow_mut().set(config));
+    Ok(())
+}
+
+#[query]
+pub fn get_outcall_config() -> OutcallConfig {
+    outcall_config()
+}
+
+// Cycles spent on HTTP outcalls and EVM RPC calls, per feature
+#[query]
+pub fn get_outcall_stats() -> OutcallStats {
+    let mut stats = OUTCALL_STATS.with(|s| s.borrow().get().clone());
+    stats.roll_over(ic_cdk::api::time() / NANOS_PER_DAY);
+    stats.daily_budget_cycles = outcall_config().daily_budget_cycles;
+    stats
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_cdk::api::management_canister::http_request::{HttpHeader, HttpMethod};
+
+    #[test]
+    fn prices_requests_by_size_and_response_limit() {
+        let request = CanisterHttpRequestArgument {
+            url: "https://example.com/x".to_string(),
+            method: HttpMethod::GET,
+            body: None,
+            max_response_bytes: Some(1_000),
+            transform: None,
+            headers: vec![HttpHeader { name: "Accept".to_string(), value: "json".to_string() }],
+        };
+        // 21 URL bytes and 10 header bytes on a 13-node subnet
+        assert_eq!(http_request_cost(&request, 13), 49_140_000 + 5_200 * 31 + 10_400 * 1_000);
+        let unbounded = CanisterHttpRequestArgument { max_response_bytes: None, ..request.clone() };
+        assert_eq!(http_request_cost(&unbounded, 13) - http_request_cost(&request, 13), 10_400 * 1_999_000);
+        assert!(http_request_cost(&request, 34) > http_request_cost(&request, 13));
+    }
+
+    #[test]
+    fn spend_resets_daily_but_totals_are_kept() {
+        let mut stats = OutcallStats::default();
+        stats.roll_over(1);
+        stats.cycles_spent_today = 500;
+        let spend = stats.feature(OutcallFeature::Nafeza);
+        spend.cycles_spent = 500;
+        spend.cycles_spent_today = 500;
+
+        stats.roll_over(1);
+        assert_eq!(stats.cycles_spent_today, 500);
+        stats.roll_over(2);
+        assert_eq!(stats.cycles_spent_today, 0);
+        assert_eq!(stats.features.len(), 1);
+        assert_eq!(stats.feature(OutcallFeature::Nafeza).cycles_spent, 500);
+        assert_eq!(stats.feature(OutcallFeature::Nafeza).cycles_spent_today, 0);
+    }
+
+    #[test]
+    fn only_lookups_count_against_the_lookup_share() {
+        let mut stats = OutcallStats::default();
+        stats.feature(OutcallFeature::EthPoller).cycles_spent_today = 700;
+        stats.feature(OutcallFeature::TokenMetadata).cycles_spent_today = 200;
+        stats.feature(OutcallFeature::Nafeza).cycles_spent_today = 50;
+        assert_eq!(stats.lookups_spent_today(), 250);
+    }
+}
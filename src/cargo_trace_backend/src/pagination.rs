@@ -0,0 +1,196 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::Storable;
+
+use crate::indexed::{term, Indexed, IndexedMap};
+
+pub(crate) const DEFAULT_PAGE_SIZE: usize = 100;
+pub(crate) const MAX_PAGE_SIZE: usize = 500;
+// Bounds the work one query does when few candidates match the filter
+pub(crate) const MAX_SCANNED: usize = 10_000;
+
+// ---- Queries ----
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    // Pass back as `cursor` to fetch the next page; None when there are no more matches
+    pub next_cursor: Option<String>,
+}
+
+// All set fields must match. A record without the filtered field, such as a customs verification
+// filtered by owner, never matches.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct ListFilter {
+    // Status variant name, e.g. "Pending"; compared case-insensitively
+    pub status: Option<String>,
+    pub owner: Option<Principal>,
+    // Inclusive created_at range, in nanoseconds
+    pub created_from: Option<u64>,
+    pub created_to: Option<u64>,
+    pub acid_number: Option<String>,
+}
+
+// The fields of a record a ListFilter looks at
+pub(crate) trait Listed {
+    // Fields the record's IndexedMap keeps an index for
+    const INDEXED: &'static [&'static str];
+
+    fn status(&self) -> Option<String>;
+    fn owner(&self) -> Option<String>;
+    fn created_at(&self) -> u64;
+    fn acid_number(&self) -> Option<&str>;
+}
+
+impl ListFilter {
+    pub(crate) fn matches<T: Listed>(&self, record: &T) -> bool {
+        let same = |wanted: Option<&str>, actual: Option<&str>| {
+            wanted.is_none_or(|w| actual.is_some_and(|a| a.eq_ignore_ascii_case(w)))
+        };
+        let owner = self.owner.map(|owner| owner.to_text());
+        same(self.status.as_deref(), record.status().as_deref())
+            && same(owner.as_deref(), record.owner().as_deref())
+            && same(self.acid_number.as_deref(), record.acid_number())
+            && self.created_from.is_none_or(|from| record.created_at() >= from)
+            && self.created_to.is_none_or(|to| record.created_at() <= to)
+    }
+
+    // The most selective index term the filter can use for `T`
+    pub(crate) fn index_term<T: Listed>(&self) -> Option<String> {
+        let indexed = |field: &str| T::INDEXED.contains(&field);
+        let acid = self.acid_number.as_ref().filter(|_| indexed("acid")).map(|acid| term("acid", acid));
+        acid.or_else(|| self.owner.filter(|_| indexed("owner")).map(|owner| term("owner", &owner.to_text())))
+            .or_else(|| self.status.as_ref().filter(|_| indexed("status")).map(|status| term("status", status)))
+    }
+}
+
+pub(crate) fn page_limit(limit: Option<u32>) -> usize {
+    limit.map_or(DEFAULT_PAGE_SIZE, |l| l as usize).clamp(1, MAX_PAGE_SIZE)
+}
+
+// Keeps the candidates `matches` accepts, read in key order, until `limit` are found or MAX_SCANNED
+// have been read. Candidates are pulled one at a time, so records past the stopping point are never
+// decoded. Returns the matches with the cursor to resume after: the last key read.
+pub(crate) fn collect_page<K, T>(
+    candidates: impl IntoIterator<Item = (K, T)>,
+    limit: usize,
+    matches: impl Fn(&T) -> bool,
+) -> (Vec<T>, Option<K>) {
+    let mut items = Vec::new();
+    let mut last_scanned = None;
+    let mut scanned = 0;
+    for (key, record) in candidates.into_iter().take(MAX_SCANNED) {
+        scanned += 1;
+        last_scanned = Some(key);
+        if matches(&record) {
+            items.push(record);
+            if items.len() == limit {
+                break;
+            }
+        }
+    }
+    // A short page can still have a cursor when the scan budget ran out before the matches did
+    let next_cursor = if items.len() == limit || scanned == MAX_SCANNED { last_scanned } else { None };
+    (items, next_cursor)
+}
+
+pub(crate) fn page<T: Listed>(filter: &ListFilter, candidates: impl IntoIterator<Item = (String, T)>, limit: usize) -> Page<T> {
+    let (items, next_cursor) = collect_page(candidates, limit, |record| filter.matches(record));
+    Page { items, next_cursor }
+}
+
+// One page of an IndexedMap, read through the filter's index when it has one
+pub(crate) fn list<T: Storable + Indexed + Listed>(
+    map: &IndexedMap<T>,
+    filter: &ListFilter,
+    cursor: Option<String>,
+    limit: Option<u32>,
+) -> Page<T> {
+    let term = filter.index_term::<T>();
+    page(filter, map.scan(term.as_deref(), cursor.as_ref()), page_limit(limit))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Record {
+        status: &'static str,
+        owner: Option<Principal>,
+        created_at: u64,
+    }
+
+    impl Listed for Record {
+        const INDEXED: &'static [&'static str] = &["status"];
+
+        fn status(&self) -> Option<String> {
+            Some(self.status.to_string())
+        }
+        fn owner(&self) -> Option<String> {
+            self.owner.map(|owner| owner.to_text())
+        }
+        fn created_at(&self) -> u64 {
+            self.created_at
+        }
+        fn acid_number(&self) -> Option<&str> {
+            None
+        }
+    }
+
+    fn records(n: u64) -> Vec<(String, Record)> {
+        (0..n)
+            .map(|i| {
+                let status = if i % 2 == 0 { "Pending" } else { "Verified" };
+                (format!("R-{:03}", i), Record { status, owner: None, created_at: i })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn filters_match_fields_and_pick_an_index() {
+        let filter = ListFilter { status: Some("pending".to_string()), created_from: Some(2), ..Default::default() };
+        let record = Record { status: "Pending", owner: None, created_at: 2 };
+        assert!(filter.matches(&record));
+        assert!(!ListFilter { created_to: Some(1), ..filter.clone() }.matches(&record));
+        assert!(!ListFilter { owner: Some(Principal::anonymous()), ..filter.clone() }.matches(&record));
+        assert!(!ListFilter { acid_number: Some("1".to_string()), ..Default::default() }.matches(&record));
+
+        assert_eq!(filter.index_term::<Record>(), Some("status:pending".to_string()));
+        let by_owner = ListFilter { owner: Some(Principal::anonymous()), ..Default::default() };
+        assert_eq!(by_owner.index_term::<Record>(), None);
+    }
+
+    #[test]
+    fn pages_stop_at_the_limit_and_hand_back_a_cursor() {
+        let filter = ListFilter { status: Some("Verified".to_string()), ..Default::default() };
+        let first = page(&filter, records(10), 3);
+        assert_eq!(first.items.iter().map(|r| r.created_at).collect::<Vec<_>>(), [1, 3, 5]);
+        assert_eq!(first.next_cursor.as_deref(), Some("R-005"));
+
+        let rest: Vec<_> = records(10).into_iter().filter(|(key, _)| key.as_str() > "R-005").collect();
+        let last = page(&filter, rest, 3);
+        assert_eq!(last.items.iter().map(|r| r.created_at).collect::<Vec<_>>(), [7, 9]);
+        assert_eq!(last.next_cursor, None);
+    }
+
+    #[test]
+    fn a_spent_scan_budget_hands_back_a_cursor_without_matches() {
+        let candidates: Vec<(u64, u64)> = (0..MAX_SCANNED as u64).map(|i| (i, i)).collect();
+        let (items, next_cursor) = collect_page(candidates, 3, |_| false);
+        assert!(items.is_empty());
+        assert_eq!(next_cursor, Some(MAX_SCANNED as u64 - 1));
+    }
+
+    #[test]
+    fn scans_read_no_further_than_the_page_needs() {
+        let read = std::cell::Cell::new(0);
+        let candidates = (0..u64::MAX).inspect(|_| read.set(read.get() + 1)).map(|i| (i, i));
+        let (items, next_cursor) = collect_page(candidates, 3, |i| i % 2 == 1);
+        assert_eq!(items, [1, 3, 5]);
+        assert_eq!(next_cursor, Some(5));
+        assert_eq!(read.get(), 6);
+
+        read.set(0);
+        let candidates = (0..u64::MAX).inspect(|_| read.set(read.get() + 1)).map(|i| (i, i));
+        collect_page(candidates, 3, |_| false);
+        assert_eq!(read.get(), MAX_SCANNED);
+    }
+}
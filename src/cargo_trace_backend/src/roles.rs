@@ -0,0 +1,172 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::caller;
+use ic_cdk_macros::{query, update};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+use crate::{Memory, MEMORY_MANAGER};
+
+// ---- Roles ----
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    Admin,
+    CustomsOfficer,
+    LoanOfficer,
+    Watcher,
+    Borrower,
+}
+
+impl Role {
+    const ALL: [Role; 5] = [
+        Role::Admin,
+        Role::CustomsOfficer,
+        Role::LoanOfficer,
+        Role::Watcher,
+        Role::Borrower,
+    ];
+
+    // Each role is one bit in the mask stored per principal
+    fn bit(self) -> u8 {
+        match self {
+            Role::Admin => 1 << 0,
+            Role::CustomsOfficer => 1 << 1,
+            Role::LoanOfficer => 1 << 2,
+            Role::Watcher => 1 << 3,
+            Role::Borrower => 1 << 4,
+        }
+    }
+}
+
+// ---- STATE ----
+thread_local! {
+    static ROLES: RefCell<StableBTreeMap<Principal, u8, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(7))))
+    );
+}
+
+pub fn has_role(principal: &Principal, role: Role) -> bool {
+    ROLES.with(|r| r.borrow().get(principal).unwrap_or(0) & role.bit() != 0)
+}
+
+fn roles_of(principal: &Principal) -> Vec<Role> {
+    let mask = ROLES.with(|r| r.borrow().get(principal).unwrap_or(0));
+    Role::ALL.into_iter().filter(|role| mask & role.bit() != 0).collect()
+}
+
+fn set_role(principal: Principal, role: Role, granted: bool) {
+    ROLES.with(|r| {
+        let mut roles = r.borrow_mut();
+        let mask = roles.get(&principal).unwrap_or(0);
+        let mask = if granted { mask | role.bit() } else { mask & !role.bit() };
+        if mask == 0 {
+            roles.remove(&principal);
+        } else {
+            roles.insert(principal, mask);
+        }
+    });
+}
+
+fn admin_count() -> usize {
+    ROLES.with(|r| {
+        r.borrow()
+            .iter()
+            .filter(|entry| entry.value() & Role::Admin.bit() != 0)
+            .count()
+    })
+}
+
+// Admins implicitly hold every role
+fn require_role(role: Role) -> Result<(), String> {
+    let caller = caller();
+    if has_role(&caller, Role::Admin) || has_role(&caller, role) {
+        Ok(())
+    } else {
+        Err(format!("Caller {} does not have the {:?} role.", caller, role))
+    }
+}
+
+// ---- Guards ----
+pub fn is_admin() -> Result<(), String> {
+    require_role(Role::Admin)
+}
+
+pub fn is_customs_officer() -> Result<(), String> {
+    require_role(Role::CustomsOfficer)
+}
+
+pub fn is_loan_officer() -> Result<(), String> {
+    require_role(Role::LoanOfficer)
+}
+
+pub fn is_watcher() -> Result<(), String> {
+    require_role(Role::Watcher)
+}
+
+pub fn is_borrower() -> Result<(), String> {
+    require_role(Role::Borrower)
+}
+
+// Any caller other than the anonymous principal
+pub fn is_signed_in() -> Result<(), String> {
+    if caller() == Principal::anonymous() {
+        Err("Sign in to call this method.".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+// ---- API ----
+// Controllers use this once after install to appoint the first admin
+#[update]
+pub fn bootstrap_admin(principal: Principal) -> Result<(), String> {
+    if !ic_cdk::api::is_controller(&caller()) {
+        return Err("Only a canister controller can bootstrap an admin.".to_string());
+    }
+    if principal == Principal::anonymous() {
+        return Err("Cannot grant roles to the anonymous principal.".to_string());
+    }
+    set_role(principal, Role::Admin, true);
+    ic_cdk::println!("Bootstrapped admin {}", principal);
+    Ok(())
+}
+
+#[update(guard = "is_admin")]
+pub fn grant_role(principal: Principal, role: Role) -> Result<(), String> {
+    if principal == Principal::anonymous() {
+        return Err("Cannot grant roles to the anonymous principal.".to_string());
+    }
+    set_role(principal, role, true);
+    ic_cdk::println!("{} granted {:?} to {}", caller(), role, principal);
+    Ok(())
+}
+
+#[update(guard = "is_admin")]
+pub fn revoke_role(principal: Principal, role: Role) -> Result<(), String> {
+    if role == Role::Admin && has_role(&principal, Role::Admin) && admin_count() == 1 {
+        return Err("Cannot revoke the last admin.".to_string());
+    }
+    set_role(principal, role, false);
+    ic_cdk::println!("{} revoked {:?} from {}", caller(), role, principal);
+    Ok(())
+}
+
+#[query]
+pub fn get_roles(principal: Principal) -> Vec<Role> {
+    roles_of(&principal)
+}
+
+#[query]
+pub fn get_my_roles() -> Vec<Role> {
+    roles_of(&caller())
+}
+
+#[query(guard = "is_admin")]
+pub fn list_role_assignments() -> Vec<(Principal, Vec<Role>)> {
+    ROLES.with(|r| {
+        r.borrow()
+            .iter()
+            .map(|entry| (*entry.key(), roles_of(entry.key())))
+            .collect()
+    })
+}
@@ -0,0 +1,476 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk::api::management_canister::http_request::{
+    CanisterHttpRequestArgument, HttpHeader, HttpMethod, TransformContext,
+};
+use ic_cdk_macros::{query, update};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_cdk_timers::TimerId;
+use ic_stable_structures::{StableBTreeMap, StableCell};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::time::Duration;
+
+use crate::abi::encode_hex;
+use crate::cargox_watcher::{fetch_token_metadata, parse_metadata};
+use crate::outcalls::{http_outcall, OutcallFeature};
+use crate::roles::is_admin;
+use crate::versioned::{versioned_storable, Versioned};
+use crate::watcher_config::{TokenRef, POLLED_NETWORK};
+use crate::{DocumentMetadata, InFlight, Memory, MEMORY_MANAGER};
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+// Bounds the outcalls a single refresher run can make
+const MAX_REFRESHES_PER_RUN: usize = 10;
+// Multicodec codes for CIDs whose digest is the plain sha2-256 of the content
+const RAW_CODEC: u64 = 0x55;
+const SHA2_256: u64 = 0x12;
+const BASE32_ALPHABET: &[u8] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+// ---- Configuration ----
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct MetadataConfig {
+    // Tried in order: ipfs://<cid>/<path> is read from {gateway}/<cid>/<path>
+    pub ipfs_gateways: Vec<String>,
+    // ar://<tx id> is read from {gateway}/<tx id>
+    pub arweave_gateways: Vec<String>,
+    pub max_response_bytes: u64,
+    // How long resolved metadata is served from the cache (default one day)
+    pub cache_ttl_secs: Option<u64>,
+    // How long a failed lookup is remembered before it is retried (default one hour)
+    pub failure_ttl_secs: Option<u64>,
+    // How often the refresher fetches missing and expired entries (default ten minutes)
+    pub refresh_interval_secs: Option<u64>,
+}
+
+impl Default for MetadataConfig {
+    fn default() -> Self {
+        MetadataConfig {
+            ipfs_gateways: vec!["https://ipfs.io/ipfs".to_string(), "https://dweb.link/ipfs".to_string()],
+            arweave_gateways: vec!["https://arweave.net".to_string()],
+            max_response_bytes: 500_000,
+            cache_ttl_secs: None,
+            failure_ttl_secs: None,
+            refresh_interval_secs: None,
+        }
+    }
+}
+
+impl MetadataConfig {
+    fn ttl_secs(&self, resolution: &MetadataResolution) -> u64 {
+        match resolution {
+            MetadataResolution::Resolved { .. } => self.cache_ttl_secs.unwrap_or(86_400),
+            MetadataResolution::Unresolved { .. } => self.failure_ttl_secs.unwrap_or(3_600),
+        }
+    }
+
+    fn refresh_interval(&self) -> Duration {
+        Duration::from_secs(self.refresh_interval_secs.unwrap_or(600))
+    }
+}
+
+impl Versioned for MetadataConfig {
+    const NAME: &'static str = "MetadataConfig";
+    const VERSION: u8 = 1;
+}
+versioned_storable!(MetadataConfig);
+
+// ---- Resolution ----
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub enum MetadataResolution {
+    // Read from the token URI. `cid_verified` is set when the content was checked against its CID.
+    Resolved {
+        token_uri: String,
+        url: String,
+        content_sha256: String,
+        cid_verified: bool,
+    },
+    // Nothing could be read; the metadata fields are left empty
+    Unresolved { reason: String },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CachedMetadata {
+    pub contract: String,
+    pub token_id: String,
+    pub metadata: DocumentMetadata,
+    pub fetched_at: u64,
+    pub expires_at: u64,
+}
+
+impl Versioned for CachedMetadata {
+    const NAME: &'static str = "CachedMetadata";
+    const VERSION: u8 = 1;
+}
+versioned_storable!(CachedMetadata);
+
+#[derive(Debug, PartialEq)]
+enum ContentLocation {
+    Ipfs { cid: String, path: String },
+    Arweave { tx_id: String },
+    Https(String),
+}
+
+// ---- STATE ----
+thread_local! {
+    static METADATA_CONFIG: RefCell<StableCell<MetadataConfig, Memory>> = RefCell::new(
+        StableCell::init(MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(30))), MetadataConfig::default())
+    );
+    // "<contract>|<token id>" -> last lookup, successful or not. Only tokens on the polled network
+    // are looked up, so the network is implied.
+    static METADATA_CACHE: RefCell<StableBTreeMap<String, CachedMetadata, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(31))))
+    );
+    static METADATA_REFRESHER: RefCell<Option<TimerId>> = const { RefCell::new(None) };
+}
+
+fn metadata_config() -> MetadataConfig {
+    METADATA_CONFIG.with(|c| c.borrow().get().clone())
+}
+
+pub fn arm_metadata_refresher() {
+    let timer = ic_cdk_timers::set_timer_interval(metadata_config().refresh_interval(), || {
+        ic_cdk::spawn(refresh_stale_metadata())
+    });
+    if let Some(previous) = METADATA_REFRESHER.with(|t| t.borrow_mut().replace(timer)) {
+        ic_cdk_timers::clear_timer(previous);
+    }
+}
+
+fn check_gateway(url: &str) -> Result<(), String> {
+    let is_local = ["http://localhost", "http://127.0.0.1"]
+        .iter()
+        .any(|prefix| url.starts_with(prefix));
+    if url.starts_with("https://") || is_local {
+        Ok(())
+    } else {
+        Err(format!("Gateway {} must use https.", url))
+    }
+}
+
+fn locate(token_uri: &str) -> Result<ContentLocation, String> {
+    if let Some(rest) = token_uri.strip_prefix("ipfs://") {
+        // Some contracts repeat the namespace: ipfs://ipfs/<cid>
+        let rest = rest.strip_prefix("ipfs/").unwrap_or(rest);
+        let (cid, path) = rest.split_once('/').unwrap_or((rest, ""));
+        if cid.is_empty() {
+            return Err(format!("Token URI {} has no CID.", token_uri));
+        }
+        Ok(ContentLocation::Ipfs { cid: cid.to_string(), path: path.to_string() })
+    } else if let Some(tx_id) = token_uri.strip_prefix("ar://") {
+        if tx_id.is_empty() {
+            return Err(format!("Token URI {} has no transaction id.", token_uri));
+        }
+        Ok(ContentLocation::Arweave { tx_id: tx_id.to_string() })
+    } else if token_uri.starts_with("https://") {
+        Ok(ContentLocation::Https(token_uri.to_string()))
+    } else {
+        Err(format!("Token URI {} uses an unsupported scheme.", token_uri))
+    }
+}
+
+fn gateway_urls(location: &ContentLocation, config: &MetadataConfig) -> Vec<String> {
+    match location {
+        ContentLocation::Ipfs { cid, path } => config
+            .ipfs_gateways
+            .iter()
+            .map(|gateway| {
+                let url = format!("{}/{}", gateway.trim_end_matches('/'), cid);
+                if path.is_empty() { url } else { format!("{}/{}", url, path) }
+            })
+            .collect(),
+        ContentLocation::Arweave { tx_id } => config
+            .arweave_gateways
+            .iter()
+            .map(|gateway| format!("{}/{}", gateway.trim_end_matches('/'), tx_id))
+            .collect(),
+        ContentLocation::Https(url) => vec![url.clone()],
+    }
+}
+
+// The sha2-256 digest a CID commits the content to, when the CID addresses the bytes themselves.
+// That holds for CIDv1 with the raw codec; CIDv0 and dag-pb CIDs hash a UnixFS encoding of the
+// file instead, so their content cannot be checked without the whole DAG.
+fn raw_sha256_digest(cid: &str) -> Option<Vec<u8>> {
+    // "b" is the multibase prefix for lowercase base32
+    let bytes = decode_base32(cid.strip_prefix('b')?)?;
+    let mut rest = bytes.as_slice();
+    let version = read_varint(&mut rest)?;
+    let codec = read_varint(&mut rest)?;
+    let hash_code = read_varint(&mut rest)?;
+    let length = read_varint(&mut rest)?;
+    (version == 1 && codec == RAW_CODEC && hash_code == SHA2_256 && length == 32 && rest.len() == 32)
+        .then(|| rest.to_vec())
+}
+
+fn read_varint(bytes: &mut &[u8]) -> Option<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = bytes.split_first()?;
+        *bytes = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+// RFC 4648 base32 without padding
+fn decode_base32(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(text.len() * 5 / 8);
+    let (mut buffer, mut bits) = (0u32, 0);
+    for c in text.bytes() {
+        let value = BASE32_ALPHABET.iter().position(|a| *a == c.to_ascii_lowercase())? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+async fn fetch_content(url: &str, max_response_bytes: u64) -> Result<Vec<u8>, String> {
+    let request = CanisterHttpRequestArgument {
+        url: url.to_string(),
+        method: HttpMethod::GET,
+        body: None,
+        max_response_bytes: Some(max_response_bytes),
+        transform: Some(TransformContext::from_name("transform_response".to_string(), vec![])),
+        headers: vec![
+            HttpHeader {
+                name: "User-Agent".to_string(),
+                value: "CargoX-Watcher/1.0".to_string(),
+            },
+            HttpHeader {
+                name: "Accept".to_string(),
+                value: "application/json".to_string(),
+            },
+        ],
+    };
+
+    match http_outcall(OutcallFeature::TokenMetadata, request).await {
+        Ok(response) if response.status == 200u16 => Ok(response.body),
+        Ok(response) => Err(format!("{} returned HTTP {}", url, response.status)),
+        Err(e) => Err(format!("Request to {} failed: {}", url, e)),
+    }
+}
+
+// Tries each gateway in turn. Content that does not match its CID is treated like a failed
+// gateway, so one misbehaving gateway cannot substitute a document.
+async fn resolve(token_uri: &str) -> Result<DocumentMetadata, String> {
+    let config = metadata_config();
+    let location = locate(token_uri)?;
+    let expected_digest = match &location {
+        ContentLocation::Ipfs { cid, path } if path.is_empty() => raw_sha256_digest(cid),
+        _ => None,
+    };
+    let mut last_error = format!("No gateway is configured for {}.", token_uri);
+    for url in gateway_urls(&location, &config) {
+        let content = match fetch_content(&url, config.max_response_bytes).await {
+            Ok(content) => content,
+            Err(e) => {
+                last_error = e;
+                continue;
+            }
+        };
+        let digest = Sha256::digest(&content);
+        if expected_digest.as_ref().is_some_and(|expected| expected.as_slice() != digest.as_slice()) {
+            last_error = format!("Content from {} does not match its CID.", url);
+            continue;
+        }
+        let resolution = MetadataResolution::Resolved {
+            token_uri: token_uri.to_string(),
+            url: url.clone(),
+            content_sha256: format!("0x{}", encode_hex(&digest)),
+            cid_verified: expected_digest.is_some(),
+        };
+        let parsed = String::from_utf8(content)
+            .map_err(|e| format!("Content from {} is not UTF-8: {}", url, e))
+            .and_then(|text| parse_metadata(&text, resolution));
+        match parsed {
+            Ok(metadata) => return Ok(metadata),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+pub(crate) async fn resolve_metadata(token_uri: &str) -> DocumentMetadata {
+    resolve(token_uri).await.unwrap_or_else(DocumentMetadata::unresolved)
+}
+
+// ---- Cache ----
+fn cache_key(token: &TokenRef) -> String {
+    format!("{}|{}", token.contract.to_ascii_lowercase(), token.token_id)
+}
+
+fn cached_entry(token: &TokenRef) -> Option<CachedMetadata> {
+    if !token.is_polled() {
+        return None;
+    }
+    METADATA_CACHE.with(|c| c.borrow().get(&cache_key(token)))
+}
+
+// Cached metadata that has not expired yet, including remembered failures
+pub(crate) fn fresh_metadata(token: &TokenRef) -> Option<DocumentMetadata> {
+    let now = ic_cdk::api::time();
+    cached_entry(token)
+        .filter(|entry| entry.expires_at > now)
+        .map(|entry| entry.metadata)
+}
+
+// Whatever the cache holds, however old; never makes an outcall
+pub(crate) fn cached_metadata(token: &TokenRef) -> DocumentMetadata {
+    cached_entry(token)
+        .map(|entry| entry.metadata)
+        .unwrap_or_else(|| DocumentMetadata::unresolved(not_cached_reason(token)))
+}
+
+fn not_cached_reason(token: &TokenRef) -> String {
+    if token.is_polled() {
+        "Metadata has not been fetched yet.".to_string()
+    } else {
+        format!("Metadata is only read for tokens on {}.", POLLED_NETWORK)
+    }
+}
+
+async fn refresh_metadata(token: &TokenRef) -> DocumentMetadata {
+    if !token.is_polled() {
+        return DocumentMetadata::unresolved(not_cached_reason(token));
+    }
+    let metadata = fetch_token_metadata(token).await;
+    let fetched_at = ic_cdk::api::time();
+    let ttl = metadata_config().ttl_secs(&metadata.resolution);
+    let entry = CachedMetadata {
+        contract: token.contract.clone(),
+        token_id: token.token_id.clone(),
+        metadata: metadata.clone(),
+        fetched_at,
+        expires_at: fetched_at.saturating_add(ttl.saturating_mul(NANOS_PER_SECOND)),
+    };
+    METADATA_CACHE.with(|c| c.borrow_mut().insert(cache_key(token), entry));
+    metadata
+}
+
+// Serves from the cache until the entry expires, then fetches again
+pub(crate) async fn token_metadata(token: &TokenRef) -> DocumentMetadata {
+    match fresh_metadata(token) {
+        Some(metadata) => metadata,
+        None => refresh_metadata(token).await,
+    }
+}
+
+// Fetches metadata for transferred tokens that have no entry or an expired one, oldest transfers first
+async fn refresh_stale_metadata() {
+    let Some(_refresh) = InFlight::claim("metadata_refresh".to_string()) else {
+        return;
+    };
+    let mut stale: Vec<TokenRef> = Vec::new();
+    for transfer in crate::final_transfers() {
+        if stale.len() == MAX_REFRESHES_PER_RUN {
+            break;
+        }
+        let token = transfer.token();
+        if token.is_polled() && !stale.contains(&token) && fresh_metadata(&token).is_none() {
+            stale.push(token);
+        }
+    }
+    for token in stale {
+        refresh_metadata(&token).await;
+    }
+}
+
+// ---- API ----
+#[update(guard = "is_admin")]
+pub fn set_metadata_config(config: MetadataConfig) -> Result<(), String> {
+    for gateway in config.ipfs_gateways.iter().chain(&config.arweave_gateways) {
+        check_gateway(gateway)?;
+    }
+    if config.max_response_bytes == 0 || config.max_response_bytes > 2_000_000 {
+        return Err("Metadata responses must be capped between 1 byte and 2 MB.".to_string());
+    }
+    if config.refresh_interval() < Duration::from_secs(10) {
+        return Err("Metadata refresh interval must be at least 10 seconds.".to_string());
+    }
+    METADATA_CONFIG.with(|c| c.borrow_mut().set(config));
+    arm_metadata_refresher();
+    Ok(())
+}
+
+#[query]
+pub fn get_metadata_config() -> MetadataConfig {
+    metadata_config()
+}
+
+#[query]
+pub fn get_cached_metadata(token: TokenRef) -> Option<CachedMetadata> {
+    cached_entry(&token)
+}
+
+// Drops one token's entry, or the whole cache, so the next lookup fetches again.
+// Returns how many entries were removed.
+#[update(guard = "is_admin")]
+pub fn invalidate_metadata(token: Option<TokenRef>) -> u64 {
+    match token {
+        Some(token) if !token.is_polled() => 0,
+        Some(token) => METADATA_CACHE.with(|c| c.borrow_mut().remove(&cache_key(&token)).map_or(0, |_| 1)),
+        None => METADATA_CACHE.with(|c| {
+            let mut cache = c.borrow_mut();
+            let removed = cache.len();
+            cache.clear_new();
+            removed
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locates_ipfs_arweave_and_https_uris() {
+        let config = MetadataConfig::default();
+        let ipfs = locate("ipfs://ipfs/bafkreiabc/meta.json").unwrap();
+        assert_eq!(ipfs, ContentLocation::Ipfs { cid: "bafkreiabc".to_string(), path: "meta.json".to_string() });
+        assert_eq!(
+            gateway_urls(&ipfs, &config),
+            ["https://ipfs.io/ipfs/bafkreiabc/meta.json", "https://dweb.link/ipfs/bafkreiabc/meta.json"]
+        );
+        let arweave = locate("ar://tx123").unwrap();
+        assert_eq!(gateway_urls(&arweave, &config), ["https://arweave.net/tx123"]);
+        assert_eq!(locate("https://docs.example/1.json").unwrap(), ContentLocation::Https("https://docs.example/1.json".to_string()));
+        assert!(locate("http://docs.example/1.json").is_err());
+        assert!(locate("ipfs://").is_err());
+    }
+
+    #[test]
+    fn failed_lookups_expire_sooner_than_resolved_ones() {
+        let resolved = MetadataResolution::Resolved {
+            token_uri: "ipfs://cid".to_string(),
+            url: "https://ipfs.io/ipfs/cid".to_string(),
+            content_sha256: "0x00".to_string(),
+            cid_verified: false,
+        };
+        let unresolved = MetadataResolution::Unresolved { reason: "HTTP 404".to_string() };
+        let config = MetadataConfig::default();
+        assert_eq!(config.ttl_secs(&resolved), 86_400);
+        assert_eq!(config.ttl_secs(&unresolved), 3_600);
+        let config = MetadataConfig { cache_ttl_secs: Some(60), failure_ttl_secs: Some(0), ..config };
+        assert_eq!(config.ttl_secs(&resolved), 60);
+        assert_eq!(config.ttl_secs(&unresolved), 0);
+    }
+
+    #[test]
+    fn only_raw_cids_commit_to_the_content_digest() {
+        // `ipfs add --cid-version 1 --raw-leaves` of the bytes "hello\n"
+        let digest = raw_sha256_digest("bafkreicysg23kiwv34eg2d7qweipxwosdo2py4ldv42nbauguluen5v6am").unwrap();
+        assert_eq!(digest.as_slice(), Sha256::digest(b"hello\n").as_slice());
+        // CIDv0 and dag-pb CIDs hash the UnixFS encoding instead
+        assert_eq!(raw_sha256_digest("QmWATWQ7fVPP2EFGu71UkfnqhYXDYH566qy47CnJDgvs8u"), None);
+        assert_eq!(raw_sha256_digest("bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi"), None);
+        assert_eq!(raw_sha256_digest("b!!"), None);
+    }
+}
@@ -0,0 +1,245 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk_macros::query;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::ops::Bound;
+
+use crate::indexed::term;
+use crate::pagination::{collect_page, page_limit, MAX_SCANNED};
+use crate::{transfer_at, with_transfers_after, CargoTraceError, Memory, TokenRef, TransferPayload, MEMORY_MANAGER};
+
+// ---- Queries ----
+// All set fields must match. Addresses and contracts compare case-insensitively.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct TransferFilter {
+    pub network: Option<String>,
+    pub contract: Option<String>,
+    pub token_id: Option<String>,
+    pub from: Option<String>,
+    pub to: Option<String>,
+    // Inclusive block range
+    pub from_block: Option<u64>,
+    pub to_block: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TransferPage {
+    pub transfers: Vec<TransferPayload>,
+    // Pass back to fetch the next page; None when there are no more matches
+    pub next_cursor: Option<u64>,
+}
+
+impl TransferFilter {
+    fn matches(&self, transfer: &TransferPayload) -> bool {
+        let same = |wanted: &Option<String>, actual: &str| wanted.as_ref().is_none_or(|w| w.eq_ignore_ascii_case(actual));
+        same(&self.network, &transfer.network)
+            && same(&self.contract, &transfer.contract)
+            && same(&self.token_id, &transfer.token_id)
+            && same(&self.from, &transfer.from)
+            && same(&self.to, &transfer.to)
+            && self.from_block.is_none_or(|b| transfer.block_number >= b)
+            && self.to_block.is_none_or(|b| transfer.block_number <= b)
+    }
+
+    // The most selective index the filter can use
+    fn index_prefix(&self) -> Option<String> {
+        self.token_id
+            .as_ref()
+            .map(|token_id| index_prefix("token", token_id))
+            .or_else(|| self.from.as_ref().map(|from| index_prefix("from", from)))
+            .or_else(|| self.to.as_ref().map(|to| index_prefix("to", to)))
+            .or_else(|| self.contract.as_ref().map(|contract| index_prefix("contract", contract)))
+    }
+}
+
+// ---- STATE ----
+thread_local! {
+    // "<field>:<value>|<transfer key>" -> transfer key. Zero-padded keys keep each value's entries
+    // in arrival order, which is also the pagination order. Custody entries put the block number
+    // and log index before the key, so a token's history reads in chain order.
+    static TRANSFER_INDEX: RefCell<StableBTreeMap<String, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(29))))
+    );
+}
+
+fn index_prefix(field: &str, value: &str) -> String {
+    format!("{}|", term(field, value))
+}
+
+fn index_entries(key: u64, transfer: &TransferPayload) -> [String; 6] {
+    let [token, from, to, contract, tx] = [
+        ("token", &transfer.token_id),
+        ("from", &transfer.from),
+        ("to", &transfer.to),
+        ("contract", &transfer.contract),
+        ("tx", &transfer.tx_hash),
+    ]
+    .map(|(field, value)| format!("{}{:020}", index_prefix(field, value), key));
+    [token, from, to, contract, tx, custody_entry(key, transfer)]
+}
+
+fn custody_entry(key: u64, transfer: &TransferPayload) -> String {
+    let position = format!("{:020}{:020}{:020}", transfer.block_number, transfer.log_index, key);
+    format!("{}{}", index_prefix("custody", &transfer.token().key()), position)
+}
+
+pub(crate) fn index_transfer(key: u64, transfer: &TransferPayload) {
+    TRANSFER_INDEX.with(|i| {
+        let mut index = i.borrow_mut();
+        for entry in index_entries(key, transfer) {
+            index.insert(entry, key);
+        }
+    });
+}
+
+pub(crate) fn unindex_transfer(key: u64, transfer: &TransferPayload) {
+    TRANSFER_INDEX.with(|i| {
+        let mut index = i.borrow_mut();
+        for entry in index_entries(key, transfer) {
+            index.remove(&entry);
+        }
+    });
+}
+
+pub(crate) fn clear_transfer_index() {
+    TRANSFER_INDEX.with(|i| i.borrow_mut().clear_new());
+}
+
+// Hands `f` the transfers after `cursor` whose index entry starts with `prefix`, each read only when
+// `f` reaches it
+fn with_indexed_transfers<R>(
+    prefix: &str,
+    cursor: Option<u64>,
+    f: impl FnOnce(&mut dyn Iterator<Item = (u64, TransferPayload)>) -> R,
+) -> R {
+    let start = match cursor {
+        Some(cursor) => format!("{}{:020}", prefix, cursor.saturating_add(1)),
+        None => prefix.to_string(),
+    };
+    with_index_range(prefix, Bound::Included(start), f)
+}
+
+fn with_index_range<R>(
+    prefix: &str,
+    start: Bound<String>,
+    f: impl FnOnce(&mut dyn Iterator<Item = (u64, TransferPayload)>) -> R,
+) -> R {
+    TRANSFER_INDEX.with(|i| {
+        let index = i.borrow();
+        let mut transfers = index
+            .range((start, Bound::Unbounded))
+            .take_while(|entry| entry.key().starts_with(prefix))
+            .filter_map(|entry| Some((entry.value(), transfer_at(entry.value())?)));
+        f(&mut transfers)
+    })
+}
+
+// The logs of one transaction, in arrival order
+pub(crate) fn transfers_in_tx(tx_hash: &str) -> Vec<TransferPayload> {
+    with_indexed_transfers(&index_prefix("tx", tx_hash), None, |transfers| {
+        transfers.take(MAX_SCANNED).map(|(_, transfer)| transfer).collect()
+    })
+}
+
+fn page(filter: &TransferFilter, cursor: Option<u64>, limit: usize) -> TransferPage {
+    let collect = |candidates: &mut dyn Iterator<Item = (u64, TransferPayload)>| {
+        collect_page(candidates, limit, |transfer| filter.matches(transfer))
+    };
+    let (transfers, next_cursor) = match filter.index_prefix() {
+        Some(prefix) => with_indexed_transfers(&prefix, cursor, collect),
+        None => with_transfers_after(cursor, collect),
+    };
+    TransferPage { transfers, next_cursor }
+}
+
+// ---- API ----
+// Transfers in arrival order
+#[query]
+pub fn list_transfers(filter: TransferFilter, cursor: Option<u64>, limit: Option<u32>) -> TransferPage {
+    page(&filter, cursor, page_limit(limit))
+}
+
+// Chain of custody for one CargoX document, oldest transfer first. The cursor is the key of the last
+// transfer of the previous page; one that has since been dropped by a reorg is rejected.
+#[query]
+pub fn get_token_history(
+    token: TokenRef,
+    cursor: Option<u64>,
+    limit: Option<u32>,
+) -> Result<TransferPage, CargoTraceError> {
+    let prefix = index_prefix("custody", &token.normalized().key());
+    let start = match cursor {
+        None => Bound::Included(prefix.clone()),
+        Some(cursor) => {
+            let transfer = transfer_at(cursor)
+                .ok_or_else(|| CargoTraceError::InvalidArgument(format!("Invalid cursor {}.", cursor)))?;
+            Bound::Excluded(custody_entry(cursor, &transfer))
+        }
+    };
+    let (transfers, next_cursor) =
+        with_index_range(&prefix, start, |transfers| collect_page(transfers, page_limit(limit), |_| true));
+    Ok(TransferPage { transfers, next_cursor })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transfer() -> TransferPayload {
+        TransferPayload {
+            network: "ethereum".to_string(),
+            contract: "0xd4190DD1dA460fC7Bc41a792e688604778820aC9".to_string(),
+            tx_hash: "0xtx".to_string(),
+            block_number: 20_000_010,
+            token_id: "42".to_string(),
+            from: "0xAAA".to_string(),
+            to: "0xbbb".to_string(),
+            log_index: 0,
+            block_hash: None,
+        }
+    }
+
+    #[test]
+    fn transfer_filters_ignore_address_case_and_pick_an_index() {
+        let filter = TransferFilter { from: Some("0xaaa".to_string()), from_block: Some(20_000_010), ..Default::default() };
+        assert!(filter.matches(&transfer()));
+        assert_eq!(filter.index_prefix(), Some("from:0xaaa|".to_string()));
+        assert!(!TransferFilter { to_block: Some(20_000_009), ..filter.clone() }.matches(&transfer()));
+        assert!(!TransferFilter { network: Some("polygon".to_string()), ..filter }.matches(&transfer()));
+
+        let by_token = TransferFilter { token_id: Some("42".to_string()), to: Some("0xbbb".to_string()), ..Default::default() };
+        assert_eq!(by_token.index_prefix(), Some("token:42|".to_string()));
+        assert_eq!(TransferFilter::default().index_prefix(), None);
+        assert_eq!(index_entries(7, &transfer())[0], "token:42|00000000000000000007");
+    }
+
+    #[test]
+    fn token_history_keeps_contracts_apart() {
+        let other = TransferPayload {
+            contract: "0x00000000000000000000000000000000000000c2".to_string(),
+            tx_hash: "0xother".to_string(),
+            ..transfer()
+        };
+        crate::store_transfer(TransferPayload { token_id: "77".to_string(), ..transfer() });
+        crate::store_transfer(TransferPayload { token_id: "77".to_string(), ..other });
+        let token = TokenRef {
+            network: "Ethereum".to_string(),
+            contract: "0xD4190DD1DA460FC7BC41A792E688604778820AC9".to_string(),
+            token_id: "77".to_string(),
+        };
+        let history = get_token_history(token.clone(), None, None).unwrap();
+        assert_eq!(history.transfers.len(), 1);
+        assert_eq!(history.transfers[0].tx_hash, "0xtx");
+        assert_eq!(history.next_cursor, None);
+
+        // An earlier block reported late still comes first, and pages resume after the cursor
+        let early = TransferPayload { tx_hash: "0xearly".to_string(), block_number: 20_000_005, ..transfer() };
+        crate::store_transfer(TransferPayload { token_id: "77".to_string(), ..early });
+        let first = get_token_history(token.clone(), None, Some(1)).unwrap();
+        assert_eq!(first.transfers[0].tx_hash, "0xearly");
+        let rest = get_token_history(token.clone(), first.next_cursor, Some(1)).unwrap();
+        assert_eq!(rest.transfers[0].tx_hash, "0xtx");
+        assert!(get_token_history(token, Some(u64::MAX), None).is_err());
+    }
+}
@@ -0,0 +1,356 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{StableBTreeMap, StableCell, Storable};
+use std::cell::RefCell;
+
+use crate::{Memory, MEMORY_MANAGER};
+
+// Records are stored as [ENVELOPE_TAG, version, candid bytes]. Candid is self-describing, so new
+// `Option` fields decode as None from older records without a version bump. Changes Candid cannot
+// absorb bump `VERSION` and teach `migrate` to read the previous version.
+//
+// Version 0 is whatever a type stored before it was versioned. The original hand-written layouts
+// start with a UTF-8 id and raw Candid starts with "DIDL", so neither can begin with ENVELOPE_TAG.
+const ENVELOPE_TAG: u8 = 0xFF;
+
+// Bump when any record type changes VERSION, or an index must be built from stored records, so
+// post_upgrade rewrites them
+const SCHEMA_VERSION: u32 = 13;
+
+pub(crate) trait Versioned: CandidType + for<'de> Deserialize<'de> + Sized {
+    const NAME: &'static str;
+    const VERSION: u8;
+
+    // Decodes a record stored with an older version. Types that never changed shape have nothing
+    // to read.
+    fn migrate(version: u8, _bytes: &[u8]) -> Result<Self, String> {
+        Err(format!("no migration from {} version {}", Self::NAME, version))
+    }
+}
+
+pub(crate) fn encode<T: Versioned>(value: &T) -> Vec<u8> {
+    let mut bytes = vec![ENVELOPE_TAG, T::VERSION];
+    bytes.extend(candid::encode_one(value).unwrap_or_else(|e| panic!("Failed to encode {}: {}", T::NAME, e)));
+    bytes
+}
+
+pub(crate) fn decode<T: Versioned>(bytes: &[u8]) -> Result<T, String> {
+    match bytes {
+        [ENVELOPE_TAG, version, payload @ ..] if *version == T::VERSION => {
+            candid::decode_one(payload).map_err(|e| e.to_string())
+        }
+        [ENVELOPE_TAG, version, payload @ ..] if *version < T::VERSION => T::migrate(*version, payload),
+        [ENVELOPE_TAG, version, ..] => Err(format!("unknown version {}", version)),
+        legacy => T::migrate(0, legacy),
+    }
+}
+
+// Implements Storable on top of the versioned envelope
+macro_rules! versioned_storable {
+    ($t:ty) => {
+        impl ic_stable_structures::Storable for $t {
+            fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+                std::borrow::Cow::Owned($crate::versioned::encode(self))
+            }
+
+            fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+                $crate::versioned::decode(&bytes).unwrap_or_else(|e| {
+                    panic!("Failed to decode {}: {}", <$t as $crate::versioned::Versioned>::NAME, e)
+                })
+            }
+
+            fn into_bytes(self) -> Vec<u8> {
+                $crate::versioned::encode(&self)
+            }
+
+            const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+        }
+    };
+}
+pub(crate) use versioned_storable;
+
+// Bounds-checked cursor over the original hand-written layouts
+pub(crate) struct LegacyReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> LegacyReader<'a> {
+    pub(crate) fn new(bytes: &'a [u8]) -> Self {
+        LegacyReader { bytes, pos: 0 }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.pos >= self.bytes.len()
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let end = self.pos.checked_add(len).filter(|end| *end <= self.bytes.len()).ok_or_else(|| {
+            format!("buffer underrun: need {} bytes at position {} of {}", len, self.pos, self.bytes.len())
+        })?;
+        let slice = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(slice)
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn f64(&mut self) -> Result<f64, String> {
+        Ok(f64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    // NUL-terminated string
+    pub(crate) fn string(&mut self) -> Result<String, String> {
+        let len = self.bytes[self.pos.min(self.bytes.len())..]
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or_else(|| format!("missing string terminator at position {}", self.pos))?;
+        let value = String::from_utf8(self.take(len)?.to_vec()).map_err(|e| e.to_string())?;
+        self.pos += 1;
+        Ok(value)
+    }
+
+    // NUL-terminated string where an empty value means None
+    pub(crate) fn optional_string(&mut self) -> Result<Option<String>, String> {
+        self.string().map(|s| if s.is_empty() { None } else { Some(s) })
+    }
+
+    // Length-prefixed principal
+    pub(crate) fn principal(&mut self) -> Result<Principal, String> {
+        let len = self.u8()? as usize;
+        Principal::try_from_slice(self.take(len)?).map_err(|e| e.to_string())
+    }
+
+    // u32 length-prefixed bytes
+    pub(crate) fn blob(&mut self) -> Result<&'a [u8], String> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+}
+
+thread_local! {
+    static STORED_SCHEMA_VERSION: RefCell<StableCell<u32, Memory>> = RefCell::new(
+        StableCell::init(MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(13))), 0)
+    );
+}
+
+// Re-inserting a record re-encodes it with the current version
+pub(crate) fn rewrite_all<K, V>(map: &mut StableBTreeMap<K, V, Memory>)
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+{
+    let keys: Vec<K> = map.iter().map(|entry| entry.key().clone()).collect();
+    for key in keys {
+        if let Some(value) = map.get(&key) {
+            map.insert(key, value);
+        }
+    }
+}
+
+// Older records are decoded lazily on every read; this rewrites them eagerly once per schema bump
+// so the legacy decoders only ever run during an upgrade.
+pub(crate) fn migrate_stored_records(rewrite: impl FnOnce()) {
+    let stored = STORED_SCHEMA_VERSION.with(|v| *v.borrow().get());
+    if stored >= SCHEMA_VERSION {
+        return;
+    }
+    ic_cdk::println!("Migrating stable records from schema {} to {}", stored, SCHEMA_VERSION);
+    rewrite();
+    STORED_SCHEMA_VERSION.with(|v| v.borrow_mut().set(SCHEMA_VERSION));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        AcidValidation, CargoXMapping, CargoXMappingV1, CustomsStatus, CustomsVerification, CustomsVerificationV1,
+        Document, DocumentStatus, DocumentV1, Loan, LoanStatus,
+    };
+
+    fn principal() -> Principal {
+        Principal::from_slice(&[7; 29])
+    }
+
+    fn push_str(bytes: &mut Vec<u8>, s: &str) {
+        bytes.extend_from_slice(s.as_bytes());
+        bytes.push(0);
+    }
+
+    fn push_principal(bytes: &mut Vec<u8>, p: Principal) {
+        bytes.push(p.as_slice().len() as u8);
+        bytes.extend_from_slice(p.as_slice());
+    }
+
+    #[test]
+    fn decodes_legacy_loan_layout() {
+        let mut bytes = Vec::new();
+        push_str(&mut bytes, "LOAN-000001");
+        push_str(&mut bytes, "DOC-000001");
+        bytes.extend_from_slice(&5_000u64.to_le_bytes());
+        bytes.extend_from_slice(&4.5f64.to_le_bytes());
+        bytes.extend_from_slice(&10u64.to_le_bytes());
+        bytes.extend_from_slice(&20u64.to_le_bytes());
+        push_principal(&mut bytes, principal());
+        bytes.push(2);
+        bytes.push(1);
+        bytes.extend_from_slice(&1u32.to_le_bytes());
+        bytes.push(9);
+
+        let loan: Loan = decode(&bytes).unwrap();
+        assert_eq!(loan.id, "LOAN-000001");
+        assert_eq!(loan.amount, 5_000);
+        assert_eq!(loan.interest_rate_bps, 450);
+        assert_eq!(loan.principal_outstanding, 5_000);
+        assert_eq!(loan.accrued_until, 10);
+        assert_eq!(loan.status, LoanStatus::Active);
+        assert_eq!(loan.borrower, principal());
+        assert_eq!(loan.transfer_block_height, Some(candid::Nat::from(9u64)));
+        assert!(loan.transfer_error.is_none());
+
+        let reencoded = encode(&loan);
+        assert_eq!(&reencoded[..2], &[ENVELOPE_TAG, Loan::VERSION]);
+        let roundtrip: Loan = decode(&reencoded).unwrap();
+        assert_eq!(roundtrip.document_id, "DOC-000001");
+    }
+
+    #[test]
+    fn decodes_legacy_document_and_customs_layouts() {
+        let mut bytes = Vec::new();
+        push_str(&mut bytes, "DOC-000001");
+        push_str(&mut bytes, "123456789");
+        push_str(&mut bytes, "0xabc");
+        bytes.extend_from_slice(&1_000u64.to_le_bytes());
+        bytes.extend_from_slice(&10u64.to_le_bytes());
+        push_principal(&mut bytes, principal());
+        bytes.push(3);
+        let document: Document = decode(&bytes).unwrap();
+        assert_eq!(document.tx_hash, "0xabc");
+        assert_eq!(document.chain, "ethereum");
+        assert!(document.token_id.is_empty());
+        assert!(document.status == DocumentStatus::NftMinted);
+
+        let mut bytes = Vec::new();
+        push_str(&mut bytes, "123456789");
+        bytes.push(1);
+        bytes.extend_from_slice(&10u64.to_le_bytes());
+        push_str(&mut bytes, "");
+        let validation: AcidValidation = decode(&bytes).unwrap();
+        assert!(validation.is_valid);
+        assert!(validation.customs_data.is_none());
+
+        let mut bytes = Vec::new();
+        push_str(&mut bytes, "VER-000001");
+        push_str(&mut bytes, "0xnft");
+        push_str(&mut bytes, "123456789");
+        bytes.push(1);
+        bytes.push(1);
+        bytes.extend_from_slice(&30u64.to_le_bytes());
+        push_str(&mut bytes, "ok");
+        bytes.extend_from_slice(&10u64.to_le_bytes());
+        bytes.push(1);
+        push_principal(&mut bytes, principal());
+        let verification: CustomsVerification = decode(&bytes).unwrap();
+        assert!(matches!(verification.verification_status, CustomsStatus::Verified));
+        assert_eq!(verification.verified_at, Some(30));
+        assert_eq!(verification.customs_data.as_deref(), Some("ok"));
+        assert_eq!(verification.verified_by, Some(principal()));
+    }
+
+    #[test]
+    fn version_1_documents_and_mappings_gain_token_fields() {
+        let v1 = DocumentV1 {
+            id: "DOC-000002".to_string(),
+            acid_number: "123456789".to_string(),
+            ethereum_tx_hash: "0xdef".to_string(),
+            value_usd: 1_000,
+            status: DocumentStatus::Verified,
+            created_at: 10,
+            owner: principal(),
+            nft_token_id: Some(4),
+        };
+        let mut bytes = vec![ENVELOPE_TAG, 1];
+        bytes.extend(candid::encode_one(&v1).unwrap());
+        let document: Document = decode(&bytes).unwrap();
+        assert_eq!(document.tx_hash, "0xdef");
+        assert_eq!(document.chain, "ethereum");
+        assert!(document.contract.is_empty() && document.token_id.is_empty());
+        assert_eq!(document.nft_token_id, Some(4));
+
+        let v1 = CargoXMappingV1 {
+            id: "MAP-000001".to_string(),
+            nft_hash: "0xdef".to_string(),
+            acid_number: "123456789".to_string(),
+            verified: true,
+            created_at: 10,
+            owner: principal(),
+            customs_entry_id: None,
+        };
+        let mut bytes = vec![ENVELOPE_TAG, 1];
+        bytes.extend(candid::encode_one(&v1).unwrap());
+        let mapping: CargoXMapping = decode(&bytes).unwrap();
+        assert_eq!(mapping.nft_hash, "0xdef");
+        assert!(mapping.verified);
+        assert!(mapping.document_id.is_none());
+    }
+
+    #[test]
+    fn version_1_verifications_gain_a_document_id() {
+        let v1 = CustomsVerificationV1 {
+            id: "VER-000001".to_string(),
+            nft_hash: "0xdef".to_string(),
+            acid_number: "123456789".to_string(),
+            verification_status: CustomsStatus::Pending,
+            verified_at: None,
+            customs_data: None,
+            created_at: 10,
+            verified_by: None,
+        };
+        let mut bytes = vec![ENVELOPE_TAG, 1];
+        bytes.extend(candid::encode_one(&v1).unwrap());
+        let verification: CustomsVerification = decode(&bytes).unwrap();
+        assert_eq!(verification.nft_hash, "0xdef");
+        assert!(matches!(verification.verification_status, CustomsStatus::Pending));
+        assert!(verification.document_id.is_none());
+    }
+
+    #[test]
+    fn collateral_locks_stored_with_an_nft_id_still_decode() {
+        #[derive(CandidType)]
+        struct StoredLock {
+            document_id: String,
+            loan_id: String,
+            nft_token_id: Option<u64>,
+            locked_at: u64,
+        }
+        let stored = StoredLock {
+            document_id: "DOC-000001".to_string(),
+            loan_id: "LOAN-000001".to_string(),
+            nft_token_id: Some(4),
+            locked_at: 10,
+        };
+        let mut bytes = vec![ENVELOPE_TAG, 1];
+        bytes.extend(candid::encode_one(&stored).unwrap());
+        let lock: crate::CollateralLock = decode(&bytes).unwrap();
+        assert_eq!(lock.loan_id, "LOAN-000001");
+        assert_eq!(lock.locked_at, 10);
+    }
+
+    #[test]
+    fn truncated_legacy_record_is_an_error() {
+        let mut bytes = Vec::new();
+        push_str(&mut bytes, "LOAN-000001");
+        bytes.extend_from_slice(&[1, 2, 3]);
+        assert!(decode::<Loan>(&bytes).is_err());
+    }
+}
@@ -0,0 +1,253 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk_macros::{query, update};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableCell;
+use std::cell::RefCell;
+
+use crate::abi::TRANSFER_TOPIC;
+use crate::roles::is_admin;
+use crate::versioned::{versioned_storable, Versioned};
+use crate::{Memory, MEMORY_MANAGER};
+
+// The network the canister's own poller and tokenURI calls reach; other networks are covered by
+// off-chain watchers reading this config
+pub const POLLED_NETWORK: &str = "ethereum";
+
+// ---- Configuration ----
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct WatchedContract {
+    // Lowercase network name, e.g. "ethereum" or "polygon"
+    pub network: String,
+    pub address: String,
+    // Transfers in earlier blocks are ignored
+    pub start_block: u64,
+}
+
+// Token ids are only unique within their contract, so tokens are always named with both
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct TokenRef {
+    pub network: String,
+    pub contract: String,
+    pub token_id: String,
+}
+
+impl TokenRef {
+    // Networks and addresses compare case-insensitively, so they are kept in lowercase
+    pub(crate) fn new(network: &str, contract: &str, token_id: &str) -> Self {
+        TokenRef {
+            network: network.to_ascii_lowercase(),
+            contract: contract.to_ascii_lowercase(),
+            token_id: token_id.to_string(),
+        }
+    }
+
+    pub(crate) fn normalized(self) -> Self {
+        TokenRef::new(&self.network, &self.contract, &self.token_id)
+    }
+
+    // Names the token in index terms and map keys
+    pub(crate) fn key(&self) -> String {
+        format!("{}:{}:{}", self.network, self.contract, self.token_id)
+    }
+
+    // Token URIs can only be read on the network the canister polls itself
+    pub(crate) fn is_polled(&self) -> bool {
+        self.network.eq_ignore_ascii_case(POLLED_NETWORK)
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct WatcherConfig {
+    // The first contract is the one the canister polls first; it must be on the polled network
+    pub contracts: Vec<WatchedContract>,
+    // topic0 of the logs fetched from each contract
+    pub transfer_topic: String,
+}
+
+impl Default for WatcherConfig {
+    fn default() -> Self {
+        WatcherConfig {
+            contracts: vec![WatchedContract {
+                network: POLLED_NETWORK.to_string(),
+                address: "0xd4190DD1dA460fC7Bc41a792e688604778820aC9".to_string(),
+                start_block: 20_000_000,
+            }],
+            transfer_topic: TRANSFER_TOPIC.to_string(),
+        }
+    }
+}
+
+impl Versioned for WatcherConfig {
+    const NAME: &'static str = "WatcherConfig";
+    const VERSION: u8 = 1;
+}
+versioned_storable!(WatcherConfig);
+
+// Credentials for the outcalls the watcher makes. Write-only: no endpoint returns them.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+struct WatcherSecrets {
+    etherscan_api_key: Option<String>,
+}
+
+impl Versioned for WatcherSecrets {
+    const NAME: &'static str = "WatcherSecrets";
+    const VERSION: u8 = 1;
+}
+versioned_storable!(WatcherSecrets);
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct InitArgs {
+    pub watcher: Option<WatcherConfig>,
+    pub etherscan_api_key: Option<String>,
+}
+
+// ---- STATE ----
+thread_local! {
+    static WATCHER_CONFIG: RefCell<StableCell<WatcherConfig, Memory>> = RefCell::new(
+        StableCell::init(MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(32))), WatcherConfig::default())
+    );
+    static WATCHER_SECRETS: RefCell<StableCell<WatcherSecrets, Memory>> = RefCell::new(
+        StableCell::init(MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(33))), WatcherSecrets::default())
+    );
+}
+
+pub(crate) fn watcher_config() -> WatcherConfig {
+    WATCHER_CONFIG.with(|c| c.borrow().get().clone())
+}
+
+// Contracts on the network the canister polls itself
+pub(crate) fn polled_contracts() -> Vec<WatchedContract> {
+    watcher_config().contracts.into_iter().filter(|c| c.network == POLLED_NETWORK).collect()
+}
+
+pub(crate) fn is_watched(network: &str, address: &str) -> bool {
+    watcher_config()
+        .contracts
+        .iter()
+        .any(|c| c.network.eq_ignore_ascii_case(network) && c.address.eq_ignore_ascii_case(address))
+}
+
+pub(crate) fn etherscan_api_key() -> Option<String> {
+    WATCHER_SECRETS.with(|s| s.borrow().get().etherscan_api_key.clone())
+}
+
+fn is_hex(value: &str, digits: usize) -> bool {
+    value.len() == 2 + digits && value.starts_with("0x") && value[2..].bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+fn check_watcher_config(config: &WatcherConfig) -> Result<(), String> {
+    match config.contracts.first() {
+        None => return Err("At least one contract must be watched.".to_string()),
+        Some(first) if first.network != POLLED_NETWORK => {
+            return Err(format!("The first watched contract must be on {}.", POLLED_NETWORK));
+        }
+        Some(_) => {}
+    }
+    for (i, contract) in config.contracts.iter().enumerate() {
+        let network_ok = !contract.network.is_empty()
+            && contract.network.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-');
+        if !network_ok {
+            return Err(format!("Invalid network name {}.", contract.network));
+        }
+        if !is_hex(&contract.address, 40) {
+            return Err(format!("Invalid contract address {}.", contract.address));
+        }
+        if config.contracts[..i]
+            .iter()
+            .any(|c| c.network == contract.network && c.address.eq_ignore_ascii_case(&contract.address))
+        {
+            return Err(format!("Contract {} on {} is listed twice.", contract.address, contract.network));
+        }
+    }
+    if !is_hex(&config.transfer_topic, 64) {
+        return Err(format!("Invalid transfer topic {}.", config.transfer_topic));
+    }
+    Ok(())
+}
+
+fn set_secret_api_key(api_key: Option<String>) {
+    let api_key = api_key.filter(|key| !key.trim().is_empty());
+    WATCHER_SECRETS.with(|s| s.borrow_mut().set(WatcherSecrets { etherscan_api_key: api_key }));
+}
+
+pub(crate) fn apply_init_args(args: InitArgs) -> Result<(), String> {
+    if let Some(config) = args.watcher {
+        check_watcher_config(&config)?;
+        WATCHER_CONFIG.with(|c| c.borrow_mut().set(config));
+    }
+    if args.etherscan_api_key.is_some() {
+        set_secret_api_key(args.etherscan_api_key);
+    }
+    Ok(())
+}
+
+// Settings that older builds kept in the poller config, carried over once on upgrade
+pub(crate) fn adopt_legacy_watch_settings(contract: String, start_block: u64, api_key: Option<String>) {
+    let mut config = watcher_config();
+    config.contracts[0] = WatchedContract { network: POLLED_NETWORK.to_string(), address: contract, start_block };
+    WATCHER_CONFIG.with(|c| c.borrow_mut().set(config));
+    if api_key.is_some() {
+        set_secret_api_key(api_key);
+    }
+}
+
+// ---- API ----
+#[update(guard = "is_admin")]
+pub fn set_watcher_config(config: WatcherConfig) -> Result<(), String> {
+    check_watcher_config(&config)?;
+    WATCHER_CONFIG.with(|c| c.borrow_mut().set(config));
+    Ok(())
+}
+
+#[query]
+pub fn get_watcher_config() -> WatcherConfig {
+    watcher_config()
+}
+
+// Replaces the Etherscan API key; None removes it
+#[update(guard = "is_admin")]
+pub fn set_etherscan_api_key(api_key: Option<String>) -> Result<(), String> {
+    set_secret_api_key(api_key);
+    Ok(())
+}
+
+// Whether an API key is set, without revealing it
+#[query]
+pub fn has_etherscan_api_key() -> bool {
+    etherscan_api_key().is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contract(network: &str, address: &str) -> WatchedContract {
+        WatchedContract { network: network.to_string(), address: address.to_string(), start_block: 0 }
+    }
+
+    #[test]
+    fn validates_watched_contracts() {
+        assert_eq!(check_watcher_config(&WatcherConfig::default()), Ok(()));
+
+        let polygon = contract("polygon", "0x1111111111111111111111111111111111111111");
+        let mut config = WatcherConfig::default();
+        config.contracts.push(polygon.clone());
+        assert_eq!(check_watcher_config(&config), Ok(()));
+
+        config.contracts.push(contract("polygon", "0x1111111111111111111111111111111111111111"));
+        assert!(check_watcher_config(&config).unwrap_err().contains("listed twice"));
+
+        let polygon_first = WatcherConfig { contracts: vec![polygon], ..WatcherConfig::default() };
+        assert!(check_watcher_config(&polygon_first).is_err());
+        let bad_address = WatcherConfig { contracts: vec![contract("ethereum", "0xd4190")], ..WatcherConfig::default() };
+        assert!(check_watcher_config(&bad_address).is_err());
+        let bad_network = WatcherConfig {
+            contracts: vec![contract("Ethereum Mainnet", "0x1111111111111111111111111111111111111111")],
+            ..WatcherConfig::default()
+        };
+        assert!(check_watcher_config(&bad_network).is_err());
+        let bad_topic = WatcherConfig { transfer_topic: "0xddf252ad".to_string(), ..WatcherConfig::default() };
+        assert!(check_watcher_config(&bad_topic).is_err());
+        assert!(check_watcher_config(&WatcherConfig { contracts: vec![], ..WatcherConfig::default() }).is_err());
+    }
+}
@@ -19,7 +19,7 @@ import {
   Users,
   Building
 } from 'lucide-react';
-import { backendService } from '../../../services/backendService';
+import { backendService, customsKey, errorMessage } from '../../../services/backendService';
 
 const AdminCustoms = () => {
   const [searchQuery, setSearchQuery] = useState('');
@@ -100,24 +100,24 @@ const AdminCustoms = () => {
     return `inline-flex items-center px-3 py-1 rounded-full text-xs font-medium ${statusClasses[status] || 'bg-slate-500/20 text-slate-400 border border-slate-500/30'}`;
   };
 
-  const handleVerifyCustomsEntry = async (nftHash) => {
+  const handleVerifyCustomsEntry = async (key) => {
     try {
-      setProcessingAction(nftHash);
+      setProcessingAction(key);
       
       if (!backendService.isReady()) {
         throw new Error('Backend service not initialized');
       }
 
-      const result = await backendService.verifyCustomsEntry(nftHash);
+      const result = await backendService.verifyCustomsEntry(key);
       
       if (result.Err) {
-        throw new Error(result.Err);
+        throw new Error(errorMessage(result.Err));
       }
 
       // Reload data to reflect changes
       await loadData();
       
-      console.log('✅ Customs entry verified successfully:', nftHash);
+      console.log('✅ Customs entry verified successfully:', key);
     } catch (err) {
       console.error('Failed to verify customs entry:', err);
       setError(err.message);
@@ -126,27 +126,27 @@ const AdminCustoms = () => {
     }
   };
 
-  const handleRejectCustomsEntry = async (nftHash) => {
+  const handleRejectCustomsEntry = async (key) => {
     const reason = prompt('Please provide a reason for rejection:');
     if (!reason) return;
 
     try {
-      setProcessingAction(nftHash);
+      setProcessingAction(key);
       
       if (!backendService.isReady()) {
         throw new Error('Backend service not initialized');
       }
 
-      const result = await backendService.rejectCustomsEntry(nftHash, reason);
+      const result = await backendService.rejectCustomsEntry(key, reason);
       
       if (result.Err) {
-        throw new Error(result.Err);
+        throw new Error(errorMessage(result.Err));
       }
 
       // Reload data to reflect changes
       await loadData();
       
-      console.log('✅ Customs entry rejected successfully:', nftHash);
+      console.log('✅ Customs entry rejected successfully:', key);
     } catch (err) {
       console.error('Failed to reject customs entry:', err);
       setError(err.message);
@@ -167,7 +167,7 @@ const AdminCustoms = () => {
     
     let matchesStatus = true;
     if (statusFilter !== 'all') {
-      const verification = verifications.find(v => v.nft_hash === mapping.nft_hash);
+      const verification = verifications.find(v => customsKey(v) === customsKey(mapping));
       const verificationStatus = verification ? getVerificationStatus(verification.verification_status) : 'pending';
       matchesStatus = verificationStatus === statusFilter;
     }
@@ -391,7 +391,7 @@ const AdminCustoms = () => {
               </thead>
               <tbody className="divide-y divide-slate-700/50">
                 {filteredMappings.map((mapping) => {
-                  const verification = verifications.find(v => v.nft_hash === mapping.nft_hash);
+                  const verification = verifications.find(v => customsKey(v) === customsKey(mapping));
                   const verificationStatus = verification ? getVerificationStatus(verification.verification_status) : 'pending';
                   
                   return (
@@ -439,24 +439,24 @@ const AdminCustoms = () => {
                           {verificationStatus === 'pending' && (
                             <>
                               <button
-                                onClick={() => handleVerifyCustomsEntry(mapping.nft_hash)}
-                                disabled={processingAction === mapping.nft_hash}
+                                onClick={() => handleVerifyCustomsEntry(customsKey(mapping))}
+                                disabled={processingAction === customsKey(mapping)}
                                 className="group/btn p-2 text-green-400 hover:text-green-300 hover:bg-green-500/20 rounded-lg transition-all duration-300 hover:scale-110 disabled:opacity-50 disabled:cursor-not-allowed"
                                 title="Verify Entry"
                               >
-                                {processingAction === mapping.nft_hash ? (
+                                {processingAction === customsKey(mapping) ? (
                                   <Loader2 size={16} className="animate-spin" />
                                 ) : (
                                   <CheckCircle size={16} className="group-hover/btn:scale-110 transition-transform duration-300" />
                                 )}
                               </button>
                               <button
-                                onClick={() => handleRejectCustomsEntry(mapping.nft_hash)}
-                                disabled={processingAction === mapping.nft_hash}
+                                onClick={() => handleRejectCustomsEntry(customsKey(mapping))}
+                                disabled={processingAction === customsKey(mapping)}
                                 className="group/btn p-2 text-red-400 hover:text-red-300 hover:bg-red-500/20 rounded-lg transition-all duration-300 hover:scale-110 disabled:opacity-50 disabled:cursor-not-allowed"
                                 title="Reject Entry"
                               >
-                                {processingAction === mapping.nft_hash ? (
+                                {processingAction === customsKey(mapping) ? (
                                   <Loader2 size={16} className="animate-spin" />
                                 ) : (
                                   <XCircle size={16} className="group-hover/btn:scale-110 transition-transform duration-300" />
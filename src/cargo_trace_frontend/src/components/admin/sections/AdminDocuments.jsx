@@ -19,6 +19,7 @@ import {
   FileX
 } from 'lucide-react';
 import { cargo_trace_backend as backend } from '../../../../../declarations/cargo_trace_backend';
+import { errorMessage } from '../../../services/backendService';
 
 const AdminDocuments = () => {
   const [searchQuery, setSearchQuery] = useState('');
@@ -50,7 +51,7 @@ const AdminDocuments = () => {
         createdAt: new Date(Number(doc.created_at) / 1000000).toLocaleDateString(),
         description: `Document for ACID: ${doc.acid_number}`,
         acidNumber: doc.acid_number,
-        ethereumTxHash: doc.ethereum_tx_hash,
+        ethereumTxHash: doc.tx_hash,
         owner: doc.owner.toString()
       }));
 
@@ -103,7 +104,7 @@ const AdminDocuments = () => {
       const result = await backend.approve_document(documentId);
       
       if ('Err' in result) {
-        throw new Error(result.Err);
+        throw new Error(errorMessage(result.Err));
       }
 
       // Reload documents to reflect changes
@@ -126,7 +127,7 @@ const AdminDocuments = () => {
       const result = await backend.reject_document(documentId, "Rejected by admin");
       
       if ('Err' in result) {
-        throw new Error(result.Err);
+        throw new Error(errorMessage(result.Err));
       }
 
       // Reload documents to reflect changes
@@ -152,7 +153,7 @@ const AdminDocuments = () => {
       const doc = {
         id: backendDoc.id,
         acid: backendDoc.acid_number,
-        ethereumTxHash: backendDoc.ethereum_tx_hash,
+        ethereumTxHash: backendDoc.tx_hash,
         value: Number(backendDoc.value_usd),
         status: getDocumentStatus(backendDoc.status),
         createdAt: new Date(Number(backendDoc.created_at) / 1000000).toLocaleString(),
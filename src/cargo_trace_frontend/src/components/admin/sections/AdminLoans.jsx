@@ -8,7 +8,6 @@ import {
   Clock,
   Loader2,
   AlertCircle,
-  Wallet,
   TrendingUp,
   TrendingDown,
   Hash,
@@ -20,6 +19,7 @@ import {
   Activity
 } from 'lucide-react';
 import { cargo_trace_backend as backend } from '../../../../../declarations/cargo_trace_backend';
+import { allPages, errorMessage, NO_FILTER } from '../../../services/backendService';
 
 const AdminLoans = () => {
   const [searchQuery, setSearchQuery] = useState('');
@@ -28,7 +28,6 @@ const AdminLoans = () => {
   const [loading, setLoading] = useState(true);
   const [error, setError] = useState(null);
   const [processingAction, setProcessingAction] = useState(null);
-  const [funding, setFunding] = useState(false);
 
   useEffect(() => {
     loadLoans();
@@ -38,7 +37,7 @@ const AdminLoans = () => {
     try {
       setLoading(true);
       setError(null);
-      const backendLoans = await backend.get_all_loans();
+      const backendLoans = await allPages((cursor) => backend.get_all_loans(NO_FILTER, cursor, []));
       console.log('📊 Raw loans from backend:', backendLoans);
       const transformedLoans = backendLoans
         .filter(loan => loan !== null && loan !== undefined)
@@ -47,7 +46,7 @@ const AdminLoans = () => {
           status: getLoanStatus(loan.status),
           company: 'Trade Company',
           amount: loan.amount ? `$${Number(loan.amount).toLocaleString()}` : '$0',
-          interestRate: loan.interest_rate ? `${loan.interest_rate}%` : 'N/A',
+          interestRate: loan.interest_rate_bps !== undefined ? `${Number(loan.interest_rate_bps) / 100}%` : 'N/A',
           term: calculateTerm(loan.created_at, loan.repayment_date),
           requestedAt: loan.created_at ? new Date(Number(loan.created_at) / 1000000).toISOString() : 'N/A',
           dueDate: loan.repayment_date ? new Date(Number(loan.repayment_date) / 1000000).toISOString() : 'N/A',
@@ -67,26 +66,6 @@ const AdminLoans = () => {
     }
   };
 
-  const handleFundCanister = async () => {
-    try {
-      setFunding(true);
-      setError(null);
-      const result = await backend.request_test_tokens(1000n);
-      if ('Err' in result) {
-        throw new Error(result.Err);
-      }
-      console.log('✅ Canister funded with 1000 USD worth of test tokens');
-      alert('Canister successfully funded with 1000 USD worth of test tokens');
-      const balance = await backend.check_canister_balance();
-      console.log('📈 Canister balance after funding:', balance);
-    } catch (err) {
-      console.error('❌ Failed to fund canister:', err);
-      setError(err.message || 'Failed to fund canister');
-    } finally {
-      setFunding(false);
-    }
-  };
-
   const getLoanStatus = (status) => {
     if (!status) return 'unknown';
     if ('Pending' in status) return 'pending';
@@ -148,7 +127,7 @@ const AdminLoans = () => {
       setError(null);
       const result = await backend.approve_loan(loanId);
       if ('Err' in result) {
-        throw new Error(result.Err);
+        throw new Error(errorMessage(result.Err));
       }
       await loadLoans();
       console.log('✅ Loan approved successfully:', loanId);
@@ -166,7 +145,7 @@ const AdminLoans = () => {
       setError(null);
       const result = await backend.reject_loan(loanId);
       if ('Err' in result) {
-        throw new Error(result.Err);
+        throw new Error(errorMessage(result.Err));
       }
       await loadLoans();
       console.log('✅ Loan rejected successfully:', loanId);
@@ -180,7 +159,7 @@ const AdminLoans = () => {
 
   const handleViewLoan = async (loanId) => {
     try {
-      const loan = (await backend.get_all_loans()).find(l => l.id === loanId);
+      const [loan] = await backend.get_loan(loanId);
       if (!loan) {
         throw new Error('Loan not found');
       }
@@ -188,7 +167,7 @@ const AdminLoans = () => {
         id: loan.id || 'Unknown',
         documentId: loan.document_id || 'N/A',
         amount: loan.amount ? Number(loan.amount).toLocaleString() : '0',
-        interestRate: loan.interest_rate ? `${loan.interest_rate}%` : 'N/A',
+        interestRate: loan.interest_rate_bps !== undefined ? `${Number(loan.interest_rate_bps) / 100}%` : 'N/A',
         status: getLoanStatus(loan.status),
         createdAt: loan.created_at ? new Date(Number(loan.created_at) / 1000000).toLocaleString() : 'N/A',
         repaymentDate: loan.repayment_date ? new Date(Number(loan.repayment_date) / 1000000).toLocaleDateString() : 'N/A',
@@ -443,25 +422,6 @@ const AdminLoans = () => {
           </div>
         )}
       </div>
-      {/* Fund Canister Button */}
-      <div className="mt-8 flex justify-center">
-        <button
-          onClick={handleFundCanister}
-          disabled={funding}
-          className="group relative px-6 py-3 bg-gradient-to-r from-blue-500 to-cyan-600 text-white rounded-lg hover:from-blue-600 hover:to-cyan-700 transition-all duration-300 hover:scale-105 transform hover:-translate-y-0.5 shadow-lg hover:shadow-purple-500/25 disabled:opacity-50 disabled:cursor-not-allowed disabled:transform-none"
-          title="Fund Canister with Test Tokens"
-        >
-          <div className="absolute -inset-0.5 bg-gradient-to-r from-blue-500/20 to-cyan-400/20 rounded-lg blur opacity-0 group-hover:opacity-100 transition-opacity duration-300"></div>
-          <div className="relative flex items-center space-x-2">
-            {funding ? (
-              <Loader2 className="w-4 h-4 animate-spin" />
-            ) : (
-              <Wallet className="w-4 h-4" />
-            )}
-            <span>Fund Canister</span>
-          </div>
-        </button>
-      </div>
       <style jsx>{`
         .overflow-x-auto {
           overflow-x: auto;
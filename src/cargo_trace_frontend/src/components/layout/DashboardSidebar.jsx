@@ -12,6 +12,7 @@ import {
   RefreshCw,
 } from 'lucide-react';
 import { cargo_trace_backend as backend } from '../../../../declarations/cargo_trace_backend';
+import { errorMessage } from '../../services/backendService';
 
 const DashboardSidebar = ({ activeTab, setActiveTab, isMobileMenuOpen }) => {
   const [walletBalance, setWalletBalance] = useState(null);
@@ -35,7 +36,7 @@ const DashboardSidebar = ({ activeTab, setActiveTab, isMobileMenuOpen }) => {
       const balanceResult = await backend.get_wallet_balance_usd();
       console.log('Balance Result:', balanceResult);
       if ('Err' in balanceResult) {
-        throw new Error(balanceResult.Err);
+        throw new Error(errorMessage(balanceResult.Err));
       }
       if ('Ok' in balanceResult) {
         const balanceInUSD = parseFloat(balanceResult.Ok || 0).toFixed(2);
@@ -50,7 +51,7 @@ const DashboardSidebar = ({ activeTab, setActiveTab, isMobileMenuOpen }) => {
       if (loanResult) {
         const loan = loanResult;
         const loanAmountUSD = (Number(loan.amount || 0) / 100).toFixed(2);
-        const interestRate = Number(loan.interest_rate || 0);
+        const interestRate = Number(loan.interest_rate_bps || 0) / 100;
         const repaymentAmount = (
           (Number(loan.amount || 0) * (1 + interestRate / 100)) / 100
         ).toFixed(2);
@@ -26,12 +26,12 @@ import {
   Lock,
   Activity
 } from 'lucide-react';
-import { backendService } from '../../services/backendService';
+import { backendService, customsKey, errorMessage } from '../../services/backendService';
 
 const CustomsIntegration = () => {
   const [searchTerm, setSearchTerm] = useState('');
   const [filterStatus, setFilterStatus] = useState('all');
-  const [nftHash, setNftHash] = useState('');
+  const [documentId, setDocumentId] = useState('');
   const [acidNumber, setAcidNumber] = useState('');
   const [mappings, setMappings] = useState([]);
   const [verifications, setVerifications] = useState([]);
@@ -152,13 +152,13 @@ const CustomsIntegration = () => {
     if (e) e.preventDefault();
     
     try {
-      console.log('🚀 Starting link process...', { nftHash, acidNumber });
+      console.log('🚀 Starting link process...', { documentId, acidNumber });
       setSubmitting(true);
       setError(null);
       setSuccessMessage('');
       
       // Basic validation first
-      if (!nftHash || !acidNumber) {
+      if (!documentId || !acidNumber) {
         throw new Error('Please fill in all required fields');
       }
 
@@ -173,19 +173,19 @@ const CustomsIntegration = () => {
       }
 
       console.log('📞 Calling backend service...');
-      const result = await backendService.linkCargoxToAcid(nftHash, acidNumber);
+      const result = await backendService.linkCargoxToAcid(documentId.trim(), acidNumber);
       console.log('📋 Backend result:', result);
       
       if (result.Err) {
-        throw new Error(result.Err);
+        throw new Error(errorMessage(result.Err));
       }
 
       // Clear form
-      setNftHash('');
+      setDocumentId('');
       setAcidNumber('');
 
       // Show success message
-      setSuccessMessage(`CargoX NFT successfully linked to ACID! Mapping ID: ${result.Ok}`);
+      setSuccessMessage(`Document successfully linked to ACID! Mapping ID: ${result.Ok}`);
       setShowToast(true);
       setNewlyCreatedMapping(result.Ok);
       
@@ -209,7 +209,7 @@ const CustomsIntegration = () => {
     }
   };
 
-  const handleVerifyCustomsEntry = async (nftHash) => {
+  const handleVerifyCustomsEntry = async (key) => {
     try {
       setError(null);
       
@@ -217,23 +217,23 @@ const CustomsIntegration = () => {
         throw new Error('Backend service not initialized');
       }
 
-      const result = await backendService.verifyCustomsEntry(nftHash);
+      const result = await backendService.verifyCustomsEntry(key);
       
       if (result.Err) {
-        throw new Error(result.Err);
+        throw new Error(errorMessage(result.Err));
       }
 
       // Reload data
       await loadData();
       
-      console.log('✅ Customs entry verified successfully:', nftHash);
+      console.log('✅ Customs entry verified successfully:', key);
     } catch (err) {
       console.error('Failed to verify customs entry:', err);
       setError(err.message);
     }
   };
 
-  const handleRejectCustomsEntry = async (nftHash) => {
+  const handleRejectCustomsEntry = async (key) => {
     const reason = prompt('Please provide a reason for rejection:');
     if (!reason) return;
 
@@ -244,16 +244,16 @@ const CustomsIntegration = () => {
         throw new Error('Backend service not initialized');
       }
 
-      const result = await backendService.rejectCustomsEntry(nftHash, reason);
+      const result = await backendService.rejectCustomsEntry(key, reason);
       
       if (result.Err) {
-        throw new Error(result.Err);
+        throw new Error(errorMessage(result.Err));
       }
 
       // Reload data
       await loadData();
       
-      console.log('✅ Customs entry rejected successfully:', nftHash);
+      console.log('✅ Customs entry rejected successfully:', key);
     } catch (err) {
       console.error('Failed to reject customs entry:', err);
       setError(err.message);
@@ -493,14 +493,14 @@ const CustomsIntegration = () => {
               <div className="space-y-3">
                 <label className="flex items-center space-x-2 text-lg font-bold text-white">
                   <Hash className="w-5 h-5 text-blue-400" />
-                  <span>CargoX NFT Hash *</span>
+                  <span>Document ID *</span>
                 </label>
                 <input
                   type="text"
-                  value={nftHash}
-                  onChange={(e) => setNftHash(e.target.value)}
+                  value={documentId}
+                  onChange={(e) => setDocumentId(e.target.value)}
                   className="w-full px-6 py-4 bg-slate-700/50 border border-slate-600/50 rounded-xl text-white placeholder-slate-400 focus:border-blue-400/50 focus:ring-2 focus:ring-blue-400/20 focus:outline-none transition-all duration-300 font-mono disabled:opacity-50 hover:border-blue-400/30"
-                  placeholder="0x1234567890abcdef..."
+                  placeholder="DOC-00000000000000000001"
                   disabled={submitting}
                   required
                 />
@@ -738,7 +738,7 @@ const CustomsIntegration = () => {
                   </thead>
                   <tbody className="divide-y divide-slate-700/30">
                     {filteredMappings.map((mapping) => {
-                      const verification = verifications.find(v => v.nft_hash === mapping.nft_hash);
+                      const verification = verifications.find(v => customsKey(v) === customsKey(mapping));
                       const verificationStatus = verification ? getVerificationStatus(verification.verification_status) : 'pending';
                       const StatusIcon = getStatusIcon(verificationStatus);
                       
@@ -807,7 +807,7 @@ const CustomsIntegration = () => {
                           <td className="px-8 py-6">
                             <div className="flex items-center space-x-3">
                               <button 
-                                onClick={() => handleVerifyCustomsEntry(mapping.nft_hash)}
+                                onClick={() => handleVerifyCustomsEntry(customsKey(mapping))}
                                 disabled={verificationStatus === 'verified'}
                                 className="group/btn p-3 text-slate-400 hover:text-cyan-400 hover:bg-cyan-500/10 rounded-xl transition-all duration-300 disabled:opacity-50 disabled:cursor-not-allowed hover:scale-110 transform hover:-translate-y-1"
                                 title="Verify Entry"
@@ -815,7 +815,7 @@ const CustomsIntegration = () => {
                                 <CheckSquare size={18} className="group-hover/btn:scale-110 transition-transform duration-300" />
                               </button>
                               <button 
-                                onClick={() => handleRejectCustomsEntry(mapping.nft_hash)}
+                                onClick={() => handleRejectCustomsEntry(customsKey(mapping))}
                                 disabled={verificationStatus === 'rejected'}
                                 className="group/btn p-3 text-slate-400 hover:text-slate-400 hover:bg-slate-500/10 rounded-xl transition-all duration-300 disabled:opacity-50 disabled:cursor-not-allowed hover:scale-110 transform hover:-translate-y-1"
                                 title="Reject Entry"
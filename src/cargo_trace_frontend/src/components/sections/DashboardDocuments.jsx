@@ -21,6 +21,7 @@ import {
   Loader2
 } from 'lucide-react';
 import { cargo_trace_backend as backend } from '../../../../declarations/cargo_trace_backend';
+import { errorMessage } from '../../services/backendService';
 
 const DashboardDocuments = () => {
   const [searchTerm, setSearchTerm] = useState('');
@@ -67,7 +68,7 @@ const DashboardDocuments = () => {
         destination: 'Destination Country',
         shipper: 'Shipper Company',
         consignee: 'Consignee Company',
-        ethereumTxHash: doc.ethereum_tx_hash,
+        ethereumTxHash: doc.tx_hash,
         rawValue: doc.value_usd, // Keep as BigInt
         owner: doc.owner.toString()
       }));
@@ -165,10 +166,10 @@ const DashboardDocuments = () => {
       }
 
       // Submit document to backend
-      const result = await backend.submit_document(acidNumber, ethereumTxHash, BigInt(value));
+      const result = await backend.submit_document(acidNumber, ethereumTxHash, BigInt(value), []);
       
       if ('Err' in result) {
-        throw new Error(result.Err);
+        throw new Error(errorMessage(result.Err));
       }
 
       // Clear form
@@ -204,7 +205,7 @@ const DashboardDocuments = () => {
       const result = await backend.approve_document(documentId);
       
       if ('Err' in result) {
-        throw new Error(result.Err);
+        throw new Error(errorMessage(result.Err));
       }
 
       // Reload documents to reflect changes
@@ -230,7 +231,7 @@ const DashboardDocuments = () => {
       const doc = {
         id: backendDoc.id,
         acid: backendDoc.acid_number,
-        ethereumTxHash: backendDoc.ethereum_tx_hash,
+        ethereumTxHash: backendDoc.tx_hash,
         value: backendDoc.value_usd.toString(),  // Convert BigInt to string for display
         status: getDocumentStatus(backendDoc.status),
         createdAt: new Date(Number(backendDoc.created_at) / 1000000).toLocaleString(),
@@ -26,6 +26,7 @@ import {
   Loader2
 } from 'lucide-react';
 import { cargo_trace_backend as backend } from '../../../../declarations/cargo_trace_backend';
+import { errorMessage } from '../../services/backendService';
 
 const DashboardLoans = () => {
   const [searchTerm, setSearchTerm] = useState('');
@@ -62,7 +63,7 @@ const DashboardLoans = () => {
         documentId: loan.document_id,
         amount: `$${loan.amount.toString().toLocaleString()}`,
         collateral: 'Document NFT',
-        apr: `${loan.interest_rate}%`,
+        apr: `${Number(loan.interest_rate_bps) / 100}%`,
         status: getLoanStatus(loan.status),
         applicationDate: new Date(Number(loan.created_at) / 1000000).toLocaleDateString(),
         approvalDate: 'Approved' in loan.status ? new Date(Number(loan.created_at) / 1000000).toLocaleDateString() : null,
@@ -91,7 +92,7 @@ const DashboardLoans = () => {
           value: `$${doc.value_usd.toString().toLocaleString()}`,
           description: `Document for ACID: ${doc.acid_number}`,
           date: new Date(Number(doc.created_at) / 1000000).toLocaleDateString(),
-          ethereumTxHash: doc.ethereum_tx_hash || null,
+          ethereumTxHash: doc.tx_hash || null,
           rawValue: doc.value_usd
         }));
 
@@ -187,7 +188,7 @@ const DashboardLoans = () => {
       const result = await backend.request_loan(collateralDocument, BigInt(amount), repaymentTimestamp);
       
       if ('Err' in result) {
-        throw new Error(result.Err);
+        throw new Error(errorMessage(result.Err));
       }
 
       // Clear form
@@ -1,5 +1,6 @@
 import { useState } from "react";
 import { cargo_trace_backend } from "declarations/cargo_trace_backend";
+import { errorMessage } from "../services/backendService";
 
 export default function UploadDoc() {
   const [fileHash, setFileHash] = useState("");
@@ -14,7 +15,7 @@ export default function UploadDoc() {
 
   const transfer = async (id, newOwner) => {
     const result = await cargo_trace_backend.transfer_document(id, newOwner);
-    setMsg(result);
+    setMsg('Ok' in result ? result.Ok : errorMessage(result.Err));
   };
 
   return (
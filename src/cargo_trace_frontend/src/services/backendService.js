@@ -3,6 +3,55 @@ import { Actor, HttpAgent } from '@dfinity/agent';
 import { Principal } from '@dfinity/principal';
 
 
+// Readable text for a CargoTraceError variant; plain string errors pass through
+export function errorMessage(err) {
+    if (typeof err === 'string') {
+        return err;
+    }
+    const [kind, detail] = Object.entries(err)[0];
+    switch (kind) {
+        case 'NotFound': return `${detail.kind} ${detail.id} not found.`;
+        case 'Unauthorized': return 'Only the owner can do this.';
+        case 'InvalidState': return `Cannot move from ${detail.from} to ${detail.to}.`;
+        case 'InvalidAcid': return `Invalid ACID number ${detail.acid_number}.`;
+        case 'AcidLookupFailed': return `ACID lookup failed: ${detail}`;
+        case 'ChainCallFailed': return `Ethereum call failed: ${detail}`;
+        case 'AlreadyLinked': return `Document ${detail.document_id} is already linked to an ACID number.`;
+        case 'DocumentInUse': return `Document already backs loan ${detail.loan_id}.`;
+        case 'TransferNotFinal': return `Transfer ${detail.tx_hash} is not yet confirmed.`;
+        case 'AmountTooLarge': return `Loan amount cannot exceed ${detail.max} (80% of document value).`;
+        case 'InvalidArgument': return detail;
+        case 'InsufficientFunds': return 'Insufficient balance.';
+        case 'InsufficientAllowance': return `Insufficient allowance: approved ${detail.allowance}.`;
+        case 'LedgerError': return `Ledger transfer failed: ${Object.keys(detail)[0]}`;
+        case 'BatchFailed': return `Some documents failed: ${detail.failed.join(', ')}`;
+        case 'InProgress': return `${detail.id} is busy with another request; try again shortly.`;
+        default: return kind;
+    }
+}
+
+// Key of a CargoX mapping or customs verification: its document id, or the transaction hash of a
+// link made before links were keyed by document
+export function customsKey(record) {
+    const documentId = Array.isArray(record.document_id) ? record.document_id[0] : record.document_id;
+    return documentId || record.nft_hash;
+}
+
+// ListFilter with every field unset; candid needs each opt field present
+export const NO_FILTER = { status: [], owner: [], created_from: [], created_to: [], acid_number: [] };
+
+// Follows next_cursor until the canister reports no more pages
+export async function allPages(fetchPage) {
+    const items = [];
+    let cursor = [];
+    do {
+        const page = await fetchPage(cursor);
+        items.push(...page.items);
+        cursor = page.next_cursor;
+    } while (cursor.length > 0);
+    return items;
+}
+
 class BackendService {
     constructor() {
         this.agent = null;
@@ -81,6 +130,7 @@ class BackendService {
         const sampleMapping = {
             id: 'MAP-000001',
             nft_hash: '0x1234567890abcdef1234567890abcdef12345678',
+            document_id: null,
             acid_number: '123456789',
             verified: false,
             created_at: Date.now() - 86400000, // 1 day ago
@@ -92,6 +142,7 @@ class BackendService {
         const sampleVerification = {
             id: 'VER-000001',
             nft_hash: '0x1234567890abcdef1234567890abcdef12345678',
+            document_id: null,
             acid_number: '123456789',
             verification_status: { Pending: null },
             verified_at: null,
@@ -101,6 +152,35 @@ class BackendService {
         };
         mockData.customsVerifications.set(sampleMapping.nft_hash, sampleVerification);
 
+        // Only open entries can be settled, and only Pending documents move with them
+        const settleCustomsEntry = (key, status, customsData) => {
+            const mapping = mockData.cargoxMappings.get(key);
+            if (!mapping) {
+                return { Err: { NotFound: { kind: 'CargoXMapping', id: key } } };
+            }
+            const verification = mockData.customsVerifications.get(key);
+            if (!verification) {
+                return { Err: { NotFound: { kind: 'CustomsVerification', id: key } } };
+            }
+            const current = Object.keys(verification.verification_status)[0];
+            if (current !== 'Pending' && current !== 'UnderReview') {
+                return { Err: { InvalidState: { from: current, to: status } } };
+            }
+            const document = mapping.document_id && mockData.documents.get(mapping.document_id);
+            if (document && document.status.Pending === undefined) {
+                return { Err: { InvalidState: { from: Object.keys(document.status)[0], to: status } } };
+            }
+            if (document) {
+                document.status = { [status]: null };
+            }
+            mapping.verified = mapping.verified || status === 'Verified';
+            verification.verification_status = { [status]: null };
+            verification.verified_at = Date.now();
+            verification.verified_by = '2vxsx-fae';
+            verification.customs_data = customsData;
+            return { Ok: null };
+        };
+
         return {
             // ACID Validation
             validate_acid: async (acidNumber) => {
@@ -108,7 +188,7 @@ class BackendService {
                 
                 // Basic validation
                 if (acidNumber.length !== 9 || !/^\d+$/.test(acidNumber)) {
-                    return { Err: "Invalid ACID format. Must be 9 digits." };
+                    return { Err: { InvalidAcid: { acid_number: acidNumber } } };
                 }
 
                 // Check against static dataset (same as backend)
@@ -131,8 +211,11 @@ class BackendService {
             },
 
             // Document Management
-            submit_document: async (acidNumber, ethereumTxHash, valueUsd) => {
+            submit_document: async (acidNumber, ethereumTxHash, valueUsd, token = []) => {
                 console.log('📄 Submitting document:', { acidNumber, ethereumTxHash, valueUsd });
+                if (!/^0x[0-9a-fA-F]{64}$/.test(ethereumTxHash)) {
+                    return { Err: { InvalidArgument: `Invalid transaction hash ${ethereumTxHash}.` } };
+                }
                 
                 // Validate ACID first
                 const validation = await this.actor.validate_acid(acidNumber);
@@ -140,18 +223,21 @@ class BackendService {
                     return validation;
                 }
                 if (!validation.Ok) {
-                    return { Err: "Invalid ACID number." };
+                    return { Err: { InvalidAcid: { acid_number: acidNumber } } };
                 }
 
                 // Generate document ID
                 mockData.counters.document++;
-                const documentId = `DOC-${mockData.counters.document.toString().padStart(6, '0')}`;
+                const documentId = `DOC-${mockData.counters.document.toString().padStart(20, '0')}`;
                 
                 // Create document
                 const document = {
                     id: documentId,
                     acid_number: acidNumber,
-                    ethereum_tx_hash: ethereumTxHash,
+                    chain: token[0]?.network ?? 'ethereum',
+                    contract: token[0]?.contract ?? '',
+                    token_id: token[0]?.token_id ?? '',
+                    tx_hash: ethereumTxHash,
                     value_usd: valueUsd,
                     status: { Pending: null },
                     created_at: Date.now(),
@@ -177,7 +263,7 @@ class BackendService {
                 
                 const document = mockData.documents.get(documentId);
                 if (!document) {
-                    return { Err: "Document not found." };
+                    return { Err: { NotFound: { kind: 'Document', id: documentId } } };
                 }
                 
                 document.status = { NftMinted: null };
@@ -192,25 +278,25 @@ class BackendService {
                 
                 const document = mockData.documents.get(documentId);
                 if (!document) {
-                    return { Err: "Document not found." };
+                    return { Err: { NotFound: { kind: 'Document', id: documentId } } };
                 }
                 
                 if (document.status.NftMinted === undefined) {
-                    return { Err: "Document must be approved and NFT minted before requesting loan." };
+                    return { Err: { InvalidState: { from: Object.keys(document.status)[0], to: 'NftMinted' } } };
                 }
                 
                 if (amount > document.value_usd * 80 / 100) {
-                    return { Err: "Loan amount cannot exceed 80% of document value." };
+                    return { Err: { AmountTooLarge: { max: document.value_usd * 80 / 100 } } };
                 }
                 
                 mockData.counters.loan++;
-                const loanId = `LOAN-${mockData.counters.loan.toString().padStart(6, '0')}`;
+                const loanId = `LOAN-${mockData.counters.loan.toString().padStart(20, '0')}`;
                 
                 const loan = {
                     id: loanId,
                     document_id: documentId,
                     amount: amount,
-                    interest_rate: 4.5,
+                    interest_rate_bps: 450,
                     status: { Pending: null },
                     created_at: Date.now(),
                     borrower: '2vxsx-fae', // Mock principal
@@ -236,7 +322,7 @@ class BackendService {
                 
                 const loan = mockData.loans.get(loanId);
                 if (!loan) {
-                    return { Err: "Loan not found." };
+                    return { Err: { NotFound: { kind: 'Loan', id: loanId } } };
                 }
                 
                 loan.status = { Approved: null };
@@ -254,12 +340,12 @@ class BackendService {
                 
                 const loan = mockData.loans.get(loanId);
                 if (!loan) {
-                    return { Err: "Loan not found." };
+                    return { Err: { NotFound: { kind: 'Loan', id: loanId } } };
                 }
                 
                 const currentBalance = mockData.balances.get(loan.borrower) || 0;
                 if (currentBalance < amount) {
-                    return { Err: "Insufficient balance for repayment." };
+                    return { Err: { InsufficientFunds: { balance: BigInt(currentBalance) } } };
                 }
                 
                 // Deduct from balance
@@ -293,7 +379,7 @@ class BackendService {
                 
                 const currentBalance = mockData.balances.get('2vxsx-fae') || 0;
                 if (currentBalance < amount) {
-                    return { Err: "Insufficient balance." };
+                    return { Err: { InsufficientFunds: { balance: BigInt(currentBalance) } } };
                 }
                 
                 // Deduct from sender
@@ -307,19 +393,15 @@ class BackendService {
             },
 
             // Transfer Management
-            get_transfers: async () => {
-                return []; // Mock empty transfers
-            },
-
             ingest_transfer: async (transferPayload) => {
                 console.log('📦 Ingesting transfer:', transferPayload);
                 return { Ok: null };
             },
 
             // Customs Integration Functions
-            link_cargox_to_acid: async (nftHash, acidNumber) => {
+            link_cargox_to_acid: async (documentId, acidNumber) => {
                 try {
-                    console.log('🔗 Linking CargoX to ACID:', { nftHash, acidNumber });
+                    console.log('🔗 Linking CargoX to ACID:', { documentId, acidNumber });
                     
                     // Validate ACID first
                     const validation = await this.actor.validate_acid(acidNumber);
@@ -329,22 +411,28 @@ class BackendService {
                         return validation;
                     }
                     if (!validation.Ok) {
-                        return { Err: "Invalid ACID number." };
+                        return { Err: { InvalidAcid: { acid_number: acidNumber } } };
                     }
 
+                const document = mockData.documents.get(documentId);
+                if (!document) {
+                    return { Err: { NotFound: { kind: 'Document', id: documentId } } };
+                }
+
                 // Check if mapping already exists
-                const existingMapping = mockData.cargoxMappings.get(nftHash);
-                if (existingMapping) {
-                    return { Err: "CargoX NFT hash already linked to an ACID number." };
+                const existingMapping = mockData.cargoxMappings.get(documentId);
+                if (existingMapping && existingMapping.acid_number) {
+                    return { Err: { AlreadyLinked: { document_id: documentId } } };
                 }
 
                 // Create mapping
                 mockData.counters.mapping = (mockData.counters.mapping || 0) + 1;
-                const mappingId = `MAP-${mockData.counters.mapping.toString().padStart(6, '0')}`;
+                const mappingId = existingMapping ? existingMapping.id : `MAP-${mockData.counters.mapping.toString().padStart(20, '0')}`;
                 
                 const mapping = {
                     id: mappingId,
-                    nft_hash: nftHash,
+                    nft_hash: document.tx_hash,
+                    document_id: documentId,
                     acid_number: acidNumber,
                     verified: false,
                     created_at: Date.now(),
@@ -352,15 +440,16 @@ class BackendService {
                     customs_entry_id: null
                 };
 
-                mockData.cargoxMappings.set(nftHash, mapping);
+                mockData.cargoxMappings.set(documentId, mapping);
 
                 // Create customs verification record
                 mockData.counters.verification = (mockData.counters.verification || 0) + 1;
-                const verificationId = `VER-${mockData.counters.verification.toString().padStart(6, '0')}`;
+                const verificationId = `VER-${mockData.counters.verification.toString().padStart(20, '0')}`;
                 
                 const verification = {
                     id: verificationId,
-                    nft_hash: nftHash,
+                    nft_hash: document.tx_hash,
+                    document_id: documentId,
                     acid_number: acidNumber,
                     verification_status: { Pending: null },
                     verified_at: null,
@@ -369,17 +458,17 @@ class BackendService {
                     verified_by: null
                 };
 
-                mockData.customsVerifications.set(nftHash, verification);
+                mockData.customsVerifications.set(documentId, verification);
 
                 return { Ok: mappingId };
                 } catch (error) {
                     console.error('Error in link_cargox_to_acid:', error);
-                    return { Err: `Failed to link CargoX to ACID: ${error.message}` };
+                    return { Err: { InvalidArgument: `Failed to link CargoX to ACID: ${error.message}` } };
                 }
             },
 
-            get_cargox_mapping: async (nftHash) => {
-                return mockData.cargoxMappings.get(nftHash) || null;
+            get_cargox_mapping: async (documentId) => {
+                return mockData.cargoxMappings.get(documentId) || null;
             },
 
             get_my_cargox_mappings: async () => {
@@ -387,75 +476,25 @@ class BackendService {
             },
 
             get_all_cargox_mappings: async () => {
-                return Array.from(mockData.cargoxMappings.values());
+                return { items: Array.from(mockData.cargoxMappings.values()), next_cursor: [] };
             },
 
-            verify_customs_entry: async (nftHash) => {
-                console.log('✅ Verifying customs entry:', nftHash);
-                
-                const mapping = mockData.cargoxMappings.get(nftHash);
-                if (!mapping) {
-                    return { Err: "CargoX mapping not found" };
-                }
-
-                // Update mapping as verified
-                mapping.verified = true;
-                mockData.cargoxMappings.set(nftHash, mapping);
-
-                // Update customs verification status
-                const verification = mockData.customsVerifications.get(nftHash);
-                if (verification) {
-                    verification.verification_status = { Verified: null };
-                    verification.verified_at = Date.now();
-                    verification.verified_by = '2vxsx-fae';
-                    verification.customs_data = "Customs entry verified manually";
-                    mockData.customsVerifications.set(nftHash, verification);
-                }
-
-                // Find and update related document by ACID number
-                const relatedDocument = Array.from(mockData.documents.values())
-                    .find(doc => doc.acid_number === acidNumber);
-                
-                if (relatedDocument) {
-                    relatedDocument.status = { Verified: null };
-                    mockData.documents.set(relatedDocument.id, relatedDocument);
-                    console.log('📄 Updated related document status to Verified:', relatedDocument.id);
-                }
-
-                return { Ok: null };
+            verify_customs_entry: async (documentId) => {
+                console.log('✅ Verifying customs entry:', documentId);
+                return settleCustomsEntry(documentId, 'Verified', 'Customs entry verified manually');
             },
 
-            reject_customs_entry: async (nftHash, reason) => {
-                console.log('❌ Rejecting customs entry:', { nftHash, reason });
-                
-                const verification = mockData.customsVerifications.get(nftHash);
-                if (verification) {
-                    verification.verification_status = { Rejected: null };
-                    verification.verified_at = Date.now();
-                    verification.verified_by = '2vxsx-fae';
-                    verification.customs_data = `Rejected: ${reason}`;
-                    mockData.customsVerifications.set(nftHash, verification);
-                }
-
-                // Find and update related document by ACID number
-                const relatedDocument = Array.from(mockData.documents.values())
-                    .find(doc => doc.acid_number === acidNumber);
-                
-                if (relatedDocument) {
-                    relatedDocument.status = { Rejected: null };
-                    mockData.documents.set(relatedDocument.id, relatedDocument);
-                    console.log('📄 Updated related document status to Rejected:', relatedDocument.id);
-                }
-
-                return { Ok: null };
+            reject_customs_entry: async (documentId, reason) => {
+                console.log('❌ Rejecting customs entry:', { documentId, reason });
+                return settleCustomsEntry(documentId, 'Rejected', `Rejected: ${reason}`);
             },
 
-            get_customs_verification: async (nftHash) => {
-                return mockData.customsVerifications.get(nftHash) || null;
+            get_customs_verification: async (documentId) => {
+                return mockData.customsVerifications.get(documentId) || null;
             },
 
             get_all_customs_verifications: async () => {
-                return Array.from(mockData.customsVerifications.values());
+                return { items: Array.from(mockData.customsVerifications.values()), next_cursor: [] };
             },
 
             get_pending_customs_verifications: async () => {
@@ -468,11 +507,11 @@ class BackendService {
                 
                 const document = mockData.documents.get(documentId);
                 if (!document) {
-                    return { Err: "Document not found" };
+                    return { Err: { NotFound: { kind: 'Document', id: documentId } } };
                 }
 
                 if (document.status.Verified === undefined) {
-                    return { Err: "Document must be verified before triggering lending" };
+                    return { Err: { InvalidState: { from: Object.keys(document.status)[0], to: 'NftMinted' } } };
                 }
 
                 // Mark document as ready for lending
@@ -493,22 +532,17 @@ class BackendService {
                     if (result.Ok) {
                         successful.push(documentId);
                     } else {
-                        failed.push(`${documentId}: ${result.Err}`);
+                        failed.push(documentId);
                     }
                 }
 
                 if (failed.length === 0) {
                     return { Ok: successful };
                 } else {
-                    return { Err: `Some documents failed: ${failed.join(', ')}` };
+                    return { Err: { BatchFailed: { failed } } };
                 }
             },
 
-            get_document_by_nft_hash: async (nftHash) => {
-                return Array.from(mockData.documents.values())
-                    .find(doc => doc.ethereum_tx_hash === nftHash) || null;
-            },
-
             get_verification_stats: async () => {
                 const verifications = Array.from(mockData.customsVerifications.values());
                 const pending = verifications.filter(v => v.verification_status.Pending !== undefined).length;
@@ -537,11 +571,12 @@ class BackendService {
     }
 
     // Document Management
-    async submitDocument(acidNumber, ethereumTxHash, valueUsd) {
+    // `token` ({ network, contract, token_id }) is needed when the transaction moved several tokens
+    async submitDocument(acidNumber, ethereumTxHash, valueUsd, token = null) {
         if (!this.isInitialized) {
             throw new Error('Backend service not initialized');
         }
-        return await this.actor.submit_document(acidNumber, ethereumTxHash, valueUsd);
+        return await this.actor.submit_document(acidNumber, ethereumTxHash, valueUsd, token ? [token] : []);
     }
 
     async getDocument(documentId) {
@@ -624,13 +659,6 @@ class BackendService {
     }
 
     // Transfer Management
-    async getTransfers() {
-        if (!this.isInitialized) {
-            throw new Error('Backend service not initialized');
-        }
-        return await this.actor.get_transfers();
-    }
-
     async ingestTransfer(transferPayload) {
         if (!this.isInitialized) {
             throw new Error('Backend service not initialized');
@@ -639,19 +667,19 @@ class BackendService {
     }
 
     // Customs Integration Methods
-    async linkCargoxToAcid(nftHash, acidNumber) {
+    async linkCargoxToAcid(documentId, acidNumber) {
         if (!this.isInitialized) {
             throw new Error('Backend service not initialized');
         }
-        console.log('🔗 Calling linkCargoxToAcid with:', { nftHash, acidNumber });
-        return await this.actor.link_cargox_to_acid(nftHash, acidNumber);
+        console.log('🔗 Calling linkCargoxToAcid with:', { documentId, acidNumber });
+        return await this.actor.link_cargox_to_acid(documentId, acidNumber);
     }
 
-    async getCargoxMapping(nftHash) {
+    async getCargoxMapping(documentId) {
         if (!this.isInitialized) {
             throw new Error('Backend service not initialized');
         }
-        return await this.actor.get_cargox_mapping(nftHash);
+        return await this.actor.get_cargox_mapping(documentId);
     }
 
     async getMyCargoxMappings() {
@@ -665,35 +693,35 @@ class BackendService {
         if (!this.isInitialized) {
             throw new Error('Backend service not initialized');
         }
-        return await this.actor.get_all_cargox_mappings();
+        return await allPages((cursor) => this.actor.get_all_cargox_mappings(NO_FILTER, cursor, []));
     }
 
-    async verifyCustomsEntry(nftHash) {
+    async verifyCustomsEntry(documentId) {
         if (!this.isInitialized) {
             throw new Error('Backend service not initialized');
         }
-        return await this.actor.verify_customs_entry(nftHash);
+        return await this.actor.verify_customs_entry(documentId);
     }
 
-    async rejectCustomsEntry(nftHash, reason) {
+    async rejectCustomsEntry(documentId, reason) {
         if (!this.isInitialized) {
             throw new Error('Backend service not initialized');
         }
-        return await this.actor.reject_customs_entry(nftHash, reason);
+        return await this.actor.reject_customs_entry(documentId, reason);
     }
 
-    async getCustomsVerification(nftHash) {
+    async getCustomsVerification(documentId) {
         if (!this.isInitialized) {
             throw new Error('Backend service not initialized');
         }
-        return await this.actor.get_customs_verification(nftHash);
+        return await this.actor.get_customs_verification(documentId);
     }
 
     async getAllCustomsVerifications() {
         if (!this.isInitialized) {
             throw new Error('Backend service not initialized');
         }
-        return await this.actor.get_all_customs_verifications();
+        return await allPages((cursor) => this.actor.get_all_customs_verifications(NO_FILTER, cursor, []));
     }
 
     async getPendingCustomsVerifications() {
@@ -717,13 +745,6 @@ class BackendService {
         return await this.actor.batch_trigger_lending(documentIds);
     }
 
-    async getDocumentByNftHash(nftHash) {
-        if (!this.isInitialized) {
-            throw new Error('Backend service not initialized');
-        }
-        return await this.actor.get_document_by_nft_hash(nftHash);
-    }
-
     async getVerificationStats() {
         if (!this.isInitialized) {
             throw new Error('Backend service not initialized');
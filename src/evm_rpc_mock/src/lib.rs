@@ -0,0 +1,204 @@
+// Answers the EVM RPC methods the backend uses from a fixed set of CargoX transfer logs, so the
+// EVM RPC code path can be exercised in PocketIC without HTTP outcalls.
+use candid::{CandidType, Deserialize, Nat, Reserved};
+use ic_cdk_macros::update;
+use std::cell::Cell;
+
+const FINALIZED_BLOCK: u64 = 20_000_100;
+const LATEST_BLOCK: u64 = 20_000_110;
+const CONTRACT: &str = "0xd4190dd1da460fc7bc41a792e688604778820ac9";
+const TRANSFER_TOPIC: &str = "0xddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef";
+const TOKEN_URI: &str = "ipfs://cargox/7";
+// The real canister runs on the 34-node fiduciary subnet and assumes 2 MB when no estimate is given
+const SUBNET_SIZE: u128 = 34;
+const DEFAULT_RESPONSE_SIZE_ESTIMATE: u64 = 2_000_000;
+
+#[derive(CandidType, Deserialize)]
+enum BlockTag {
+    Latest,
+    Finalized,
+    Number(Nat),
+}
+
+#[derive(CandidType, Deserialize)]
+struct GetLogsArgs {
+    #[serde(rename = "fromBlock")]
+    from_block: Option<BlockTag>,
+    #[serde(rename = "toBlock")]
+    to_block: Option<BlockTag>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct LogEntry {
+    address: String,
+    topics: Vec<String>,
+    #[serde(rename = "blockNumber")]
+    block_number: Option<Nat>,
+    #[serde(rename = "blockHash")]
+    block_hash: Option<String>,
+    #[serde(rename = "transactionHash")]
+    transaction_hash: Option<String>,
+    #[serde(rename = "logIndex")]
+    log_index: Option<Nat>,
+    removed: bool,
+}
+
+#[derive(CandidType, Deserialize)]
+struct Block {
+    number: Nat,
+    hash: String,
+}
+
+#[derive(CandidType, Deserialize)]
+enum ProviderError {
+    TooFewCycles { expected: Nat, received: Nat },
+}
+
+#[derive(CandidType, Deserialize)]
+enum RpcError {
+    ProviderError(ProviderError),
+    ValidationError(String),
+}
+
+#[derive(CandidType, Deserialize)]
+enum EthMainnetService {
+    Alchemy,
+    Ankr,
+    BlockPi,
+    Cloudflare,
+    PublicNode,
+    Llama,
+}
+
+#[derive(CandidType, Deserialize)]
+enum RpcServices {
+    EthMainnet(Option<Vec<EthMainnetService>>),
+}
+
+#[derive(CandidType, Deserialize)]
+struct RpcConfig {
+    #[serde(rename = "responseSizeEstimate")]
+    response_size_estimate: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize)]
+enum RpcService {
+    EthMainnet(EthMainnetService),
+}
+
+#[derive(CandidType, Deserialize)]
+enum MultiRpcResult<T> {
+    Consistent(Result<T, RpcError>),
+    Inconsistent(Vec<(RpcService, Result<T, RpcError>)>),
+}
+
+thread_local! {
+    // When set, every call reports that the providers disagreed
+    static INCONSISTENT: Cell<bool> = const { Cell::new(false) };
+    // Blocks from this height on have been replaced by a reorg
+    static REORG_FROM: Cell<u64> = const { Cell::new(u64::MAX) };
+}
+
+fn block_hash(number: u64) -> String {
+    let fork = if number >= REORG_FROM.with(|r| r.get()) { 1 } else { 0 };
+    format!("0x{:02x}{:062x}", fork, number)
+}
+
+// The response part of the HTTP outcall price, once per provider; the backend must attach at least this
+fn price(services: &RpcServices, config: &Option<RpcConfig>) -> u128 {
+    let RpcServices::EthMainnet(providers) = services;
+    let providers = providers.as_ref().map_or(3, Vec::len) as u128;
+    let estimate = config.as_ref().and_then(|c| c.response_size_estimate).unwrap_or(DEFAULT_RESPONSE_SIZE_ESTIMATE);
+    providers * ((3_000_000 + 60_000 * SUBNET_SIZE) * SUBNET_SIZE + 800 * SUBNET_SIZE * estimate as u128)
+}
+
+fn respond<T>(services: RpcServices, config: Option<RpcConfig>, result: impl Fn() -> T) -> MultiRpcResult<T> {
+    let expected = price(&services, &config);
+    let received = ic_cdk::api::call::msg_cycles_available128();
+    if received < expected {
+        let error = ProviderError::TooFewCycles { expected: Nat::from(expected), received: Nat::from(received) };
+        return MultiRpcResult::Consistent(Err(RpcError::ProviderError(error)));
+    }
+    ic_cdk::api::call::msg_cycles_accept128(expected);
+    if INCONSISTENT.with(|i| i.get()) {
+        MultiRpcResult::Inconsistent(vec![
+            (RpcService::EthMainnet(EthMainnetService::Alchemy), Ok(result())),
+            (RpcService::EthMainnet(EthMainnetService::Ankr), Err(RpcError::ValidationError("stale".to_string()))),
+        ])
+    } else {
+        MultiRpcResult::Consistent(Ok(result()))
+    }
+}
+
+fn topic(value: u64) -> String {
+    format!("0x{:064x}", value)
+}
+
+fn transfer_log(block: u64, token_id: u64, log_index: u64) -> LogEntry {
+    LogEntry {
+        address: CONTRACT.to_string(),
+        topics: vec![TRANSFER_TOPIC.to_string(), topic(0x11), topic(0x22), topic(token_id)],
+        block_number: Some(Nat::from(block)),
+        block_hash: Some(block_hash(block)),
+        transaction_hash: Some(format!("0x{:064x}", block)),
+        log_index: Some(Nat::from(log_index)),
+        removed: false,
+    }
+}
+
+fn fixture_logs() -> Vec<LogEntry> {
+    let mut fungible = transfer_log(20_000_020, 0, 0);
+    fungible.topics.truncate(3);
+    vec![
+        transfer_log(20_000_010, 7, 1),
+        fungible,
+        transfer_log(20_000_040, 8, 0),
+        transfer_log(20_000_090, 7, 4),
+    ]
+}
+
+fn block_number(tag: Option<BlockTag>, default: u64) -> u64 {
+    match tag {
+        Some(BlockTag::Number(n)) => u64::try_from(n.0).unwrap(),
+        Some(BlockTag::Latest) => LATEST_BLOCK,
+        Some(BlockTag::Finalized) => FINALIZED_BLOCK,
+        None => default,
+    }
+}
+
+#[update(name = "eth_getBlockByNumber")]
+fn eth_get_block_by_number(services: RpcServices, config: Option<RpcConfig>, tag: BlockTag) -> MultiRpcResult<Block> {
+    let number = block_number(Some(tag), LATEST_BLOCK);
+    respond(services, config, || Block { number: Nat::from(number), hash: block_hash(number) })
+}
+
+#[update(name = "eth_getLogs")]
+fn eth_get_logs(services: RpcServices, config: Option<RpcConfig>, args: GetLogsArgs) -> MultiRpcResult<Vec<LogEntry>> {
+    let from = block_number(args.from_block, 0);
+    let to = block_number(args.to_block, FINALIZED_BLOCK);
+    respond(services, config, || {
+        fixture_logs()
+            .into_iter()
+            .filter(|log| (from..=to).contains(&u64::try_from(log.block_number.clone().unwrap().0).unwrap()))
+            .collect()
+    })
+}
+
+// Returns tokenURI(uint256) for any call as an ABI-encoded string
+#[update(name = "eth_call")]
+fn eth_call(services: RpcServices, config: Option<RpcConfig>, _args: Reserved) -> MultiRpcResult<String> {
+    respond(services, config, || {
+        let data: String = TOKEN_URI.bytes().map(|b| format!("{:02x}", b)).collect();
+        format!("0x{:064x}{:064x}{:0<64}", 32, TOKEN_URI.len(), data)
+    })
+}
+
+#[update]
+fn reorg_from(block: u64) {
+    REORG_FROM.with(|r| r.set(block));
+}
+
+#[update]
+fn set_inconsistent(inconsistent: bool) {
+    INCONSISTENT.with(|i| i.set(inconsistent));
+}
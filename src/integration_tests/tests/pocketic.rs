@@ -0,0 +1,1707 @@
+use candid::{decode_one, encode_args, encode_one, CandidType, Deserialize, Nat, Principal};
+use pocket_ic::{PocketIc, WasmResult};
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+
+const BACKEND_WASM: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../../target/wasm32-unknown-unknown/release/cargo_trace_backend.wasm");
+const DAY: Duration = Duration::from_secs(86_400);
+const LOAN_TERM_DAYS: u32 = 30;
+
+#[test]
+fn test_backend_with_pocketic() {
+    let pic = PocketIc::new();
+
+    let canister_id = pic.create_canister();
+    pic.add_cycles(canister_id, 2_000_000_000_000);
+    pic.install_canister(canister_id, backend_wasm(), encode_args(()).unwrap(), None);
+
+    let ids: Vec<u64> = query(&pic, canister_id, Principal::anonymous(), "get_all_ids");
+    assert!(ids.is_empty());
+}
+
+// ---- ICRC-1 ledger ----
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct Account {
+    owner: Principal,
+    subaccount: Option<Vec<u8>>,
+}
+
+#[derive(CandidType)]
+struct ArchiveOptions {
+    num_blocks_to_archive: u64,
+    trigger_threshold: u64,
+    controller_id: Principal,
+}
+
+#[derive(CandidType)]
+struct FeatureFlags {
+    icrc2: bool,
+}
+
+#[derive(CandidType)]
+struct LedgerInitArgs {
+    minting_account: Account,
+    transfer_fee: Nat,
+    token_symbol: String,
+    token_name: String,
+    metadata: Vec<(String, String)>,
+    initial_balances: Vec<(Account, Nat)>,
+    feature_flags: Option<FeatureFlags>,
+    archive_options: ArchiveOptions,
+}
+
+#[derive(CandidType)]
+enum LedgerArg {
+    Init(LedgerInitArgs),
+}
+
+#[derive(CandidType, Deserialize, Debug, PartialEq)]
+enum LoanStatus {
+    Pending,
+    Approved,
+    Active,
+    Repaid,
+    Defaulted,
+    Rejected,
+    TransferPending,
+    TransferFailed,
+}
+
+#[derive(CandidType, Deserialize, Debug, PartialEq)]
+enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    TemporarilyUnavailable,
+    Duplicate { duplicate_of: Nat },
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Deserialize, Debug, PartialEq)]
+enum CargoTraceError {
+    NotFound { kind: String, id: String },
+    Unauthorized,
+    InvalidState { from: String, to: String },
+    InvalidAcid { acid_number: String },
+    AcidLookupFailed(String),
+    ChainCallFailed(String),
+    AlreadyLinked { document_id: String },
+    DocumentInUse { loan_id: String },
+    TransferNotFinal { tx_hash: String },
+    AmountTooLarge { max: u64 },
+    InvalidArgument(String),
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    LedgerError(TransferError),
+    BatchFailed { failed: Vec<String> },
+    InProgress { id: String },
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+struct Loan {
+    status: LoanStatus,
+    transfer_block_height: Option<Nat>,
+    transfer_error: Option<TransferError>,
+    interest_outstanding: u64,
+    late_penalty: Option<u64>,
+}
+
+fn account(owner: Principal) -> Account {
+    Account { owner, subaccount: None }
+}
+
+fn ledger_wasm() -> Vec<u8> {
+    let path = std::env::var("ICRC1_LEDGER_WASM")
+        .unwrap_or(concat!(env!("CARGO_MANIFEST_DIR"), "/../../.dfx/local/canisters/ledger/ledger.wasm.gz").to_string());
+    std::fs::read(&path)
+        .unwrap_or_else(|_| panic!("ICRC-1 ledger wasm not found at {}; run `dfx deploy ledger` or set ICRC1_LEDGER_WASM", path))
+}
+
+fn call<R: for<'a> Deserialize<'a> + CandidType>(
+    pic: &PocketIc,
+    canister_id: Principal,
+    sender: Principal,
+    method: &str,
+    payload: Vec<u8>,
+) -> R {
+    match pic.update_call(canister_id, sender, method, payload) {
+        Ok(WasmResult::Reply(bytes)) => decode_one(&bytes).unwrap(),
+        Ok(WasmResult::Reject(msg)) => panic!("{} rejected: {}", method, msg),
+        Err(e) => panic!("{} failed: {:?}", method, e),
+    }
+}
+
+fn backend_wasm() -> Vec<u8> {
+    std::fs::read(BACKEND_WASM)
+        .expect("Run `cargo build --target wasm32-unknown-unknown --release` first")
+}
+
+#[derive(CandidType)]
+struct AcidRecordInput {
+    acid_number: String,
+    importer_tax_id: String,
+    exporter_cargox_id: String,
+    hs_codes: Vec<String>,
+    expires_at: u64,
+}
+
+fn nanos_from_now(pic: &PocketIc, offset: Duration) -> u64 {
+    (pic.get_time() + offset).duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64
+}
+
+fn register_acid(pic: &PocketIc, backend: Principal, admin: Principal, acid_number: &str, valid_for: Duration) {
+    let record = AcidRecordInput {
+        acid_number: acid_number.to_string(),
+        importer_tax_id: "100-200-300".to_string(),
+        exporter_cargox_id: "0xexporter".to_string(),
+        hs_codes: vec!["851712".to_string()],
+        expires_at: nanos_from_now(pic, valid_for),
+    };
+    let imported: Result<u64, String> = call(pic, backend, admin, "import_acid_records", encode_one(vec![record]).unwrap());
+    assert_eq!(imported, Ok(1));
+}
+
+// Installs the backend with an admin-controlled canister and bootstraps that admin
+fn setup_backend() -> (PocketIc, Principal, Principal) {
+    let pic = PocketIc::new();
+    let admin = Principal::from_slice(&[1; 29]);
+
+    let backend = pic.create_canister_with_settings(Some(admin), None);
+    pic.add_cycles(backend, 2_000_000_000_000);
+    pic.install_canister(backend, backend_wasm(), encode_args(()).unwrap(), Some(admin));
+    let _: Result<(), String> = call(&pic, backend, admin, "bootstrap_admin", encode_one(admin).unwrap());
+    register_acid(&pic, backend, admin, "123456789", DAY * 365);
+
+    (pic, backend, admin)
+}
+
+// Installs the backend and an ICRC-1 ledger that credits the backend with `canister_funds`
+fn setup_with_ledger(canister_funds: u64) -> (PocketIc, Principal, Principal, Principal) {
+    let (pic, backend, admin) = setup_backend();
+
+    let ledger = pic.create_canister_with_settings(Some(admin), None);
+    pic.add_cycles(ledger, 2_000_000_000_000);
+    let init = LedgerArg::Init(LedgerInitArgs {
+        minting_account: account(admin),
+        transfer_fee: Nat::from(10_000u64),
+        token_symbol: "TCIP".to_string(),
+        token_name: "Test CargoTrace ICP".to_string(),
+        metadata: vec![],
+        initial_balances: vec![(account(backend), Nat::from(canister_funds))],
+        feature_flags: Some(FeatureFlags { icrc2: true }),
+        archive_options: ArchiveOptions {
+            num_blocks_to_archive: 1000,
+            trigger_threshold: 2000,
+            controller_id: admin,
+        },
+    });
+    pic.install_canister(ledger, ledger_wasm(), encode_one(init).unwrap(), Some(admin));
+
+    let _: Result<(), CargoTraceError> = call(&pic, backend, admin, "init_ledger_principal", encode_one(ledger.to_text()).unwrap());
+
+    (pic, backend, ledger, admin)
+}
+
+fn grant_borrower(pic: &PocketIc, backend: Principal, admin: Principal, borrower: Principal) {
+    let granted: Result<(), String> = call(pic, backend, admin, "grant_role", encode_args((borrower, Role::Borrower)).unwrap());
+    assert_eq!(granted, Ok(()));
+}
+
+// Submits and approves a document, then requests and approves a loan against it
+fn approve_new_loan(pic: &PocketIc, backend: Principal, admin: Principal, borrower: Principal, amount: u64) -> (String, Result<(), CargoTraceError>) {
+    grant_borrower(pic, backend, admin, borrower);
+    let document_id: Result<String, CargoTraceError> = call(pic, backend, borrower, "submit_document",
+        encode_args(("123456789".to_string(), tx_hash("abc"), 1_000_000u64)).unwrap());
+    let document_id = document_id.unwrap();
+    let _: Result<(), CargoTraceError> = call(pic, backend, admin, "approve_document", encode_one(document_id.clone()).unwrap());
+
+    let repayment_date = nanos_from_now(pic, DAY * LOAN_TERM_DAYS);
+    let loan_id: Result<String, CargoTraceError> = call(pic, backend, borrower, "request_loan",
+        encode_args((document_id, amount, repayment_date)).unwrap());
+    let loan_id = loan_id.unwrap();
+    let result = call(pic, backend, admin, "approve_loan", encode_one(loan_id.clone()).unwrap());
+    (loan_id, result)
+}
+
+#[test]
+fn test_approve_loan_disburses_through_icrc1_ledger() {
+    let (pic, backend, ledger, admin) = setup_with_ledger(1_000_000_000_000);
+    let borrower = Principal::from_slice(&[2; 29]);
+
+    // 50,000 USD cents are 50,000,000,000 e8s
+    let (loan_id, result) = approve_new_loan(&pic, backend, admin, borrower, 50_000);
+    assert_eq!(result, Ok(()));
+
+    let balance: Nat = call(&pic, ledger, borrower, "icrc1_balance_of", encode_one(account(borrower)).unwrap());
+    assert_eq!(balance, Nat::from(50_000_000_000u64));
+
+    let loan: Option<Loan> = call(&pic, backend, borrower, "get_loan", encode_one(loan_id).unwrap());
+    let loan = loan.unwrap();
+    assert_eq!(loan.status, LoanStatus::Active);
+    assert!(loan.transfer_block_height.is_some());
+    assert!(loan.transfer_error.is_none());
+}
+
+#[test]
+fn test_ledger_transfer_error_is_recorded_on_loan() {
+    let (pic, backend, _ledger, admin) = setup_with_ledger(0);
+    let borrower = Principal::from_slice(&[2; 29]);
+
+    let (loan_id, result) = approve_new_loan(&pic, backend, admin, borrower, 50_000);
+    assert!(matches!(result, Err(CargoTraceError::LedgerError(TransferError::InsufficientFunds { .. }))));
+
+    let loan: Option<Loan> = call(&pic, backend, borrower, "get_loan", encode_one(loan_id).unwrap());
+    let loan = loan.unwrap();
+    assert_eq!(loan.status, LoanStatus::TransferFailed);
+    assert!(matches!(loan.transfer_error, Some(TransferError::InsufficientFunds { .. })));
+}
+
+// ---- Upgrades ----
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct TransferPayload {
+    network: String,
+    contract: String,
+    tx_hash: String,
+    block_number: u64,
+    token_id: String,
+    from: String,
+    to: String,
+    log_index: u64,
+    block_hash: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Debug, PartialEq)]
+enum IngestOutcome {
+    New,
+    Duplicate,
+    Rejected(String),
+}
+
+fn query<R: for<'a> Deserialize<'a> + CandidType>(pic: &PocketIc, canister_id: Principal, sender: Principal, method: &str) -> R {
+    match pic.query_call(canister_id, sender, method, encode_args(()).unwrap()) {
+        Ok(WasmResult::Reply(bytes)) => decode_one(&bytes).unwrap(),
+        Ok(WasmResult::Reject(msg)) => panic!("{} rejected: {}", method, msg),
+        Err(e) => panic!("{} failed: {:?}", method, e),
+    }
+}
+
+#[test]
+fn test_upgrade_preserves_state_and_id_counters() {
+    let (pic, backend, admin) = setup_backend();
+    let user = Principal::from_slice(&[2; 29]);
+
+    let _: () = call(&pic, backend, user, "save_principal", encode_one(user).unwrap());
+    let file_id: u64 = call(&pic, backend, user, "upload_document",
+        encode_args(("bill.pdf".to_string(), "0xhash".to_string(), "alice".to_string())).unwrap());
+    let transfer = TransferPayload {
+        network: "ethereum".to_string(),
+        contract: "0xd4190DD1dA460fC7Bc41a792e688604778820aC9".to_string(),
+        tx_hash: "0xtx".to_string(),
+        block_number: 20_000_001,
+        token_id: "42".to_string(),
+        from: "0xfrom".to_string(),
+        to: "0xto".to_string(),
+        log_index: 3,
+        block_hash: None,
+    };
+    let _: IngestOutcome = call(&pic, backend, admin, "ingest_transfer", encode_one(transfer).unwrap());
+    let _: Result<(), CargoTraceError> = call(&pic, backend, admin, "set_ledger_test_mode", encode_one(true).unwrap());
+
+    let (loan_id, _) = approve_new_loan(&pic, backend, admin, user, 50_000);
+    let mapping_id: Result<String, CargoTraceError> = call(&pic, backend, user, "link_cargox_to_acid",
+        encode_args(("DOC-00000000000000000001".to_string(), "123456789".to_string())).unwrap());
+    assert_eq!(loan_id, "LOAN-00000000000000000001");
+    assert_eq!(mapping_id, Ok("MAP-00000000000000000001".to_string()));
+
+    pic.upgrade_canister(backend, backend_wasm(), vec![], Some(admin)).unwrap();
+
+    let principals: Vec<Principal> = query(&pic, backend, user, "get_principals");
+    assert_eq!(principals, vec![user]);
+    let files: Option<candid::Reserved> = call(&pic, backend, user, "getdocument", encode_one(file_id).unwrap());
+    assert!(files.is_some());
+    let transfers = all_transfers(&pic, backend, user);
+    assert_eq!(transfers.len(), 1);
+    let test_mode: bool = query(&pic, backend, user, "get_ledger_test_mode");
+    assert!(test_mode);
+    let loan: Option<Loan> = call(&pic, backend, user, "get_loan", encode_one(loan_id).unwrap());
+    assert!(loan.is_some());
+
+    // New ids continue from the persisted counters instead of restarting at 1
+    let document_id: Result<String, CargoTraceError> = call(&pic, backend, user, "submit_document",
+        encode_args(("123456789".to_string(), tx_hash("def"), 1_000_000u64)).unwrap());
+    assert_eq!(document_id, Ok("DOC-00000000000000000002".to_string()));
+    let (loan_id, _) = approve_new_loan(&pic, backend, admin, user, 10_000);
+    assert_eq!(loan_id, "LOAN-00000000000000000002");
+    let file_id_after: u64 = call(&pic, backend, user, "upload_document",
+        encode_args(("invoice.pdf".to_string(), "0xhash2".to_string(), "alice".to_string())).unwrap());
+    assert_eq!(file_id_after, file_id + 1);
+}
+
+// ---- Maturity ----
+
+#[derive(CandidType, Deserialize, Debug, PartialEq)]
+enum LoanEventKind {
+    PenaltyApplied { amount: u64 },
+    Defaulted { principal_outstanding: u64, interest_outstanding: u64 },
+    CollateralSeized { document_id: String, nft_token_id: Option<u64> },
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+struct LoanEvent {
+    loan_id: String,
+    kind: LoanEventKind,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+struct EventPage {
+    items: Vec<LoanEvent>,
+    next_cursor: Option<String>,
+}
+
+// Advances time and lets the maturity timer fire
+fn advance_days(pic: &PocketIc, days: u32) {
+    pic.advance_time(DAY * days);
+    for _ in 0..3 {
+        pic.tick();
+    }
+}
+
+#[test]
+fn test_overdue_loan_is_penalized_then_defaulted_by_timer() {
+    let (pic, backend, admin) = setup_backend();
+    let borrower = Principal::from_slice(&[2; 29]);
+    let _: Result<(), CargoTraceError> = call(&pic, backend, admin, "set_ledger_test_mode", encode_one(true).unwrap());
+    let _: Result<(), CargoTraceError> = call(&pic, backend, admin, "request_test_tokens", encode_one(100_000u64).unwrap());
+
+    let (loan_id, result) = approve_new_loan(&pic, backend, admin, borrower, 50_000);
+    assert_eq!(result, Ok(()));
+    let get_loan = || -> Loan {
+        let loan: Option<Loan> = call(&pic, backend, borrower, "get_loan", encode_one(loan_id.clone()).unwrap());
+        loan.unwrap()
+    };
+
+    // Default config: 5 grace days, 2% penalty, default 30 days after maturity
+    advance_days(&pic, LOAN_TERM_DAYS + 1);
+    let loan = get_loan();
+    assert_eq!(loan.status, LoanStatus::Active);
+    assert_eq!(loan.late_penalty, None);
+
+    advance_days(&pic, 5);
+    let loan = get_loan();
+    assert_eq!(loan.status, LoanStatus::Active);
+    assert_eq!(loan.late_penalty, Some(1_000));
+    assert!(loan.interest_outstanding > 1_000);
+
+    advance_days(&pic, 25);
+    assert_eq!(get_loan().status, LoanStatus::Defaulted);
+
+    let events: Result<EventPage, CargoTraceError> = call(&pic, backend, borrower, "get_loan_events",
+        encode_args((Some(loan_id.clone()), None::<String>, None::<u32>)).unwrap());
+    let events = events.unwrap();
+    assert_eq!(events.next_cursor, None);
+    let events = events.items;
+    assert_eq!(events.len(), 3);
+    assert_eq!(events[0].kind, LoanEventKind::PenaltyApplied { amount: 1_000 });
+    assert!(matches!(events[1].kind, LoanEventKind::Defaulted { principal_outstanding: 50_000, .. }));
+    assert!(matches!(events[2].kind, LoanEventKind::CollateralSeized { nft_token_id: Some(_), .. }));
+    assert!(events.iter().all(|event| event.loan_id == loan_id));
+
+    // Only the borrower and loan officers see a loan's events
+    let outsider = Principal::from_slice(&[9; 29]);
+    let hidden: Result<EventPage, CargoTraceError> = call(&pic, backend, outsider, "get_loan_events",
+        encode_args((Some(loan_id), None::<String>, None::<u32>)).unwrap());
+    assert_eq!(hidden.unwrap_err(), CargoTraceError::Unauthorized);
+    let all: Result<EventPage, CargoTraceError> = call(&pic, backend, borrower, "get_loan_events",
+        encode_args((None::<String>, None::<String>, None::<u32>)).unwrap());
+    assert_eq!(all.unwrap_err(), CargoTraceError::Unauthorized);
+}
+
+// ---- ACID registry ----
+
+#[derive(CandidType, Deserialize, Debug)]
+struct AcidValidation {
+    is_valid: bool,
+    customs_data: Option<String>,
+}
+
+#[test]
+fn test_acid_registry_controls_validation() {
+    let (pic, backend, admin) = setup_backend();
+    let user = Principal::from_slice(&[2; 29]);
+    register_acid(&pic, backend, admin, "555000111", DAY * 2);
+
+    let valid: Result<bool, CargoTraceError> = call(&pic, backend, user, "validate_acid", encode_one("555000111".to_string()).unwrap());
+    assert_eq!(valid, Ok(true));
+    let validation: Option<AcidValidation> = call(&pic, backend, user, "get_acid_validation", encode_one("555000111".to_string()).unwrap());
+    assert!(validation.unwrap().customs_data.unwrap().contains("\"importer_tax_id\":\"100-200-300\""));
+
+    // Unregistered ACIDs are rejected even when well-formed
+    let unknown: Result<bool, CargoTraceError> = call(&pic, backend, user, "validate_acid", encode_one("987654321".to_string()).unwrap());
+    assert_eq!(unknown, Ok(false));
+
+    // Expired
+    pic.advance_time(DAY * 3);
+    let expired: Result<bool, CargoTraceError> = call(&pic, backend, user, "validate_acid", encode_one("555000111".to_string()).unwrap());
+    assert_eq!(expired, Ok(false));
+
+    // Revoked
+    let _: Result<(), String> = call(&pic, backend, admin, "revoke_acid", encode_one("123456789".to_string()).unwrap());
+    let submitted: Result<String, CargoTraceError> = call(&pic, backend, user, "submit_document",
+        encode_args(("123456789".to_string(), tx_hash("abc"), 1_000_000u64)).unwrap());
+    assert_eq!(submitted, Err(CargoTraceError::InvalidAcid { acid_number: "123456789".to_string() }));
+}
+
+// ---- NAFEZA ----
+
+#[derive(CandidType)]
+struct NafezaConfig {
+    enabled: bool,
+    base_url: String,
+    api_key: Option<String>,
+    cache_ttl_secs: u64,
+}
+
+// Serves NAFEZA-style ACID lookups on localhost and counts the requests it receives
+fn start_nafeza_mock() -> (String, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let base_url = format!("http://{}", listener.local_addr().unwrap());
+    let hits = Arc::new(AtomicUsize::new(0));
+    let counter = hits.clone();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut request_line = String::new();
+            BufReader::new(&stream).read_line(&mut request_line).unwrap();
+            counter.fetch_add(1, Ordering::SeqCst);
+            let acid = request_line.split_whitespace().nth(1).unwrap_or("").rsplit('/').next().unwrap_or("").to_string();
+            let (status, body) = match acid.as_str() {
+                "222333444" => ("200 OK", format!(
+                    r#"{{"acidNumber":"{}","status":"APPROVED","importer":{{"name":"Nile Imports","taxId":"100-200-300"}},"goods":[{{"hsCode":"851712","description":"Mobile phones"}}]}}"#,
+                    acid)),
+                "222333555" => ("200 OK", format!(
+                    r#"{{"acidNumber":"{}","status":"REJECTED","importer":{{"name":"Nile Imports","taxId":"100-200-300"}},"goods":[]}}"#,
+                    acid)),
+                _ => ("404 Not Found", "{}".to_string()),
+            };
+            let response = format!(
+                "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status, body.len(), body
+            );
+            stream.write_all(response.as_bytes()).unwrap();
+        }
+    });
+    (base_url, hits)
+}
+
+#[test]
+fn test_nafeza_validation_against_local_mock_server() {
+    let (mut pic, backend, admin) = setup_backend();
+    let user = Principal::from_slice(&[2; 29]);
+    let (base_url, hits) = start_nafeza_mock();
+    // Live mode lets the canister's HTTP outcalls reach the mock server
+    pic.make_live(None);
+
+    let config = NafezaConfig { enabled: true, base_url, api_key: Some("test-key".to_string()), cache_ttl_secs: 3_600 };
+    let configured: Result<(), String> = call(&pic, backend, admin, "set_nafeza_config", encode_one(config).unwrap());
+    assert_eq!(configured, Ok(()));
+
+    let valid: Result<bool, CargoTraceError> = call(&pic, backend, user, "validate_acid", encode_one("222333444".to_string()).unwrap());
+    assert_eq!(valid, Ok(true));
+    let validation: Option<AcidValidation> = call(&pic, backend, user, "get_acid_validation", encode_one("222333444".to_string()).unwrap());
+    let customs_data = validation.unwrap().customs_data.unwrap();
+    assert!(customs_data.contains("Nile Imports"));
+    assert!(customs_data.contains("Mobile phones"));
+
+    // Served from the cache within the TTL
+    let requests = hits.load(Ordering::SeqCst);
+    let again: Result<bool, CargoTraceError> = call(&pic, backend, user, "validate_acid", encode_one("222333444".to_string()).unwrap());
+    assert_eq!(again, Ok(true));
+    assert_eq!(hits.load(Ordering::SeqCst), requests);
+
+    let rejected: Result<bool, CargoTraceError> = call(&pic, backend, user, "validate_acid", encode_one("222333555".to_string()).unwrap());
+    assert_eq!(rejected, Ok(false));
+    let unknown: Result<bool, CargoTraceError> = call(&pic, backend, user, "validate_acid", encode_one("999999999".to_string()).unwrap());
+    assert!(matches!(unknown, Err(CargoTraceError::AcidLookupFailed(reason)) if reason.contains("HTTP 404")));
+
+    // Failures are cached too, and anonymous callers cannot trigger lookups
+    let requests = hits.load(Ordering::SeqCst);
+    let unknown: Result<bool, CargoTraceError> = call(&pic, backend, user, "validate_acid", encode_one("999999999".to_string()).unwrap());
+    assert!(matches!(unknown, Err(CargoTraceError::AcidLookupFailed(_))));
+    assert_eq!(hits.load(Ordering::SeqCst), requests);
+    let anonymous = pic.update_call(backend, Principal::anonymous(), "validate_acid", encode_one("888888888".to_string()).unwrap());
+    assert!(matches!(anonymous, Ok(WasmResult::Reject(_))));
+    assert_eq!(hits.load(Ordering::SeqCst), requests);
+}
+
+// ---- ICRC-7 ----
+
+#[derive(CandidType, Deserialize, Debug, PartialEq)]
+enum Value {
+    Blob(Vec<u8>),
+    Text(String),
+    Nat(Nat),
+    Int(candid::Int),
+    Array(Vec<Value>),
+    Map(Vec<(String, Value)>),
+}
+
+#[derive(CandidType, Clone)]
+struct Icrc7TransferArg {
+    from_subaccount: Option<Vec<u8>>,
+    to: Account,
+    token_id: Nat,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+enum Icrc7TransferError {
+    NonExistingTokenId,
+    InvalidRecipient,
+    Unauthorized,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+#[test]
+fn test_approved_document_is_minted_as_icrc7_token() {
+    let (pic, backend, admin) = setup_backend();
+    let owner = Principal::from_slice(&[2; 29]);
+    let buyer = Principal::from_slice(&[3; 29]);
+
+    let document_id: Result<String, CargoTraceError> = call(&pic, backend, owner, "submit_document",
+        encode_args(("123456789".to_string(), tx_hash("abc"), 1_000_000u64)).unwrap());
+    let _: Result<(), CargoTraceError> = call(&pic, backend, admin, "approve_document", encode_one(document_id.unwrap()).unwrap());
+
+    let supply: Nat = call(&pic, backend, owner, "icrc7_total_supply", encode_args(()).unwrap());
+    assert_eq!(supply, Nat::from(1u64));
+    let tokens: Vec<Nat> = call(&pic, backend, owner, "icrc7_tokens_of", encode_args((account(owner), None::<Nat>, None::<Nat>)).unwrap());
+    assert_eq!(tokens, vec![Nat::from(1u64)]);
+
+    let metadata: Vec<Option<Vec<(String, Value)>>> = call(&pic, backend, owner, "icrc7_token_metadata", encode_one(tokens.clone()).unwrap());
+    let metadata = metadata[0].as_ref().unwrap();
+    assert!(metadata.contains(&("cargotrace:acid_number".to_string(), Value::Text("123456789".to_string()))));
+    assert!(metadata.contains(&("cargotrace:eth_tx_hash".to_string(), Value::Text("0xabc".to_string()))));
+
+    let transfer = |from: Principal, to: Principal| -> Vec<Option<Result<Nat, Icrc7TransferError>>> {
+        let arg = Icrc7TransferArg { from_subaccount: None, to: account(to), token_id: Nat::from(1u64), memo: None, created_at_time: None };
+        call(&pic, backend, from, "icrc7_transfer", encode_one(vec![arg]).unwrap())
+    };
+    assert_eq!(transfer(buyer, buyer), vec![Some(Err(Icrc7TransferError::Unauthorized))]);
+    assert!(matches!(transfer(owner, buyer)[0], Some(Ok(_))));
+
+    let owners: Vec<Option<Account>> = call(&pic, backend, owner, "icrc7_owner_of", encode_one(tokens).unwrap());
+    assert_eq!(owners[0].as_ref().unwrap().owner, buyer);
+
+    // The document follows its token
+    let documents: Vec<ClaimedDocument> = query(&pic, backend, owner, "get_my_documents");
+    assert!(documents.is_empty());
+    let documents: Vec<ClaimedDocument> = query(&pic, backend, buyer, "get_my_documents");
+    assert_eq!(documents.len(), 1);
+
+    // A retried transfer with the same created_at_time is reported as a duplicate
+    let arg = Icrc7TransferArg {
+        from_subaccount: None,
+        to: account(owner),
+        token_id: Nat::from(1u64),
+        memo: Some(vec![7]),
+        created_at_time: Some(nanos_from_now(&pic, Duration::ZERO)),
+    };
+    let first: Vec<Option<Result<Nat, Icrc7TransferError>>> = call(&pic, backend, buyer, "icrc7_transfer", encode_one(vec![arg.clone()]).unwrap());
+    let tx_id = first[0].clone().unwrap().unwrap();
+    let retry: Vec<Option<Result<Nat, Icrc7TransferError>>> = call(&pic, backend, buyer, "icrc7_transfer", encode_one(vec![arg]).unwrap());
+    assert_eq!(retry, vec![Some(Err(Icrc7TransferError::Duplicate { duplicate_of: tx_id }))]);
+}
+
+// ---- Collateral ----
+
+#[derive(CandidType, Deserialize, Debug)]
+struct CollateralLock {
+    document_id: String,
+    loan_id: String,
+}
+
+// The NFT minted for the borrower's only document
+fn only_token(pic: &PocketIc, backend: Principal, owner: Principal) -> Nat {
+    let tokens: Vec<Nat> = call(pic, backend, owner, "icrc7_tokens_of", encode_args((account(owner), None::<Nat>, None::<Nat>)).unwrap());
+    assert_eq!(tokens.len(), 1);
+    tokens[0].clone()
+}
+
+#[test]
+fn test_document_is_locked_while_loan_is_active() {
+    let (pic, backend, admin) = setup_backend();
+    let borrower = Principal::from_slice(&[2; 29]);
+    let _: Result<(), CargoTraceError> = call(&pic, backend, admin, "set_ledger_test_mode", encode_one(true).unwrap());
+    let _: Result<(), CargoTraceError> = call(&pic, backend, admin, "request_test_tokens", encode_one(100_000u64).unwrap());
+
+    let (loan_id, result) = approve_new_loan(&pic, backend, admin, borrower, 50_000);
+    assert_eq!(result, Ok(()));
+    let loan: Option<LoanDocument> = call(&pic, backend, borrower, "get_loan", encode_one(loan_id.clone()).unwrap());
+    let document_id = loan.unwrap().document_id;
+
+    let lock: Option<CollateralLock> = call(&pic, backend, borrower, "get_collateral_lock", encode_one(document_id.clone()).unwrap());
+    let lock = lock.unwrap();
+    assert_eq!(lock.loan_id, loan_id);
+    let token_id = only_token(&pic, backend, borrower);
+
+    // No second loan and no NFT transfer while locked
+    let repayment_date = nanos_from_now(&pic, DAY * LOAN_TERM_DAYS);
+    let second: Result<String, CargoTraceError> = call(&pic, backend, borrower, "request_loan",
+        encode_args((document_id.clone(), 1_000u64, repayment_date)).unwrap());
+    assert_eq!(second, Err(CargoTraceError::DocumentInUse { loan_id: loan_id.clone() }));
+    let arg = Icrc7TransferArg {
+        from_subaccount: None,
+        to: account(Principal::from_slice(&[3; 29])),
+        token_id: token_id.clone(),
+        memo: None,
+        created_at_time: None,
+    };
+    let transfer: Vec<Option<Result<Nat, Icrc7TransferError>>> = call(&pic, backend, borrower, "icrc7_transfer", encode_one(vec![arg]).unwrap());
+    assert!(matches!(transfer[0], Some(Err(Icrc7TransferError::GenericError { .. }))));
+
+    // Repaying in full releases the lock
+    let _: Result<(), CargoTraceError> = call(&pic, backend, borrower, "init_user_balance", encode_one(100_000u64).unwrap());
+    let repaid: Result<(), CargoTraceError> = call(&pic, backend, borrower, "repay_loan", encode_args((loan_id, 60_000u64)).unwrap());
+    assert_eq!(repaid, Ok(()));
+    let lock: Option<CollateralLock> = call(&pic, backend, borrower, "get_collateral_lock", encode_one(document_id).unwrap());
+    assert!(lock.is_none());
+}
+
+#[test]
+fn test_document_is_locked_from_request_until_rejection() {
+    let (pic, backend, admin) = setup_backend();
+    let borrower = Principal::from_slice(&[2; 29]);
+    grant_borrower(&pic, backend, admin, borrower);
+    let document_id: Result<String, CargoTraceError> = call(&pic, backend, borrower, "submit_document",
+        encode_args(("123456789".to_string(), tx_hash("abc"), 1_000_000u64)).unwrap());
+    let document_id = document_id.unwrap();
+    let _: Result<(), CargoTraceError> = call(&pic, backend, admin, "approve_document", encode_one(document_id.clone()).unwrap());
+    let repayment_date = nanos_from_now(&pic, DAY * LOAN_TERM_DAYS);
+    let loan_id: Result<String, CargoTraceError> = call(&pic, backend, borrower, "request_loan",
+        encode_args((document_id.clone(), 1_000u64, repayment_date)).unwrap());
+    let loan_id = loan_id.unwrap();
+
+    // The token cannot leave while the loan is only pending
+    let lock: Option<CollateralLock> = call(&pic, backend, borrower, "get_collateral_lock", encode_one(document_id.clone()).unwrap());
+    assert!(lock.is_some());
+    let token_id = only_token(&pic, backend, borrower);
+    let arg = Icrc7TransferArg {
+        from_subaccount: None,
+        to: account(Principal::from_slice(&[3; 29])),
+        token_id: token_id.clone(),
+        memo: None,
+        created_at_time: None,
+    };
+    let transfer: Vec<Option<Result<Nat, Icrc7TransferError>>> = call(&pic, backend, borrower, "icrc7_transfer", encode_one(vec![arg]).unwrap());
+    assert!(matches!(transfer[0], Some(Err(Icrc7TransferError::GenericError { .. }))));
+
+    let rejected: Result<(), CargoTraceError> = call(&pic, backend, admin, "reject_loan", encode_one(loan_id).unwrap());
+    assert_eq!(rejected, Ok(()));
+    let lock: Option<CollateralLock> = call(&pic, backend, borrower, "get_collateral_lock", encode_one(document_id).unwrap());
+    assert!(lock.is_none());
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+struct LoanDocument {
+    document_id: String,
+}
+
+// ---- EVM RPC ----
+
+const EVM_RPC_MOCK_WASM: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../../target/wasm32-unknown-unknown/release/evm_rpc_mock.wasm");
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+enum LogSource {
+    Etherscan,
+    EvmRpc,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct EthPollerConfig {
+    enabled: bool,
+    interval_secs: u64,
+    api_url: String,
+    block_window: u64,
+    source: Option<LogSource>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+struct EthPollerState {
+    next_block: u64,
+    finalized_block: u64,
+    last_error: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+enum EthMainnetService {
+    Alchemy,
+    Ankr,
+    PublicNode,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+enum ConsensusStrategy {
+    Equality,
+    Threshold { total: Option<u8>, min: u8 },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct EvmRpcConfig {
+    canister_id: Principal,
+    providers: Vec<EthMainnetService>,
+    consensus: ConsensusStrategy,
+    cycles: u128,
+}
+
+// Installs the mock EVM RPC canister and points the backend's Ethereum source at it
+fn setup_evm_rpc_mock(pic: &PocketIc, backend: Principal, admin: Principal) -> Principal {
+    let evm_rpc = pic.create_canister_with_settings(Some(admin), None);
+    pic.add_cycles(evm_rpc, 2_000_000_000_000);
+    let wasm = std::fs::read(EVM_RPC_MOCK_WASM)
+        .expect("Run `cargo build -p evm_rpc_mock --target wasm32-unknown-unknown --release` first");
+    pic.install_canister(evm_rpc, wasm, vec![], Some(admin));
+
+    let rpc_config = EvmRpcConfig {
+        canister_id: evm_rpc,
+        providers: vec![EthMainnetService::Alchemy, EthMainnetService::Ankr, EthMainnetService::PublicNode],
+        consensus: ConsensusStrategy::Threshold { total: Some(3), min: 2 },
+        cycles: 1_000_000_000,
+    };
+    let configured: Result<(), String> = call(pic, backend, admin, "set_evm_rpc_config", encode_one(rpc_config).unwrap());
+    assert_eq!(configured, Ok(()));
+    let poller = EthPollerConfig {
+        enabled: false,
+        interval_secs: 60,
+        api_url: "https://api.etherscan.io/api".to_string(),
+        block_window: 50,
+        source: Some(LogSource::EvmRpc),
+    };
+    let configured: Result<(), String> = call(pic, backend, admin, "set_eth_poller_config", encode_one(poller).unwrap());
+    assert_eq!(configured, Ok(()));
+    evm_rpc
+}
+
+#[test]
+fn test_eth_logs_are_polled_through_evm_rpc_canister() {
+    let (pic, backend, admin) = setup_backend();
+    let evm_rpc = setup_evm_rpc_mock(&pic, backend, admin);
+
+    // The first window covers blocks 20000000-20000049: two NFT transfers and one ERC-20 transfer
+    let polled: Result<u64, String> = call(&pic, backend, admin, "poll_eth_logs_now", encode_args(()).unwrap());
+    assert_eq!(polled, Ok(2));
+    let state: EthPollerState = query(&pic, backend, admin, "get_eth_poller_state");
+    assert_eq!(state.next_block, 20_000_050);
+    assert_eq!(state.finalized_block, 20_000_100);
+
+    // Providers that disagree fail the poll without moving the cursor
+    let _: () = call(&pic, evm_rpc, admin, "set_inconsistent", encode_one(true).unwrap());
+    let polled: Result<u64, String> = call(&pic, backend, admin, "poll_eth_logs_now", encode_args(()).unwrap());
+    assert!(polled.unwrap_err().contains("inconsistent"));
+    let state: EthPollerState = query(&pic, backend, admin, "get_eth_poller_state");
+    assert_eq!(state.next_block, 20_000_050);
+    assert!(state.last_error.is_some());
+
+    let _: () = call(&pic, evm_rpc, admin, "set_inconsistent", encode_one(false).unwrap());
+    let polled: Result<u64, String> = call(&pic, backend, admin, "poll_eth_logs_now", encode_args(()).unwrap());
+    assert_eq!(polled, Ok(1));
+    let transfers = all_transfers(&pic, backend, admin);
+    assert_eq!(transfers.iter().map(|t| t.token_id.as_str()).collect::<Vec<_>>(), ["7", "8", "7"]);
+
+    let uri: Result<String, CargoTraceError> = call(&pic, backend, admin, "get_token_uri", encode_one(cargox_token("7")).unwrap());
+    assert_eq!(uri.unwrap(), "ipfs://cargox/7");
+    // Token ids are decimal uint256 values; anything else is refused before any outcall
+    let uri: Result<String, CargoTraceError> = call(&pic, backend, admin, "get_token_uri", encode_one(cargox_token("0x7")).unwrap());
+    assert!(matches!(uri, Err(CargoTraceError::InvalidArgument(_))));
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+struct CargoXMapping {
+    id: String,
+    nft_hash: String,
+    document_id: Option<String>,
+    acid_number: String,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+struct FinalityRun {
+    checked: u64,
+    confirmed: u64,
+    orphaned: u64,
+}
+
+// A well-formed transaction hash that spells `label` in hex
+fn tx_hash(label: &str) -> String {
+    let hex: String = label.bytes().map(|b| format!("{:02x}", b)).collect();
+    format!("0x{:0>64}", hex)
+}
+
+fn watched_transfer(tx_hash: &str, block_number: u64, block_hash: Option<String>) -> TransferPayload {
+    TransferPayload {
+        network: "ethereum".to_string(),
+        contract: "0xd4190DD1dA460fC7Bc41a792e688604778820aC9".to_string(),
+        tx_hash: tx_hash.to_string(),
+        block_number,
+        token_id: "9".to_string(),
+        from: "0xfrom".to_string(),
+        to: "0xto".to_string(),
+        log_index: 0,
+        block_hash,
+    }
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+struct TokenRef {
+    network: String,
+    contract: String,
+    token_id: String,
+}
+
+// A token of the default watched CargoX contract
+fn cargox_token(token_id: &str) -> TokenRef {
+    TokenRef {
+        network: "ethereum".to_string(),
+        contract: "0xd4190DD1dA460fC7Bc41a792e688604778820aC9".to_string(),
+        token_id: token_id.to_string(),
+    }
+}
+
+// Matches the mock's block hashes before any reorg
+fn mock_block_hash(block_number: u64) -> String {
+    format!("0x00{:062x}", block_number)
+}
+
+#[test]
+fn test_reorg_rolls_back_unconfirmed_transfers_and_links() {
+    let (pic, backend, admin) = setup_backend();
+    let user = Principal::from_slice(&[2; 29]);
+    let evm_rpc = setup_evm_rpc_mock(&pic, backend, admin);
+
+    let submit = |label: &str| -> String {
+        let id: Result<String, CargoTraceError> = call(&pic, backend, user, "submit_document",
+            encode_args(("123456789".to_string(), tx_hash(label), 1_000_000u64)).unwrap());
+        id.unwrap()
+    };
+    // Linked before the watcher saw the transaction
+    let reorged_id = submit("reorged");
+    let linked: Result<String, CargoTraceError> = call(&pic, backend, user, "link_cargox_to_acid",
+        encode_args((reorged_id.clone(), "123456789".to_string())).unwrap());
+    assert!(linked.is_ok());
+
+    // The mock's finalized head is 20000100 and the default depth is 0
+    for transfer in [
+        watched_transfer(&tx_hash("deep"), 20_000_098, Some(mock_block_hash(20_000_098))),
+        watched_transfer(&tx_hash("reorged"), 20_000_104, Some(mock_block_hash(20_000_104))),
+        watched_transfer(&tx_hash("shallow"), 20_000_105, None),
+    ] {
+        let _: IngestOutcome = call(&pic, backend, admin, "ingest_transfer", encode_one(transfer).unwrap());
+    }
+    let unconfirmed = unconfirmed_transfers(&pic, backend, admin);
+    assert_eq!(unconfirmed.len(), 3);
+    // The document submitted early takes its token from the transfer once it arrives
+    let reorged: Option<ClaimedDocument> = call(&pic, backend, user, "get_document", encode_one(reorged_id.clone()).unwrap());
+    assert_eq!(reorged.unwrap().token_id, "9");
+    let premature: Result<String, CargoTraceError> = call(&pic, backend, user, "link_cargox_to_acid",
+        encode_args((submit("shallow"), "123456789".to_string())).unwrap());
+    assert_eq!(premature, Err(CargoTraceError::TransferNotFinal { tx_hash: tx_hash("shallow") }));
+
+    let _: () = call(&pic, evm_rpc, admin, "reorg_from", encode_one(20_000_103u64).unwrap());
+    let run: Result<FinalityRun, CargoTraceError> = call(&pic, backend, admin, "check_finality_now", encode_args(()).unwrap());
+    let run = run.unwrap();
+    assert_eq!((run.checked, run.confirmed, run.orphaned), (3, 1, 1));
+
+    let transfers = all_transfers(&pic, backend, admin);
+    assert_eq!(transfers.iter().map(|t| t.tx_hash.clone()).collect::<Vec<_>>(), [tx_hash("deep"), tx_hash("shallow")]);
+    // The shallow transfer adopts the post-reorg hash and waits for more blocks
+    let unconfirmed = unconfirmed_transfers(&pic, backend, admin);
+    assert_eq!(unconfirmed.len(), 1);
+    assert_eq!(unconfirmed[0].block_hash, Some(format!("0x01{:062x}", 20_000_105u64)));
+    let mapping: Option<CargoXMapping> = call(&pic, backend, user, "get_cargox_mapping", encode_one(reorged_id).unwrap());
+    assert!(mapping.is_none());
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+enum Role {
+    Admin,
+    CustomsOfficer,
+    LoanOfficer,
+    Watcher,
+    Borrower,
+}
+
+#[test]
+fn test_ingest_is_restricted_to_watchers_and_deduplicated() {
+    let (pic, backend, admin) = setup_backend();
+    let watcher = Principal::from_slice(&[4; 29]);
+    let outsider = Principal::from_slice(&[5; 29]);
+    let transfer = watched_transfer("0xreplayed", 20_000_050, None);
+
+    let forged = pic.update_call(backend, outsider, "ingest_transfer", encode_one(transfer.clone()).unwrap());
+    assert!(matches!(forged, Ok(WasmResult::Reject(_))));
+
+    let granted: Result<(), String> = call(&pic, backend, admin, "grant_role", encode_args((watcher, Role::Watcher)).unwrap());
+    assert_eq!(granted, Ok(()));
+    let first_transfer = transfer.clone();
+    let first: IngestOutcome = call(&pic, backend, watcher, "ingest_transfer", encode_one(transfer.clone()).unwrap());
+    assert_eq!(first, IngestOutcome::New);
+
+    // A restarted watcher resends the same log, here with different casing
+    let replay = TransferPayload { tx_hash: "0xREPLAYED".to_string(), ..transfer.clone() };
+    let batch = vec![
+        replay,
+        TransferPayload { log_index: 1, ..transfer.clone() },
+        TransferPayload { tx_hash: "unknown_tx".to_string(), ..transfer },
+    ];
+    let outcomes: Result<Vec<IngestOutcome>, CargoTraceError> = call(&pic, backend, watcher, "ingest_transfers", encode_one(batch).unwrap());
+    let outcomes = outcomes.unwrap();
+    assert_eq!(outcomes[0], IngestOutcome::Duplicate);
+    assert_eq!(outcomes[1], IngestOutcome::New);
+    assert!(matches!(outcomes[2], IngestOutcome::Rejected(_)));
+
+    let oversized = vec![first_transfer.clone(); 501];
+    let outcomes: Result<Vec<IngestOutcome>, CargoTraceError> = call(&pic, backend, watcher, "ingest_transfers", encode_one(oversized).unwrap());
+    assert!(matches!(outcomes, Err(CargoTraceError::InvalidArgument(_))));
+
+    let transfers = all_transfers(&pic, backend, admin);
+    assert_eq!(transfers.len(), 2);
+}
+
+#[derive(CandidType, Deserialize, Default)]
+struct TransferFilter {
+    network: Option<String>,
+    contract: Option<String>,
+    token_id: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    from_block: Option<u64>,
+    to_block: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+struct TransferPage {
+    transfers: Vec<TransferPayload>,
+    next_cursor: Option<u64>,
+}
+
+fn all_transfers(pic: &PocketIc, backend: Principal, caller: Principal) -> Vec<TransferPayload> {
+    let args = encode_args((TransferFilter::default(), None::<u64>, Some(500u32))).unwrap();
+    let page: TransferPage = call(pic, backend, caller, "list_transfers", args);
+    assert!(page.next_cursor.is_none());
+    page.transfers
+}
+
+fn unconfirmed_transfers(pic: &PocketIc, backend: Principal, caller: Principal) -> Vec<TransferPayload> {
+    let page: TransferPage = call(pic, backend, caller, "get_unconfirmed_transfers", encode_args((None::<u64>, Some(500u32))).unwrap());
+    assert!(page.next_cursor.is_none());
+    page.transfers
+}
+
+#[test]
+fn test_transfers_are_paged_filtered_and_traced_per_token() {
+    let (pic, backend, admin) = setup_backend();
+    // Token 9 moves exporter -> carrier -> importer; a second log of the first hop arrives last
+    let hops = vec![
+        TransferPayload { from: "0xexporter".to_string(), to: "0xcarrier".to_string(), ..watched_transfer("0xhop1", 20_000_010, None) },
+        TransferPayload { token_id: "10".to_string(), ..watched_transfer("0xother", 20_000_015, None) },
+        TransferPayload { from: "0xcarrier".to_string(), to: "0xImporter".to_string(), ..watched_transfer("0xhop2", 20_000_030, None) },
+        TransferPayload { from: "0xexporter".to_string(), to: "0xcarrier".to_string(), log_index: 3, ..watched_transfer("0xhop1", 20_000_010, None) },
+    ];
+    let outcomes: Result<Vec<IngestOutcome>, CargoTraceError> = call(&pic, backend, admin, "ingest_transfers", encode_one(hops).unwrap());
+    assert_eq!(outcomes.unwrap().len(), 4);
+
+    let mut cursor: Option<u64> = None;
+    let mut pages = Vec::new();
+    loop {
+        let page: TransferPage = call(&pic, backend, admin, "list_transfers", encode_args((TransferFilter::default(), cursor, Some(3u32))).unwrap());
+        pages.push(page.transfers.len());
+        cursor = page.next_cursor;
+        if cursor.is_none() {
+            break;
+        }
+    }
+    assert_eq!(pages, vec![3, 1]);
+
+    let by_recipient = TransferFilter { to: Some("0ximporter".to_string()), ..Default::default() };
+    let page: TransferPage = call(&pic, backend, admin, "list_transfers", encode_args((by_recipient, None::<u64>, None::<u32>)).unwrap());
+    assert_eq!(page.transfers.len(), 1);
+    assert_eq!(page.transfers[0].tx_hash, "0xhop2");
+    assert!(page.next_cursor.is_none());
+
+    let in_range = TransferFilter { token_id: Some("9".to_string()), from_block: Some(20_000_011), ..Default::default() };
+    let page: TransferPage = call(&pic, backend, admin, "list_transfers", encode_args((in_range, None::<u64>, None::<u32>)).unwrap());
+    assert_eq!(page.transfers.len(), 1);
+
+    let mut history = Vec::new();
+    let mut cursor: Option<u64> = None;
+    loop {
+        let args = encode_args((cargox_token("9"), cursor, Some(2u32))).unwrap();
+        let page: Result<TransferPage, CargoTraceError> = call(&pic, backend, admin, "get_token_history", args);
+        let page = page.unwrap();
+        history.extend(page.transfers);
+        cursor = page.next_cursor;
+        if cursor.is_none() {
+            break;
+        }
+    }
+    let hashes: Vec<(&str, u64)> = history.iter().map(|t| (t.tx_hash.as_str(), t.log_index)).collect();
+    assert_eq!(hashes, vec![("0xhop1", 0), ("0xhop1", 3), ("0xhop2", 0)]);
+}
+
+// ---- Token metadata ----
+
+#[derive(CandidType)]
+struct MetadataConfig {
+    ipfs_gateways: Vec<String>,
+    arweave_gateways: Vec<String>,
+    max_response_bytes: u64,
+    cache_ttl_secs: Option<u64>,
+    failure_ttl_secs: Option<u64>,
+    refresh_interval_secs: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+struct CachedMetadata {
+    contract: String,
+    token_id: String,
+    metadata: DocumentMetadata,
+    fetched_at: u64,
+    expires_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Debug, PartialEq)]
+enum MetadataResolution {
+    Resolved { token_uri: String, url: String, content_sha256: String, cid_verified: bool },
+    Unresolved { reason: String },
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+struct DocumentAttribute {
+    trait_type: String,
+    value: String,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+struct DocumentMetadata {
+    name: Option<String>,
+    description: Option<String>,
+    image: Option<String>,
+    external_url: Option<String>,
+    attributes: Vec<DocumentAttribute>,
+    document_hash: Option<String>,
+    document_type: Option<String>,
+    issuer: Option<String>,
+    creation_date: Option<String>,
+    resolution: MetadataResolution,
+}
+
+// Serves the metadata the mock EVM RPC canister's tokenURI points to, under /ipfs only, and
+// counts the requests it receives
+fn start_gateway_mock() -> (String, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let base_url = format!("http://{}", listener.local_addr().unwrap());
+    let hits = Arc::new(AtomicUsize::new(0));
+    let counter = hits.clone();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut request_line = String::new();
+            BufReader::new(&stream).read_line(&mut request_line).unwrap();
+            counter.fetch_add(1, Ordering::SeqCst);
+            let path = request_line.split_whitespace().nth(1).unwrap_or("").to_string();
+            let (status, body) = match path.as_str() {
+                "/ipfs/cargox/7" => ("200 OK", r#"{"name":"Bill of Lading 7","attributes":[{"trait_type":"Document Hash","value":"0xb1"},{"trait_type":"Document Type","value":"Bill of Lading"}]}"#),
+                _ => ("404 Not Found", "{}"),
+            };
+            let response = format!(
+                "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status, body.len(), body
+            );
+            stream.write_all(response.as_bytes()).unwrap();
+        }
+    });
+    (base_url, hits)
+}
+
+#[test]
+fn test_token_metadata_is_resolved_from_token_uri_through_gateways() {
+    let (mut pic, backend, admin) = setup_backend();
+    setup_evm_rpc_mock(&pic, backend, admin);
+    let (base_url, _) = start_gateway_mock();
+    pic.make_live(None);
+
+    let unresolved: DocumentMetadata = call(&pic, backend, admin, "get_token_metadata", encode_one(cargox_token("not-a-number")).unwrap());
+    assert!(matches!(unresolved.resolution, MetadataResolution::Unresolved { .. }));
+    assert!(unresolved.document_hash.is_none());
+    // Token URIs are only read on the polled network
+    let elsewhere = TokenRef { network: "polygon".to_string(), ..cargox_token("7") };
+    let unresolved: DocumentMetadata = call(&pic, backend, admin, "get_token_metadata", encode_one(elsewhere).unwrap());
+    assert!(matches!(unresolved.resolution, MetadataResolution::Unresolved { .. }));
+
+    // The first gateway fails, so the second one serves the document
+    let config = MetadataConfig {
+        ipfs_gateways: vec![format!("{}/broken", base_url), format!("{}/ipfs", base_url)],
+        arweave_gateways: vec![],
+        max_response_bytes: 10_000,
+        cache_ttl_secs: None,
+        failure_ttl_secs: None,
+        refresh_interval_secs: None,
+    };
+    let configured: Result<(), String> = call(&pic, backend, admin, "set_metadata_config", encode_one(config).unwrap());
+    assert_eq!(configured, Ok(()));
+
+    let metadata: DocumentMetadata = call(&pic, backend, admin, "get_token_metadata", encode_one(cargox_token("7")).unwrap());
+    assert_eq!(metadata.name.as_deref(), Some("Bill of Lading 7"));
+    assert_eq!(metadata.document_hash.as_deref(), Some("0xb1"));
+    match metadata.resolution {
+        MetadataResolution::Resolved { token_uri, url, cid_verified, .. } => {
+            assert_eq!(token_uri, "ipfs://cargox/7");
+            assert_eq!(url, format!("{}/ipfs/cargox/7", base_url));
+            assert!(!cid_verified);
+        }
+        unresolved => panic!("expected resolved metadata, got {:?}", unresolved),
+    }
+
+    let insecure = MetadataConfig {
+        ipfs_gateways: vec!["http://ipfs.example".to_string()],
+        arweave_gateways: vec![],
+        max_response_bytes: 10_000,
+        cache_ttl_secs: None,
+        failure_ttl_secs: None,
+        refresh_interval_secs: None,
+    };
+    let rejected: Result<(), String> = call(&pic, backend, admin, "set_metadata_config", encode_one(insecure).unwrap());
+    assert!(rejected.is_err());
+}
+
+#[test]
+fn test_token_metadata_is_cached_until_invalidated() {
+    let (mut pic, backend, admin) = setup_backend();
+    setup_evm_rpc_mock(&pic, backend, admin);
+    let (base_url, hits) = start_gateway_mock();
+    pic.make_live(None);
+    let config = MetadataConfig {
+        ipfs_gateways: vec![format!("{}/ipfs", base_url)],
+        arweave_gateways: vec![],
+        max_response_bytes: 10_000,
+        cache_ttl_secs: Some(3_600),
+        failure_ttl_secs: Some(600),
+        refresh_interval_secs: Some(3_600),
+    };
+    let configured: Result<(), String> = call(&pic, backend, admin, "set_metadata_config", encode_one(config).unwrap());
+    assert_eq!(configured, Ok(()));
+
+    let first: DocumentMetadata = call(&pic, backend, admin, "get_token_metadata", encode_one(cargox_token("7")).unwrap());
+    assert_eq!(first.name.as_deref(), Some("Bill of Lading 7"));
+    let requests = hits.load(Ordering::SeqCst);
+    let cached: DocumentMetadata = call(&pic, backend, admin, "get_token_metadata", encode_one(cargox_token("7")).unwrap());
+    assert_eq!(cached.name, first.name);
+    assert_eq!(hits.load(Ordering::SeqCst), requests);
+    let entry: Option<CachedMetadata> = call(&pic, backend, admin, "get_cached_metadata", encode_one(cargox_token("7")).unwrap());
+    let entry = entry.unwrap();
+    assert_eq!(entry.expires_at - entry.fetched_at, 3_600 * 1_000_000_000);
+
+    // Failures are cached too, for the shorter TTL
+    let _: DocumentMetadata = call(&pic, backend, admin, "get_token_metadata", encode_one(cargox_token("bogus")).unwrap());
+    let failure: Option<CachedMetadata> = call(&pic, backend, admin, "get_cached_metadata", encode_one(cargox_token("bogus")).unwrap());
+    let failure = failure.unwrap();
+    assert!(matches!(failure.metadata.resolution, MetadataResolution::Unresolved { .. }));
+    assert_eq!(failure.expires_at - failure.fetched_at, 600 * 1_000_000_000);
+
+    let removed: u64 = call(&pic, backend, admin, "invalidate_metadata", encode_one(Some(cargox_token("7"))).unwrap());
+    assert_eq!(removed, 1);
+    let _: DocumentMetadata = call(&pic, backend, admin, "get_token_metadata", encode_one(cargox_token("7")).unwrap());
+    assert!(hits.load(Ordering::SeqCst) > requests);
+    let removed: u64 = call(&pic, backend, admin, "invalidate_metadata", encode_one(None::<TokenRef>).unwrap());
+    assert_eq!(removed, 2);
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+struct WatchedContract {
+    network: String,
+    address: String,
+    start_block: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+struct WatcherConfig {
+    contracts: Vec<WatchedContract>,
+    transfer_topic: String,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+struct InitArgs {
+    watcher: Option<WatcherConfig>,
+    etherscan_api_key: Option<String>,
+}
+
+#[test]
+fn test_watcher_config_comes_from_init_args_and_keeps_the_api_key_secret() {
+    let pic = PocketIc::new();
+    let admin = Principal::from_slice(&[1; 29]);
+    let watcher = WatcherConfig {
+        contracts: vec![WatchedContract {
+            network: "ethereum".to_string(),
+            address: "0xd4190DD1dA460fC7Bc41a792e688604778820aC9".to_string(),
+            start_block: 19_500_000,
+        }],
+        transfer_topic: "0xddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef".to_string(),
+    };
+    let args = InitArgs { watcher: Some(watcher.clone()), etherscan_api_key: Some("etherscan-secret".to_string()) };
+    let backend = pic.create_canister_with_settings(Some(admin), None);
+    pic.add_cycles(backend, 2_000_000_000_000);
+    pic.install_canister(backend, backend_wasm(), encode_one(Some(args)).unwrap(), Some(admin));
+    let _: Result<(), String> = call(&pic, backend, admin, "bootstrap_admin", encode_one(admin).unwrap());
+
+    let config: WatcherConfig = query(&pic, backend, admin, "get_watcher_config");
+    assert_eq!(config, watcher);
+    let has_key: bool = query(&pic, backend, admin, "has_etherscan_api_key");
+    assert!(has_key);
+
+    // Transfers from contracts that are not watched are refused
+    let polygon = TransferPayload {
+        network: "polygon".to_string(),
+        contract: "0x1111111111111111111111111111111111111111".to_string(),
+        ..watched_transfer("0xpolygon", 50_000_000, None)
+    };
+    let outcome: IngestOutcome = call(&pic, backend, admin, "ingest_transfer", encode_one(polygon.clone()).unwrap());
+    assert!(matches!(outcome, IngestOutcome::Rejected(_)));
+
+    let mut with_polygon = watcher.clone();
+    with_polygon.contracts.push(WatchedContract {
+        network: "polygon".to_string(),
+        address: polygon.contract.clone(),
+        start_block: 50_000_000,
+    });
+    let user = Principal::from_slice(&[2; 29]);
+    let denied = pic.update_call(backend, user, "set_watcher_config", encode_one(with_polygon.clone()).unwrap());
+    assert!(matches!(denied, Ok(WasmResult::Reject(_))));
+    let updated: Result<(), String> = call(&pic, backend, admin, "set_watcher_config", encode_one(with_polygon).unwrap());
+    assert_eq!(updated, Ok(()));
+    let outcome: IngestOutcome = call(&pic, backend, admin, "ingest_transfer", encode_one(polygon).unwrap());
+    assert_eq!(outcome, IngestOutcome::New);
+    // Only the polled network has a finality source; the Polygon watcher reports final transfers
+    let unconfirmed = unconfirmed_transfers(&pic, backend, admin);
+    assert!(unconfirmed.is_empty());
+
+    // Settings and the key survive upgrades, and clearing the key is visible without revealing it
+    pic.upgrade_canister(backend, backend_wasm(), vec![], Some(admin)).unwrap();
+    let config: WatcherConfig = query(&pic, backend, admin, "get_watcher_config");
+    assert_eq!(config.contracts.len(), 2);
+    let has_key: bool = query(&pic, backend, admin, "has_etherscan_api_key");
+    assert!(has_key);
+    let cleared: Result<(), String> = call(&pic, backend, admin, "set_etherscan_api_key", encode_one(None::<String>).unwrap());
+    assert_eq!(cleared, Ok(()));
+    let has_key: bool = query(&pic, backend, admin, "has_etherscan_api_key");
+    assert!(!has_key);
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq)]
+enum OutcallFeature {
+    EthPoller,
+    EvmRpc,
+    Nafeza,
+    TokenMetadata,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+struct FeatureSpend {
+    feature: OutcallFeature,
+    calls: u64,
+    failures: u64,
+    cycles_spent: u128,
+    cycles_spent_today: u128,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+struct OutcallStats {
+    day: u64,
+    cycles_spent_today: u128,
+    daily_budget_cycles: Option<u128>,
+    features: Vec<FeatureSpend>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+struct OutcallConfig {
+    daily_budget_cycles: Option<u128>,
+    chain_reserve_cycles: u128,
+    subnet_size: u64,
+}
+
+#[test]
+fn test_outcall_spend_is_tracked_and_capped_by_the_daily_budget() {
+    let (mut pic, backend, admin) = setup_backend();
+    setup_evm_rpc_mock(&pic, backend, admin);
+    let (base_url, hits) = start_gateway_mock();
+    pic.make_live(None);
+    let config = MetadataConfig {
+        ipfs_gateways: vec![format!("{}/ipfs", base_url)],
+        arweave_gateways: vec![],
+        max_response_bytes: 10_000,
+        cache_ttl_secs: None,
+        failure_ttl_secs: None,
+        refresh_interval_secs: None,
+    };
+    let configured: Result<(), String> = call(&pic, backend, admin, "set_metadata_config", encode_one(config).unwrap());
+    assert_eq!(configured, Ok(()));
+
+    let metadata: DocumentMetadata = call(&pic, backend, admin, "get_token_metadata", encode_one(cargox_token("7")).unwrap());
+    assert!(matches!(metadata.resolution, MetadataResolution::Resolved { .. }));
+    let stats: OutcallStats = query(&pic, backend, admin, "get_outcall_stats");
+    let gateway = stats.features.iter().find(|f| f.feature == OutcallFeature::TokenMetadata).unwrap();
+    assert_eq!(gateway.calls, 1);
+    assert_eq!(gateway.failures, 0);
+    // Priced from the request and the 10 KB response limit, not a fixed amount
+    assert!(gateway.cycles_spent > 100_000_000 && gateway.cycles_spent < 1_000_000_000);
+    // The tokenURI call kept what the mock charges for three providers and a 4 KB response
+    let evm_rpc = stats.features.iter().find(|f| f.feature == OutcallFeature::EvmRpc).unwrap();
+    assert_eq!(evm_rpc.calls, 1);
+    assert_eq!(evm_rpc.cycles_spent, 3 * ((3_000_000 + 60_000 * 34) * 34 + 800 * 34 * 4_096));
+    assert!(stats.cycles_spent_today >= gateway.cycles_spent_today);
+
+    // Anonymous callers cannot spend the budget on lookups
+    let anonymous = pic.update_call(backend, Principal::anonymous(), "fetch_cargox_documents", encode_args(()).unwrap());
+    assert!(matches!(anonymous, Ok(WasmResult::Reject(_))));
+
+    // Lookups cannot touch the chain reserve, which still lets the poller run
+    let reserve = 1_000_000_000_000_000;
+    let reserved = OutcallConfig { daily_budget_cycles: Some(reserve), chain_reserve_cycles: reserve, subnet_size: 13 };
+    let configured: Result<(), String> = call(&pic, backend, admin, "set_outcall_config", encode_one(reserved).unwrap());
+    assert_eq!(configured, Ok(()));
+    let _: u64 = call(&pic, backend, admin, "invalidate_metadata", encode_one(None::<TokenRef>).unwrap());
+    let requests = hits.load(Ordering::SeqCst);
+    let metadata: DocumentMetadata = call(&pic, backend, admin, "get_token_metadata", encode_one(cargox_token("7")).unwrap());
+    match metadata.resolution {
+        MetadataResolution::Unresolved { reason } => assert!(reason.contains("lookups")),
+        resolved => panic!("expected the chain reserve to stop the lookup, got {:?}", resolved),
+    }
+    assert_eq!(hits.load(Ordering::SeqCst), requests);
+    let polled: Result<u64, String> = call(&pic, backend, admin, "poll_eth_logs_now", encode_args(()).unwrap());
+    assert!(polled.is_ok());
+    let stats: OutcallStats = query(&pic, backend, admin, "get_outcall_stats");
+
+    // Once today's budget is used up, lookups fail without reaching the gateway
+    let capped = OutcallConfig { daily_budget_cycles: Some(stats.cycles_spent_today), chain_reserve_cycles: 0, subnet_size: 13 };
+    let configured: Result<(), String> = call(&pic, backend, admin, "set_outcall_config", encode_one(capped).unwrap());
+    assert_eq!(configured, Ok(()));
+    let metadata: DocumentMetadata = call(&pic, backend, admin, "get_token_metadata", encode_one(cargox_token("7")).unwrap());
+    match metadata.resolution {
+        MetadataResolution::Unresolved { reason } => assert!(reason.contains("budget")),
+        resolved => panic!("expected the budget to stop the lookup, got {:?}", resolved),
+    }
+    assert_eq!(hits.load(Ordering::SeqCst), requests);
+    let stats: OutcallStats = query(&pic, backend, admin, "get_outcall_stats");
+    assert_eq!(stats.daily_budget_cycles, Some(stats.cycles_spent_today));
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+struct AddressBinding {
+    address: String,
+    owner: Principal,
+    bound_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+struct ClaimedDocument {
+    id: String,
+    acid_number: String,
+    chain: String,
+    contract: String,
+    token_id: String,
+    tx_hash: String,
+    status: DocumentStatus,
+}
+
+#[derive(CandidType, Deserialize, Debug, PartialEq)]
+enum DocumentStatus {
+    Pending,
+    Verified,
+    Rejected,
+    NftMinted,
+}
+
+// EIP-191 personal_sign, as a wallet would produce it
+fn personal_sign(key: &k256::ecdsa::SigningKey, message: &str) -> String {
+    use sha3::{Digest, Keccak256};
+    let prefixed = format!("\x19Ethereum Signed Message:\n{}{}", message.len(), message);
+    let (signature, recovery_id) = key.sign_prehash_recoverable(&Keccak256::digest(prefixed.as_bytes())).unwrap();
+    let bytes: String = signature.to_bytes().iter().map(|b| format!("{:02x}", b)).collect();
+    format!("0x{}{:02x}", bytes, recovery_id.to_byte() + 27)
+}
+
+#[test]
+fn test_transfers_to_a_bound_address_are_claimed_for_its_owner() {
+    let (pic, backend, admin) = setup_backend();
+    let user = Principal::from_slice(&[2; 29]);
+    let intruder = Principal::from_slice(&[3; 29]);
+    setup_evm_rpc_mock(&pic, backend, admin);
+    // Private key 1 controls 0x7e5f...5bdf
+    let mut secret = [0u8; 32];
+    secret[31] = 1;
+    let key = k256::ecdsa::SigningKey::from_slice(&secret).unwrap();
+    let address = "0x7e5f4552091a69125d5dfcb7b8c2659029395bdf".to_string();
+
+    let message: Result<String, CargoTraceError> = call(&pic, backend, user, "request_address_binding", encode_one(address.clone()).unwrap());
+    let message = message.unwrap();
+    assert!(message.contains("0x7E5F4552091A69125d5DfCb7b8C2659029395Bdf"));
+    assert!(message.contains(&user.to_text()));
+
+    // A signature over the message cannot complete someone else's challenge
+    let stolen: Result<AddressBinding, CargoTraceError> = call(&pic, backend, intruder, "bind_eth_address", encode_one(personal_sign(&key, &message)).unwrap());
+    assert!(matches!(stolen, Err(CargoTraceError::NotFound { .. })));
+    let wrong_key = k256::ecdsa::SigningKey::from_slice(&[7; 32]).unwrap();
+    let forged: Result<AddressBinding, CargoTraceError> = call(&pic, backend, user, "bind_eth_address", encode_one(personal_sign(&wrong_key, &message)).unwrap());
+    assert!(matches!(forged, Err(CargoTraceError::InvalidArgument(reason)) if reason.contains("not 0x7e5f")));
+    let bound: Result<AddressBinding, CargoTraceError> = call(&pic, backend, user, "bind_eth_address", encode_one(personal_sign(&key, &message)).unwrap());
+    assert_eq!(bound.unwrap().owner, user);
+    let replayed: Result<AddressBinding, CargoTraceError> = call(&pic, backend, user, "bind_eth_address", encode_one(personal_sign(&key, &message)).unwrap());
+    assert!(matches!(replayed, Err(CargoTraceError::NotFound { .. })));
+
+    // The transaction's transfers are claimed once they are deep enough to be final; each token
+    // gets its own document
+    let transfer = TransferPayload {
+        to: "0x7E5F4552091A69125d5DfCb7b8C2659029395Bdf".to_string(),
+        ..watched_transfer(&tx_hash("claimed"), 20_000_098, Some(mock_block_hash(20_000_098)))
+    };
+    let second_token = TransferPayload { token_id: "10".to_string(), log_index: 1, ..transfer.clone() };
+    let _: IngestOutcome = call(&pic, backend, admin, "ingest_transfer", encode_one(transfer).unwrap());
+    let _: IngestOutcome = call(&pic, backend, admin, "ingest_transfer", encode_one(second_token).unwrap());
+    let mappings: Vec<CargoXMapping> = query(&pic, backend, user, "get_my_cargox_mappings");
+    assert!(mappings.is_empty());
+    let run: Result<FinalityRun, CargoTraceError> = call(&pic, backend, admin, "check_finality_now", encode_args(()).unwrap());
+    assert_eq!(run.unwrap().confirmed, 2);
+
+    let mappings: Vec<CargoXMapping> = query(&pic, backend, user, "get_my_cargox_mappings");
+    assert_eq!(mappings.len(), 2);
+    assert_eq!(mappings[0].nft_hash, tx_hash("claimed"));
+    assert_eq!(mappings[0].acid_number, "");
+    let documents: Vec<ClaimedDocument> = query(&pic, backend, user, "get_my_documents");
+    assert_eq!(documents.len(), 2);
+    assert_eq!(documents[0].tx_hash, tx_hash("claimed"));
+    assert_eq!((documents[0].token_id.as_str(), documents[1].token_id.as_str()), ("9", "10"));
+    assert_eq!(mappings[0].document_id.as_ref(), Some(&documents[0].id));
+    assert_eq!(mappings[1].document_id.as_ref(), Some(&documents[1].id));
+
+    // A transaction that moved two tokens only takes documents that name theirs
+    let unnamed: Result<String, CargoTraceError> = call(&pic, backend, intruder, "submit_document",
+        encode_args(("123456789".to_string(), tx_hash("claimed"), 1_000_000u64)).unwrap());
+    assert!(matches!(unnamed, Err(CargoTraceError::InvalidArgument(_))));
+    let named: Result<String, CargoTraceError> = call(&pic, backend, intruder, "submit_document",
+        encode_args(("123456789".to_string(), tx_hash("claimed"), 1_000_000u64, Some(cargox_token("10")))).unwrap());
+    let named: Option<ClaimedDocument> = call(&pic, backend, intruder, "get_document", encode_one(named.unwrap()).unwrap());
+    assert_eq!(named.unwrap().token_id, "10");
+    let malformed: Result<String, CargoTraceError> = call(&pic, backend, intruder, "submit_document",
+        encode_args(("123456789".to_string(), "0xclaimed|".to_string(), 1_000_000u64)).unwrap());
+    assert!(matches!(malformed, Err(CargoTraceError::InvalidArgument(_))));
+
+    // The owner completes the claim with the ACID; nobody else can
+    let hijacked: Result<String, CargoTraceError> = call(&pic, backend, intruder, "link_cargox_to_acid",
+        encode_args((documents[0].id.clone(), "123456789".to_string())).unwrap());
+    assert_eq!(hijacked, Err(CargoTraceError::Unauthorized));
+    let linked: Result<String, CargoTraceError> = call(&pic, backend, user, "link_cargox_to_acid",
+        encode_args((documents[0].id.clone(), "123456789".to_string())).unwrap());
+    assert_eq!(linked, Ok(mappings[0].id.clone()));
+    let relinked: Result<String, CargoTraceError> = call(&pic, backend, user, "link_cargox_to_acid",
+        encode_args((documents[0].id.clone(), "123456789".to_string())).unwrap());
+    assert_eq!(relinked, Err(CargoTraceError::AlreadyLinked { document_id: documents[0].id.clone() }));
+    let documents: Vec<ClaimedDocument> = query(&pic, backend, user, "get_my_documents");
+    assert_eq!(documents[0].acid_number, "123456789");
+
+    // Sold on to another bound address, the token takes its document with it
+    let buyer = Principal::from_slice(&[4; 29]);
+    let mut secret = [0u8; 32];
+    secret[31] = 2;
+    let buyer_key = k256::ecdsa::SigningKey::from_slice(&secret).unwrap();
+    let buyer_address = "0x2b5ad5c4795c026514f8317c7a215e218dccd6cf".to_string();
+    let message: Result<String, CargoTraceError> = call(&pic, backend, buyer, "request_address_binding", encode_one(buyer_address.clone()).unwrap());
+    let bound: Result<AddressBinding, CargoTraceError> = call(&pic, backend, buyer, "bind_eth_address", encode_one(personal_sign(&buyer_key, &message.unwrap())).unwrap());
+    assert!(bound.is_ok());
+    let resale = TransferPayload {
+        from: address.clone(),
+        to: buyer_address,
+        ..watched_transfer("0xresold", 20_000_098, Some(mock_block_hash(20_000_098)))
+    };
+    let _: IngestOutcome = call(&pic, backend, admin, "ingest_transfer", encode_one(resale).unwrap());
+    let run: Result<FinalityRun, CargoTraceError> = call(&pic, backend, admin, "check_finality_now", encode_args(()).unwrap());
+    assert_eq!(run.unwrap().confirmed, 1);
+    let bought: Vec<ClaimedDocument> = query(&pic, backend, buyer, "get_my_documents");
+    assert_eq!(bought.len(), 1);
+    assert_eq!(bought[0].id, documents[0].id);
+    assert_eq!((bought[0].tx_hash.as_str(), bought[0].acid_number.as_str()), ("0xresold", "123456789"));
+    let kept: Vec<ClaimedDocument> = query(&pic, backend, user, "get_my_documents");
+    assert_eq!(kept.len(), 1);
+    assert_eq!(kept[0].token_id, "10");
+
+    // From an address nobody bound, a token that already has a document is not opened a second time
+    let returned = TransferPayload {
+        from: "0x00000000000000000000000000000000000000aa".to_string(),
+        to: address.clone(),
+        ..watched_transfer("0xreturned", 20_000_099, Some(mock_block_hash(20_000_099)))
+    };
+    let _: IngestOutcome = call(&pic, backend, admin, "ingest_transfer", encode_one(returned).unwrap());
+    let run: Result<FinalityRun, CargoTraceError> = call(&pic, backend, admin, "check_finality_now", encode_args(()).unwrap());
+    assert_eq!(run.unwrap().confirmed, 1);
+    let kept: Vec<ClaimedDocument> = query(&pic, backend, user, "get_my_documents");
+    assert_eq!(kept.len(), 1);
+}
+
+#[test]
+fn test_customs_verification_updates_the_mapped_document() {
+    let (pic, backend, admin) = setup_backend();
+    let owner = Principal::from_slice(&[2; 29]);
+    let other = Principal::from_slice(&[3; 29]);
+    setup_evm_rpc_mock(&pic, backend, admin);
+    let transfer = watched_transfer(&tx_hash("customs"), 20_000_098, Some(mock_block_hash(20_000_098)));
+    let _: IngestOutcome = call(&pic, backend, admin, "ingest_transfer", encode_one(transfer).unwrap());
+    let _: Result<FinalityRun, CargoTraceError> = call(&pic, backend, admin, "check_finality_now", encode_args(()).unwrap());
+
+    // Two owners submit documents for the same transaction; only the linked one is verified
+    let submit = |caller: Principal| -> String {
+        let id: Result<String, CargoTraceError> = call(&pic, backend, caller, "submit_document",
+            encode_args(("123456789".to_string(), tx_hash("customs"), 1_000_000u64)).unwrap());
+        id.unwrap()
+    };
+    let other_id = submit(other);
+    let owner_id = submit(owner);
+    let mapping_id: Result<String, CargoTraceError> = call(&pic, backend, owner, "link_cargox_to_acid",
+        encode_args((owner_id.clone(), "123456789".to_string())).unwrap());
+    let mapping: Option<CargoXMapping> = call(&pic, backend, owner, "get_cargox_mapping", encode_one(owner_id.clone()).unwrap());
+    assert_eq!(mapping.unwrap().id, mapping_id.unwrap());
+
+    let verified: Result<(), CargoTraceError> = call(&pic, backend, admin, "verify_customs_entry", encode_one(owner_id.clone()).unwrap());
+    assert!(verified.is_ok());
+    // A settled entry cannot be reviewed again
+    let rejected: Result<(), CargoTraceError> = call(&pic, backend, admin, "reject_customs_entry",
+        encode_args((owner_id.clone(), "late".to_string())).unwrap());
+    assert_eq!(rejected, Err(CargoTraceError::InvalidState { from: "Verified".to_string(), to: "Rejected".to_string() }));
+    let document: Option<ClaimedDocument> = call(&pic, backend, owner, "get_document", encode_one(owner_id.clone()).unwrap());
+    let document = document.unwrap();
+    assert_eq!(document.id, owner_id);
+    assert_eq!(document.status, DocumentStatus::Verified);
+    assert_eq!((document.chain.as_str(), document.token_id.as_str()), ("ethereum", "9"));
+    assert!(document.contract.eq_ignore_ascii_case("0xd4190DD1dA460fC7Bc41a792e688604778820aC9"));
+    let untouched: Option<ClaimedDocument> = call(&pic, backend, other, "get_document", encode_one(other_id).unwrap());
+    assert_eq!(untouched.unwrap().status, DocumentStatus::Pending);
+}
+
+// ---- Paged listings ----
+
+#[derive(CandidType, Default)]
+struct ListFilter {
+    status: Option<String>,
+    owner: Option<Principal>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+struct IdPage {
+    items: Vec<String>,
+    next_cursor: Option<String>,
+}
+
+#[test]
+fn test_loan_ids_are_paged_with_filters() {
+    let (pic, backend, admin) = setup_backend();
+    let first = Principal::from_slice(&[2; 29]);
+    let second = Principal::from_slice(&[3; 29]);
+    grant_borrower(&pic, backend, admin, first);
+    grant_borrower(&pic, backend, admin, second);
+    let mut expected = Vec::new();
+    for borrower in [first, first, second, first, second] {
+        let document_id: Result<String, CargoTraceError> = call(&pic, backend, borrower, "submit_document",
+            encode_args(("123456789".to_string(), tx_hash("abc"), 1_000_000u64)).unwrap());
+        let document_id = document_id.unwrap();
+        let _: Result<(), CargoTraceError> = call(&pic, backend, admin, "approve_document", encode_one(document_id.clone()).unwrap());
+        let loan_id: Result<String, CargoTraceError> = call(&pic, backend, borrower, "request_loan",
+            encode_args((document_id, 500_000u64, nanos_from_now(&pic, DAY * LOAN_TERM_DAYS))).unwrap());
+        expected.push((borrower, loan_id.unwrap()));
+    }
+
+    // Document owners without the Borrower role cannot request loans
+    let outsider = Principal::from_slice(&[4; 29]);
+    let document_id: Result<String, CargoTraceError> = call(&pic, backend, outsider, "submit_document",
+        encode_args(("123456789".to_string(), tx_hash("abc"), 1_000_000u64)).unwrap());
+    let document_id = document_id.unwrap();
+    let _: Result<(), CargoTraceError> = call(&pic, backend, admin, "approve_document", encode_one(document_id.clone()).unwrap());
+    let refused = pic.update_call(backend, outsider, "request_loan",
+        encode_args((document_id, 500_000u64, nanos_from_now(&pic, DAY * LOAN_TERM_DAYS))).unwrap());
+    assert!(matches!(refused, Ok(WasmResult::Reject(_))));
+
+    let list = |filter: &ListFilter, cursor: Option<String>| -> IdPage {
+        call(&pic, backend, admin, "get_all_loan_ids", encode_args((filter, cursor, Some(2u32))).unwrap())
+    };
+    let mut cursor = None;
+    let mut pages = Vec::new();
+    loop {
+        let page = list(&ListFilter::default(), cursor);
+        assert!(page.items.len() <= 2);
+        pages.push(page.items);
+        cursor = page.next_cursor;
+        if cursor.is_none() {
+            break;
+        }
+    }
+    let mut all: Vec<String> = pages.concat();
+    let mut ids: Vec<String> = expected.iter().map(|(_, id)| id.clone()).collect();
+    all.sort();
+    ids.sort();
+    assert_eq!(all, ids);
+    assert_eq!(pages.len(), 3);
+
+    let by_owner = list(&ListFilter { owner: Some(second), ..Default::default() }, None);
+    let mut seconds: Vec<String> = expected.iter().filter(|(b, _)| *b == second).map(|(_, id)| id.clone()).collect();
+    seconds.sort();
+    assert_eq!((by_owner.items, by_owner.next_cursor), (seconds, None));
+
+    let active = list(&ListFilter { status: Some("active".to_string()), ..Default::default() }, None);
+    assert!(active.items.is_empty() && active.next_cursor.is_none());
+}
+
+// ---- Index benchmark ----
+
+#[derive(CandidType, Deserialize, Debug)]
+struct LookupBench {
+    lookup: String,
+    records: u64,
+    matches: u64,
+    scan_cost: u64,
+    indexed_cost: u64,
+}
+
+// Needs the benchmark endpoints:
+// `cargo build --target wasm32-unknown-unknown --release --features bench`
+#[test]
+#[ignore]
+fn test_secondary_index_benchmark_at_100k_records() {
+    const RECORDS: u64 = 100_000;
+    const BATCH: u64 = 500;
+    let (pic, backend, admin) = setup_backend();
+    for start in (0..RECORDS).step_by(BATCH as usize) {
+        let _: u64 = call(&pic, backend, admin, "bench_seed", encode_args((start, BATCH)).unwrap());
+    }
+
+    println!("{:<36} {:>8} {:>16} {:>16}", "lookup", "matches", "scan", "indexed");
+    for lookup in [
+        "get_my_documents",
+        "get_my_loans",
+        "get_active_loan",
+        "get_my_cargox_mappings",
+        "get_pending_customs_verifications",
+        "documents_for_tx",
+    ] {
+        let bench: Result<LookupBench, String> = call(&pic, backend, admin, "bench_lookup", encode_one(lookup.to_string()).unwrap());
+        let bench = bench.unwrap();
+        println!("{:<36} {:>8} {:>16} {:>16}", bench.lookup, bench.matches, bench.scan_cost, bench.indexed_cost);
+        assert_eq!(bench.records, RECORDS);
+        // A tenth of the verifications are pending, which bounds the saving for that lookup
+        assert!(bench.indexed_cost * 2 < bench.scan_cost, "{} is not served by its index", lookup);
+    }
+}
@@ -1,6 +1,11 @@
 import { ethers } from "ethers";
 
-const WS_RPC_URL = "wss://eth-mainnet.g.alchemy.com/v2/rx3izQBwsCvFk3McMgI3P";
+// e.g. wss://eth-mainnet.g.alchemy.com/v2/<key>
+const WS_RPC_URL = process.env.WS_RPC_URL;
+if (!WS_RPC_URL) {
+  console.error("WS_RPC_URL must be set");
+  process.exit(1);
+}
 const provider = new ethers.WebSocketProvider(WS_RPC_URL);
 
 // Listen for errors
@@ -4,16 +4,32 @@ import fetch from "node-fetch";
 import pkg from "@dfinity/agent";
 const { HttpAgent, Actor } = pkg;
 import { IDL } from "@dfinity/candid";
+import { Secp256k1KeyIdentity } from "@dfinity/identity-secp256k1";
 import fs from "fs";
 import path from "path";
 import { fileURLToPath } from "url";
 
 
 // ---- CONFIG ----
-const WS_RPC_URL = "wss://eth-mainnet.g.alchemy.com/v2/rx3izQBwsCvFk3McMgI3P";
-const CONTRACT_ADDRESS = "0xd4190DD1dA460fC7Bc41a792e688604778820aC9";
-const ICP_BACKEND_URL = "http://127.0.0.1:4943";
-const CANISTER_ID = "uxrrr-q7777-77774-qaaaq-cai";
+// Contracts and the Transfer topic come from the canister's `get_watcher_config`; only the
+// endpoints and credentials are set here
+const WS_RPC_URL = process.env.WS_RPC_URL; // e.g. wss://eth-mainnet.g.alchemy.com/v2/<key>
+const CANISTER_ID = process.env.CANISTER_ID; // `dfx canister id cargo_trace_backend`
+const ICP_BACKEND_URL = process.env.ICP_BACKEND_URL ?? "http://127.0.0.1:4943";
+// Watches the configured contracts on this network; WS_RPC_URL must point at the same network
+const NETWORK = process.env.NETWORK ?? "ethereum";
+// The canister checks finality on Ethereum itself and takes transfers on other networks as final,
+// so there each log waits this many blocks and is dropped if its block was replaced meanwhile
+const CONFIRMATIONS = Number(process.env.CONFIRMATIONS ?? (NETWORK === "ethereum" ? 0 : 128));
+// PEM of an identity holding the Watcher role (`dfx identity export <name>`)
+const WATCHER_IDENTITY_PEM = process.env.WATCHER_IDENTITY_PEM ?? "watcher.pem";
+
+for (const [name, value] of Object.entries({ WS_RPC_URL, CANISTER_ID })) {
+  if (!value) {
+    console.error(`${name} must be set`);
+    process.exit(1);
+  }
+}
 
 // ---- ESM __dirname fix ----
 const __filename = fileURLToPath(import.meta.url);
@@ -34,15 +50,34 @@ async function getBackendActor() {
     from: IDL.Text,
     to: IDL.Text,
     log_index: IDL.Nat64,
+    block_hash: IDL.Opt(IDL.Text),
+  });
+  const IngestOutcome = IDL.Variant({
+    New: IDL.Null,
+    Duplicate: IDL.Null,
+    Rejected: IDL.Text,
+  });
+
+  const WatchedContract = IDL.Record({
+    network: IDL.Text,
+    address: IDL.Text,
+    start_block: IDL.Nat64,
+  });
+  const WatcherConfig = IDL.Record({
+    contracts: IDL.Vec(WatchedContract),
+    transfer_topic: IDL.Text,
   });
 
   const idlFactory = ({ IDL }) =>
     IDL.Service({
-      ingest_transfer: IDL.Func([TransferPayload], [], []),
-      get_transfers: IDL.Func([], [IDL.Vec(TransferPayload)], ["query"]),
+      ingest_transfer: IDL.Func([TransferPayload], [IngestOutcome], []),
+      get_watcher_config: IDL.Func([], [WatcherConfig], ["query"]),
     });
 
-  const agent = new HttpAgent({ host: ICP_BACKEND_URL });
+  const identity = Secp256k1KeyIdentity.fromPem(
+    fs.readFileSync(WATCHER_IDENTITY_PEM, "utf8")
+  );
+  const agent = new HttpAgent({ host: ICP_BACKEND_URL, identity });
   await agent.fetchRootKey();
 
   return Actor.createActor(idlFactory, { agent, canisterId: CANISTER_ID });
@@ -58,8 +93,12 @@ async function sendToICP(payload) {
     payload.tx_hash = payload.tx_hash ?? "unknown_tx";
 
     const backend = await getBackendActor();
-    await backend.ingest_transfer(payload);
-    console.log("Sent to ICP canister:", payload.tx_hash);
+    const outcome = await backend.ingest_transfer(payload);
+    if ("Rejected" in outcome) {
+      console.warn("ICP canister rejected", payload.tx_hash, outcome.Rejected);
+    } else {
+      console.log("Sent to ICP canister:", payload.tx_hash, Object.keys(outcome)[0]);
+    }
   } catch (err) {
     console.error("Error sending to ICP:", err);
   }
@@ -84,8 +123,34 @@ const ERC721_ABI = [
   "event Transfer(address indexed from, address indexed to, uint256 indexed tokenId)",
 ];
 
+const erc721 = new ethers.Interface(ERC721_ABI);
+
 let provider;
-let contract;
+let watched; // { contracts, filter } for NETWORK
+
+// ---- Load watched contracts from the canister ----
+async function loadWatchedContracts() {
+  const backend = await getBackendActor();
+  const config = await backend.get_watcher_config();
+  const contracts = config.contracts.filter((c) => c.network === NETWORK);
+  if (contracts.length === 0) {
+    throw new Error(`No contracts are watched on ${NETWORK}`);
+  }
+  return {
+    contracts,
+    filter: {
+      address: contracts.map((c) => c.address),
+      topics: [config.transfer_topic],
+    },
+  };
+}
+
+function startBlockOf(address) {
+  const entry = watched.contracts.find(
+    (c) => c.address.toLowerCase() === address.toLowerCase()
+  );
+  return entry ? Number(entry.start_block) : Infinity;
+}
 
 // ---- Catch up events in chunks ----
 async function catchUpEvents(fromBlock) {
@@ -97,14 +162,14 @@ async function catchUpEvents(fromBlock) {
     console.log(`Querying events from block ${start} to ${end}...`);
 
     try {
-      const events = await contract.queryFilter(
-        contract.filters.Transfer(),
-        start,
-        end
-      );
-
-      for (const event of events) {
-        await processEvent(event);
+      const logs = await provider.getLogs({
+        ...watched.filter,
+        fromBlock: start,
+        toBlock: end,
+      });
+
+      for (const log of logs) {
+        await processLog(log);
       }
 
       saveLastBlock(end);
@@ -130,40 +195,63 @@ async function startWatcher() {
     setTimeout(startWatcher, 5000);
   });
 
-  contract = new ethers.Contract(CONTRACT_ADDRESS, ERC721_ABI, provider);
+  watched = await loadWatchedContracts();
+  console.log(
+    `Watching ${watched.contracts.map((c) => c.address).join(", ")} on ${NETWORK}`
+  );
 
   const lastBlock = loadLastBlock();
-  const fromBlock = lastBlock ? lastBlock + 1 : await provider.getBlockNumber();
+  const fromBlock = lastBlock
+    ? lastBlock + 1
+    : Math.max(
+        await provider.getBlockNumber(),
+        Math.min(...watched.contracts.map((c) => Number(c.start_block)))
+      );
   console.log("Catching up from block:", fromBlock);
 
   await catchUpEvents(fromBlock);
 
   // ---- Listen to new events ----
-  contract.on("Transfer", async (from, to, tokenId, event) => {
-    await processEvent(event);
+  provider.on(watched.filter, async (log) => {
+    await processLog(log);
   });
 }
 
-// ---- Process a single event ----
-async function processEvent(event) {
-  if (!event || !event.args) return;
+// ---- Process a single log ----
+async function processLog(log) {
+  if (!log || log.blockNumber < startBlockOf(log.address)) return;
 
-  const { from, to, tokenId } = event.args;
-
-  if (!event.transactionHash) {
+  if (!log.transactionHash) {
     console.warn("Skipping event with undefined transaction hash");
     return;
   }
 
+  if (CONFIRMATIONS > 0) {
+    const receipt = await provider.waitForTransaction(log.transactionHash, CONFIRMATIONS);
+    if (!receipt || receipt.blockHash !== log.blockHash) {
+      console.warn("Skipping transfer whose block was replaced:", log.transactionHash);
+      return;
+    }
+  }
+
+  let parsed;
+  try {
+    parsed = erc721.decodeEventLog("Transfer", log.data, log.topics);
+  } catch {
+    return; // e.g. an ERC-20 Transfer, whose amount is not indexed
+  }
+  const { from, to, tokenId } = parsed;
+
   const payload = {
-    network: "ethereum",
-    contract: CONTRACT_ADDRESS,
-    tx_hash: event.transactionHash,
-    block_number: event.blockNumber ?? 0,
+    network: NETWORK,
+    contract: log.address,
+    tx_hash: log.transactionHash,
+    block_number: log.blockNumber ?? 0,
     token_id: tokenId?.toString() ?? "0",
     from: from ?? "unknown",
     to: to ?? "unknown",
-    log_index: event.logIndex ?? 0,
+    log_index: log.index ?? 0,
+    block_hash: log.blockHash ? [log.blockHash] : [],
   };
 
   console.log(
@@ -172,7 +260,7 @@ async function processEvent(event) {
     `from ${from} to ${to} (token ${payload.token_id})`
   );
   await sendToICP(payload);
-  saveLastBlock(event.blockNumber);
+  saveLastBlock(log.blockNumber);
 }
 
 // ---- Start watcher ----